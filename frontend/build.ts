@@ -41,6 +41,7 @@ async function copyToBackend() {
     path.join(backenddir, "index.js"),
   );
   await copyFile("src/style.css", path.join(backenddir, "style.css"));
+  await copyFile("src/book.html", path.join(backenddir, "book.html"));
 }
 
 bundleProject().catch(console.error);
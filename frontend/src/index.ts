@@ -109,6 +109,22 @@ class PollingService {
   }
 }
 
+// every tab scans its own book, also after the book page goes back
+const bookId = (): string => {
+  let id = sessionStorage.getItem("bookId");
+  if (id === null) {
+    const bytes = crypto.getRandomValues(new Uint8Array(16));
+    id = Array.from(bytes, (b) => b.toString(16).padStart(2, "0")).join("");
+    sessionStorage.setItem("bookId", id);
+  }
+  return id;
+};
+
+const bookIdInput = document.getElementById("book_id");
+if (bookIdInput instanceof HTMLInputElement) {
+  bookIdInput.value = bookId();
+}
+
 const pollingService = new PollingService(updateStatus);
 pollingService.setupVisibilityChangeListener();
 pollingService.startPolling();
@@ -0,0 +1,167 @@
+use bytes::Bytes;
+use tracing::{debug, info};
+
+use crate::deskew;
+use crate::jpeg::{self, ParseError};
+use crate::raster::{GrayImage, Rect};
+
+/// Resolution in dpi at which the gutter is searched
+const ANALYSIS_RESOLUTION: u32 = 75;
+/// The gutter is searched in this part of the width of the book around its center
+const SEARCH_RANGE: f64 = 0.3;
+/// Smaller differences between pixels above each other are no edges
+const EDGE_CONTRAST: u8 = 32;
+/// Width in millimeters of the strip which has to be free of text
+const STRIP_WIDTH: f64 = 3.0;
+
+/// The order of the two pages of an open book
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadingOrder {
+    /// The left page comes first
+    LeftToRight,
+    /// The right page comes first, like in Arabic, Hebrew or Japanese books
+    RightToLeft,
+}
+
+/// Splits the scan of an open book at the gutter into its two pages in
+/// reading order
+pub fn split(data: Bytes, resolution: u32, order: ReadingOrder) -> Result<Vec<Bytes>, ParseError> {
+    let image = jpeg::decode_gray(data.clone())?;
+    if image.width < 2 {
+        return Err(format!("image of width {} cannot be split", image.width).into());
+    }
+    let gutter = find_gutter(&image, resolution);
+    info!("Split book at column {gutter} of {}", image.width);
+    pages(image.width, image.height, gutter, order)
+        .into_iter()
+        .map(|rect| jpeg::rotate_and_crop(data.clone(), 0.0, rect))
+        .collect()
+}
+
+/// Returns the areas of the pages of a book in reading order
+fn pages(width: usize, height: usize, gutter: usize, order: ReadingOrder) -> [Rect; 2] {
+    let left = Rect {
+        x: 0,
+        y: 0,
+        width: gutter,
+        height,
+    };
+    let right = Rect {
+        x: gutter,
+        y: 0,
+        width: width - gutter,
+        height,
+    };
+    match order {
+        ReadingOrder::LeftToRight => [left, right],
+        ReadingOrder::RightToLeft => [right, left],
+    }
+}
+
+/// Returns the column between the pages. It is the strip around the center of
+/// the book with the fewest edges, which are caused by text and pictures,
+/// while the gutter is blank or covered by the smooth shadow of the binding.
+pub fn find_gutter(image: &GrayImage, resolution: u32) -> usize {
+    let factor = (resolution / ANALYSIS_RESOLUTION).max(1) as usize;
+    let small = image.downscale(factor);
+    let pixels_per_mm = f64::from(resolution) / factor as f64 / 25.4;
+    let background = small.border_median();
+    // a book which is smaller than the glass is searched where it lies
+    let book = deskew::content_rect(&small, background, 0.0, pixels_per_mm).unwrap_or(Rect {
+        x: 0,
+        y: 0,
+        width: small.width,
+        height: small.height,
+    });
+    // the edges of the book at the top and bottom do not count
+    let rows = book.y + book.height / 10..book.y + book.height * 9 / 10;
+    let edges: Vec<usize> = (0..small.width)
+        .map(|x| {
+            (rows.start.max(1)..rows.end)
+                .filter(|y| small.get(x, *y).abs_diff(small.get(x, y - 1)) > EDGE_CONTRAST)
+                .count()
+        })
+        .collect();
+    let half_strip = (STRIP_WIDTH * pixels_per_mm / 2.0).round() as usize;
+    let strip_edges = |x: usize| -> usize {
+        edges[x.saturating_sub(half_strip)..(x + half_strip + 1).min(small.width)]
+            .iter()
+            .sum()
+    };
+    let center = book.x + book.width / 2;
+    let range = (book.width as f64 * SEARCH_RANGE / 2.0) as usize;
+    let first = center.saturating_sub(range).max(1);
+    let last = (center + range).min(small.width - 1);
+    let fewest = (first..=last).map(strip_edges).min().unwrap_or(0);
+    // the gutter is in the middle of the widest gap between the pages, if
+    // there are several, the one closest to the center is taken
+    let mut gaps: Vec<(usize, usize)> = Vec::new();
+    for x in (first..=last).filter(|x| strip_edges(*x) == fewest) {
+        match gaps.last_mut() {
+            Some((_, end)) if *end + 1 == x => *end = x,
+            _ => gaps.push((x, x)),
+        }
+    }
+    let gutter = gaps
+        .iter()
+        .map(|(start, end)| (start + end) / 2)
+        .min_by_key(|x| x.abs_diff(center))
+        .unwrap_or(center);
+    debug!("Gutter with {fewest} edges between columns {first} and {last}");
+    (gutter * factor + factor / 2).clamp(1, image.width - 1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// An open book at 75 dpi with lines of text on both pages, which are
+    /// separated by a shadow
+    fn open_book(gutter: usize) -> GrayImage {
+        let mut image = GrayImage::new(870, 620, 250);
+        for y in 60..560 {
+            for x in 40..830usize {
+                let distance = x.abs_diff(gutter);
+                let value = if distance < 12 {
+                    120 + 10 * distance as u8
+                } else if distance > 30 && y % 12 < 5 && x % 7 != 0 {
+                    20
+                } else {
+                    240
+                };
+                image.set(x, y, value);
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn find_gutter_of_book() {
+        for gutter in [400, 435, 470] {
+            let found = find_gutter(&open_book(gutter), 75);
+            assert!(found.abs_diff(gutter) <= 2, "{found} instead of {gutter}");
+        }
+    }
+
+    #[test]
+    fn split_pages_in_reading_order() {
+        // pages of 120 blocks of text with a gap of 30 blocks between them
+        let spread = jpeg::test_page(|x, y| {
+            ((20..140).contains(&x) || (170..290).contains(&x)) && y % 3 == 0
+        });
+        let pages = split(spread, 300, ReadingOrder::LeftToRight).unwrap();
+        let widths: Vec<usize> = pages
+            .iter()
+            .map(|page| jpeg::decode_gray(page.clone()).unwrap().width)
+            .collect();
+        assert_eq!(widths.iter().sum::<usize>(), 2480);
+        assert!(widths[0] >= 1120 && widths[0] <= 1360, "{widths:?}");
+    }
+
+    #[test]
+    fn order_pages() {
+        let [first, second] = pages(100, 50, 40, ReadingOrder::RightToLeft);
+        assert_eq!((first.x, first.width), (40, 60));
+        assert_eq!((second.x, second.width), (0, 40));
+    }
+}
@@ -0,0 +1,399 @@
+use crate::raster::GrayImage;
+
+/// Codes of white runs of 0 to 63 pixels
+const WHITE_TERMINATING: [(u16, u8); 64] = [
+    (0b00110101, 8),
+    (0b000111, 6),
+    (0b0111, 4),
+    (0b1000, 4),
+    (0b1011, 4),
+    (0b1100, 4),
+    (0b1110, 4),
+    (0b1111, 4),
+    (0b10011, 5),
+    (0b10100, 5),
+    (0b00111, 5),
+    (0b01000, 5),
+    (0b001000, 6),
+    (0b000011, 6),
+    (0b110100, 6),
+    (0b110101, 6),
+    (0b101010, 6),
+    (0b101011, 6),
+    (0b0100111, 7),
+    (0b0001100, 7),
+    (0b0001000, 7),
+    (0b0010111, 7),
+    (0b0000011, 7),
+    (0b0000100, 7),
+    (0b0101000, 7),
+    (0b0101011, 7),
+    (0b0010011, 7),
+    (0b0100100, 7),
+    (0b0011000, 7),
+    (0b00000010, 8),
+    (0b00000011, 8),
+    (0b00011010, 8),
+    (0b00011011, 8),
+    (0b00010010, 8),
+    (0b00010011, 8),
+    (0b00010100, 8),
+    (0b00010101, 8),
+    (0b00010110, 8),
+    (0b00010111, 8),
+    (0b00101000, 8),
+    (0b00101001, 8),
+    (0b00101010, 8),
+    (0b00101011, 8),
+    (0b00101100, 8),
+    (0b00101101, 8),
+    (0b00000100, 8),
+    (0b00000101, 8),
+    (0b00001010, 8),
+    (0b00001011, 8),
+    (0b01010010, 8),
+    (0b01010011, 8),
+    (0b01010100, 8),
+    (0b01010101, 8),
+    (0b00100100, 8),
+    (0b00100101, 8),
+    (0b01011000, 8),
+    (0b01011001, 8),
+    (0b01011010, 8),
+    (0b01011011, 8),
+    (0b01001010, 8),
+    (0b01001011, 8),
+    (0b00110010, 8),
+    (0b00110011, 8),
+    (0b00110100, 8),
+];
+/// Codes of white runs of 64 to 1728 pixels in steps of 64
+const WHITE_MAKEUP: [(u16, u8); 27] = [
+    (0b11011, 5),
+    (0b10010, 5),
+    (0b010111, 6),
+    (0b0110111, 7),
+    (0b00110110, 8),
+    (0b00110111, 8),
+    (0b01100100, 8),
+    (0b01100101, 8),
+    (0b01101000, 8),
+    (0b01100111, 8),
+    (0b011001100, 9),
+    (0b011001101, 9),
+    (0b011010010, 9),
+    (0b011010011, 9),
+    (0b011010100, 9),
+    (0b011010101, 9),
+    (0b011010110, 9),
+    (0b011010111, 9),
+    (0b011011000, 9),
+    (0b011011001, 9),
+    (0b011011010, 9),
+    (0b011011011, 9),
+    (0b010011000, 9),
+    (0b010011001, 9),
+    (0b010011010, 9),
+    (0b011000, 6),
+    (0b010011011, 9),
+];
+/// Codes of black runs of 0 to 63 pixels
+const BLACK_TERMINATING: [(u16, u8); 64] = [
+    (0b0000110111, 10),
+    (0b010, 3),
+    (0b11, 2),
+    (0b10, 2),
+    (0b011, 3),
+    (0b0011, 4),
+    (0b0010, 4),
+    (0b00011, 5),
+    (0b000101, 6),
+    (0b000100, 6),
+    (0b0000100, 7),
+    (0b0000101, 7),
+    (0b0000111, 7),
+    (0b00000100, 8),
+    (0b00000111, 8),
+    (0b000011000, 9),
+    (0b0000010111, 10),
+    (0b0000011000, 10),
+    (0b0000001000, 10),
+    (0b00001100111, 11),
+    (0b00001101000, 11),
+    (0b00001101100, 11),
+    (0b00000110111, 11),
+    (0b00000101000, 11),
+    (0b00000010111, 11),
+    (0b00000011000, 11),
+    (0b000011001010, 12),
+    (0b000011001011, 12),
+    (0b000011001100, 12),
+    (0b000011001101, 12),
+    (0b000001101000, 12),
+    (0b000001101001, 12),
+    (0b000001101010, 12),
+    (0b000001101011, 12),
+    (0b000011010010, 12),
+    (0b000011010011, 12),
+    (0b000011010100, 12),
+    (0b000011010101, 12),
+    (0b000011010110, 12),
+    (0b000011010111, 12),
+    (0b000001101100, 12),
+    (0b000001101101, 12),
+    (0b000011011010, 12),
+    (0b000011011011, 12),
+    (0b000001010100, 12),
+    (0b000001010101, 12),
+    (0b000001010110, 12),
+    (0b000001010111, 12),
+    (0b000001100100, 12),
+    (0b000001100101, 12),
+    (0b000001010010, 12),
+    (0b000001010011, 12),
+    (0b000000100100, 12),
+    (0b000000110111, 12),
+    (0b000000111000, 12),
+    (0b000000100111, 12),
+    (0b000000101000, 12),
+    (0b000001011000, 12),
+    (0b000001011001, 12),
+    (0b000000101011, 12),
+    (0b000000101100, 12),
+    (0b000001011010, 12),
+    (0b000001100110, 12),
+    (0b000001100111, 12),
+];
+/// Codes of black runs of 64 to 1728 pixels in steps of 64
+const BLACK_MAKEUP: [(u16, u8); 27] = [
+    (0b0000001111, 10),
+    (0b000011001000, 12),
+    (0b000011001001, 12),
+    (0b000001011011, 12),
+    (0b000000110011, 12),
+    (0b000000110100, 12),
+    (0b000000110101, 12),
+    (0b0000001101100, 13),
+    (0b0000001101101, 13),
+    (0b0000001001010, 13),
+    (0b0000001001011, 13),
+    (0b0000001001100, 13),
+    (0b0000001001101, 13),
+    (0b0000001110010, 13),
+    (0b0000001110011, 13),
+    (0b0000001110100, 13),
+    (0b0000001110101, 13),
+    (0b0000001110110, 13),
+    (0b0000001110111, 13),
+    (0b0000001010010, 13),
+    (0b0000001010011, 13),
+    (0b0000001010100, 13),
+    (0b0000001010101, 13),
+    (0b0000001011010, 13),
+    (0b0000001011011, 13),
+    (0b0000001100100, 13),
+    (0b0000001100101, 13),
+];
+/// Codes of runs of either color of 1792 to 2560 pixels in steps of 64
+const EXTENDED_MAKEUP: [(u16, u8); 13] = [
+    (0b00000001000, 11),
+    (0b00000001100, 11),
+    (0b00000001101, 11),
+    (0b000000010010, 12),
+    (0b000000010011, 12),
+    (0b000000010100, 12),
+    (0b000000010101, 12),
+    (0b000000010110, 12),
+    (0b000000010111, 12),
+    (0b000000011100, 12),
+    (0b000000011101, 12),
+    (0b000000011110, 12),
+    (0b000000011111, 12),
+];
+
+/// Mode codes of two-dimensional coding
+const PASS: (u16, u8) = (0b0001, 4);
+const HORIZONTAL: (u16, u8) = (0b001, 3);
+/// Codes of the vertical modes for offsets of -3 to 3 between `a1` and `b1`
+const VERTICAL: [(u16, u8); 7] = [
+    (0b0000010, 7),
+    (0b000010, 6),
+    (0b010, 3),
+    (0b1, 1),
+    (0b011, 3),
+    (0b000011, 6),
+    (0b0000011, 7),
+];
+/// End of facsimile block, two EOL codes
+const EOFB: (u16, u8) = (0b0000_0000_0001, 12);
+
+/// Encodes the image with CCITT Group 4 (T.6) compression. Pixels up to and
+/// including the threshold are black, all others white.
+pub fn encode_g4(image: &GrayImage, threshold: u8) -> Vec<u8> {
+    let mut writer = BitWriter::default();
+    // the line above the first one is white
+    let mut reference: Vec<usize> = Vec::new();
+    for y in 0..image.height {
+        let coding = changes(image, y, threshold);
+        encode_line(&mut writer, &coding, &reference, image.width);
+        reference = coding;
+    }
+    writer.write(EOFB);
+    writer.write(EOFB);
+    writer.finish()
+}
+
+/// Returns the positions of the pixels of the row whose color differs from
+/// the pixel before. The first pixel is compared with white, so the changes
+/// at even indices start black runs.
+fn changes(image: &GrayImage, y: usize, threshold: u8) -> Vec<usize> {
+    let mut changes = Vec::new();
+    let mut black = false;
+    for x in 0..image.width {
+        if (image.get(x, y) <= threshold) != black {
+            black = !black;
+            changes.push(x);
+        }
+    }
+    changes
+}
+
+fn encode_line(writer: &mut BitWriter, coding: &[usize], reference: &[usize], width: usize) {
+    // a0 starts on an imaginary white pixel before the line
+    let mut a0: Option<usize> = None;
+    let mut black = false;
+    // the first change after a0, for a1 only changes to the other color
+    let next = |line: &[usize], a0: Option<usize>, to_black: Option<bool>| -> (usize, usize) {
+        let mut index = line.partition_point(|x| a0.is_some_and(|a0| *x <= a0));
+        if to_black.is_some_and(|black| (index % 2 == 0) != black) {
+            index += 1;
+        }
+        let first = line.get(index).copied().unwrap_or(width);
+        let second = line.get(index + 1).copied().unwrap_or(width);
+        (first, second)
+    };
+    while a0.is_none_or(|a0| a0 < width) {
+        let (a1, a2) = next(coding, a0, None);
+        let (b1, b2) = next(reference, a0, Some(!black));
+        if b2 < a1 {
+            writer.write(PASS);
+            a0 = Some(b2);
+        } else if a1.abs_diff(b1) <= 3 {
+            writer.write(VERTICAL[(a1 as isize - b1 as isize + 3) as usize]);
+            a0 = Some(a1);
+            black = !black;
+        } else {
+            writer.write(HORIZONTAL);
+            write_run(writer, a1 - a0.unwrap_or(0), black);
+            write_run(writer, a2 - a1, !black);
+            a0 = Some(a2);
+        }
+    }
+}
+
+/// Writes the makeup codes and the terminating code of a run
+fn write_run(writer: &mut BitWriter, mut length: usize, black: bool) {
+    let (terminating, makeup) = if black {
+        (&BLACK_TERMINATING, &BLACK_MAKEUP)
+    } else {
+        (&WHITE_TERMINATING, &WHITE_MAKEUP)
+    };
+    while length > 2560 {
+        writer.write(EXTENDED_MAKEUP[12]);
+        length -= 2560;
+    }
+    if length >= 1792 {
+        writer.write(EXTENDED_MAKEUP[(length - 1792) / 64]);
+        length %= 64;
+    } else if length >= 64 {
+        writer.write(makeup[length / 64 - 1]);
+        length %= 64;
+    }
+    writer.write(terminating[length]);
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    count: u8,
+}
+
+impl BitWriter {
+    fn write(&mut self, (code, len): (u16, u8)) {
+        self.buffer = (self.buffer << len) | u32::from(code);
+        self.count += len;
+        while self.count >= 8 {
+            self.count -= 8;
+            self.bytes.push((self.buffer >> self.count) as u8);
+        }
+        self.buffer &= (1 << self.count) - 1;
+    }
+
+    /// Pads the last byte with 0 bits
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.bytes.push((self.buffer << (8 - self.count)) as u8);
+        }
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Decodes the image into rows of true for black pixels
+    fn decode(data: &[u8], width: usize, height: usize) -> Vec<Vec<bool>> {
+        let mut rows = Vec::new();
+        fax::decoder::decode_g4(
+            data.iter().copied(),
+            width as u16,
+            Some(height as u16),
+            |line| {
+                rows.push(
+                    fax::decoder::pels(line, width as u16)
+                        .map(|color| color == fax::Color::Black)
+                        .collect(),
+                );
+            },
+        )
+        .unwrap();
+        rows
+    }
+
+    fn rows(image: &GrayImage) -> Vec<Vec<bool>> {
+        (0..image.height)
+            .map(|y| (0..image.width).map(|x| image.get(x, y) == 0).collect())
+            .collect()
+    }
+
+    #[test]
+    fn encode_white_page() {
+        let image = GrayImage::new(2480, 3, 255);
+        let data = encode_g4(&image, 128);
+        // three V0 codes and the end of block
+        assert_eq!(data, vec![0xe0, 0x02, 0x00, 0x20]);
+        assert_eq!(decode(&data, 2480, 3), rows(&image));
+    }
+
+    #[test]
+    fn encode_text_and_long_runs() {
+        let (width, height) = (2600, 60);
+        let mut image = GrayImage::new(width, height, 255);
+        for y in 0..height {
+            for x in 0..width {
+                // lines of letters, which shift from row to row, and long bars
+                let letter = (x + y / 3) % 11 < 4 && y % 20 < 12;
+                let bar = y % 20 == 15 && x > y * 7;
+                let column = x == width - 1 && y % 2 == 0;
+                if letter || bar || column {
+                    image.set(x, y, 0);
+                }
+            }
+        }
+        image.set(0, 0, 0);
+        let data = encode_g4(&image, 128);
+        assert_eq!(decode(&data, width, height), rows(&image));
+    }
+}
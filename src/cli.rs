@@ -1,5 +1,5 @@
 use clap::builder::TypedValueParser as _;
-use clap::{Parser, ValueEnum};
+use clap::{ArgGroup, Parser, Subcommand, ValueEnum};
 use serde::Deserialize;
 use std::path::PathBuf;
 
@@ -11,16 +11,123 @@ pub enum Source {
     Glass,
 }
 
+/// The resolutions and compression qualities offered by the web interface
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QualityProfile {
+    Base,
+    High,
+    Best,
+}
+
+impl QualityProfile {
+    pub const ALL: [Self; 3] = [Self::Base, Self::High, Self::Best];
+
+    pub fn resolution(self) -> u32 {
+        match self {
+            Self::Base => 300,
+            Self::High => 600,
+            Self::Best => 600,
+        }
+    }
+
+    pub fn quality(self) -> u32 {
+        match self {
+            Self::Base => 25,
+            Self::High => 25,
+            Self::Best => 1,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Base => "base",
+            Self::High => "high",
+            Self::Best => "best",
+        }
+    }
+
+    /// Returns the profile with the given settings
+    pub fn matching(resolution: u32, quality: u32) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|profile| profile.resolution() == resolution && profile.quality() == quality)
+    }
+}
+
+/// The text recognition language of the scans with a quality profile
+#[derive(Debug, Clone)]
+pub struct ProfileLanguage {
+    pub profile: QualityProfile,
+    pub language: String,
+}
+
+fn parse_profile_language(value: &str) -> Result<ProfileLanguage, String> {
+    let (profile, language) = value
+        .split_once('=')
+        .ok_or("expected PROFILE=LANGUAGE, like high=deu+eng")?;
+    let profile = QualityProfile::ALL
+        .into_iter()
+        .find(|p| p.name().eq_ignore_ascii_case(profile.trim()))
+        .ok_or_else(|| format!("unknown profile {profile}, expected base, high or best"))?;
+    Ok(ProfileLanguage {
+        profile,
+        language: language.trim().to_owned(),
+    })
+}
+
 #[derive(Debug, Clone, Copy, ValueEnum)]
 pub enum Format {
     Pdf,
     Jpeg,
+    /// A lossless image decoded from a JPEG scan
+    Png,
+    /// All pages in one TIFF file, decoded without further loss, in black and white with CCITT
+    /// Group 4 compression
+    Tiff,
 }
 
 #[derive(Debug, Clone, Copy, ValueEnum)]
 pub enum ColorSpace {
     Gray,
     Color,
+    /// Scan in color, but store pages without color in grayscale, or in black and white with --bilevel
+    Auto,
+    /// Black and white, scanned by the scanner if it can or thresholded at --bilevel
+    Lineart,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Transform {
+    /// Rotate 90 degrees clockwise
+    #[value(name = "90")]
+    Rotate90,
+    /// Rotate 180 degrees
+    #[value(name = "180")]
+    Rotate180,
+    /// Rotate 270 degrees clockwise
+    #[value(name = "270")]
+    Rotate270,
+    /// Mirror left and right
+    FlipHorizontal,
+    /// Mirror top and bottom
+    FlipVertical,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Separator {
+    /// Empty sheets, see --remove-blank-pages for the threshold
+    Blank,
+    /// Sheets with a QR code, whose text names the next document
+    QrCode,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ReadingOrder {
+    /// The left page comes first
+    Ltr,
+    /// The right page comes first
+    Rtl,
 }
 
 #[derive(Parser, Debug)]
@@ -63,6 +170,22 @@ pub struct ScanOpt {
     )]
     pub color: ColorSpace,
 
+    /// Store pages without color in black and white with --color auto, pixels up to THRESHOLD
+    /// (0-255) become black [default: chosen for each page]. Also applies to --color lineart
+    /// when the pages are thresholded locally
+    #[arg(long, name = "THRESHOLD", num_args = 0..=1)]
+    pub bilevel: Option<Option<u8>>,
+
+    /// The number of bits per color sample, only used if the scanner supports it
+    #[arg(
+        long,
+        name = "BITS",
+        default_value_t = 8,
+        value_parser = clap::builder::PossibleValuesParser::new(["8", "16"])
+            .map(|s| s.parse::<u8>().unwrap()),
+    )]
+    pub bit_depth: u8,
+
     /// The scan resolution in dpi
     #[arg(
         short,
@@ -83,9 +206,325 @@ pub struct ScanOpt {
     )]
     pub compression_quality: u32,
 
-    /// Do not fix the heigt of JPEG files scanned from the automatic document feeder
+    /// Do not fix the heigt of JPEG and PDF files scanned from the automatic document feeder
     #[arg(long)]
     pub disable_jpeg_fix: bool,
+
+    /// Write the scan resolution, the scan time and the scanner model into JPEG scans
+    #[arg(long)]
+    pub metadata: bool,
+
+    /// Rotate or flip JPEG scans without loss of quality
+    #[arg(long, name = "TRANSFORM")]
+    pub rotate: Option<Transform>,
+
+    /// Remove pages whose content covers less than PERCENT of the page
+    #[arg(long, name = "PERCENT", num_args = 0..=1, default_missing_value = "0.1")]
+    pub remove_blank_pages: Option<f64>,
+
+    /// Rotate skewed pages upright
+    #[arg(long)]
+    pub deskew: bool,
+
+    /// Cut off the empty border around the content of the pages
+    #[arg(long)]
+    pub crop: bool,
+
+    /// Largest skew in DEGREES which --deskew corrects, larger ones are probably misdetected
+    #[arg(long, name = "DEGREES", default_value_t = 5.0, requires = "deskew")]
+    pub max_skew: f64,
+
+    /// Cut a scan of several photos on the glass into one JPEG file per photo
+    #[arg(long)]
+    pub split_photos: bool,
+
+    /// Scan an open book spread by spread from the glass into one PDF, with the pages in ORDER
+    #[arg(
+        long,
+        name = "ORDER",
+        ignore_case(true),
+        conflicts_with_all = ["split_photos", "SEPARATOR"]
+    )]
+    pub book: Option<ReadingOrder>,
+
+    /// Split PDF scans into one file per document at separator sheets
+    #[arg(long, name = "SEPARATOR", ignore_case(true))]
+    pub separator: Option<Separator>,
+
+    /// Make PDF scans searchable with text recognized by tesseract in LANGUAGE, like deu+eng, or
+    /// without LANGUAGE in the one of the quality profile
+    #[arg(long, name = "LANGUAGE", num_args = 0..=1)]
+    pub ocr: Option<Option<String>>,
+
+    /// Recognize text in LANGUAGE in the scans of the quality PROFILE, can be given several times
+    #[arg(long = "ocr-profile", value_name = "PROFILE=LANGUAGE", value_parser = parse_profile_language)]
+    pub ocr_profiles: Vec<ProfileLanguage>,
+
+    /// Write the scan to PATH, replacing an existing file, or to stdout with "-"
+    #[arg(
+        short,
+        long,
+        name = "PATH",
+        conflicts_with_all = ["split_photos", "SEPARATOR", "DIR", "TEMPLATE", "webdav_url", "s3_bucket", "sftp_host"]
+    )]
+    pub output: Option<PathBuf>,
+
+    /// The directory the scans are written to
+    #[arg(
+        long,
+        name = "DIR",
+        default_value = ".",
+        conflicts_with_all = ["webdav_url", "s3_bucket", "sftp_host"]
+    )]
+    pub output_dir: PathBuf,
+
+    /// The file name of scans in DIR without extension, with the placeholders {time} or
+    /// {time:FORMAT} (strftime), {counter}, {profile}, {source}, {color}, {resolution}, {page}
+    /// and {model} [default: scan_{time}, photo_{time}_{page} and scan_{time}_{page}]
+    #[arg(long, name = "TEMPLATE")]
+    pub name_template: Option<String>,
+
+    /// Send the written files by e-mail to ADDRESS, can be given several times
+    #[arg(long, value_name = "ADDRESS", requires = "smtp_host")]
+    pub mail_to: Vec<String>,
+
+    #[clap(flatten)]
+    pub hook_opts: HookOpt,
+
+    #[clap(flatten)]
+    pub webhook_opts: WebhookOpt,
+
+    #[clap(flatten)]
+    pub mail_opts: MailOpt,
+
+    #[clap(flatten)]
+    pub webdav_opts: WebDavOpt,
+
+    #[clap(flatten)]
+    pub s3_opts: S3Opt,
+
+    #[clap(flatten)]
+    pub sftp_opts: SftpOpt,
+}
+
+#[derive(Parser, Debug)]
+pub struct HookOpt {
+    /// Run COMMAND with the shell on every written file, can be given several times. The web
+    /// server passes a temporary copy, which is removed once the hooks are finished, so a hook
+    /// which works in the background has to copy the file first.
+    #[arg(long = "hook", name = "COMMAND")]
+    pub hooks: Vec<String>,
+
+    /// Pass the path and metadata of the file to hooks in COVET_* environment variables or as
+    /// JSON on stdin
+    #[arg(long, name = "INPUT", default_value = "env", ignore_case(true))]
+    pub hook_input: HookInput,
+
+    /// Stop a hook which runs longer than SECONDS
+    #[arg(long, name = "SECONDS", default_value_t = 60)]
+    pub hook_timeout: u64,
+
+    /// Fail the scan if a hook fails instead of logging the failure
+    #[arg(long)]
+    pub fail_on_hook_error: bool,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum HookInput {
+    Env,
+    Json,
+}
+
+#[derive(Parser, Debug)]
+pub struct WebhookOpt {
+    /// Send scan and scanner events as JSON to URL, can be given several times
+    #[arg(long = "webhook", name = "URL")]
+    pub webhooks: Vec<String>,
+
+    /// Sign the webhook requests with an HMAC-SHA256 of SECRET in the X-Covet-Signature header
+    /// [default: $COVET_WEBHOOK_SECRET]
+    #[arg(long, name = "SECRET")]
+    pub webhook_secret: Option<String>,
+
+    /// Keep webhook events which could not be sent in FILE and send them later
+    #[arg(long, name = "FILE")]
+    pub webhook_queue: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+pub struct MqttOpt {
+    /// Publish the scanner state to the MQTT broker HOST and accept scan commands from it
+    #[arg(long, name = "HOST")]
+    pub mqtt_broker: Option<String>,
+
+    /// Port of the MQTT broker
+    #[arg(long, value_name = "PORT", default_value_t = 1883)]
+    pub mqtt_port: u16,
+
+    /// User name for the MQTT broker
+    #[arg(long, value_name = "USER")]
+    pub mqtt_user: Option<String>,
+
+    /// Password for the MQTT broker [default: $COVET_MQTT_PASSWORD]
+    #[arg(long, value_name = "PASSWORD", requires = "mqtt_user")]
+    pub mqtt_password: Option<String>,
+
+    /// Prefix of the topics of the scanner
+    #[arg(long, name = "TOPIC", default_value = "covet")]
+    pub mqtt_topic: String,
+
+    /// Prefix of the Home Assistant discovery topics
+    #[arg(long, name = "PREFIX", default_value = "homeassistant")]
+    pub mqtt_discovery_prefix: String,
+
+    /// Seconds between updates of the scanner state
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    pub mqtt_interval: u64,
+}
+
+#[derive(Parser, Debug)]
+pub struct MailOpt {
+    /// Send scans by e-mail through the SMTP server HOST
+    #[arg(long, value_name = "HOST", requires = "mail_from")]
+    pub smtp_host: Option<String>,
+
+    /// Port of the SMTP server [default: 25, 587 or 465 depending on --smtp-security]
+    #[arg(long, value_name = "PORT")]
+    pub smtp_port: Option<u16>,
+
+    /// Secure the connection to the SMTP server with STARTTLS, TLS from the start or not at all
+    #[arg(
+        long,
+        value_name = "SECURITY",
+        default_value = "starttls",
+        ignore_case(true)
+    )]
+    pub smtp_security: SmtpSecurity,
+
+    /// User name for the SMTP server
+    #[arg(long, value_name = "USER")]
+    pub smtp_user: Option<String>,
+
+    /// Password for the SMTP server [default: $COVET_SMTP_PASSWORD]
+    #[arg(long, value_name = "PASSWORD", requires = "smtp_user")]
+    pub smtp_password: Option<String>,
+
+    /// The sender of scans sent by e-mail, like "Scanner <scanner@example.com>"
+    #[arg(long, value_name = "ADDRESS", requires = "smtp_host")]
+    pub mail_from: Option<String>,
+
+    /// Send a link instead of attaching files larger than MB megabytes, or fail without
+    /// --mail-link-base
+    #[arg(long, value_name = "MB", default_value_t = 10)]
+    pub mail_max_size: u64,
+
+    /// The URL of the directory the scans are written to, for the links to files which are too
+    /// large to be attached
+    #[arg(long, value_name = "URL")]
+    pub mail_link_base: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+pub struct WebDavOpt {
+    /// Upload the scans to the WebDAV collection at URL, like a Nextcloud folder
+    #[arg(long, value_name = "URL")]
+    pub webdav_url: Option<String>,
+
+    /// Folders below URL for the scans, which are created if needed, with the placeholders of
+    /// --name-template, like {time:%Y}/{time:%m}
+    #[arg(long, value_name = "TEMPLATE", requires = "webdav_url")]
+    pub webdav_path: Option<String>,
+
+    /// User name for the WebDAV server
+    #[arg(long, value_name = "USER")]
+    pub webdav_user: Option<String>,
+
+    /// Password or Nextcloud app password for the WebDAV server [default: $COVET_WEBDAV_PASSWORD]
+    #[arg(long, value_name = "PASSWORD", requires = "webdav_user")]
+    pub webdav_password: Option<String>,
+
+    /// Retry a failed upload COUNT times
+    #[arg(long, value_name = "COUNT", default_value_t = 3)]
+    pub webdav_retries: u32,
+}
+
+#[derive(Parser, Debug)]
+pub struct S3Opt {
+    /// Upload the scans to BUCKET of an S3-compatible object storage, like MinIO
+    #[arg(long, value_name = "BUCKET", conflicts_with = "webdav_url")]
+    pub s3_bucket: Option<String>,
+
+    /// URL of the object storage, like http://localhost:9000, with the bucket in the path
+    /// [default: https://s3.REGION.amazonaws.com, with the bucket in the host name]
+    #[arg(long, value_name = "URL", requires = "s3_bucket")]
+    pub s3_endpoint: Option<String>,
+
+    /// Region of the bucket
+    #[arg(long, value_name = "REGION", default_value = "us-east-1")]
+    pub s3_region: String,
+
+    /// Access key ID for the object storage [default: $AWS_ACCESS_KEY_ID]
+    #[arg(long, value_name = "ID", requires = "s3_bucket")]
+    pub s3_access_key: Option<String>,
+
+    /// Secret access key for the object storage [default: $AWS_SECRET_ACCESS_KEY]
+    #[arg(long, value_name = "KEY", requires = "s3_bucket")]
+    pub s3_secret_key: Option<String>,
+
+    /// Object key of the scans without extension, with the placeholders of --name-template and
+    /// slashes between folders, like scans/{time:%Y}/scan_{time} [default: the file name]
+    #[arg(long, value_name = "TEMPLATE", requires = "s3_bucket")]
+    pub s3_key: Option<String>,
+
+    /// Upload the scans in parts of SIZE MiB, which are kept in memory
+    #[arg(
+        long,
+        value_name = "SIZE",
+        default_value_t = 8,
+        value_parser = clap::value_parser!(u32).range(5..=5120)
+    )]
+    pub s3_part_size: u32,
+}
+
+#[derive(Parser, Debug)]
+pub struct SftpOpt {
+    /// Upload the scans over SFTP to DESTINATION, which is [USER@]HOST or a host of the ssh
+    /// configuration, logging in with a key
+    #[arg(
+        long,
+        value_name = "DESTINATION",
+        conflicts_with_all = ["webdav_url", "s3_bucket"]
+    )]
+    pub sftp_host: Option<String>,
+
+    /// Port of the SSH server [default: 22 or the port of the ssh configuration]
+    #[arg(long, value_name = "PORT", requires = "sftp_host")]
+    pub sftp_port: Option<u16>,
+
+    /// Remote directory of the scans [default: the home directory]
+    #[arg(long, value_name = "DIR", requires = "sftp_host")]
+    pub sftp_dir: Option<String>,
+
+    /// Path of the scans in the remote directory without extension, with the placeholders of
+    /// --name-template and slashes between folders, which are created if needed, like
+    /// {time:%Y}/scan_{time} [default: the file name]
+    #[arg(long, value_name = "TEMPLATE", requires = "sftp_host")]
+    pub sftp_path: Option<String>,
+
+    /// Private key to log in with [default: the keys of the ssh configuration]
+    #[arg(long, value_name = "FILE", requires = "sftp_host")]
+    pub sftp_identity: Option<PathBuf>,
+
+    /// Known hosts file which must have the key of the server [default: ~/.ssh/known_hosts]
+    #[arg(long, value_name = "FILE", requires = "sftp_host")]
+    pub sftp_known_hosts: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum SmtpSecurity {
+    None,
+    Starttls,
+    Tls,
 }
 
 #[derive(Parser, Debug)]
@@ -101,9 +540,54 @@ pub struct WebOpt {
     #[arg(short, long, name = "ADDR", default_value = "127.0.0.1")]
     pub listen: String,
 
-    /// Do not fix the heigt of JPEG files scanned from the automatic document feeder
+    /// Do not fix the heigt of JPEG and PDF files scanned from the automatic document feeder
     #[arg(long)]
     pub disable_jpeg_fix: bool,
+
+    /// Write the scan resolution, the scan time and the scanner model into JPEG scans
+    #[arg(long)]
+    pub metadata: bool,
+
+    /// The directory the scans started by MQTT commands and the scans sent by e-mail as a link are
+    /// written to, which are not removed
+    #[arg(long, name = "DIR", default_value = ".")]
+    pub output_dir: PathBuf,
+
+    /// Recognize text in LANGUAGE in the scans of the quality PROFILE, unless another language is
+    /// entered, can be given several times
+    #[arg(long = "ocr-profile", value_name = "PROFILE=LANGUAGE", value_parser = parse_profile_language)]
+    pub ocr_profiles: Vec<ProfileLanguage>,
+
+    #[clap(flatten)]
+    pub hook_opts: HookOpt,
+
+    #[clap(flatten)]
+    pub webhook_opts: WebhookOpt,
+
+    /// Seconds between status requests, which send the scanner_offline and scanner_online events
+    /// to webhooks, unless the MQTT connection requests the status
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    pub status_interval: u64,
+
+    #[clap(flatten)]
+    pub mqtt_opts: MqttOpt,
+
+    #[clap(flatten)]
+    pub mail_opts: MailOpt,
+
+    /// Allow the web UI to send scans to RECIPIENT, an address or all addresses of a domain given
+    /// as @DOMAIN, can be given several times. Scans are not sent to other recipients.
+    #[arg(long, value_name = "RECIPIENT", requires = "smtp_host")]
+    pub mail_allow: Vec<String>,
+
+    #[clap(flatten)]
+    pub webdav_opts: WebDavOpt,
+
+    #[clap(flatten)]
+    pub s3_opts: S3Opt,
+
+    #[clap(flatten)]
+    pub sftp_opts: SftpOpt,
 }
 
 #[derive(Parser, Debug)]
@@ -120,12 +604,72 @@ pub enum Opt {
 
     /// Sets the height of the given JPEG to the number provided in a `Define Number of Lines` segment
     FixJpegHeight(FixJpegHeightOpt),
+
+    /// Sets the height of the JPEG images in the given PDF files to the number provided in a `Define Number of Lines` segment
+    FixPdfHeight(FixJpegHeightOpt),
+
+    /// Inspect and validate JPEG files
+    #[command(subcommand)]
+    Jpeg(JpegCommand),
 }
 
 #[derive(Parser, Debug)]
+#[command(
+    group(ArgGroup::new("mode").args(["OUTPUT", "in_place", "dry_run"])),
+    after_help = "Exit status: 0 if all files were fixed, 3 if no file failed but some did not need \
+        to be fixed, 4 if any file could not be processed, 1 on other errors and 2 on invalid \
+        arguments. Without --output, --in-place or --dry-run, exactly two arguments are taken as \
+        IN OUT, which writes the fixed IN to OUT and exits with 0 if IN did not need to be fixed."
+)]
 pub struct FixJpegHeightOpt {
+    /// Input files or directories, "-" reads from stdin. Directories are searched recursively for files of the matching type
+    #[arg(required = true)]
+    pub inputs: Vec<PathBuf>,
+    /// Output file, "-" writes to stdout. Only allowed with a single input file
+    #[arg(short, long, name = "OUTPUT")]
+    pub output: Option<PathBuf>,
+    /// Replace the input files with the fixed files
+    #[arg(short, long)]
+    pub in_place: bool,
+    /// Keep a copy of each replaced file with the given suffix
+    #[arg(long, name = "SUFFIX", requires = "in_place")]
+    pub backup: Option<String>,
+    /// Only report which files would be fixed
+    #[arg(short = 'n', long)]
+    pub dry_run: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum JpegCommand {
+    /// Show the segments, frame header, tables and metadata of a JPEG file
+    Inspect(JpegFileOpt),
+
+    /// Check the structure of a JPEG file and exit with a non-zero status on problems
+    Check(JpegFileOpt),
+
+    /// Rotate or flip a JPEG file without loss of quality
+    Rotate(JpegRotateOpt),
+}
+
+#[derive(Parser, Debug)]
+pub struct JpegFileOpt {
+    /// Input file
+    pub file: PathBuf,
+
+    /// Print the result as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct JpegRotateOpt {
+    /// The rotation or flip to apply
+    #[arg(short, long, name = "TRANSFORM")]
+    pub transform: Transform,
+
     /// Input file
     pub input: PathBuf,
+
     /// Output file
     pub output: PathBuf,
 }
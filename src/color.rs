@@ -0,0 +1,314 @@
+use bytes::Bytes;
+use tracing::{debug, info, warn};
+
+use crate::ccitt;
+use crate::jpeg::{self, Jpeg, ParseError};
+use crate::pdf::{self, ImageFormat, PageImage};
+use crate::raster::{GrayImage, Image};
+
+/// Resolution in dpi at which the chroma is analysed. The color fringes at the
+/// edges of black text are averaged out at this resolution.
+const ANALYSIS_RESOLUTION: f64 = 50.0;
+/// Chroma which differs less from the paper is no color
+const CHROMA_CONTRAST: u8 = 20;
+/// A page with a colored area in square millimeters below this, which is
+/// smaller than a stamp, has no color
+const MIN_COLOR_AREA: f64 = 25.0;
+/// Pixels have to be at least this much darker than the paper to become black
+/// with an automatic threshold
+const MIN_INK_CONTRAST: u8 = 48;
+
+/// What a page without color is converted to in the automatic color mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Monochrome {
+    Gray,
+    /// Black and white. Pixels up to and including the threshold become black.
+    /// Without one it is chosen for each page.
+    Bilevel {
+        threshold: Option<u8>,
+    },
+}
+
+/// The pages which are stored in black and white when they are decoded into a
+/// lossless format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BilevelPages {
+    None,
+    /// Grayscale pages, which have been converted from pages without color
+    Gray {
+        threshold: Option<u8>,
+    },
+    All {
+        threshold: Option<u8>,
+    },
+}
+
+/// The samples of a page decoded from a JPEG image
+#[derive(Debug)]
+pub enum DecodedPage {
+    /// Black and white, pixels up to and including the threshold are black
+    Bilevel {
+        image: GrayImage,
+        threshold: u8,
+    },
+    Samples(Image),
+}
+
+/// Decodes a JPEG image and decides whether it becomes black and white
+pub fn decode_page(data: Bytes, bilevel: BilevelPages) -> Result<DecodedPage, ParseError> {
+    let threshold = match bilevel {
+        BilevelPages::None => None,
+        BilevelPages::Gray { threshold } => {
+            let frame = Jpeg::from_bytes(data.clone())?.frame()?;
+            let gray = frame.is_some_and(|frame| frame.components.len() == 1);
+            gray.then_some(threshold)
+        }
+        BilevelPages::All { threshold } => Some(threshold),
+    };
+    match threshold {
+        Some(threshold) => {
+            let image = jpeg::decode_gray(data)?;
+            let threshold = threshold.unwrap_or_else(|| auto_threshold(&image));
+            Ok(DecodedPage::Bilevel { image, threshold })
+        }
+        None => jpeg::decode_image(data).map(DecodedPage::Samples),
+    }
+}
+
+/// Converts a color JPEG scan without color content to grayscale. A JPEG image
+/// cannot be bilevel, so it is always converted to grayscale. Returns `None`
+/// if the page has color or is grayscale already.
+pub fn convert_jpeg(data: Bytes, resolution: u32) -> Result<Option<Bytes>, ParseError> {
+    let Some(area) = color_area(data.clone(), resolution)? else {
+        return Ok(None);
+    };
+    if area >= MIN_COLOR_AREA {
+        info!("Page has color ({area:.0} mm²)");
+        return Ok(None);
+    }
+    jpeg::to_grayscale(data).map(Some)
+}
+
+/// Converts the JPEG images of the pages without color content. Pages which
+/// cannot be analysed are kept as they are. Returns `None` if no page was
+/// converted.
+pub fn convert_pdf(
+    data: Bytes,
+    resolution: u32,
+    target: Monochrome,
+) -> Result<Option<Bytes>, pdf::ParseError> {
+    let mut converted = Vec::new();
+    let result = pdf::replace_images(data, |index, image| {
+        match convert_image(image, resolution, target) {
+            Ok(Some(image)) => {
+                converted.push((index + 1).to_string());
+                Some(image)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                warn!("Cannot convert page {}: {e}", index + 1);
+                None
+            }
+        }
+    })?;
+    if !converted.is_empty() {
+        info!("Converted pages without color: {}", converted.join(", "));
+    }
+    Ok(result)
+}
+
+fn convert_image(
+    data: Bytes,
+    resolution: u32,
+    target: Monochrome,
+) -> Result<Option<PageImage>, ParseError> {
+    let Some(area) = color_area(data.clone(), resolution)? else {
+        return Ok(None);
+    };
+    if area >= MIN_COLOR_AREA {
+        debug!("Image has color ({area:.0} mm²)");
+        return Ok(None);
+    }
+    match target {
+        Monochrome::Gray => {
+            let gray = jpeg::to_grayscale(data)?;
+            let frame = Jpeg::from_bytes(gray.clone())?
+                .frame()?
+                .ok_or_else(|| ParseError::from("missing frame header".to_owned()))?;
+            Ok(Some(PageImage {
+                data: gray,
+                width: usize::from(frame.width),
+                height: usize::from(frame.height),
+                format: ImageFormat::Jpeg,
+            }))
+        }
+        Monochrome::Bilevel { threshold } => bilevel_image(data, threshold).map(Some),
+    }
+}
+
+/// Converts the JPEG images of all pages to black and white, at the threshold
+/// or one chosen for each page. Pages which cannot be converted are kept as
+/// they are. Returns `None` if no page was converted.
+pub fn bilevel_pdf(data: Bytes, threshold: Option<u8>) -> Result<Option<Bytes>, pdf::ParseError> {
+    pdf::replace_images(data, |index, image| match bilevel_image(image, threshold) {
+        Ok(image) => Some(image),
+        Err(e) => {
+            warn!("Cannot convert page {} to black and white: {e}", index + 1);
+            None
+        }
+    })
+}
+
+fn bilevel_image(data: Bytes, threshold: Option<u8>) -> Result<PageImage, ParseError> {
+    let image = jpeg::decode_gray(data)?;
+    let threshold = threshold.unwrap_or_else(|| auto_threshold(&image));
+    debug!("Converting image to black and white at threshold {threshold}");
+    Ok(PageImage {
+        data: ccitt::encode_g4(&image, threshold).into(),
+        width: image.width,
+        height: image.height,
+        format: ImageFormat::Bilevel,
+    })
+}
+
+/// Returns the area in square millimeters whose color stands out from the
+/// paper or `None` for a grayscale image
+fn color_area(data: Bytes, resolution: u32) -> Result<Option<f64>, ParseError> {
+    let width = Jpeg::from_bytes(data.clone())?
+        .frame()?
+        .map(|frame| usize::from(frame.width))
+        .ok_or_else(|| ParseError::from("missing frame header".to_owned()))?;
+    let Some([blue, red]) = jpeg::decode_chroma(data)? else {
+        return Ok(None);
+    };
+    if width == 0 || blue.width == 0 {
+        return Ok(Some(0.0));
+    }
+    let plane_resolution = f64::from(resolution) * blue.width as f64 / width as f64;
+    let factor = (plane_resolution / ANALYSIS_RESOLUTION).round().max(1.0) as usize;
+    let blue = blue.downscale(factor);
+    let red = red.downscale(factor);
+    // the paper may have a tint of its own
+    let paper = (blue.median(), red.median());
+    let colored = blue
+        .pixels
+        .iter()
+        .zip(&red.pixels)
+        .filter(|(b, r)| b.abs_diff(paper.0).max(r.abs_diff(paper.1)) > CHROMA_CONTRAST)
+        .count();
+    let pixel_size = 25.4 * factor as f64 / plane_resolution;
+    Ok(Some(colored as f64 * pixel_size * pixel_size))
+}
+
+/// Chooses a threshold between text and paper for the page
+pub fn auto_threshold(image: &GrayImage) -> u8 {
+    let paper = image.median();
+    image
+        .otsu_threshold()
+        .min(paper.saturating_sub(MIN_INK_CONTRAST))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pdf::JpegPage;
+
+    #[test]
+    fn measure_color_area() {
+        assert_eq!(color_area(jpeg::blank_page(), 300).unwrap(), Some(0.0));
+        // a red square of 80 chroma samples, 13.5 mm, at 150 dpi
+        let page = jpeg::color_page(|x, y| (10..20).contains(&x) && (10..20).contains(&y));
+        let area = color_area(page, 300).unwrap().unwrap();
+        assert!((area - 182.0).abs() < 30.0, "{area}");
+        // a single block is too small to count as color
+        let page = jpeg::color_page(|x, y| x == 10 && y == 10);
+        assert!(color_area(page, 300).unwrap().unwrap() < MIN_COLOR_AREA);
+        let gray = jpeg::to_grayscale(jpeg::blank_page()).unwrap();
+        assert_eq!(color_area(gray, 300).unwrap(), None);
+    }
+
+    #[test]
+    fn convert_jpeg_without_color() {
+        let gray = convert_jpeg(jpeg::test_page(|x, _| x % 4 == 0), 300)
+            .unwrap()
+            .unwrap();
+        let frame = Jpeg::from_bytes(gray.clone()).unwrap().frame().unwrap();
+        assert_eq!(frame.unwrap().components.len(), 1);
+        assert!(convert_jpeg(gray, 300).unwrap().is_none());
+        let page = jpeg::color_page(|x, y| x < 20 && y < 20);
+        assert!(convert_jpeg(page, 300).unwrap().is_none());
+    }
+
+    #[test]
+    fn convert_pdf_pages_to_bilevel() {
+        let pages: Vec<JpegPage> = [
+            jpeg::color_page(|x, y| x < 20 && y < 20),
+            jpeg::test_page(|x, y| x < 10 && y < 10),
+        ]
+        .into_iter()
+        .map(|data| JpegPage {
+            data,
+            resolution: 300,
+        })
+        .collect();
+        let data = pdf::jpeg_document(&pages).unwrap();
+        let converted = convert_pdf(data, 300, Monochrome::Bilevel { threshold: None })
+            .unwrap()
+            .unwrap();
+        let images = pdf::page_jpegs(converted).unwrap();
+        // only the page with color is still a JPEG image
+        assert_eq!(images[0], vec![pages[0].data.clone()]);
+        assert!(images[1].is_empty());
+    }
+
+    #[test]
+    fn convert_all_pdf_pages_to_bilevel() {
+        let pages: Vec<JpegPage> = [jpeg::color_page(|x, y| x < 20 && y < 20)]
+            .into_iter()
+            .map(|data| JpegPage {
+                data,
+                resolution: 300,
+            })
+            .collect();
+        let data = pdf::jpeg_document(&pages).unwrap();
+        let converted = bilevel_pdf(data, Some(128)).unwrap().unwrap();
+        assert_eq!(
+            pdf::page_jpegs(converted).unwrap(),
+            vec![Vec::<Bytes>::new()]
+        );
+    }
+
+    #[test]
+    fn decode_gray_page_to_bilevel() {
+        let gray = jpeg::to_grayscale(jpeg::blank_page()).unwrap();
+        let bilevel = BilevelPages::Gray { threshold: Some(7) };
+        assert!(matches!(
+            decode_page(gray.clone(), bilevel).unwrap(),
+            DecodedPage::Bilevel { threshold: 7, .. }
+        ));
+        let page = decode_page(jpeg::blank_page(), bilevel).unwrap();
+        assert!(matches!(
+            page,
+            DecodedPage::Samples(Image { channels: 3, .. })
+        ));
+        let page = decode_page(gray, BilevelPages::None).unwrap();
+        assert!(matches!(
+            page,
+            DecodedPage::Samples(Image { channels: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn threshold_between_text_and_paper() {
+        let mut image = GrayImage::new(100, 100, 240);
+        for x in 0..20 {
+            image.set(x, 50, 30);
+        }
+        let threshold = auto_threshold(&image);
+        assert!((30..192).contains(&threshold), "{threshold}");
+        // noise of the paper stays white
+        let mut image = GrayImage::new(100, 100, 240);
+        image.set(5, 5, 230);
+        assert!(auto_threshold(&image) < 230);
+    }
+}
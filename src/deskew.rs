@@ -0,0 +1,409 @@
+use bytes::Bytes;
+use tracing::{debug, info, warn};
+
+use std::fmt;
+
+use crate::raster::{GrayImage, Rect};
+use crate::{jpeg, pdf};
+
+/// Largest skew in degrees which is corrected if none is given
+pub const DEFAULT_MAX_ANGLE: f64 = 5.0;
+
+/// Resolution in dpi at which the pages are analysed
+const ANALYSIS_RESOLUTION: u32 = 75;
+/// Pixels which differ less from the background are not content
+const CONTENT_CONTRAST: u8 = 48;
+/// Skews are searched in steps of this many degrees first and refined around the best one
+const COARSE_STEP: f64 = 0.25;
+const FINE_STEP: f64 = 0.025;
+/// Smaller skews are not worth the loss of re-encoding the image
+const MIN_ANGLE: f64 = 0.1;
+/// Fewer dark pixels give no reliable skew
+const MIN_DARK_PIXELS: usize = 200;
+/// More dark pixels are sampled to limit the time of the search
+const MAX_DARK_PIXELS: usize = 50_000;
+/// How much better the projection at the best angle has to be than on average.
+/// Text lines give a clear peak, photos and noise do not.
+const MIN_PEAK_RATIO: f64 = 1.1;
+/// Space left around the content in millimeters
+const CROP_MARGIN: f64 = 3.0;
+/// Content which is smaller in either direction is probably dust
+const MIN_CONTENT_SIZE: f64 = 10.0;
+/// A crop has to remove at least this fraction of the width or height
+const MIN_CROP: f64 = 0.02;
+
+/// Which corrections are applied to the scanned pages
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Straighten {
+    /// Rotate skewed pages upright
+    pub deskew: bool,
+    /// Cut off the empty border around the content
+    pub crop: bool,
+    /// Larger skews in degrees are left alone, they are probably misdetected
+    pub max_angle: f64,
+}
+
+/// The correction of a page
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Adjustment {
+    /// Counterclockwise rotation in degrees
+    pub angle: f64,
+    /// The kept part of the rotated page in pixels
+    pub rect: Rect,
+    /// False if `rect` is the whole page
+    pub cropped: bool,
+}
+
+impl Adjustment {
+    fn is_empty(&self) -> bool {
+        self.angle == 0.0 && !self.cropped
+    }
+}
+
+impl fmt::Display for Adjustment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Rect {
+            x,
+            y,
+            width,
+            height,
+        } = self.rect;
+        match (self.angle != 0.0, self.cropped) {
+            (true, true) => write!(
+                f,
+                "rotated by {:.2}° and cropped to {width}x{height} at {x},{y}",
+                self.angle
+            ),
+            (true, false) => write!(f, "rotated by {:.2}°", self.angle),
+            (false, true) => write!(f, "cropped to {width}x{height} at {x},{y}"),
+            (false, false) => write!(f, "unchanged"),
+        }
+    }
+}
+
+/// Straightens and crops a JPEG image scanned at the given resolution in dpi.
+/// Returns the new image or `None` if the page needs no correction.
+pub fn straighten_jpeg(
+    data: Bytes,
+    resolution: u32,
+    settings: Straighten,
+) -> Result<Option<(Bytes, Adjustment)>, jpeg::ParseError> {
+    let image = jpeg::decode_gray(data.clone())?;
+    let adjustment = detect(&image, resolution, settings);
+    if adjustment.is_empty() {
+        return Ok(None);
+    }
+    let data = jpeg::rotate_and_crop(data, adjustment.angle.to_radians(), adjustment.rect)?;
+    Ok(Some((data, adjustment)))
+}
+
+/// Straightens and crops the pages of a PDF document which consist of a single
+/// JPEG image. Pages which cannot be analysed are kept as they are. Returns
+/// `None` if no page needs a correction.
+pub fn straighten_pdf(
+    data: Bytes,
+    resolution: u32,
+    settings: Straighten,
+) -> Result<Option<Bytes>, pdf::ParseError> {
+    pdf::replace_page_images(data, resolution, |index, image| {
+        match straighten_jpeg(image, resolution, settings) {
+            Ok(Some((data, adjustment))) => {
+                info!("Page {} {adjustment}", index + 1);
+                Some(pdf::PageImage {
+                    data,
+                    width: adjustment.rect.width,
+                    height: adjustment.rect.height,
+                    format: pdf::ImageFormat::Jpeg,
+                })
+            }
+            Ok(None) => {
+                debug!("Page {} needs no correction", index + 1);
+                None
+            }
+            Err(e) => {
+                warn!("Cannot straighten page {}: {e}", index + 1);
+                None
+            }
+        }
+    })
+}
+
+/// Finds the skew and the content of a page. Corrections which are not
+/// reliable or too small to matter are left out.
+pub fn detect(image: &GrayImage, resolution: u32, settings: Straighten) -> Adjustment {
+    let full = Rect {
+        x: 0,
+        y: 0,
+        width: image.width,
+        height: image.height,
+    };
+    let mut adjustment = Adjustment {
+        angle: 0.0,
+        rect: full,
+        cropped: false,
+    };
+    if image.width == 0 || image.height == 0 {
+        return adjustment;
+    }
+    let factor = (resolution / ANALYSIS_RESOLUTION).max(1) as usize;
+    let small = image.downscale(factor);
+    let background = small.border_median();
+    if settings.deskew {
+        adjustment.angle = skew(&small, background, settings.max_angle).unwrap_or(0.0);
+    }
+    if settings.crop {
+        let pixels_per_mm = f64::from(resolution) / factor as f64 / 25.4;
+        if let Some(rect) = content_rect(&small, background, adjustment.angle, pixels_per_mm) {
+            let scaled = Rect {
+                x: rect.x * factor,
+                y: rect.y * factor,
+                width: (rect.width * factor).min(image.width - rect.x * factor),
+                height: (rect.height * factor).min(image.height - rect.y * factor),
+            };
+            let removed_x = 1.0 - scaled.width as f64 / image.width as f64;
+            let removed_y = 1.0 - scaled.height as f64 / image.height as f64;
+            if removed_x.max(removed_y) >= MIN_CROP {
+                adjustment.rect = scaled;
+                adjustment.cropped = true;
+            } else {
+                debug!("Content covers nearly the whole page, do not crop");
+            }
+        }
+    }
+    adjustment
+}
+
+/// Returns the counterclockwise rotation in degrees which aligns the lines of
+/// dark pixels with the rows of the image or `None` if there is no clear skew.
+/// The rotation with the most uneven projection of the dark pixels onto the
+/// rows wins.
+fn skew(image: &GrayImage, background: u8, max_angle: f64) -> Option<f64> {
+    let threshold = image.otsu_threshold();
+    let mut points = Vec::new();
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let value = image.get(x, y);
+            if value <= threshold && value.saturating_add(CONTENT_CONTRAST) < background {
+                points.push((x as f64, y as f64));
+            }
+        }
+    }
+    if points.len() < MIN_DARK_PIXELS {
+        debug!("Too little content to detect the skew");
+        return None;
+    }
+    if points.len() > MAX_DARK_PIXELS {
+        let step = points.len().div_ceil(MAX_DARK_PIXELS);
+        points = points.into_iter().step_by(step).collect();
+    }
+    let cx = (image.width as f64 - 1.0) / 2.0;
+    let cy = (image.height as f64 - 1.0) / 2.0;
+    // rows of the rotated points may lie above or below the image by up to its width
+    let offset = image.width as f64;
+    let mut rows = vec![0u64; image.height + 2 * image.width + 1];
+    let mut score = |degrees: f64| {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        rows.fill(0);
+        for (x, y) in &points {
+            let (dx, dy) = (x - cx, y - cy);
+            let row = -dx * sin + dy * cos + cy + offset;
+            rows[row.round() as usize] += 1;
+        }
+        rows.iter().map(|count| count * count).sum::<u64>() as f64
+    };
+
+    let steps = (max_angle / COARSE_STEP).floor() as i32;
+    let coarse: Vec<(f64, f64)> = (-steps..=steps)
+        .map(|step| {
+            let angle = f64::from(step) * COARSE_STEP;
+            (angle, score(angle))
+        })
+        .collect();
+    let mean = coarse.iter().map(|(_, s)| s).sum::<f64>() / coarse.len() as f64;
+    // ties go to the smaller rotation
+    let (coarse_angle, _) = coarse
+        .iter()
+        .copied()
+        .max_by(|a, b| a.1.total_cmp(&b.1).then(b.0.abs().total_cmp(&a.0.abs())))?;
+    let fine_steps = (COARSE_STEP / FINE_STEP).round() as i32;
+    let fine: Vec<(f64, f64)> = (-fine_steps..=fine_steps)
+        .map(|step| {
+            let angle = coarse_angle + f64::from(step) * FINE_STEP;
+            (angle, score(angle))
+        })
+        .collect();
+    let best = fine.iter().map(|(_, s)| *s).fold(0.0, f64::max);
+    // small rotations hardly move the pixels between rows, so several angles
+    // may share the best score and the one in the middle is the most likely
+    let plateau: Vec<f64> = fine
+        .iter()
+        .filter(|(_, s)| *s == best)
+        .map(|(angle, _)| *angle)
+        .collect();
+    let angle = (plateau[0] + plateau[plateau.len() - 1]) / 2.0;
+    debug!(
+        "Detected skew of {angle:.3}° with a peak ratio of {:.2}",
+        best / mean
+    );
+    if best < mean * MIN_PEAK_RATIO {
+        debug!("Skew is not clear enough, do not rotate");
+        return None;
+    }
+    if angle.abs() > max_angle - COARSE_STEP / 2.0 {
+        debug!("Skew of {angle:.2}° exceeds the limit of {max_angle}°, do not rotate");
+        return None;
+    }
+    if angle.abs() < MIN_ANGLE {
+        return None;
+    }
+    Some(angle)
+}
+
+/// Returns the area with content of the image after rotating it by `angle`
+/// degrees, with a margin around it. Returns `None` if there is no content or
+/// too little to be more than dust.
+pub fn content_rect(
+    image: &GrayImage,
+    background: u8,
+    angle: f64,
+    pixels_per_mm: f64,
+) -> Option<Rect> {
+    let full = Rect {
+        x: 0,
+        y: 0,
+        width: image.width,
+        height: image.height,
+    };
+    let rotated = image.rotate_and_crop(angle.to_radians(), full, background);
+    let mut columns = vec![0usize; rotated.width];
+    let mut rows = vec![0usize; rotated.height];
+    for (row, pixels) in rows.iter_mut().zip(rotated.pixels.chunks(rotated.width)) {
+        for (column, pixel) in columns.iter_mut().zip(pixels) {
+            if pixel.abs_diff(background) > CONTENT_CONTRAST {
+                *column += 1;
+                *row += 1;
+            }
+        }
+    }
+    // single specks of dust do not count
+    let bounds = |counts: &[usize], length: usize| {
+        let min_count = (length / 200).max(2);
+        let first = counts.iter().position(|count| *count >= min_count)?;
+        let last = counts.iter().rposition(|count| *count >= min_count)?;
+        Some((first, last + 1))
+    };
+    let (left, right) = bounds(&columns, rotated.height)?;
+    let (top, bottom) = bounds(&rows, rotated.width)?;
+    let min_size = MIN_CONTENT_SIZE * pixels_per_mm;
+    if ((right - left) as f64) < min_size || ((bottom - top) as f64) < min_size {
+        debug!("Content is too small to crop to");
+        return None;
+    }
+    let margin = (CROP_MARGIN * pixels_per_mm).round() as usize;
+    let (left, top) = (left.saturating_sub(margin), top.saturating_sub(margin));
+    let right = (right + margin).min(rotated.width);
+    let bottom = (bottom + margin).min(rotated.height);
+    Some(Rect {
+        x: left,
+        y: top,
+        width: right - left,
+        height: bottom - top,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SETTINGS: Straighten = Straighten {
+        deskew: true,
+        crop: true,
+        max_angle: DEFAULT_MAX_ANGLE,
+    };
+
+    /// A page at 75 dpi with lines of text between the given rows
+    fn text_page(width: usize, height: usize, rows: std::ops::Range<usize>) -> GrayImage {
+        let mut image = GrayImage::new(width, height, 250);
+        for y in rows.step_by(12) {
+            for x in 80..width - 80 {
+                // words of dark letters with spaces between them
+                if x % 40 < 32 && x % 4 != 0 {
+                    for dy in 0..5 {
+                        image.set(x, y + dy, 20);
+                    }
+                }
+            }
+        }
+        image
+    }
+
+    fn full(image: &GrayImage) -> Rect {
+        Rect {
+            x: 0,
+            y: 0,
+            width: image.width,
+            height: image.height,
+        }
+    }
+
+    #[test]
+    fn detect_skew() {
+        let page = text_page(600, 800, 100..700);
+        for degrees in [-3.0, -0.8, 1.5, 4.0] {
+            let skewed = page.rotate_and_crop(f64::to_radians(degrees), full(&page), 250);
+            let adjustment = detect(&skewed, 75, SETTINGS);
+            assert!(
+                (adjustment.angle + degrees).abs() <= 2.0 * FINE_STEP,
+                "skew of {degrees}° detected as {}",
+                -adjustment.angle
+            );
+        }
+    }
+
+    #[test]
+    fn ignore_large_or_small_skew() {
+        let page = text_page(600, 800, 100..700);
+        let straight = detect(&page, 75, SETTINGS);
+        assert_eq!(straight.angle, 0.0);
+        let skewed = page.rotate_and_crop(f64::to_radians(8.0), full(&page), 250);
+        assert_eq!(detect(&skewed, 75, SETTINGS).angle, 0.0);
+    }
+
+    #[test]
+    fn crop_to_content() {
+        let page = text_page(600, 800, 100..400);
+        let adjustment = detect(&page, 75, SETTINGS);
+        // three millimeters are 9 pixels
+        assert_eq!(
+            adjustment,
+            Adjustment {
+                angle: 0.0,
+                rect: Rect {
+                    x: 72,
+                    y: 91,
+                    width: 449,
+                    height: 311,
+                },
+                cropped: true,
+            }
+        );
+    }
+
+    #[test]
+    fn keep_blank_page() {
+        let page = GrayImage::new(600, 800, 250);
+        let adjustment = detect(&page, 75, SETTINGS);
+        assert_eq!(adjustment.angle, 0.0);
+        assert!(!adjustment.cropped);
+    }
+
+    #[test]
+    fn straighten_scanned_jpeg() {
+        let page = jpeg::test_page(|x, y| (10..50).contains(&x) && y % 4 == 0 && y > 20 && y < 300);
+        let (data, adjustment) = straighten_jpeg(page, 300, SETTINGS).unwrap().unwrap();
+        assert!(adjustment.cropped);
+        let image = jpeg::decode_gray(data).unwrap();
+        assert_eq!(image.width, adjustment.rect.width);
+        assert_eq!(image.height, adjustment.rect.height);
+    }
+}
@@ -0,0 +1,396 @@
+use anyhow::{Context, Result, bail};
+use bytes::Bytes;
+use tempfile::NamedTempFile;
+
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::cli::FixJpegHeightOpt;
+use crate::{jpeg, pdf};
+
+/// Exit status if all files were fixed
+pub const EXIT_FIXED: i32 = 0;
+/// Exit status if at least one file could not be processed, which differs
+/// from the status of other errors
+pub const EXIT_FAILED: i32 = 4;
+/// Exit status if no file failed but at least one did not need to be fixed
+pub const EXIT_UNCHANGED: i32 = 3;
+
+const STDIO: &str = "-";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Input {
+    Stdin,
+    File(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Output {
+    Stdout,
+    File(PathBuf),
+    InPlace { backup: Option<String> },
+    DryRun,
+}
+
+/// The type of files whose images are fixed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Jpeg,
+    Pdf,
+}
+
+impl Kind {
+    fn extensions(self) -> &'static [&'static str] {
+        match self {
+            Kind::Jpeg => &["jpg", "jpeg"],
+            Kind::Pdf => &["pdf"],
+        }
+    }
+
+    fn matches_file_name(self, path: &Path) -> bool {
+        path.extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| self.extensions().iter().any(|x| e.eq_ignore_ascii_case(x)))
+    }
+
+    /// Returns the fixed data and a description of the repair
+    fn fix(self, data: Bytes) -> Result<Option<(Bytes, String)>> {
+        Ok(match self {
+            Kind::Jpeg => jpeg::fix_jpeg_height(data, None)?
+                .map(|(fixed, repair)| (fixed, repair.to_string())),
+            Kind::Pdf => pdf::fix_jpeg_heights(data)?
+                .map(|(fixed, count)| (fixed, format!("{count} image(s) fixed"))),
+        })
+    }
+}
+
+#[derive(Debug)]
+enum Outcome {
+    Fixed(String),
+    Unchanged,
+}
+
+/// Counts the outcomes of all processed files
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Summary {
+    fixed: usize,
+    unchanged: usize,
+    failed: usize,
+}
+
+impl Summary {
+    fn exit_status(&self) -> i32 {
+        if self.failed > 0 {
+            EXIT_FAILED
+        } else if self.unchanged > 0 {
+            EXIT_UNCHANGED
+        } else {
+            EXIT_FIXED
+        }
+    }
+}
+
+/// Fixes the height of all given files and returns the exit status
+pub fn run(opt: &FixJpegHeightOpt, kind: Kind) -> Result<i32> {
+    // the original form of the command with an input and an output file
+    let legacy = opt.output.is_none() && !opt.in_place && !opt.dry_run && opt.inputs.len() == 2;
+    let (inputs, output) = if legacy {
+        (&opt.inputs[..1], Some(&opt.inputs[1]))
+    } else {
+        (&opt.inputs[..], opt.output.as_ref())
+    };
+    let inputs = collect_inputs(inputs, kind)?;
+    let output = if opt.dry_run {
+        Output::DryRun
+    } else if opt.in_place {
+        if inputs.contains(&Input::Stdin) {
+            bail!("Cannot rewrite stdin in place");
+        }
+        Output::InPlace {
+            backup: opt.backup.clone(),
+        }
+    } else if let Some(output) = output {
+        if inputs.len() != 1 {
+            bail!("An output file can only be used with a single input file");
+        }
+        if output.as_os_str() == STDIO {
+            Output::Stdout
+        } else {
+            Output::File(output.clone())
+        }
+    } else {
+        bail!("Either an output file, --in-place or --dry-run is required");
+    };
+    // the report must not be mixed with the image data
+    let mut report: Box<dyn Write> = if output == Output::Stdout {
+        Box::new(std::io::stderr())
+    } else {
+        Box::new(std::io::stdout())
+    };
+
+    let mut summary = Summary::default();
+    for input in &inputs {
+        let name = match input {
+            Input::Stdin => "<stdin>".to_owned(),
+            Input::File(path) => path.display().to_string(),
+        };
+        match process(input, &output, kind) {
+            Ok(Outcome::Fixed(repair)) => {
+                summary.fixed += 1;
+                writeln!(report, "fixed: {name} ({repair})")?;
+            }
+            Ok(Outcome::Unchanged) => {
+                summary.unchanged += 1;
+                writeln!(report, "unchanged: {name}")?;
+            }
+            Err(e) => {
+                summary.failed += 1;
+                writeln!(report, "failed: {name}: {e:#}")?;
+            }
+        }
+    }
+    if inputs.len() > 1 {
+        writeln!(
+            report,
+            "{} fixed, {} unchanged, {} failed",
+            summary.fixed, summary.unchanged, summary.failed
+        )?;
+    }
+    match summary.exit_status() {
+        // the original form succeeded for files which did not need to be fixed
+        EXIT_UNCHANGED if legacy => Ok(EXIT_FIXED),
+        status => Ok(status),
+    }
+}
+
+/// Expands directories recursively into the files of the given kind they contain
+fn collect_inputs(paths: &[PathBuf], kind: Kind) -> Result<Vec<Input>> {
+    let mut inputs = Vec::new();
+    for path in paths {
+        if path.as_os_str() == STDIO {
+            inputs.push(Input::Stdin);
+        } else if path.is_dir() {
+            let mut files = Vec::new();
+            collect_files(path, kind, &mut files)
+                .with_context(|| format!("Cannot read directory {}", path.display()))?;
+            files.sort();
+            inputs.extend(files.into_iter().map(Input::File));
+        } else {
+            inputs.push(Input::File(path.clone()));
+        }
+    }
+    Ok(inputs)
+}
+
+fn collect_files(dir: &Path, kind: Kind, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, kind, files)?;
+        } else if kind.matches_file_name(&path) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn process(input: &Input, output: &Output, kind: Kind) -> Result<Outcome> {
+    let data = match input {
+        Input::Stdin => {
+            let mut buffer = Vec::new();
+            std::io::stdin().read_to_end(&mut buffer)?;
+            buffer
+        }
+        Input::File(path) => std::fs::read(path)?,
+    };
+    let data = Bytes::from(data);
+    let (fixed, outcome) = match kind.fix(data.clone())? {
+        Some((fixed, repair)) => (fixed, Outcome::Fixed(repair)),
+        None => (data, Outcome::Unchanged),
+    };
+    match (output, input, &outcome) {
+        (Output::DryRun, _, _) => (),
+        (Output::Stdout, _, _) => std::io::stdout().write_all(&fixed)?,
+        (Output::File(path), _, _) => std::fs::write(path, &fixed)?,
+        (Output::InPlace { .. }, _, Outcome::Unchanged) => (),
+        (Output::InPlace { backup }, Input::File(path), Outcome::Fixed(_)) => {
+            replace_file(path, &fixed, backup.as_deref())?
+        }
+        (Output::InPlace { .. }, Input::Stdin, _) => bail!("Cannot rewrite stdin in place"),
+    }
+    Ok(outcome)
+}
+
+/// Atomically replaces the file by writing a temporary file in the same directory and renaming it
+fn replace_file(path: &Path, data: &[u8], backup_suffix: Option<&str>) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let permissions = std::fs::metadata(path)?.permissions();
+    let mut file = NamedTempFile::new_in(dir)?;
+    file.write_all(data)?;
+    file.as_file().sync_all()?;
+    std::fs::set_permissions(file.path(), permissions)?;
+    if let Some(suffix) = backup_suffix {
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(suffix);
+        let backup = PathBuf::from(backup);
+        std::fs::copy(path, &backup)
+            .with_context(|| format!("Cannot create backup {}", backup.display()))?;
+    }
+    file.persist(path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DNL_TEST_FILE: &str = "doc/testdata/scan_from_adf_with_dnl_header.jpeg";
+
+    fn opt(inputs: Vec<PathBuf>) -> FixJpegHeightOpt {
+        FixJpegHeightOpt {
+            inputs,
+            output: None,
+            in_place: false,
+            backup: None,
+            dry_run: false,
+        }
+    }
+
+    /// Creates a directory with a file that needs to be fixed, one that is
+    /// already fixed, one that is invalid and one that is not a JPEG file
+    fn test_dir() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let data = std::fs::read(DNL_TEST_FILE).unwrap();
+        let (fixed, _) = jpeg::fix_jpeg_height(data.clone().into(), None)
+            .unwrap()
+            .unwrap();
+        let fixed = [&fixed[..fixed.len() - 8], &jpeg::END_OF_IMAGE].concat();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("sub/dnl.JPG"), &data).unwrap();
+        std::fs::write(dir.path().join("fixed.jpeg"), fixed).unwrap();
+        std::fs::write(dir.path().join("invalid.jpg"), b"invalid").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), b"invalid").unwrap();
+        dir
+    }
+
+    #[test]
+    fn collect_jpeg_files_recursively() {
+        let dir = test_dir();
+        let paths = [dir.path().to_owned(), PathBuf::from("-")];
+        let inputs = collect_inputs(&paths, Kind::Jpeg).unwrap();
+        assert_eq!(
+            inputs,
+            vec![
+                Input::File(dir.path().join("fixed.jpeg")),
+                Input::File(dir.path().join("invalid.jpg")),
+                Input::File(dir.path().join("sub/dnl.JPG")),
+                Input::Stdin,
+            ]
+        );
+    }
+
+    #[test]
+    fn collect_pdf_files() {
+        let dir = test_dir();
+        std::fs::write(dir.path().join("sub/scan.PDF"), b"%PDF-1.4").unwrap();
+        let inputs = collect_inputs(&[dir.path().to_owned()], Kind::Pdf).unwrap();
+        assert_eq!(inputs, vec![Input::File(dir.path().join("sub/scan.PDF"))]);
+    }
+
+    #[test]
+    fn dry_run_does_not_modify_files() {
+        let dir = test_dir();
+        let before = std::fs::read(dir.path().join("sub/dnl.JPG")).unwrap();
+        let mut opt = opt(vec![dir.path().to_owned()]);
+        opt.dry_run = true;
+        assert_eq!(run(&opt, Kind::Jpeg).unwrap(), EXIT_FAILED);
+        assert_eq!(
+            std::fs::read(dir.path().join("sub/dnl.JPG")).unwrap(),
+            before
+        );
+    }
+
+    #[test]
+    fn fix_in_place_with_backup() {
+        let dir = test_dir();
+        let path = dir.path().join("sub/dnl.JPG");
+        let before = std::fs::read(&path).unwrap();
+        let mut opt = opt(vec![path.clone()]);
+        opt.in_place = true;
+        opt.backup = Some(".orig".to_owned());
+        assert_eq!(run(&opt, Kind::Jpeg).unwrap(), EXIT_FIXED);
+        let after = std::fs::read(&path).unwrap();
+        assert_ne!(after, before);
+        assert_eq!(
+            std::fs::read(dir.path().join("sub/dnl.JPG.orig")).unwrap(),
+            before
+        );
+        // a second run finds nothing to fix
+        opt.backup = None;
+        assert_eq!(run(&opt, Kind::Jpeg).unwrap(), EXIT_UNCHANGED);
+        assert_eq!(std::fs::read(&path).unwrap(), after);
+    }
+
+    #[test]
+    fn write_unchanged_file_to_output() {
+        let dir = test_dir();
+        let output = dir.path().join("out.jpeg");
+        let mut opt = opt(vec![dir.path().join("fixed.jpeg")]);
+        opt.output = Some(output.clone());
+        assert_eq!(run(&opt, Kind::Jpeg).unwrap(), EXIT_UNCHANGED);
+        assert_eq!(
+            std::fs::read(output).unwrap(),
+            std::fs::read(dir.path().join("fixed.jpeg")).unwrap()
+        );
+    }
+
+    #[test]
+    fn fix_input_to_output_argument() {
+        use clap::Parser;
+        let dir = test_dir();
+        let input = dir.path().join("sub/dnl.JPG");
+        let output = dir.path().join("out.jpeg");
+        let opt = FixJpegHeightOpt::try_parse_from([
+            "fix-jpeg-height".as_ref(),
+            input.as_os_str(),
+            output.as_os_str(),
+        ])
+        .unwrap();
+        assert_eq!(run(&opt, Kind::Jpeg).unwrap(), EXIT_FIXED);
+        assert_ne!(
+            std::fs::read(&output).unwrap(),
+            std::fs::read(&input).unwrap()
+        );
+        // a file which does not need to be fixed is no failure either
+        let opt = FixJpegHeightOpt::try_parse_from([
+            "fix-jpeg-height".as_ref(),
+            dir.path().join("fixed.jpeg").as_os_str(),
+            output.as_os_str(),
+        ])
+        .unwrap();
+        assert_eq!(run(&opt, Kind::Jpeg).unwrap(), EXIT_FIXED);
+    }
+
+    #[test]
+    fn output_file_requires_single_input() {
+        let dir = test_dir();
+        let mut opt = opt(vec![dir.path().to_owned()]);
+        opt.output = Some(dir.path().join("out.jpeg"));
+        assert!(run(&opt, Kind::Jpeg).is_err());
+    }
+
+    #[test]
+    fn exit_status_of_summary() {
+        let summary = |fixed, unchanged, failed| Summary {
+            fixed,
+            unchanged,
+            failed,
+        };
+        assert_eq!(summary(2, 0, 0).exit_status(), EXIT_FIXED);
+        assert_eq!(summary(2, 1, 0).exit_status(), EXIT_UNCHANGED);
+        assert_eq!(summary(2, 1, 1).exit_status(), EXIT_FAILED);
+    }
+}
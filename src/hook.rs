@@ -0,0 +1,300 @@
+use serde::Serialize;
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::{debug, error, info, warn};
+
+use std::path::Path;
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
+
+use crate::output::NameFields;
+
+/// Prefix of the environment variables passed to hooks
+const ENV_PREFIX: &str = "COVET_";
+
+#[derive(Debug, Error)]
+pub enum HookError {
+    #[error("Cannot run hook {command}: {source}")]
+    Spawn {
+        command: String,
+        source: std::io::Error,
+    },
+    #[error("Hook {command} did not finish within {} seconds", timeout.as_secs_f64())]
+    Timeout { command: String, timeout: Duration },
+    #[error("Hook {command} failed with {status}")]
+    Failed { command: String, status: ExitStatus },
+}
+
+/// How the path and metadata of a file are passed to a hook
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookInput {
+    /// Environment variables like `COVET_PATH`
+    Env,
+    /// A JSON object on the standard input
+    Json,
+}
+
+/// Shell commands which are run in order on every written file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hooks {
+    pub commands: Vec<String>,
+    pub input: HookInput,
+    pub timeout: Duration,
+    /// Fail the scan if a hook fails instead of logging the failure
+    pub required: bool,
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Hooks {
+            commands: Vec::new(),
+            input: HookInput::Env,
+            timeout: Duration::from_secs(60),
+            required: false,
+        }
+    }
+}
+
+/// The path and metadata of a written file as passed to hooks
+#[derive(Debug, Serialize)]
+struct ScanFile<'a> {
+    path: &'a Path,
+    format: &'a str,
+    time: String,
+    profile: &'a str,
+    source: &'a str,
+    color: &'a str,
+    resolution: u32,
+    page: usize,
+    model: &'a str,
+}
+
+impl Hooks {
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    /// Runs the hooks on the file with the given page number. A failed hook
+    /// is logged and the remaining hooks are run, unless hooks are required.
+    pub async fn run(
+        &self,
+        path: &Path,
+        extension: &str,
+        fields: &NameFields,
+        page: usize,
+    ) -> Result<(), HookError> {
+        let file = ScanFile {
+            path,
+            format: extension,
+            time: fields.time.to_string(),
+            profile: &fields.profile,
+            source: &fields.source,
+            color: &fields.color,
+            resolution: fields.resolution,
+            page,
+            model: &fields.model,
+        };
+        let value = serde_json::to_value(&file).expect("the file serializes to json");
+        for command in &self.commands {
+            match self.run_command(command, &value).await {
+                Ok(()) => info!("Hook {command} finished"),
+                Err(e) if self.required => return Err(e),
+                Err(e) => error!("{e}"),
+            }
+        }
+        Ok(())
+    }
+
+    async fn run_command(&self, command: &str, file: &serde_json::Value) -> Result<(), HookError> {
+        let mut process = shell(command);
+        process
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        match self.input {
+            HookInput::Env => {
+                let fields = file.as_object().into_iter().flatten();
+                for (name, value) in fields {
+                    let value = match value {
+                        serde_json::Value::String(value) => value.clone(),
+                        value => value.to_string(),
+                    };
+                    process.env(format!("{ENV_PREFIX}{}", name.to_uppercase()), value);
+                }
+            }
+            HookInput::Json => {
+                process.stdin(Stdio::piped());
+            }
+        }
+        let spawn_error = |source| HookError::Spawn {
+            command: command.to_owned(),
+            source,
+        };
+        // the commands started by the shell are stopped with it
+        #[cfg(unix)]
+        process.process_group(0);
+        let mut child = process.spawn().map_err(spawn_error)?;
+        let id = child.id();
+        let stdin = child.stdin.take();
+        let input = async {
+            if let Some(mut stdin) = stdin {
+                // the hook may not read its input
+                if let Err(e) = stdin.write_all(file.to_string().as_bytes()).await {
+                    warn!("Cannot write to hook {command}. {e}");
+                }
+            }
+        };
+        let run = async { tokio::join!(input, child.wait_with_output()).1 };
+        // the shell is killed when the timeout drops it
+        let output = match tokio::time::timeout(self.timeout, run).await {
+            Ok(output) => output.map_err(spawn_error)?,
+            Err(_) => {
+                kill_group(id);
+                return Err(HookError::Timeout {
+                    command: command.to_owned(),
+                    timeout: self.timeout,
+                });
+            }
+        };
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            info!("{command}: {line}");
+        }
+        for line in String::from_utf8_lossy(&output.stderr).lines() {
+            warn!("{command}: {line}");
+        }
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(HookError::Failed {
+                command: command.to_owned(),
+                status: output.status,
+            })
+        }
+    }
+}
+
+/// Kills the process group of the hook
+#[cfg(unix)]
+fn kill_group(id: Option<u32>) {
+    use rustix::process::{Pid, Signal, kill_process_group};
+
+    let pid = id
+        .and_then(|id| i32::try_from(id).ok())
+        .and_then(Pid::from_raw);
+    if let Some(pid) = pid {
+        if let Err(e) = kill_process_group(pid, Signal::KILL) {
+            debug!("Cannot kill the processes of the hook. {e}");
+        }
+    }
+}
+
+#[cfg(windows)]
+fn kill_group(_id: Option<u32>) {}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use jiff::Timestamp;
+
+    fn fields() -> NameFields {
+        NameFields {
+            time: Timestamp::from_second(1486905545).unwrap(),
+            profile: "high".to_owned(),
+            source: "adf".to_owned(),
+            color: "gray".to_owned(),
+            resolution: 600,
+            model: "HP ENVY 5530 series".to_owned(),
+        }
+    }
+
+    fn hooks(commands: &[&str], input: HookInput) -> Hooks {
+        Hooks {
+            commands: commands.iter().map(|&c| c.to_owned()).collect(),
+            input,
+            timeout: Duration::from_secs(10),
+            required: true,
+        }
+    }
+
+    async fn run(hooks: &Hooks) -> Result<(), HookError> {
+        hooks
+            .run(Path::new("/tmp/scan 1.pdf"), "pdf", &fields(), 2)
+            .await
+    }
+
+    #[tokio::test]
+    async fn pass_environment() {
+        let hooks = hooks(
+            &[
+                r#"test "$COVET_PATH" = "/tmp/scan 1.pdf""#,
+                r#"test "$COVET_TIME" = 2017-02-12T13:19:05Z"#,
+                r#"test "$COVET_RESOLUTION$COVET_PAGE$COVET_FORMAT" = 6002pdf"#,
+                r#"test "$COVET_MODEL" = "HP ENVY 5530 series""#,
+            ],
+            HookInput::Env,
+        );
+        run(&hooks).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn pass_json() {
+        let json = hooks(
+            &[
+                r#"grep -q '"path":"/tmp/scan 1.pdf"'"#,
+                r#"grep -q '"resolution":600'"#,
+                r#"grep -q '"page":2'"#,
+            ],
+            HookInput::Json,
+        );
+        run(&json).await.unwrap();
+        // a hook which does not read its input
+        let ignored = hooks(&["true"], HookInput::Json);
+        run(&ignored).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn fail_only_required_hooks() {
+        let mut hooks = hooks(&["echo failing >&2; exit 3", "true"], HookInput::Env);
+        let error = run(&hooks).await.unwrap_err();
+        assert!(matches!(error, HookError::Failed { status, .. } if status.code() == Some(3)));
+        hooks.required = false;
+        run(&hooks).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stop_after_timeout() {
+        let mut hooks = hooks(&["sleep 10"], HookInput::Env);
+        hooks.timeout = Duration::from_millis(100);
+        let error = run(&hooks).await.unwrap_err();
+        assert!(matches!(error, HookError::Timeout { .. }));
+    }
+
+    #[tokio::test]
+    async fn stop_started_commands_after_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("late");
+        let command = format!("(sleep 1; touch '{}') & wait", file.display());
+        let mut hooks = hooks(&[command.as_str()], HookInput::Json);
+        hooks.timeout = Duration::from_millis(100);
+        let error = run(&hooks).await.unwrap_err();
+        assert!(matches!(error, HookError::Timeout { .. }));
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(!file.exists());
+    }
+}
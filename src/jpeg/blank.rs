@@ -0,0 +1,144 @@
+use bytes::Bytes;
+
+use crate::jpeg::decoder::Coefficients;
+use crate::jpeg::{Jpeg, ParseError, fix_jpeg_height};
+
+/// Blocks whose dequantized AC coefficients add up to more than this contain edges
+const EDGE_LIMIT: u32 = 320;
+/// Blocks whose DC coefficient differs more than this from the background have a
+/// different tone. The DC coefficient is eight times the mean sample value.
+const TONE_LIMIT: u32 = 8 * 40;
+/// Share of the page in percent at each side that is ignored, because
+/// scanners often leave shadows or the edge of the paper there
+const MARGIN_PERCENT: usize = 4;
+
+/// Estimates how much content a page contains without decoding it to pixels.
+///
+/// The luminance blocks of the page are classified by their DCT coefficients:
+/// a block counts as content if it contains edges or if its tone differs from
+/// the background. Returns the share of content blocks in percent, so an empty
+/// page scores close to 0.
+pub fn content_score(buffer: Bytes) -> Result<f64, ParseError> {
+    let buffer = match fix_jpeg_height(buffer.clone(), None)? {
+        Some((fixed, _)) => fixed,
+        None => buffer,
+    };
+    let jpeg = Jpeg::from_bytes(buffer)?;
+    let coefficients = Coefficients::decode(&jpeg)?;
+    let frame = &coefficients.frame;
+    let table = frame.components[0].tq;
+    let quantization = coefficients
+        .quantization_tables
+        .iter()
+        .find(|t| t.id == table)
+        .ok_or_else(|| format!("missing quantization table {table}"))?;
+    let blocks = &coefficients.components[0];
+    let width = frame.blocks_per_line(0).min(blocks.width);
+    let height = frame.block_rows(0, frame.height).min(blocks.height);
+    let margin_x = width * MARGIN_PERCENT / 100;
+    let margin_y = height * MARGIN_PERCENT / 100;
+
+    let mut tones = Vec::new();
+    let mut edges = Vec::new();
+    for y in margin_y..height - margin_y {
+        for x in margin_x..width - margin_x {
+            let block = blocks.get(x, y);
+            let dequantized = |k: usize| i32::from(block[k]) * i32::from(quantization.values[k]);
+            tones.push(dequantized(0));
+            edges.push((1..64).map(|k| dequantized(k).unsigned_abs()).sum::<u32>());
+        }
+    }
+    if tones.is_empty() {
+        return Ok(0.0);
+    }
+    let mut sorted = tones.clone();
+    sorted.sort_unstable();
+    let background = sorted[sorted.len() / 2];
+    let content = tones
+        .iter()
+        .zip(&edges)
+        .filter(|(tone, edge)| **edge > EDGE_LIMIT || tone.abs_diff(background) > TONE_LIMIT)
+        .count();
+    Ok(100.0 * content as f64 / tones.len() as f64)
+}
+
+/// Returns an empty white page with the size of the scan in the test data
+#[cfg(test)]
+pub fn blank_page() -> Bytes {
+    test_page(|_, _| false)
+}
+
+/// Returns a white page with the size of the scan in the test data and black
+/// 8x8 blocks of luminance where `dark` returns true for the block coordinates
+#[cfg(test)]
+pub fn test_page(dark: impl Fn(usize, usize) -> bool) -> Bytes {
+    block_page(|index, x, y| if index == 0 && dark(x, y) { -120 } else { 120 })
+}
+
+/// Returns a white page with the size of the scan in the test data and red
+/// 8x8 blocks of chroma where `red` returns true for the block coordinates of
+/// the subsampled chroma components
+#[cfg(test)]
+pub fn color_page(red: impl Fn(usize, usize) -> bool) -> Bytes {
+    block_page(|index, x, y| match index {
+        0 => 120,
+        2 if red(x, y) => 60,
+        _ => 0,
+    })
+}
+
+/// Returns a page with the size of the scan in the test data whose blocks
+/// only have the DC coefficient returned by `dc` for the component and the
+/// block coordinates
+#[cfg(test)]
+fn block_page(dc: impl Fn(usize, usize, usize) -> i16) -> Bytes {
+    let data = std::fs::read("doc/testdata/scan_from_adf_with_dnl_header.jpeg").unwrap();
+    let data = fix_jpeg_height(data.into(), None).unwrap().unwrap().0;
+    let mut coefficients = Coefficients::decode(&Jpeg::from_bytes(data).unwrap()).unwrap();
+    for (index, component) in coefficients.components.iter_mut().enumerate() {
+        for y in 0..component.height {
+            for x in 0..component.width {
+                let block = component.get_mut(x, y);
+                *block = [0; 64];
+                block[0] = dc(index, x, y);
+            }
+        }
+    }
+    crate::jpeg::encoder::encode(&coefficients, &[]).unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DNL_TEST_FILE: &str = "doc/testdata/scan_from_adf_with_dnl_header.jpeg";
+
+    fn decode(data: Bytes) -> Coefficients {
+        Coefficients::decode(&Jpeg::from_bytes(data).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn scanned_page_has_content() {
+        // the page only contains a thin hand-drawn arrow
+        let data = std::fs::read(DNL_TEST_FILE).unwrap();
+        let score = content_score(data.into()).unwrap();
+        assert!(score > 0.3, "score {score}");
+    }
+
+    #[test]
+    fn empty_page_scores_zero() {
+        assert_eq!(content_score(blank_page()).unwrap(), 0.0);
+
+        // dark blocks in the margin are ignored, but not in the middle of the page
+        let mut coefficients = decode(blank_page());
+        let blocks = &mut coefficients.components[0];
+        blocks.get_mut(0, 0)[0] = -100;
+        let (x, y) = (blocks.width / 2, blocks.height / 2);
+        for dx in 0..10 {
+            blocks.get_mut(x + dx, y)[1] = 50;
+        }
+        let page = crate::jpeg::encoder::encode(&coefficients, &[]).unwrap();
+        let score = content_score(page).unwrap();
+        assert!(score > 0.0 && score < 0.1, "score {score}");
+    }
+}
@@ -0,0 +1,818 @@
+use crate::jpeg::{Jpeg, Segment};
+
+/// Maps the position in the zig-zag sequence to the position in the 8x8 block
+pub const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27, 20,
+    13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58, 59,
+    52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+/// DCT coefficients of an 8x8 block in natural (row-major) order
+pub type Block = [i16; 64];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub marker: u8,
+    pub precision: u8,
+    pub height: u16,
+    pub width: u16,
+    pub components: Vec<FrameComponent>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameComponent {
+    pub id: u8,
+    pub h: u8,
+    pub v: u8,
+    pub tq: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanHeader {
+    pub components: Vec<ScanComponent>,
+    pub ss: u8,
+    pub se: u8,
+    pub ah: u8,
+    pub al: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScanComponent {
+    /// Index of the component in the frame header
+    pub index: usize,
+    pub td: u8,
+    pub ta: u8,
+}
+
+impl FrameHeader {
+    pub fn parse(marker: u8, payload: &[u8]) -> Result<Self, String> {
+        if payload.len() < 6 {
+            return Err("frame header too short".to_owned());
+        }
+        let count = usize::from(payload[5]);
+        if payload.len() < 6 + 3 * count {
+            return Err("frame header too short for components".to_owned());
+        }
+        let components = payload[6..6 + 3 * count]
+            .chunks(3)
+            .map(|c| FrameComponent {
+                id: c[0],
+                h: c[1] >> 4,
+                v: c[1] & 0x0f,
+                tq: c[2],
+            })
+            .collect::<Vec<_>>();
+        if components
+            .iter()
+            .any(|c| !(1..=4).contains(&c.h) || !(1..=4).contains(&c.v))
+        {
+            return Err("invalid sampling factor".to_owned());
+        }
+        Ok(Self {
+            marker,
+            precision: payload[0],
+            height: u16::from_be_bytes([payload[1], payload[2]]),
+            width: u16::from_be_bytes([payload[3], payload[4]]),
+            components,
+        })
+    }
+
+    pub fn max_h(&self) -> usize {
+        self.components
+            .iter()
+            .map(|c| usize::from(c.h))
+            .max()
+            .unwrap_or(1)
+    }
+
+    pub fn max_v(&self) -> usize {
+        self.components
+            .iter()
+            .map(|c| usize::from(c.v))
+            .max()
+            .unwrap_or(1)
+    }
+
+    /// Number of MCUs in a row of an interleaved scan
+    pub fn mcus_per_line(&self) -> usize {
+        usize::from(self.width).div_ceil(8 * self.max_h())
+    }
+
+    /// Number of MCU rows of an interleaved scan for the given height
+    pub fn mcu_rows(&self, height: u16) -> usize {
+        usize::from(height).div_ceil(8 * self.max_v())
+    }
+
+    /// Number of blocks in a row of the given component, without padding to whole MCUs
+    pub fn blocks_per_line(&self, index: usize) -> usize {
+        let h = usize::from(self.components[index].h);
+        (usize::from(self.width) * h)
+            .div_ceil(self.max_h())
+            .div_ceil(8)
+    }
+
+    /// Number of block rows of the given component, without padding to whole MCUs
+    pub fn block_rows(&self, index: usize, height: u16) -> usize {
+        let v = usize::from(self.components[index].v);
+        (usize::from(height) * v).div_ceil(self.max_v()).div_ceil(8)
+    }
+
+    /// Returns true if the frame is coded with Huffman coded sequential DCT
+    pub fn is_huffman_sequential(&self) -> bool {
+        matches!(self.marker, Jpeg::SOF0 | Jpeg::SOF1)
+    }
+}
+
+impl ScanHeader {
+    pub fn parse(payload: &[u8], frame: &FrameHeader) -> Result<Self, String> {
+        if payload.is_empty() {
+            return Err("scan header too short".to_owned());
+        }
+        let count = usize::from(payload[0]);
+        if payload.len() < 1 + 2 * count + 3 {
+            return Err("scan header too short for components".to_owned());
+        }
+        let components = payload[1..1 + 2 * count]
+            .chunks(2)
+            .map(|c| {
+                let index = frame
+                    .components
+                    .iter()
+                    .position(|fc| fc.id == c[0])
+                    .ok_or_else(|| format!("unknown component {} in scan", c[0]))?;
+                Ok(ScanComponent {
+                    index,
+                    td: c[1] >> 4,
+                    ta: c[1] & 0x0f,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let rest = &payload[1 + 2 * count..];
+        Ok(Self {
+            components,
+            ss: rest[0],
+            se: rest[1],
+            ah: rest[2] >> 4,
+            al: rest[2] & 0x0f,
+        })
+    }
+}
+
+/// A Huffman table in the form used by the decoding procedure of the standard (F.2.2.3)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HuffmanTable {
+    values: Vec<u8>,
+    max_code: [i32; 17],
+    val_ptr: [i32; 17],
+    min_code: [i32; 17],
+    /// Code length and value for all codes of up to `LOOKUP_BITS` bits
+    lookup: Vec<(u8, u8)>,
+}
+
+const LOOKUP_BITS: u32 = 9;
+
+impl HuffmanTable {
+    pub fn new(counts: [u8; 16], values: Vec<u8>) -> Result<Self, String> {
+        let total: usize = counts.iter().map(|c| usize::from(*c)).sum();
+        if total != values.len() || total > 256 {
+            return Err("invalid huffman table".to_owned());
+        }
+        let mut max_code = [-1; 17];
+        let mut val_ptr = [0; 17];
+        let mut min_code = [0; 17];
+        let mut lookup = vec![(0, 0); 1 << LOOKUP_BITS];
+        let mut code = 0i32;
+        let mut k = 0usize;
+        for len in 1..=16 {
+            let count = usize::from(counts[len - 1]);
+            if count > 0 {
+                val_ptr[len] = k as i32;
+                min_code[len] = code;
+                for _ in 0..count {
+                    if len as u32 <= LOOKUP_BITS {
+                        let shift = LOOKUP_BITS - len as u32;
+                        let start = (code as usize) << shift;
+                        for entry in &mut lookup[start..start + (1 << shift)] {
+                            *entry = (len as u8, values[k]);
+                        }
+                    }
+                    code += 1;
+                    k += 1;
+                }
+                max_code[len] = code - 1;
+            }
+            if code > 1 << len {
+                return Err("invalid huffman code lengths".to_owned());
+            }
+            code <<= 1;
+        }
+        Ok(Self {
+            values,
+            max_code,
+            val_ptr,
+            min_code,
+            lookup,
+        })
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Option<u8> {
+        let peek = reader.peek(LOOKUP_BITS);
+        let (len, value) = self.lookup[peek as usize];
+        if len > 0 {
+            reader.consume(u32::from(len));
+            return Some(value);
+        }
+        let mut code = reader.peek(LOOKUP_BITS + 1) as i32;
+        let mut len = LOOKUP_BITS as usize + 1;
+        while len <= 16 {
+            if code <= self.max_code[len] {
+                reader.consume(len as u32);
+                let index = self.val_ptr[len] + code - self.min_code[len];
+                return self.values.get(index as usize).copied();
+            }
+            len += 1;
+            code = reader.peek(len as u32) as i32;
+        }
+        None
+    }
+}
+
+/// Huffman tables defined so far, indexed by their destination identifier
+#[derive(Debug, Clone, Default)]
+pub struct HuffmanTables {
+    pub dc: [Option<HuffmanTable>; 4],
+    pub ac: [Option<HuffmanTable>; 4],
+}
+
+impl HuffmanTables {
+    /// Adds the tables of a `Define Huffman Table` segment payload
+    pub fn parse(&mut self, mut payload: &[u8]) -> Result<(), String> {
+        while !payload.is_empty() {
+            if payload.len() < 17 {
+                return Err("huffman table too short".to_owned());
+            }
+            let class = payload[0] >> 4;
+            let id = usize::from(payload[0] & 0x0f);
+            if id > 3 || class > 1 {
+                return Err(format!("invalid huffman table {:x}", payload[0]));
+            }
+            let mut counts = [0u8; 16];
+            counts.copy_from_slice(&payload[1..17]);
+            let total: usize = counts.iter().map(|c| usize::from(*c)).sum();
+            if payload.len() < 17 + total {
+                return Err("huffman table too short for values".to_owned());
+            }
+            let table = HuffmanTable::new(counts, payload[17..17 + total].to_vec())?;
+            if class == 0 {
+                self.dc[id] = Some(table);
+            } else {
+                self.ac[id] = Some(table);
+            }
+            payload = &payload[17 + total..];
+        }
+        Ok(())
+    }
+}
+
+/// Reads bits from entropy-coded data, removing stuffed zero bytes.
+///
+/// Once a marker or the end of the data is reached, zero bits are returned
+/// and counted as overrun.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    acc: u64,
+    bits: u32,
+    marker: Option<u8>,
+    overrun: bool,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            pos: 0,
+            acc: 0,
+            bits: 0,
+            marker: None,
+            overrun: false,
+        }
+    }
+
+    fn fill(&mut self) {
+        while self.bits <= 56 {
+            let byte = if self.marker.is_some() || self.pos >= self.data.len() {
+                None
+            } else if self.data[self.pos] == Jpeg::MARKER_START {
+                match self.data.get(self.pos + 1).copied() {
+                    Some(0x00) => {
+                        self.pos += 2;
+                        Some(Jpeg::MARKER_START)
+                    }
+                    Some(Jpeg::MARKER_START) => {
+                        // fill byte before a marker
+                        self.pos += 1;
+                        continue;
+                    }
+                    Some(m) => {
+                        self.marker = Some(m);
+                        None
+                    }
+                    None => {
+                        self.pos += 1;
+                        None
+                    }
+                }
+            } else {
+                self.pos += 1;
+                Some(self.data[self.pos - 1])
+            };
+            match byte {
+                Some(b) => {
+                    self.acc |= u64::from(b) << (56 - self.bits);
+                    self.bits += 8;
+                }
+                None => return,
+            }
+        }
+    }
+
+    fn peek(&mut self, n: u32) -> u32 {
+        if self.bits < n {
+            self.fill();
+        }
+        (self.acc >> (64 - n)) as u32
+    }
+
+    fn consume(&mut self, n: u32) {
+        if self.bits < n {
+            self.overrun = true;
+            self.acc = 0;
+            self.bits = 0;
+        } else {
+            self.acc <<= n;
+            self.bits -= n;
+        }
+    }
+
+    fn receive(&mut self, n: u32) -> u32 {
+        if n == 0 {
+            return 0;
+        }
+        let value = self.peek(n);
+        self.consume(n);
+        value
+    }
+
+    /// Skips to the next restart marker and resets the reader
+    fn restart(&mut self) -> bool {
+        self.acc = 0;
+        self.bits = 0;
+        if self.marker.is_none() {
+            // the marker may follow right after the buffered bits
+            self.fill();
+            self.acc = 0;
+            self.bits = 0;
+        }
+        match self.marker {
+            Some(m) if (Jpeg::RST0..=Jpeg::RST7).contains(&m) => {
+                self.pos += 2;
+                self.marker = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+fn extend(value: u32, size: u32) -> i32 {
+    if size == 0 {
+        0
+    } else if value < 1 << (size - 1) {
+        value as i32 - (1 << size) + 1
+    } else {
+        value as i32
+    }
+}
+
+/// Position of a decoded block within its component
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockPosition {
+    pub component: usize,
+    pub x: usize,
+    pub y: usize,
+}
+
+/// Result of decoding the entropy-coded data of a scan
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScanSummary {
+    /// Number of completely decoded MCUs
+    pub mcus: usize,
+    /// True if the data ended before all MCUs of the frame were decoded
+    pub truncated: bool,
+}
+
+/// Decodes the entropy-coded data of a Huffman coded sequential scan.
+///
+/// If the frame height is not known (0 or 0xffff), decoding continues until
+/// the data ends. Every completely decoded block is passed to `visit`.
+pub fn decode_scan(
+    frame: &FrameHeader,
+    scan: &ScanHeader,
+    tables: &HuffmanTables,
+    restart_interval: u16,
+    data: &[u8],
+    mut visit: impl FnMut(BlockPosition, &Block),
+) -> Result<ScanSummary, String> {
+    if !frame.is_huffman_sequential() {
+        return Err(format!("unsupported frame type {:X}", frame.marker));
+    }
+    let mut dc_tables = Vec::new();
+    let mut ac_tables = Vec::new();
+    for c in &scan.components {
+        let dc = tables.dc[usize::from(c.td) & 3].as_ref();
+        let ac = tables.ac[usize::from(c.ta) & 3].as_ref();
+        match dc.zip(ac) {
+            Some((dc, ac)) => {
+                dc_tables.push(dc);
+                ac_tables.push(ac);
+            }
+            None => return Err("missing huffman table".to_owned()),
+        }
+    }
+    let interleaved = scan.components.len() > 1;
+    let known_height = !matches!(frame.height, 0 | u16::MAX);
+    let (mcus_per_line, total_mcus) = if interleaved {
+        let per_line = frame.mcus_per_line();
+        (per_line, per_line * frame.mcu_rows(frame.height))
+    } else {
+        let index = scan.components[0].index;
+        let per_line = frame.blocks_per_line(index);
+        (per_line, per_line * frame.block_rows(index, frame.height))
+    };
+    let total_mcus = if known_height { total_mcus } else { usize::MAX };
+
+    let mut reader = BitReader::new(data);
+    let mut predictions = vec![0i32; scan.components.len()];
+    let mut block = [0i16; 64];
+    let mut blocks = Vec::new();
+    let mut mcus = 0usize;
+    while mcus < total_mcus {
+        if restart_interval > 0 && mcus > 0 && mcus % usize::from(restart_interval) == 0 {
+            if !reader.restart() {
+                break;
+            }
+            predictions.iter_mut().for_each(|p| *p = 0);
+        }
+        let mcu_x = mcus % mcus_per_line;
+        let mcu_y = mcus / mcus_per_line;
+        blocks.clear();
+        for (i, c) in scan.components.iter().enumerate() {
+            let fc = &frame.components[c.index];
+            let (h, v) = if interleaved {
+                (usize::from(fc.h), usize::from(fc.v))
+            } else {
+                (1, 1)
+            };
+            for by in 0..v {
+                for bx in 0..h {
+                    decode_block(
+                        &mut reader,
+                        dc_tables[i],
+                        ac_tables[i],
+                        &mut predictions[i],
+                        &mut block,
+                    )?;
+                    let position = BlockPosition {
+                        component: c.index,
+                        x: mcu_x * h + bx,
+                        y: mcu_y * v + by,
+                    };
+                    blocks.push((position, block));
+                }
+            }
+        }
+        if reader.overrun {
+            break;
+        }
+        for (position, block) in &blocks {
+            visit(*position, block);
+        }
+        mcus += 1;
+    }
+    Ok(ScanSummary {
+        mcus,
+        truncated: known_height && mcus < total_mcus,
+    })
+}
+
+fn decode_block(
+    reader: &mut BitReader,
+    dc_table: &HuffmanTable,
+    ac_table: &HuffmanTable,
+    prediction: &mut i32,
+    block: &mut Block,
+) -> Result<(), String> {
+    block.fill(0);
+    let Some(size) = dc_table.decode(reader) else {
+        return invalid_code(reader);
+    };
+    let size = u32::from(size);
+    if size > 16 {
+        return Err("invalid DC coefficient size".to_owned());
+    }
+    let diff = extend(reader.receive(size), size);
+    *prediction += diff;
+    block[0] = *prediction as i16;
+    let mut k = 1;
+    while k < 64 {
+        let Some(rs) = ac_table.decode(reader) else {
+            return invalid_code(reader);
+        };
+        let run = usize::from(rs >> 4);
+        let size = u32::from(rs & 0x0f);
+        if size == 0 {
+            if run == 15 {
+                k += 16;
+                continue;
+            }
+            break;
+        }
+        k += run;
+        if k > 63 {
+            return Err("AC coefficient index out of range".to_owned());
+        }
+        block[ZIGZAG[k]] = extend(reader.receive(size), size) as i16;
+        k += 1;
+    }
+    Ok(())
+}
+
+/// An invalid code at the end of the data is caused by truncation
+fn invalid_code(reader: &mut BitReader) -> Result<(), String> {
+    if reader.marker.is_some() || reader.pos >= reader.data.len() {
+        reader.overrun = true;
+        Ok(())
+    } else {
+        Err("invalid huffman code".to_owned())
+    }
+}
+
+/// Decoding state collected while walking through the segments of an image
+#[derive(Debug, Default)]
+pub struct DecoderState {
+    pub frame: Option<FrameHeader>,
+    pub tables: HuffmanTables,
+    pub restart_interval: u16,
+}
+
+impl DecoderState {
+    /// Updates the state with a table or frame definition segment
+    pub fn update(&mut self, segment: &Segment) -> Result<(), String> {
+        match segment.marker() {
+            Jpeg::DHT => self.tables.parse(segment.payload())?,
+            Jpeg::DRI => {
+                let payload = segment.payload();
+                if payload.len() < 2 {
+                    return Err("restart interval too short".to_owned());
+                }
+                self.restart_interval = u16::from_be_bytes([payload[0], payload[1]]);
+            }
+            m if Jpeg::is_sof(m) => self.frame = Some(FrameHeader::parse(m, segment.payload())?),
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+/// A quantization table with its values in natural (row-major) order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuantizationTable {
+    pub id: u8,
+    /// 0 for 8 bit and 1 for 16 bit values
+    pub precision: u8,
+    pub values: [u16; 64],
+}
+
+impl QuantizationTable {
+    /// Parses all tables of a `Define Quantization Table` segment payload
+    pub fn parse_all(mut payload: &[u8]) -> Result<Vec<Self>, String> {
+        let mut tables = Vec::new();
+        while !payload.is_empty() {
+            let precision = payload[0] >> 4;
+            let id = payload[0] & 0x0f;
+            let size = if precision == 0 { 64 } else { 128 };
+            if id > 3 || precision > 1 {
+                return Err(format!("invalid quantization table {:x}", payload[0]));
+            }
+            if payload.len() < 1 + size {
+                return Err("quantization table too short".to_owned());
+            }
+            let mut values = [0u16; 64];
+            for (k, position) in ZIGZAG.iter().enumerate() {
+                values[*position] = if precision == 0 {
+                    u16::from(payload[1 + k])
+                } else {
+                    u16::from_be_bytes([payload[1 + 2 * k], payload[2 + 2 * k]])
+                };
+            }
+            tables.push(Self {
+                id,
+                precision,
+                values,
+            });
+            payload = &payload[1 + size..];
+        }
+        Ok(tables)
+    }
+}
+
+/// The DCT coefficients of all blocks of a component, padded to whole MCUs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ComponentBlocks {
+    /// Number of blocks per row
+    pub width: usize,
+    /// Number of block rows
+    pub height: usize,
+    pub blocks: Vec<Block>,
+}
+
+impl ComponentBlocks {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            blocks: vec![[0; 64]; width * height],
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> &Block {
+        &self.blocks[y * self.width + x]
+    }
+
+    pub fn get_mut(&mut self, x: usize, y: usize) -> &mut Block {
+        &mut self.blocks[y * self.width + x]
+    }
+}
+
+/// The decoded DCT coefficients of a Huffman coded sequential image
+#[derive(Debug, Clone)]
+pub struct Coefficients {
+    pub frame: FrameHeader,
+    pub quantization_tables: Vec<QuantizationTable>,
+    pub components: Vec<ComponentBlocks>,
+}
+
+impl Coefficients {
+    /// Decodes all scans of the image. The height in the frame header has to be known.
+    /// Blocks missing from truncated scans are left empty.
+    pub fn decode(jpeg: &Jpeg) -> Result<Self, String> {
+        let mut state = DecoderState::default();
+        let mut quantization_tables: Vec<QuantizationTable> = Vec::new();
+        let mut coefficients: Option<Coefficients> = None;
+        for segment in jpeg.segments() {
+            match segment.marker() {
+                Jpeg::DQT => {
+                    for table in QuantizationTable::parse_all(segment.payload())? {
+                        quantization_tables.retain(|t| t.id != table.id);
+                        quantization_tables.push(table);
+                    }
+                }
+                Jpeg::SOS => {
+                    let frame = state.frame.as_ref().ok_or("scan before frame header")?;
+                    if !frame.is_huffman_sequential() {
+                        return Err(format!("unsupported frame type {:X}", frame.marker));
+                    }
+                    if matches!(frame.height, 0 | u16::MAX) {
+                        return Err("unknown image height".to_owned());
+                    }
+                    let coefficients = coefficients.get_or_insert_with(|| Self::new(frame));
+                    let scan = ScanHeader::parse(segment.payload(), frame)?;
+                    let components = &mut coefficients.components;
+                    decode_scan(
+                        &coefficients.frame,
+                        &scan,
+                        &state.tables,
+                        state.restart_interval,
+                        segment.entropy_data(),
+                        |position, block| {
+                            let blocks = &mut components[position.component];
+                            if position.x < blocks.width && position.y < blocks.height {
+                                *blocks.get_mut(position.x, position.y) = *block;
+                            }
+                        },
+                    )?;
+                }
+                _ => state.update(segment)?,
+            }
+        }
+        let mut coefficients = coefficients.ok_or("no scan found")?;
+        coefficients.quantization_tables = quantization_tables;
+        Ok(coefficients)
+    }
+
+    pub fn new(frame: &FrameHeader) -> Self {
+        let mut frame = frame.clone();
+        // the sampling factors of a single component are irrelevant
+        if let [component] = frame.components.as_mut_slice() {
+            component.h = 1;
+            component.v = 1;
+        }
+        let components = frame
+            .components
+            .iter()
+            .map(|c| {
+                ComponentBlocks::new(
+                    frame.mcus_per_line() * usize::from(c.h),
+                    frame.mcu_rows(frame.height) * usize::from(c.v),
+                )
+            })
+            .collect();
+        Self {
+            frame,
+            quantization_tables: Vec::new(),
+            components,
+        }
+    }
+}
+
+/// Counts the lines of all complete MCU rows in the first scan of the image.
+/// Returns `None` if the frame is not Huffman coded sequential.
+pub fn count_complete_lines(jpeg: &Jpeg) -> Result<Option<usize>, String> {
+    let mut state = DecoderState::default();
+    for segment in jpeg.segments() {
+        if segment.marker() == Jpeg::SOS {
+            let frame = state.frame.as_ref().ok_or("scan before frame header")?;
+            if !frame.is_huffman_sequential() {
+                return Ok(None);
+            }
+            let scan = ScanHeader::parse(segment.payload(), frame)?;
+            // decode until the data ends
+            let mut unknown_height = frame.clone();
+            unknown_height.height = 0;
+            let summary = decode_scan(
+                &unknown_height,
+                &scan,
+                &state.tables,
+                state.restart_interval,
+                segment.entropy_data(),
+                |_, _| (),
+            )?;
+            let lines = if scan.components.len() > 1 {
+                summary.mcus / frame.mcus_per_line() * 8 * frame.max_v()
+            } else {
+                let index = scan.components[0].index;
+                let v = usize::from(frame.components[index].v);
+                summary.mcus / frame.blocks_per_line(index) * 8 * frame.max_v() / v
+            };
+            return Ok(Some(lines));
+        }
+        state.update(segment)?;
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn extend_values() {
+        assert_eq!(extend(0, 0), 0);
+        assert_eq!(extend(0, 1), -1);
+        assert_eq!(extend(1, 1), 1);
+        assert_eq!(extend(0b00, 2), -3);
+        assert_eq!(extend(0b01, 2), -2);
+        assert_eq!(extend(0b10, 2), 2);
+        assert_eq!(extend(0b11, 2), 3);
+    }
+
+    #[test]
+    fn huffman_table_decodes_short_and_long_codes() {
+        // lengths: one code of length 1, one of length 2, one of length 12
+        let mut counts = [0u8; 16];
+        counts[0] = 1;
+        counts[1] = 1;
+        counts[11] = 1;
+        let table = HuffmanTable::new(counts, vec![7, 8, 9]).unwrap();
+        // codes: 0, 10, 110000000000
+        let data = [0b0101_1000, 0b0000_0000, 0b0111_1111];
+        let mut reader = BitReader::new(&data);
+        assert_eq!(table.decode(&mut reader), Some(7));
+        assert_eq!(table.decode(&mut reader), Some(8));
+        assert_eq!(table.decode(&mut reader), Some(9));
+        assert!(!reader.overrun);
+    }
+
+    #[test]
+    fn bit_reader_removes_stuffed_bytes_and_stops_at_marker() {
+        let data = [0xff, 0x00, 0x0f, 0xff, 0xd9];
+        let mut reader = BitReader::new(&data);
+        assert_eq!(reader.receive(8), 0xff);
+        assert_eq!(reader.receive(8), 0x0f);
+        assert!(!reader.overrun);
+        assert_eq!(reader.marker, Some(Jpeg::EOI));
+        assert_eq!(reader.receive(1), 0);
+        assert!(reader.overrun);
+    }
+}
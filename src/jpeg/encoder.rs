@@ -0,0 +1,444 @@
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::jpeg::decoder::{Block, Coefficients, ComponentBlocks, FrameHeader, ZIGZAG};
+use crate::jpeg::{Jpeg, Segment};
+
+/// Huffman table as stored in a `Define Huffman Table` segment
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct HuffmanSpec {
+    counts: [u8; 16],
+    values: Vec<u8>,
+}
+
+/// Code and code length of every symbol
+struct HuffmanCodes {
+    codes: [u16; 256],
+    sizes: [u8; 256],
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Class {
+    Dc,
+    Ac,
+}
+
+impl HuffmanSpec {
+    /// Generates an optimal table for the given symbol frequencies (K.2)
+    fn optimal(frequencies: &[u64; 256]) -> Self {
+        if frequencies.iter().all(|f| *f == 0) {
+            // an unused table still needs a valid definition
+            let mut counts = [0; 16];
+            counts[0] = 1;
+            return Self {
+                counts,
+                values: vec![0],
+            };
+        }
+        let mut freq = [0u64; 257];
+        freq[..256].copy_from_slice(frequencies);
+        // reserve one code point so that no code consists of only 1 bits
+        freq[256] = 1;
+        let mut code_size = [0usize; 257];
+        let mut others = [None; 257];
+        while let Some(mut c1) = least_frequent(&freq, None) {
+            let Some(mut c2) = least_frequent(&freq, Some(c1)) else {
+                break;
+            };
+            freq[c1] += freq[c2];
+            freq[c2] = 0;
+            code_size[c1] += 1;
+            while let Some(next) = others[c1] {
+                c1 = next;
+                code_size[c1] += 1;
+            }
+            others[c1] = Some(c2);
+            code_size[c2] += 1;
+            while let Some(next) = others[c2] {
+                c2 = next;
+                code_size[c2] += 1;
+            }
+        }
+        // a code can be as long as the number of symbols before limiting
+        let mut bits = [0u32; 258];
+        for size in code_size.iter().filter(|s| **s > 0) {
+            bits[*size] += 1;
+        }
+        // limit the code lengths to 16 bits
+        for i in (17..bits.len()).rev() {
+            while bits[i] > 0 {
+                let mut j = i - 2;
+                while bits[j] == 0 {
+                    j -= 1;
+                }
+                bits[i] -= 2;
+                bits[i - 1] += 1;
+                bits[j + 1] += 2;
+                bits[j] -= 1;
+            }
+        }
+        // remove the reserved code point
+        let mut longest = 16;
+        while bits[longest] == 0 {
+            longest -= 1;
+        }
+        bits[longest] -= 1;
+
+        let mut counts = [0u8; 16];
+        for (count, bits) in counts.iter_mut().zip(&bits[1..=16]) {
+            *count = *bits as u8;
+        }
+        let mut values = Vec::new();
+        for size in 1..bits.len() {
+            values.extend((0..=255u8).filter(|v| code_size[usize::from(*v)] == size));
+        }
+        values.truncate(counts.iter().map(|c| usize::from(*c)).sum());
+        Self { counts, values }
+    }
+
+    fn codes(&self) -> HuffmanCodes {
+        let mut codes = HuffmanCodes {
+            codes: [0; 256],
+            sizes: [0; 256],
+        };
+        let mut code = 0u16;
+        let mut values = self.values.iter();
+        for (len, count) in self.counts.iter().enumerate() {
+            for value in values.by_ref().take(usize::from(*count)) {
+                codes.codes[usize::from(*value)] = code;
+                codes.sizes[usize::from(*value)] = len as u8 + 1;
+                code += 1;
+            }
+            code <<= 1;
+        }
+        codes
+    }
+}
+
+fn least_frequent(freq: &[u64; 257], exclude: Option<usize>) -> Option<usize> {
+    let mut result = None;
+    let mut min = u64::MAX;
+    // ties are resolved in favor of the largest symbol
+    for (i, f) in freq.iter().enumerate() {
+        if *f != 0 && *f <= min && Some(i) != exclude {
+            min = *f;
+            result = Some(i);
+        }
+    }
+    result
+}
+
+/// Writes entropy-coded data, stuffing a zero byte after each 0xff byte
+struct BitWriter {
+    data: BytesMut,
+    acc: u32,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        Self {
+            data: BytesMut::new(),
+            acc: 0,
+            bits: 0,
+        }
+    }
+
+    fn write(&mut self, value: u32, size: u32) {
+        if size == 0 {
+            return;
+        }
+        self.acc = (self.acc << size) | (value & ((1 << size) - 1));
+        self.bits += size;
+        while self.bits >= 8 {
+            let byte = (self.acc >> (self.bits - 8)) as u8;
+            self.data.put_u8(byte);
+            if byte == Jpeg::MARKER_START {
+                self.data.put_u8(0);
+            }
+            self.bits -= 8;
+        }
+        self.acc &= (1 << self.bits) - 1;
+    }
+
+    /// Pads the last byte with 1 bits
+    fn finish(mut self) -> BytesMut {
+        if self.bits > 0 {
+            let padding = 8 - self.bits;
+            self.write((1 << padding) - 1, padding);
+        }
+        self.data
+    }
+}
+
+/// Number of bits needed to represent the magnitude of the value
+fn category(value: i32) -> u32 {
+    32 - value.unsigned_abs().leading_zeros()
+}
+
+/// The additional bits following the Huffman code of a value
+fn value_bits(value: i32, size: u32) -> u32 {
+    let value = if value < 0 { value - 1 } else { value };
+    (value as u32) & ((1 << size) - 1)
+}
+
+/// Emits the symbols and additional bits of a block
+fn encode_block(block: &Block, prediction: &mut i32, mut emit: impl FnMut(Class, u8, u32, u32)) {
+    let dc = i32::from(block[0]);
+    let diff = dc - *prediction;
+    *prediction = dc;
+    let size = category(diff);
+    emit(Class::Dc, size as u8, value_bits(diff, size), size);
+    let mut run = 0;
+    for position in &ZIGZAG[1..] {
+        let value = i32::from(block[*position]);
+        if value == 0 {
+            run += 1;
+            continue;
+        }
+        while run > 15 {
+            emit(Class::Ac, 0xf0, 0, 0);
+            run -= 16;
+        }
+        let size = category(value);
+        emit(
+            Class::Ac,
+            (run << 4) | size as u8,
+            value_bits(value, size),
+            size,
+        );
+        run = 0;
+    }
+    if run > 0 {
+        emit(Class::Ac, 0x00, 0, 0);
+    }
+}
+
+/// Calls `visit` with the index of the component within the scan and each
+/// block of the scan in coding order
+fn for_each_block(
+    frame: &FrameHeader,
+    components: &[ComponentBlocks],
+    scan: &[usize],
+    mut visit: impl FnMut(usize, &Block),
+) {
+    if let [index] = scan {
+        let blocks = &components[*index];
+        for y in 0..frame.block_rows(*index, frame.height) {
+            for x in 0..frame.blocks_per_line(*index) {
+                visit(0, blocks.get(x, y));
+            }
+        }
+        return;
+    }
+    for mcu_y in 0..frame.mcu_rows(frame.height) {
+        for mcu_x in 0..frame.mcus_per_line() {
+            for (i, index) in scan.iter().enumerate() {
+                let c = &frame.components[*index];
+                let (h, v) = (usize::from(c.h), usize::from(c.v));
+                for by in 0..v {
+                    for bx in 0..h {
+                        visit(i, components[*index].get(mcu_x * h + bx, mcu_y * v + by));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Encodes the coefficients as a Huffman coded sequential image with optimized
+/// Huffman tables and without restart intervals.
+///
+/// The `segments` are written after the SOI marker, usually the application
+/// segments of the original image.
+pub fn encode(coefficients: &Coefficients, segments: &[&Segment]) -> Result<Bytes, String> {
+    let frame = &coefficients.frame;
+    if !frame.is_huffman_sequential() {
+        return Err(format!("unsupported frame type {:X}", frame.marker));
+    }
+    // the first component uses table 0, all others share table 1
+    let table_id = |index: usize| usize::from(index > 0);
+    let scans: Vec<Vec<usize>> = if (2..=4).contains(&frame.components.len()) {
+        vec![(0..frame.components.len()).collect()]
+    } else {
+        (0..frame.components.len()).map(|i| vec![i]).collect()
+    };
+
+    let mut frequencies = [[[0u64; 256]; 2]; 2];
+    for scan in &scans {
+        let mut predictions = vec![0; scan.len()];
+        for_each_block(frame, &coefficients.components, scan, |i, block| {
+            let table = table_id(scan[i]);
+            encode_block(block, &mut predictions[i], |class, symbol, _, _| {
+                frequencies[class as usize][table][usize::from(symbol)] += 1;
+            });
+        });
+    }
+    let tables_used = if frame.components.len() > 1 { 2 } else { 1 };
+    let specs: Vec<[HuffmanSpec; 2]> = (0..tables_used)
+        .map(|table| {
+            [
+                HuffmanSpec::optimal(&frequencies[Class::Dc as usize][table]),
+                HuffmanSpec::optimal(&frequencies[Class::Ac as usize][table]),
+            ]
+        })
+        .collect();
+    let codes: Vec<[HuffmanCodes; 2]> = specs
+        .iter()
+        .map(|[dc, ac]| [dc.codes(), ac.codes()])
+        .collect();
+
+    let mut buffer = BytesMut::new();
+    buffer.put_slice(&[Jpeg::MARKER_START, Jpeg::SOI]);
+    for segment in segments {
+        buffer.put_slice(&segment.buffer);
+    }
+
+    let mut dqt = Vec::new();
+    for table in &coefficients.quantization_tables {
+        dqt.push((table.precision << 4) | table.id);
+        for position in ZIGZAG {
+            let value = table.values[position];
+            if table.precision == 0 {
+                dqt.push(value as u8);
+            } else {
+                dqt.extend_from_slice(&value.to_be_bytes());
+            }
+        }
+    }
+    put_segment(&mut buffer, Jpeg::DQT, &dqt)?;
+
+    let mut sof = vec![frame.precision];
+    sof.extend_from_slice(&frame.height.to_be_bytes());
+    sof.extend_from_slice(&frame.width.to_be_bytes());
+    sof.push(frame.components.len() as u8);
+    for c in &frame.components {
+        sof.extend_from_slice(&[c.id, (c.h << 4) | c.v, c.tq]);
+    }
+    put_segment(&mut buffer, frame.marker, &sof)?;
+
+    let mut dht = Vec::new();
+    for (table, spec) in specs.iter().enumerate() {
+        for (class, spec) in spec.iter().enumerate() {
+            dht.push(((class as u8) << 4) | table as u8);
+            dht.extend_from_slice(&spec.counts);
+            dht.extend_from_slice(&spec.values);
+        }
+    }
+    put_segment(&mut buffer, Jpeg::DHT, &dht)?;
+
+    for scan in &scans {
+        let mut sos = vec![scan.len() as u8];
+        for index in scan {
+            let table = table_id(*index) as u8;
+            sos.extend_from_slice(&[frame.components[*index].id, (table << 4) | table]);
+        }
+        sos.extend_from_slice(&[0, 63, 0]);
+        put_segment(&mut buffer, Jpeg::SOS, &sos)?;
+
+        let mut writer = BitWriter::new();
+        let mut predictions = vec![0; scan.len()];
+        for_each_block(frame, &coefficients.components, scan, |i, block| {
+            let codes = &codes[table_id(scan[i])];
+            encode_block(block, &mut predictions[i], |class, symbol, bits, size| {
+                let codes = &codes[class as usize];
+                let symbol = usize::from(symbol);
+                writer.write(
+                    u32::from(codes.codes[symbol]),
+                    u32::from(codes.sizes[symbol]),
+                );
+                writer.write(bits, size);
+            });
+        });
+        buffer.put(writer.finish());
+    }
+    buffer.put_slice(&[Jpeg::MARKER_START, Jpeg::EOI]);
+    Ok(buffer.into())
+}
+
+fn put_segment(buffer: &mut BytesMut, marker: u8, payload: &[u8]) -> Result<(), String> {
+    let len = u16::try_from(payload.len() + 2).map_err(|_| "segment too large".to_owned())?;
+    buffer.put_slice(&[Jpeg::MARKER_START, marker]);
+    buffer.put_u16(len);
+    buffer.put_slice(payload);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::jpeg::decoder::{HuffmanTable, HuffmanTables, ScanHeader, decode_scan};
+
+    #[test]
+    fn optimal_table_has_valid_code_lengths() {
+        let mut frequencies = [0u64; 256];
+        // a very skewed distribution needs codes longer than 16 bits before limiting
+        for (i, f) in frequencies.iter_mut().enumerate() {
+            *f = 1 << (i % 40);
+        }
+        let spec = HuffmanSpec::optimal(&frequencies);
+        assert_eq!(spec.values.len(), 256);
+        assert!(HuffmanTable::new(spec.counts, spec.values.clone()).is_ok());
+        let codes = spec.codes();
+        // the most frequent symbols get the shortest codes
+        assert!(codes.sizes[39] <= codes.sizes[0]);
+        // no code consists of only 1 bits
+        for (code, size) in codes.codes.iter().zip(codes.sizes).filter(|(_, s)| *s > 0) {
+            assert_ne!(u32::from(*code), (1 << size) - 1);
+        }
+    }
+
+    #[test]
+    fn bit_writer_stuffs_and_pads() {
+        let mut writer = BitWriter::new();
+        writer.write(0xff, 8);
+        writer.write(0b101, 3);
+        assert_eq!(writer.finish().as_ref(), &[0xff, 0x00, 0b1011_1111]);
+    }
+
+    #[test]
+    fn encoded_blocks_decode_to_same_coefficients() {
+        let mut block = [0i16; 64];
+        block[0] = -20;
+        block[1] = 3;
+        block[8] = -1;
+        block[63] = 1000;
+        let blocks = [block, [0; 64], block];
+        let mut frequencies = [[0u64; 256]; 2];
+        let mut prediction = 0;
+        for block in &blocks {
+            encode_block(block, &mut prediction, |class, symbol, _, _| {
+                frequencies[class as usize][usize::from(symbol)] += 1;
+            });
+        }
+        let dc = HuffmanSpec::optimal(&frequencies[0]);
+        let ac = HuffmanSpec::optimal(&frequencies[1]);
+        let codes = [dc.codes(), ac.codes()];
+        let mut writer = BitWriter::new();
+        let mut prediction = 0;
+        for block in &blocks {
+            encode_block(block, &mut prediction, |class, symbol, bits, size| {
+                let codes = &codes[class as usize];
+                let symbol = usize::from(symbol);
+                writer.write(
+                    u32::from(codes.codes[symbol]),
+                    u32::from(codes.sizes[symbol]),
+                );
+                writer.write(bits, size);
+            });
+        }
+        let data = writer.finish();
+
+        let frame = FrameHeader::parse(Jpeg::SOF0, &[8, 0, 8, 0, 24, 1, 1, 0x11, 0]).unwrap();
+        let scan = ScanHeader::parse(&[1, 1, 0x00, 0, 63, 0], &frame).unwrap();
+        let mut tables = HuffmanTables::default();
+        tables.dc[0] = Some(HuffmanTable::new(dc.counts, dc.values).unwrap());
+        tables.ac[0] = Some(HuffmanTable::new(ac.counts, ac.values).unwrap());
+        let mut decoded = Vec::new();
+        let summary = decode_scan(&frame, &scan, &tables, 0, &data, |_, block| {
+            decoded.push(*block)
+        })
+        .unwrap();
+        assert!(!summary.truncated);
+        assert_eq!(decoded, blocks);
+    }
+}
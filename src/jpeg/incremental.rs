@@ -0,0 +1,225 @@
+use tracing::debug;
+
+use crate::jpeg::{Jpeg, is_placeholder_height};
+
+/// Incrementally parses a JPEG byte stream without keeping it in memory.
+///
+/// It remembers where the height of the frame is stored and the value of a
+/// `Define Number of Lines` segment, so the height can be patched in place
+/// once the whole image has been written somewhere else.
+#[derive(Debug)]
+pub struct HeightScanner {
+    offset: u64,
+    state: State,
+    sof_height_offset: Option<u64>,
+    sof_height: Option<u16>,
+    dnl_height: Option<u16>,
+    eoi: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Marker,
+    MarkerCode,
+    Length { marker: u8, high: Option<u8> },
+    Body { marker: u8, pos: usize, len: usize },
+    EntropyData,
+    EntropyDataMarker,
+    Failed,
+}
+
+impl Default for HeightScanner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HeightScanner {
+    pub fn new() -> Self {
+        Self {
+            offset: 0,
+            state: State::Marker,
+            sof_height_offset: None,
+            sof_height: None,
+            dnl_height: None,
+            eoi: false,
+        }
+    }
+
+    /// Feeds the next chunk of the image into the scanner
+    pub fn feed(&mut self, chunk: &[u8]) {
+        for &byte in chunk {
+            self.state = self.next_state(byte);
+            self.offset += 1;
+        }
+    }
+
+    /// Returns true if the data seen so far is not a valid JPEG
+    pub fn failed(&self) -> bool {
+        self.state == State::Failed
+    }
+
+    /// Returns the offset and value of the height if it needs to be patched
+    pub fn height_patch(&self) -> Option<(u64, u16)> {
+        if self.failed() {
+            return None;
+        }
+        self.sof_height_offset.zip(self.dnl_height)
+    }
+
+    /// Returns true if the image does not end with an EOI marker
+    pub fn is_truncated(&self) -> bool {
+        !self.eoi
+    }
+
+    /// Returns true if the height cannot be patched in place but the image
+    /// still needs to be repaired because its height is unknown or it is truncated
+    pub fn needs_repair(&self) -> bool {
+        !self.failed()
+            && self.dnl_height.is_none()
+            && (self.sof_height.is_none_or(is_placeholder_height) || !self.eoi)
+    }
+
+    fn next_state(&mut self, byte: u8) -> State {
+        match self.state {
+            State::Marker => {
+                if byte == Jpeg::MARKER_START {
+                    State::MarkerCode
+                } else {
+                    debug!("segment not starting with 0xff at offset {}", self.offset);
+                    State::Failed
+                }
+            }
+            State::MarkerCode => match byte {
+                Jpeg::MARKER_START => State::MarkerCode,
+                marker if Jpeg::is_standalone(marker) => {
+                    self.eoi = marker == Jpeg::EOI;
+                    State::Marker
+                }
+                marker => State::Length { marker, high: None },
+            },
+            State::Length { marker, high: None } => State::Length {
+                marker,
+                high: Some(byte),
+            },
+            State::Length {
+                marker,
+                high: Some(high),
+            } => {
+                let len = usize::from(u16::from_be_bytes([high, byte]));
+                if len < 2 {
+                    debug!("invalid segment length {len} at offset {}", self.offset);
+                    return State::Failed;
+                }
+                self.after_payload(marker, 2, len)
+            }
+            State::Body { marker, pos, len } => {
+                match (marker, pos) {
+                    (m, 3) if Jpeg::is_sof(m) => {
+                        self.sof_height_offset = Some(self.offset);
+                        self.sof_height = Some(u16::from(byte) << 8);
+                    }
+                    (m, 4) if Jpeg::is_sof(m) => {
+                        self.sof_height = self.sof_height.map(|h| h | u16::from(byte));
+                    }
+                    (Jpeg::DNL, 2) => self.dnl_height = Some(u16::from(byte) << 8),
+                    (Jpeg::DNL, 3) => {
+                        self.dnl_height = self.dnl_height.map(|h| h | u16::from(byte));
+                    }
+                    _ => (),
+                }
+                self.after_payload(marker, pos + 1, len)
+            }
+            State::EntropyData => {
+                if byte == Jpeg::MARKER_START {
+                    State::EntropyDataMarker
+                } else {
+                    State::EntropyData
+                }
+            }
+            State::EntropyDataMarker => match byte {
+                0x00 | Jpeg::RST0..=Jpeg::RST7 => State::EntropyData,
+                Jpeg::MARKER_START => State::EntropyDataMarker,
+                marker if Jpeg::is_standalone(marker) => {
+                    self.eoi = marker == Jpeg::EOI;
+                    State::Marker
+                }
+                marker => State::Length { marker, high: None },
+            },
+            State::Failed => State::Failed,
+        }
+    }
+
+    fn after_payload(&self, marker: u8, pos: usize, len: usize) -> State {
+        if pos < len {
+            State::Body { marker, pos, len }
+        } else if marker == Jpeg::SOS {
+            State::EntropyData
+        } else {
+            State::Marker
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::jpeg::fix_jpeg_height;
+    use crate::jpeg::test::{frame, marker, scan, segment};
+
+    const DNL_TEST_FILE: &str = "doc/testdata/scan_from_adf_with_dnl_header.jpeg";
+
+    fn scan_in_chunks(data: &[u8], chunk_size: usize) -> HeightScanner {
+        let mut scanner = HeightScanner::new();
+        for chunk in data.chunks(chunk_size) {
+            scanner.feed(chunk);
+        }
+        scanner
+    }
+
+    #[test]
+    fn find_height_patch_in_file_with_dnl_header() {
+        let data = std::fs::read(DNL_TEST_FILE).unwrap();
+        for chunk_size in [1, 2, 3, 7, 4096, data.len()] {
+            let scanner = scan_in_chunks(&data, chunk_size);
+            assert!(!scanner.failed());
+            assert_eq!(scanner.dnl_height, Some(3490));
+            assert_eq!(scanner.sof_height_offset, Some(2 + 18 + 69 + 69 + 5));
+        }
+    }
+
+    #[test]
+    fn patched_file_is_identical_to_fixed_file() {
+        let mut data = std::fs::read(DNL_TEST_FILE).unwrap();
+        let (fixed, _) = fix_jpeg_height(data.clone().into(), None).unwrap().unwrap();
+        let (offset, height) = scan_in_chunks(&data, 1000).height_patch().unwrap();
+        let offset = usize::try_from(offset).unwrap();
+        data[offset..offset + 2].copy_from_slice(&height.to_be_bytes());
+        assert_eq!(data, fixed.as_ref());
+    }
+
+    #[test]
+    fn no_height_patch_when_invalid_data() {
+        let scanner = scan_in_chunks(&[0x00, 0x00, 0xff, 0xd8], 1);
+        assert!(scanner.failed());
+        assert_eq!(scanner.height_patch(), None);
+    }
+
+    #[test]
+    fn find_height_patch_in_progressive_frame_with_fill_bytes() {
+        let data = [
+            marker(Jpeg::SOI),
+            vec![Jpeg::MARKER_START],
+            frame(Jpeg::SOF2, 0),
+            scan(&[0x01, Jpeg::MARKER_START, Jpeg::RST0, 0x02]),
+            marker(Jpeg::RST0 + 1),
+            segment(Jpeg::DNL, &[0x01, 0x00]),
+            scan(&[0x03, Jpeg::MARKER_START, Jpeg::MARKER_START]),
+            marker(Jpeg::EOI),
+        ]
+        .concat();
+        let scanner = scan_in_chunks(&data, 1);
+        assert!(!scanner.failed());
+        assert_eq!(scanner.height_patch(), Some((2 + 1 + 5, 256)));
+    }
+}
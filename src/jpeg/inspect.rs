@@ -0,0 +1,561 @@
+use bytes::Bytes;
+use serde::Serialize;
+
+use std::fmt::Display;
+
+use crate::jpeg::decoder::{self, DecoderState, FrameHeader, ScanHeader};
+use crate::jpeg::{Jpeg, Segment, is_placeholder_height};
+
+/// Summary of the structure and metadata of a JPEG image
+#[derive(Debug, Serialize)]
+pub struct Inspection {
+    pub size: usize,
+    pub segments: Vec<SegmentInfo>,
+    pub frame: Option<FrameInfo>,
+    pub quantization_tables: Vec<QuantizationTableInfo>,
+    pub huffman_tables: Vec<HuffmanTableInfo>,
+    pub restart_interval: Option<u16>,
+    pub dnl_height: Option<u16>,
+    pub scans: usize,
+    pub metadata: Vec<AppInfo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SegmentInfo {
+    pub offset: usize,
+    pub marker: String,
+    pub name: String,
+    pub length: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FrameInfo {
+    pub coding: &'static str,
+    pub precision: u8,
+    pub width: u16,
+    pub height: u16,
+    pub components: Vec<ComponentInfo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ComponentInfo {
+    pub id: u8,
+    pub sampling: String,
+    pub quantization_table: u8,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QuantizationTableInfo {
+    pub id: u8,
+    pub precision: u8,
+    pub dc: u16,
+    pub min: u16,
+    pub max: u16,
+    pub average: f32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HuffmanTableInfo {
+    pub class: &'static str,
+    pub id: u8,
+    pub symbols: usize,
+    pub max_code_length: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AppInfo {
+    pub marker: String,
+    pub identifier: String,
+    pub details: Option<String>,
+}
+
+/// Result of checking the structure of a JPEG image
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub valid: bool,
+    pub problems: Vec<String>,
+}
+
+/// Returns the name of the given marker
+pub fn marker_name(marker: u8) -> String {
+    match marker {
+        Jpeg::TEM => "TEM".to_owned(),
+        Jpeg::DHT => "DHT".to_owned(),
+        Jpeg::JPG => "JPG".to_owned(),
+        Jpeg::DAC => "DAC".to_owned(),
+        m if Jpeg::is_sof(m) => format!("SOF{}", m - Jpeg::SOF0),
+        m @ Jpeg::RST0..=Jpeg::RST7 => format!("RST{}", m - Jpeg::RST0),
+        Jpeg::SOI => "SOI".to_owned(),
+        Jpeg::EOI => "EOI".to_owned(),
+        Jpeg::SOS => "SOS".to_owned(),
+        Jpeg::DQT => "DQT".to_owned(),
+        Jpeg::DNL => "DNL".to_owned(),
+        Jpeg::DRI => "DRI".to_owned(),
+        Jpeg::DHP => "DHP".to_owned(),
+        Jpeg::EXP => "EXP".to_owned(),
+        m @ Jpeg::APP0..=Jpeg::APP15 => format!("APP{}", m - Jpeg::APP0),
+        m @ 0xf0..=0xfd => format!("JPG{}", m - 0xf0),
+        Jpeg::COM => "COM".to_owned(),
+        _ => "unknown".to_owned(),
+    }
+}
+
+fn coding_name(marker: u8) -> &'static str {
+    match marker {
+        Jpeg::SOF0 => "baseline",
+        Jpeg::SOF1 => "extended sequential",
+        Jpeg::SOF2 => "progressive",
+        Jpeg::SOF3 => "lossless",
+        Jpeg::SOF5 => "differential sequential",
+        Jpeg::SOF6 => "differential progressive",
+        Jpeg::SOF7 => "differential lossless",
+        Jpeg::SOF9 => "extended sequential, arithmetic",
+        Jpeg::SOF10 => "progressive, arithmetic",
+        Jpeg::SOF11 => "lossless, arithmetic",
+        Jpeg::SOF13 => "differential sequential, arithmetic",
+        Jpeg::SOF14 => "differential progressive, arithmetic",
+        Jpeg::SOF15 => "differential lossless, arithmetic",
+        _ => "unknown",
+    }
+}
+
+impl Inspection {
+    pub fn new(jpeg: &Jpeg) -> Result<Self, String> {
+        let mut inspection = Inspection {
+            size: jpeg.segments().iter().map(Segment::len).sum(),
+            segments: Vec::new(),
+            frame: None,
+            quantization_tables: Vec::new(),
+            huffman_tables: Vec::new(),
+            restart_interval: None,
+            dnl_height: jpeg.get_height_from_dnl(),
+            scans: 0,
+            metadata: Vec::new(),
+        };
+        let mut offset = 0;
+        for segment in jpeg.segments() {
+            let marker = segment.marker();
+            inspection.segments.push(SegmentInfo {
+                offset: offset + segment.fill,
+                marker: format!("FF{marker:02X}"),
+                name: marker_name(marker),
+                length: segment.len() - segment.fill,
+            });
+            offset += segment.len();
+            match marker {
+                m if Jpeg::is_sof(m) && inspection.frame.is_none() => {
+                    let frame = FrameHeader::parse(m, segment.payload())?;
+                    inspection.frame = Some(FrameInfo::new(&frame));
+                }
+                Jpeg::DQT => inspection
+                    .quantization_tables
+                    .extend(QuantizationTableInfo::parse(segment.payload())?),
+                Jpeg::DHT => inspection
+                    .huffman_tables
+                    .extend(HuffmanTableInfo::parse(segment.payload())?),
+                Jpeg::DRI => {
+                    let payload = segment.payload();
+                    if payload.len() >= 2 {
+                        inspection.restart_interval =
+                            Some(u16::from_be_bytes([payload[0], payload[1]]));
+                    }
+                }
+                Jpeg::SOS => inspection.scans += 1,
+                m @ (Jpeg::APP0..=Jpeg::APP15 | Jpeg::COM) => {
+                    inspection.metadata.push(AppInfo::new(m, segment.payload()))
+                }
+                _ => (),
+            }
+        }
+        Ok(inspection)
+    }
+}
+
+impl FrameInfo {
+    fn new(frame: &FrameHeader) -> Self {
+        Self {
+            coding: coding_name(frame.marker),
+            precision: frame.precision,
+            width: frame.width,
+            height: frame.height,
+            components: frame
+                .components
+                .iter()
+                .map(|c| ComponentInfo {
+                    id: c.id,
+                    sampling: format!("{}x{}", c.h, c.v),
+                    quantization_table: c.tq,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl QuantizationTableInfo {
+    fn parse(mut payload: &[u8]) -> Result<Vec<Self>, String> {
+        let mut tables = Vec::new();
+        while !payload.is_empty() {
+            let precision = payload[0] >> 4;
+            let id = payload[0] & 0x0f;
+            let value_size = if precision == 0 { 1 } else { 2 };
+            if payload.len() < 1 + 64 * value_size {
+                return Err("quantization table too short".to_owned());
+            }
+            let values: Vec<u16> = payload[1..1 + 64 * value_size]
+                .chunks(value_size)
+                .map(|v| v.iter().fold(0u16, |acc, b| (acc << 8) | u16::from(*b)))
+                .collect();
+            tables.push(Self {
+                id,
+                precision: if precision == 0 { 8 } else { 16 },
+                dc: values[0],
+                min: values.iter().copied().min().unwrap_or_default(),
+                max: values.iter().copied().max().unwrap_or_default(),
+                average: values.iter().map(|v| f32::from(*v)).sum::<f32>() / 64.0,
+            });
+            payload = &payload[1 + 64 * value_size..];
+        }
+        Ok(tables)
+    }
+}
+
+impl HuffmanTableInfo {
+    fn parse(mut payload: &[u8]) -> Result<Vec<Self>, String> {
+        let mut tables = Vec::new();
+        while !payload.is_empty() {
+            if payload.len() < 17 {
+                return Err("huffman table too short".to_owned());
+            }
+            let counts = &payload[1..17];
+            let symbols: usize = counts.iter().map(|c| usize::from(*c)).sum();
+            tables.push(Self {
+                class: if payload[0] >> 4 == 0 { "DC" } else { "AC" },
+                id: payload[0] & 0x0f,
+                symbols,
+                max_code_length: counts.iter().rposition(|c| *c > 0).map_or(0, |i| i + 1),
+            });
+            if payload.len() < 17 + symbols {
+                return Err("huffman table too short for values".to_owned());
+            }
+            payload = &payload[17 + symbols..];
+        }
+        Ok(tables)
+    }
+}
+
+impl AppInfo {
+    fn new(marker: u8, payload: &[u8]) -> Self {
+        if marker == Jpeg::COM {
+            return Self {
+                marker: marker_name(marker),
+                identifier: "comment".to_owned(),
+                details: Some(String::from_utf8_lossy(payload).into_owned()),
+            };
+        }
+        let identifier_len = payload
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(payload.len());
+        let identifier = String::from_utf8_lossy(&payload[..identifier_len]).into_owned();
+        let data = payload.get(identifier_len + 1..).unwrap_or_default();
+        let details = match identifier.as_str() {
+            "JFIF" if data.len() >= 7 => {
+                let units = match data[2] {
+                    0 => "no units",
+                    1 => "dpi",
+                    2 => "dots per cm",
+                    _ => "unknown units",
+                };
+                let x = u16::from_be_bytes([data[3], data[4]]);
+                let y = u16::from_be_bytes([data[5], data[6]]);
+                Some(format!(
+                    "version {}.{:02}, density {x}x{y} {units}",
+                    data[0], data[1]
+                ))
+            }
+            "Exif" => Some(format!(
+                "{} bytes of TIFF data",
+                data.len().saturating_sub(1)
+            )),
+            _ if identifier.starts_with("Adobe") && payload.len() >= 12 => {
+                let transform = match payload[11] {
+                    0 => "RGB or CMYK",
+                    1 => "YCbCr",
+                    2 => "YCCK",
+                    _ => "unknown",
+                };
+                Some(format!("color transform {transform}"))
+            }
+            _ => None,
+        };
+        Self {
+            marker: marker_name(marker),
+            identifier,
+            details,
+        }
+    }
+}
+
+impl Display for Inspection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Size: {} bytes", self.size)?;
+        if let Some(frame) = &self.frame {
+            writeln!(
+                f,
+                "Frame: {}, {} bit, {}x{}",
+                frame.coding, frame.precision, frame.width, frame.height
+            )?;
+            for c in &frame.components {
+                writeln!(
+                    f,
+                    "  Component {}: sampling {}, quantization table {}",
+                    c.id, c.sampling, c.quantization_table
+                )?;
+            }
+        } else {
+            writeln!(f, "Frame: none")?;
+        }
+        if let Some(height) = self.dnl_height {
+            writeln!(f, "DNL height: {height}")?;
+        }
+        if let Some(interval) = self.restart_interval {
+            writeln!(f, "Restart interval: {interval}")?;
+        }
+        writeln!(f, "Scans: {}", self.scans)?;
+        for q in &self.quantization_tables {
+            writeln!(
+                f,
+                "Quantization table {}: {} bit, dc {}, min {}, max {}, average {:.1}",
+                q.id, q.precision, q.dc, q.min, q.max, q.average
+            )?;
+        }
+        for h in &self.huffman_tables {
+            writeln!(
+                f,
+                "Huffman table {} {}: {} symbols, max code length {}",
+                h.class, h.id, h.symbols, h.max_code_length
+            )?;
+        }
+        for app in &self.metadata {
+            write!(f, "{} {}", app.marker, app.identifier)?;
+            if let Some(details) = &app.details {
+                write!(f, ": {details}")?;
+            }
+            writeln!(f)?;
+        }
+        writeln!(f, "----------------------------------------------")?;
+        writeln!(
+            f,
+            "| {: <10} | {: <6} | {: <6} | {: <12} |",
+            "offset", "marker", "name", "total length"
+        )?;
+        writeln!(f, "----------------------------------------------")?;
+        for s in &self.segments {
+            writeln!(
+                f,
+                "| {: <10} | {: <6} | {: <6} | {: <12} |",
+                s.offset, s.marker, s.name, s.length
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Check {
+    /// Checks the structure of the image in the buffer
+    pub fn new(buffer: Bytes) -> Self {
+        let problems = match Jpeg::from_bytes(buffer) {
+            Ok(jpeg) => check_structure(&jpeg),
+            Err(e) => vec![e.to_string()],
+        };
+        Self {
+            valid: problems.is_empty(),
+            problems,
+        }
+    }
+}
+
+impl Display for Check {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.valid {
+            writeln!(f, "OK")
+        } else {
+            for problem in &self.problems {
+                writeln!(f, "{problem}")?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn check_structure(jpeg: &Jpeg) -> Vec<String> {
+    let mut problems = Vec::new();
+    let segments = jpeg.segments();
+    if segments.first().map(Segment::marker) != Some(Jpeg::SOI) {
+        problems.push("image does not start with SOI marker".to_owned());
+    }
+    match segments.iter().position(|s| s.marker() == Jpeg::EOI) {
+        None => problems.push("image does not end with EOI marker".to_owned()),
+        Some(i) if i + 1 < segments.len() => problems.push(format!(
+            "{} segments after EOI marker",
+            segments.len() - i - 1
+        )),
+        Some(_) => (),
+    }
+    let mut state = DecoderState::default();
+    let mut scans = 0;
+    for segment in segments {
+        let marker = segment.marker();
+        if marker == Jpeg::SOS {
+            scans += 1;
+            let Some(frame) = &state.frame else {
+                problems.push(format!("scan {scans} before frame header"));
+                continue;
+            };
+            if let Err(e) = check_scan(frame, &state, segment, scans, jpeg) {
+                problems.push(e);
+            }
+        } else if let Err(e) = state.update(segment) {
+            problems.push(format!("{}: {e}", marker_name(marker)));
+        }
+    }
+    match &state.frame {
+        None => problems.push("no frame header".to_owned()),
+        Some(frame) => {
+            if is_placeholder_height(frame.height) && jpeg.get_height_from_dnl().is_none() {
+                problems.push(format!(
+                    "frame height {} is not valid and there is no DNL segment",
+                    frame.height
+                ));
+            }
+            if jpeg.get_height_from_dnl().is_some() {
+                problems.push("height is defined by a DNL segment".to_owned());
+            }
+        }
+    }
+    if scans == 0 {
+        problems.push("no scan".to_owned());
+    }
+    problems
+}
+
+fn check_scan(
+    frame: &FrameHeader,
+    state: &DecoderState,
+    segment: &Segment,
+    number: usize,
+    jpeg: &Jpeg,
+) -> Result<(), String> {
+    let scan =
+        ScanHeader::parse(segment.payload(), frame).map_err(|e| format!("scan {number}: {e}"))?;
+    if !frame.is_huffman_sequential() {
+        return Ok(());
+    }
+    let mut frame = frame.clone();
+    if is_placeholder_height(frame.height) {
+        match jpeg.get_height_from_dnl() {
+            Some(height) => frame.height = height,
+            None => return Ok(()),
+        }
+    }
+    let summary = decoder::decode_scan(
+        &frame,
+        &scan,
+        &state.tables,
+        state.restart_interval,
+        segment.entropy_data(),
+        |_, _| (),
+    )
+    .map_err(|e| format!("scan {number}: {e}"))?;
+    if summary.truncated {
+        return Err(format!(
+            "scan {number}: entropy-coded data ends after {} MCUs",
+            summary.mcus
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::jpeg::END_OF_IMAGE;
+
+    const DNL_TEST_FILE: &str = "doc/testdata/scan_from_adf_with_dnl_header.jpeg";
+
+    fn load_image() -> Bytes {
+        std::fs::read(DNL_TEST_FILE).unwrap().into()
+    }
+
+    #[test]
+    fn inspect_file_with_dnl_header() {
+        let jpeg = Jpeg::from_bytes(load_image()).unwrap();
+        let inspection = Inspection::new(&jpeg).unwrap();
+        assert_eq!(inspection.segments.len(), 10);
+        assert_eq!(inspection.segments[4].name, "SOF0");
+        assert_eq!(inspection.segments[4].offset, 158);
+        let frame = inspection.frame.unwrap();
+        assert_eq!(frame.coding, "baseline");
+        assert_eq!((frame.width, frame.height), (2480, 0xffff));
+        let sampling: Vec<&str> = frame
+            .components
+            .iter()
+            .map(|c| c.sampling.as_str())
+            .collect();
+        assert_eq!(sampling, vec!["2x2", "1x1", "1x1"]);
+        assert_eq!(inspection.quantization_tables.len(), 2);
+        assert_eq!(inspection.huffman_tables.len(), 4);
+        assert_eq!(inspection.restart_interval, Some(155));
+        assert_eq!(inspection.dnl_height, Some(3490));
+        assert_eq!(inspection.scans, 1);
+        assert_eq!(inspection.metadata.len(), 1);
+        assert_eq!(
+            inspection.metadata[0].details.as_deref(),
+            Some("version 1.01, density 300x300 dpi")
+        );
+    }
+
+    #[test]
+    fn check_reports_dnl_height() {
+        let check = Check::new(load_image());
+        assert!(!check.valid);
+        assert_eq!(check.problems, vec!["height is defined by a DNL segment"]);
+    }
+
+    #[test]
+    fn check_fixed_file_is_valid() {
+        let (fixed, _) = crate::jpeg::fix_jpeg_height(load_image(), None)
+            .unwrap()
+            .unwrap();
+        // remove the DNL segment
+        let data = [&fixed[..fixed.len() - 8], &END_OF_IMAGE].concat();
+        let check = Check::new(data.into());
+        assert!(check.valid, "{:?}", check.problems);
+    }
+
+    #[test]
+    fn check_truncated_file() {
+        let buffer = load_image();
+        let check = Check::new(buffer.slice(..buffer.len() / 2));
+        assert!(!check.valid);
+        assert!(
+            check
+                .problems
+                .contains(&"image does not end with EOI marker".to_owned())
+        );
+        assert!(
+            check.problems.contains(
+                &"frame height 65535 is not valid and there is no DNL segment".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn check_invalid_data() {
+        let check = Check::new(Bytes::from_static(&[0x00, 0x01]));
+        assert_eq!(
+            check.problems,
+            vec!["Failed to parse: segment not starting with 0xff"]
+        );
+    }
+}
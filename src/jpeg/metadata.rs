@@ -0,0 +1,449 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use jiff::civil::DateTime;
+
+use crate::jpeg::{Jpeg, ParseError, Segment};
+
+const JFIF_IDENTIFIER: &[u8] = b"JFIF\0";
+const EXIF_IDENTIFIER: &[u8] = b"Exif\0\0";
+const ADOBE_IDENTIFIER: &[u8] = b"Adobe";
+
+// TIFF field types
+const ASCII: u16 = 2;
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+const RATIONAL: u16 = 5;
+
+// TIFF tags
+const MAKE: u16 = 0x010f;
+const MODEL: u16 = 0x0110;
+const ORIENTATION: u16 = 0x0112;
+const X_RESOLUTION: u16 = 0x011a;
+const Y_RESOLUTION: u16 = 0x011b;
+const RESOLUTION_UNIT: u16 = 0x0128;
+const EXIF_IFD_POINTER: u16 = 0x8769;
+const DATE_TIME_ORIGINAL: u16 = 0x9003;
+
+/// Offset of the first IFD after the TIFF header
+const FIRST_IFD_OFFSET: u32 = 8;
+
+/// Information about a scan that is written into the JFIF and EXIF segments
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    /// Resolution in dots per inch
+    pub resolution: u16,
+    pub date_time: Option<DateTime>,
+    pub make: Option<String>,
+    pub model: Option<String>,
+    /// EXIF orientation, 1 means the image is upright
+    pub orientation: u16,
+}
+
+impl Metadata {
+    pub fn new(resolution: u16) -> Self {
+        Self {
+            resolution,
+            date_time: None,
+            make: None,
+            model: None,
+            orientation: 1,
+        }
+    }
+}
+
+/// Returns the length of the SOI marker and the application segments that
+/// follow it, or `None` if more data is needed to find their end.
+pub fn header_len(data: &[u8]) -> Result<Option<usize>, ParseError> {
+    if data.len() < 2 {
+        return Ok(None);
+    }
+    if data[..2] != [Jpeg::MARKER_START, Jpeg::SOI] {
+        return Err(ParseError::from("data not starting with SOI".to_owned()));
+    }
+    let mut pos = 2;
+    loop {
+        match data.get(pos..pos + 2) {
+            None => return Ok(None),
+            Some([Jpeg::MARKER_START, Jpeg::APP0..=Jpeg::APP15]) => {
+                let Some(len) = data.get(pos + 2..pos + 4) else {
+                    return Ok(None);
+                };
+                pos += 2 + usize::from(u16::from_be_bytes([len[0], len[1]]));
+            }
+            Some([Jpeg::MARKER_START, _]) => return Ok(Some(pos)),
+            Some(_) => {
+                return Err(ParseError::from(
+                    "segment not starting with 0xff".to_owned(),
+                ));
+            }
+        }
+    }
+}
+
+/// Sets the JFIF density to the scan resolution and replaces any EXIF segment
+/// with one describing the scan.
+///
+/// `data` has to consist of complete segments, for example the header found by
+/// [`header_len`] or the whole image. A JFIF segment is only inserted if there
+/// is none and no Adobe segment declares a different color transform.
+pub fn apply_metadata(data: Bytes, metadata: &Metadata) -> Result<Bytes, ParseError> {
+    let jpeg = Jpeg::from_bytes(data)?;
+    let mut segments = jpeg.segments.into_iter();
+    let soi = segments
+        .next()
+        .filter(|s| s.marker() == Jpeg::SOI)
+        .ok_or_else(|| ParseError::from("data not starting with SOI".to_owned()))?;
+    let segments: Vec<Segment> = segments.collect();
+    let jfif = segments
+        .iter()
+        .find(|s| is_app(s, Jpeg::APP0, JFIF_IDENTIFIER));
+    let adobe = segments
+        .iter()
+        .any(|s| is_app(s, Jpeg::APP14, ADOBE_IDENTIFIER));
+
+    let mut buffer = BytesMut::new();
+    buffer.put(soi.buffer);
+    match jfif {
+        Some(jfif) => put_segment(&mut buffer, Jpeg::APP0, &with_density(jfif, metadata)),
+        None if !adobe => put_segment(&mut buffer, Jpeg::APP0, &jfif_payload(metadata)),
+        None => (),
+    }
+    put_segment(&mut buffer, Jpeg::APP1, &exif_payload(metadata));
+    for segment in segments {
+        if !is_app(&segment, Jpeg::APP0, JFIF_IDENTIFIER)
+            && !is_app(&segment, Jpeg::APP1, EXIF_IDENTIFIER)
+        {
+            buffer.put(segment.buffer);
+        }
+    }
+    Ok(buffer.into())
+}
+
+fn is_app(segment: &Segment, marker: u8, identifier: &[u8]) -> bool {
+    segment.marker() == marker && segment.payload().starts_with(identifier)
+}
+
+fn put_segment(buffer: &mut BytesMut, marker: u8, payload: &[u8]) {
+    buffer.put_u8(Jpeg::MARKER_START);
+    buffer.put_u8(marker);
+    buffer.put_u16(u16::try_from(payload.len() + 2).expect("segment too large"));
+    buffer.put_slice(payload);
+}
+
+fn jfif_payload(metadata: &Metadata) -> Vec<u8> {
+    let mut payload = JFIF_IDENTIFIER.to_vec();
+    // version 1.01, dots per inch
+    payload.extend_from_slice(&[1, 1, 1]);
+    payload.extend_from_slice(&metadata.resolution.to_be_bytes());
+    payload.extend_from_slice(&metadata.resolution.to_be_bytes());
+    // no thumbnail
+    payload.extend_from_slice(&[0, 0]);
+    payload
+}
+
+/// Keeps the version and thumbnail of an existing JFIF segment
+fn with_density(jfif: &Segment, metadata: &Metadata) -> Vec<u8> {
+    let mut payload = jfif.payload().to_vec();
+    if payload.len() < 12 {
+        return jfif_payload(metadata);
+    }
+    payload[7] = 1;
+    payload[8..10].copy_from_slice(&metadata.resolution.to_be_bytes());
+    payload[10..12].copy_from_slice(&metadata.resolution.to_be_bytes());
+    payload
+}
+
+struct Entry {
+    tag: u16,
+    field_type: u16,
+    count: u32,
+    value: Vec<u8>,
+}
+
+impl Entry {
+    fn ascii(tag: u16, value: &str) -> Self {
+        let mut value = value.as_bytes().to_vec();
+        value.push(0);
+        Self {
+            tag,
+            field_type: ASCII,
+            count: u32::try_from(value.len()).unwrap_or(u32::MAX),
+            value,
+        }
+    }
+
+    fn short(tag: u16, value: u16) -> Self {
+        Self {
+            tag,
+            field_type: SHORT,
+            count: 1,
+            value: value.to_be_bytes().to_vec(),
+        }
+    }
+
+    fn long(tag: u16, value: u32) -> Self {
+        Self {
+            tag,
+            field_type: LONG,
+            count: 1,
+            value: value.to_be_bytes().to_vec(),
+        }
+    }
+
+    fn rational(tag: u16, numerator: u32, denominator: u32) -> Self {
+        let mut value = numerator.to_be_bytes().to_vec();
+        value.extend_from_slice(&denominator.to_be_bytes());
+        Self {
+            tag,
+            field_type: RATIONAL,
+            count: 1,
+            value,
+        }
+    }
+
+    /// Size of the value if it does not fit into the entry itself
+    fn external_len(&self) -> usize {
+        if self.value.len() > 4 {
+            self.value.len().next_multiple_of(2)
+        } else {
+            0
+        }
+    }
+}
+
+fn ifd_len(entries: &[Entry]) -> usize {
+    2 + 12 * entries.len() + 4 + entries.iter().map(Entry::external_len).sum::<usize>()
+}
+
+/// Writes an IFD starting at `offset` from the TIFF header, followed by the
+/// values that do not fit into their entries
+fn write_ifd(buffer: &mut Vec<u8>, entries: &[Entry], offset: usize) {
+    let mut data_offset = offset + 2 + 12 * entries.len() + 4;
+    let mut data = Vec::new();
+    buffer.extend_from_slice(&u16::try_from(entries.len()).unwrap().to_be_bytes());
+    for entry in entries {
+        buffer.extend_from_slice(&entry.tag.to_be_bytes());
+        buffer.extend_from_slice(&entry.field_type.to_be_bytes());
+        buffer.extend_from_slice(&entry.count.to_be_bytes());
+        if entry.value.len() > 4 {
+            buffer.extend_from_slice(&u32::try_from(data_offset).unwrap().to_be_bytes());
+            data.extend_from_slice(&entry.value);
+            data.resize(data.len().next_multiple_of(2), 0);
+            data_offset += entry.external_len();
+        } else {
+            let mut value = [0; 4];
+            value[..entry.value.len()].copy_from_slice(&entry.value);
+            buffer.extend_from_slice(&value);
+        }
+    }
+    // no next IFD
+    buffer.extend_from_slice(&[0; 4]);
+    buffer.extend_from_slice(&data);
+}
+
+fn exif_payload(metadata: &Metadata) -> Vec<u8> {
+    let resolution = u32::from(metadata.resolution);
+    let mut ifd0 = Vec::new();
+    if let Some(make) = &metadata.make {
+        ifd0.push(Entry::ascii(MAKE, make));
+    }
+    if let Some(model) = &metadata.model {
+        ifd0.push(Entry::ascii(MODEL, model));
+    }
+    ifd0.push(Entry::short(ORIENTATION, metadata.orientation));
+    ifd0.push(Entry::rational(X_RESOLUTION, resolution, 1));
+    ifd0.push(Entry::rational(Y_RESOLUTION, resolution, 1));
+    // inches
+    ifd0.push(Entry::short(RESOLUTION_UNIT, 2));
+    let exif_ifd: Vec<Entry> = metadata
+        .date_time
+        .iter()
+        .map(|dt| {
+            Entry::ascii(
+                DATE_TIME_ORIGINAL,
+                &dt.strftime("%Y:%m:%d %H:%M:%S").to_string(),
+            )
+        })
+        .collect();
+    if !exif_ifd.is_empty() {
+        // the pointer entry has the same size no matter where the IFD ends up
+        let exif_offset = FIRST_IFD_OFFSET as usize + ifd_len(&ifd0) + 12;
+        ifd0.push(Entry::long(
+            EXIF_IFD_POINTER,
+            u32::try_from(exif_offset).unwrap(),
+        ));
+    }
+
+    let mut payload = EXIF_IDENTIFIER.to_vec();
+    let mut tiff = b"MM\0\x2a".to_vec();
+    tiff.extend_from_slice(&FIRST_IFD_OFFSET.to_be_bytes());
+    write_ifd(&mut tiff, &ifd0, FIRST_IFD_OFFSET as usize);
+    if !exif_ifd.is_empty() {
+        let offset = tiff.len();
+        write_ifd(&mut tiff, &exif_ifd, offset);
+    }
+    payload.extend_from_slice(&tiff);
+    payload
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::jpeg::Inspection;
+    use crate::jpeg::test::{frame, marker, scan, segment};
+
+    const DNL_TEST_FILE: &str = "doc/testdata/scan_from_adf_with_dnl_header.jpeg";
+
+    fn metadata() -> Metadata {
+        Metadata {
+            resolution: 300,
+            date_time: Some(DateTime::constant(2024, 5, 17, 13, 4, 59, 0)),
+            make: Some("HP".to_owned()),
+            model: Some("ENVY 5530 series".to_owned()),
+            orientation: 1,
+        }
+    }
+
+    fn read_u16(data: &[u8], offset: usize) -> u16 {
+        u16::from_be_bytes([data[offset], data[offset + 1]])
+    }
+
+    fn read_u32(data: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// Returns the raw value field of a tag in the IFD at `offset`
+    fn find_tag(tiff: &[u8], offset: usize, tag: u16) -> Option<(u16, u32, u32)> {
+        let count = usize::from(read_u16(tiff, offset));
+        (0..count).map(|i| offset + 2 + 12 * i).find_map(|entry| {
+            (read_u16(tiff, entry) == tag).then(|| {
+                (
+                    read_u16(tiff, entry + 2),
+                    read_u32(tiff, entry + 4),
+                    read_u32(tiff, entry + 8),
+                )
+            })
+        })
+    }
+
+    /// Reads a string that is either stored in the value field or at its offset
+    fn read_ascii(tiff: &[u8], value: u32, count: u32) -> String {
+        let len = count as usize - 1;
+        let bytes = if count <= 4 {
+            value.to_be_bytes()[..len].to_vec()
+        } else {
+            tiff[value as usize..value as usize + len].to_vec()
+        };
+        String::from_utf8(bytes).unwrap()
+    }
+
+    fn exif_tiff(jpeg: &Jpeg) -> Vec<u8> {
+        let exif = jpeg
+            .segments()
+            .iter()
+            .find(|s| is_app(s, Jpeg::APP1, EXIF_IDENTIFIER))
+            .unwrap();
+        exif.payload()[EXIF_IDENTIFIER.len()..].to_vec()
+    }
+
+    #[test]
+    fn header_len_of_incomplete_data() {
+        let data = [
+            marker(Jpeg::SOI),
+            segment(Jpeg::APP0, b"JFIF\0\x01\x01\x00\x00\x01\x00\x01\x00\x00"),
+            segment(Jpeg::APP14, b"Adobe"),
+            frame(Jpeg::SOF0, 16),
+        ]
+        .concat();
+        let header = 2 + 18 + 9;
+        assert_eq!(header_len(&data).unwrap(), Some(header));
+        for len in [0, 1, 2, 3, 10, 21, header + 1] {
+            assert_eq!(header_len(&data[..len]).unwrap(), None, "length {len}");
+        }
+        assert!(header_len(&[0xff, Jpeg::EOI]).is_err());
+    }
+
+    #[test]
+    fn write_density_and_exif_into_file() {
+        let data = Bytes::from(std::fs::read(DNL_TEST_FILE).unwrap());
+        let len = header_len(&data).unwrap().unwrap();
+        let header = apply_metadata(data.slice(..len), &metadata()).unwrap();
+        let jpeg = Jpeg::from_bytes([header, data.slice(len..)].concat().into()).unwrap();
+
+        let inspection = Inspection::new(&jpeg).unwrap();
+        let jfif = &inspection.metadata[0];
+        assert_eq!(jfif.identifier, "JFIF");
+        assert_eq!(
+            jfif.details.as_deref(),
+            Some("version 1.01, density 300x300 dpi")
+        );
+
+        let tiff = exif_tiff(&jpeg);
+        assert_eq!(&tiff[..8], b"MM\0\x2a\0\0\0\x08");
+        let (_, count, offset) = find_tag(&tiff, 8, MAKE).unwrap();
+        assert_eq!(read_ascii(&tiff, offset, count), "HP");
+        let (_, count, offset) = find_tag(&tiff, 8, MODEL).unwrap();
+        assert_eq!(read_ascii(&tiff, offset, count), "ENVY 5530 series");
+        assert_eq!(find_tag(&tiff, 8, ORIENTATION), Some((SHORT, 1, 1 << 16)));
+        let (field_type, _, offset) = find_tag(&tiff, 8, X_RESOLUTION).unwrap();
+        assert_eq!(field_type, RATIONAL);
+        assert_eq!(read_u32(&tiff, offset as usize), 300);
+        assert_eq!(read_u32(&tiff, offset as usize + 4), 1);
+        assert_eq!(
+            find_tag(&tiff, 8, RESOLUTION_UNIT),
+            Some((SHORT, 1, 2 << 16))
+        );
+        let (_, _, exif_offset) = find_tag(&tiff, 8, EXIF_IFD_POINTER).unwrap();
+        let (field_type, count, offset) =
+            find_tag(&tiff, exif_offset as usize, DATE_TIME_ORIGINAL).unwrap();
+        assert_eq!(field_type, ASCII);
+        assert_eq!(read_ascii(&tiff, offset, count), "2024:05:17 13:04:59");
+        // the image data is untouched
+        assert_eq!(jpeg.get_height_from_dnl(), Some(3490));
+    }
+
+    #[test]
+    fn replace_existing_exif_and_keep_other_segments() {
+        let data = [
+            marker(Jpeg::SOI),
+            segment(Jpeg::APP1, b"Exif\0\0MM\0\x2a\0\0\0\x08\0\0\0\0\0\0"),
+            segment(Jpeg::APP14, b"Adobe\0\x64\0\0\0\0\0"),
+            segment(Jpeg::COM, b"comment"),
+            frame(Jpeg::SOF0, 16),
+            scan(&[0x01]),
+            marker(Jpeg::EOI),
+        ]
+        .concat();
+        let mut metadata = metadata();
+        metadata.date_time = None;
+        metadata.make = None;
+        metadata.orientation = 6;
+        let jpeg = Jpeg::from_bytes(apply_metadata(data.into(), &metadata).unwrap()).unwrap();
+        let markers: Vec<u8> = jpeg.segments().iter().map(Segment::marker).collect();
+        // no JFIF segment because of the Adobe segment
+        assert_eq!(
+            markers,
+            vec![
+                Jpeg::SOI,
+                Jpeg::APP1,
+                Jpeg::APP14,
+                Jpeg::COM,
+                Jpeg::SOF0,
+                Jpeg::SOS,
+                Jpeg::EOI
+            ]
+        );
+        let tiff = exif_tiff(&jpeg);
+        assert_eq!(find_tag(&tiff, 8, MAKE), None);
+        assert_eq!(find_tag(&tiff, 8, EXIF_IFD_POINTER), None);
+        assert_eq!(find_tag(&tiff, 8, ORIENTATION), Some((SHORT, 1, 6 << 16)));
+    }
+
+    #[test]
+    fn insert_jfif_segment() {
+        let data = [marker(Jpeg::SOI), frame(Jpeg::SOF0, 16)].concat();
+        let jpeg = Jpeg::from_bytes(apply_metadata(data.into(), &metadata()).unwrap()).unwrap();
+        let app0 = &jpeg.segments()[1];
+        assert_eq!(app0.marker(), Jpeg::APP0);
+        assert_eq!(app0.payload(), b"JFIF\0\x01\x01\x01\x01\x2c\x01\x2c\0\0");
+    }
+}
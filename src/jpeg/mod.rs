@@ -0,0 +1,791 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::fmt::Display;
+use thiserror::Error;
+use tracing::{debug, info, trace};
+
+mod blank;
+mod decoder;
+mod encoder;
+mod incremental;
+mod inspect;
+mod metadata;
+mod pixels;
+mod transform;
+
+pub use blank::content_score;
+#[cfg(test)]
+pub use blank::{blank_page, color_page, test_page};
+pub use decoder::FrameHeader;
+pub use incremental::HeightScanner;
+pub use inspect::{Check, Inspection};
+pub use metadata::{Metadata, apply_metadata, header_len};
+pub use pixels::{
+    decode_chroma, decode_gray, decode_image, extract_area, rotate_and_crop, to_grayscale,
+};
+pub use transform::{Transform, transform_jpeg};
+
+/// The `End of Image` marker
+pub const END_OF_IMAGE: [u8; 2] = [Jpeg::MARKER_START, Jpeg::EOI];
+
+pub struct Jpeg {
+    segments: Vec<Segment>,
+}
+
+pub struct Segment {
+    buffer: Bytes,
+    /// Number of 0xff fill bytes preceding the marker
+    fill: usize,
+}
+
+/// Where a recovered image height was taken from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeightSource {
+    /// A `Define Number of Lines` segment
+    Dnl,
+    /// The number of lines reported by the scanner
+    TotalLines,
+    /// The number of completely decoded MCU rows
+    McuRows,
+}
+
+/// Describes what was changed to repair an image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Repair {
+    pub height: Option<(u16, HeightSource)>,
+    pub appended_eoi: bool,
+}
+
+impl Display for Repair {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.height {
+            Some((height, HeightSource::Dnl)) => write!(f, "height {height} from DNL segment")?,
+            Some((height, HeightSource::TotalLines)) => write!(
+                f,
+                "height {height} from total lines reported by the scanner"
+            )?,
+            Some((height, HeightSource::McuRows)) => {
+                write!(f, "height {height} from complete MCU rows")?
+            }
+            None => write!(f, "height unchanged")?,
+        }
+        if self.appended_eoi {
+            write!(f, ", appended missing EOI marker")?;
+        }
+        Ok(())
+    }
+}
+
+/// Fixes the height of an image whose frame header does not contain the real height.
+///
+/// The height is taken from a DNL segment. If there is none and the frame header has
+/// no valid height, `total_lines` is used or the height is recovered by counting the
+/// complete MCU rows of the first scan. A missing EOI marker of a truncated image is
+/// appended. Returns `None` if nothing needs to be repaired.
+pub fn fix_jpeg_height(
+    buffer: Bytes,
+    total_lines: Option<u32>,
+) -> Result<Option<(Bytes, Repair)>, ParseError> {
+    let mut jpeg = Jpeg::from_bytes(buffer)?;
+    trace!("{}", jpeg);
+    let frame_height = jpeg.frame()?.map(|frame| frame.height);
+    let height = if let Some(height) = jpeg.get_height_from_dnl() {
+        if frame_height == Some(height) {
+            info!("Jpeg height already matches DNL segment: {height}");
+            None
+        } else {
+            info!("Use jpeg height from DNL segment: {height}");
+            Some((height, HeightSource::Dnl))
+        }
+    } else {
+        info!("No DNL segment found.");
+        match frame_height {
+            Some(height) if is_placeholder_height(height) => jpeg.recover_height(total_lines)?,
+            _ => None,
+        }
+    };
+    let appended_eoi = !jpeg.ends_with_eoi();
+    if height.is_none() && !appended_eoi {
+        return Ok(None);
+    }
+    if let Some((height, _)) = height {
+        jpeg = jpeg.with_height(height);
+    }
+    if appended_eoi {
+        info!("Append missing EOI marker");
+        let eoi = Bytes::from_static(&END_OF_IMAGE);
+        jpeg.segments.push(Segment::new(eoi, 0));
+    }
+    let repair = Repair {
+        height,
+        appended_eoi,
+    };
+    Ok(Some((jpeg.into(), repair)))
+}
+
+/// Scanners write 0 or 0xffff into the frame header if they do not know the height yet
+pub fn is_placeholder_height(height: u16) -> bool {
+    matches!(height, 0 | u16::MAX)
+}
+
+#[derive(Debug, Error)]
+#[error("Failed to parse: {message}")]
+pub struct ParseError {
+    message: String,
+}
+
+impl From<String> for ParseError {
+    fn from(message: String) -> Self {
+        Self { message }
+    }
+}
+
+#[allow(unused)]
+impl Jpeg {
+    const MARKER_START: u8 = 0xff;
+
+    // Temporary use in arithmetic coding
+    const TEM: u8 = 0x01;
+    // Start of Frame: baseline, extended sequential, progressive and lossless
+    const SOF0: u8 = 0xc0;
+    const SOF1: u8 = 0xc1;
+    const SOF2: u8 = 0xc2;
+    const SOF3: u8 = 0xc3;
+    // Define Huffman Table
+    const DHT: u8 = 0xc4;
+    // Start of Frame: differential Huffman coding
+    const SOF5: u8 = 0xc5;
+    const SOF6: u8 = 0xc6;
+    const SOF7: u8 = 0xc7;
+    // Reserved for JPEG extensions
+    const JPG: u8 = 0xc8;
+    // Start of Frame: arithmetic coding
+    const SOF9: u8 = 0xc9;
+    const SOF10: u8 = 0xca;
+    const SOF11: u8 = 0xcb;
+    // Define Arithmetic Coding Conditioning
+    const DAC: u8 = 0xcc;
+    // Start of Frame: differential arithmetic coding
+    const SOF13: u8 = 0xcd;
+    const SOF14: u8 = 0xce;
+    const SOF15: u8 = 0xcf;
+    // Restart markers
+    const RST0: u8 = 0xd0;
+    const RST7: u8 = 0xd7;
+    // {Start,End} of Image
+    const SOI: u8 = 0xd8;
+    const EOI: u8 = 0xd9;
+    // Start of Scan
+    const SOS: u8 = 0xda;
+    // Define Quantization Table
+    const DQT: u8 = 0xdb;
+    // Define Number of Lines
+    const DNL: u8 = 0xdc;
+    // Define Restart Interval
+    const DRI: u8 = 0xdd;
+    // Define Hierarchical Progression
+    const DHP: u8 = 0xde;
+    // Expand Reference Components
+    const EXP: u8 = 0xdf;
+    // Application Segments
+    const APP0: u8 = 0xe0;
+    const APP1: u8 = 0xe1;
+    const APP14: u8 = 0xee;
+    const APP15: u8 = 0xef;
+    // Comment
+    const COM: u8 = 0xfe;
+}
+
+impl Jpeg {
+    pub fn from_bytes(mut buffer: Bytes) -> Result<Self, ParseError> {
+        let mut segments = Vec::new();
+        while buffer.has_remaining() {
+            let segment = split_segment(&mut buffer)?;
+            segments.push(segment);
+        }
+        Ok(Self { segments })
+    }
+
+    pub fn get_height_from_dnl(&self) -> Option<u16> {
+        if let Some(dnl) = self.segments.iter().find(|s| s.marker() == Jpeg::DNL) {
+            let mut buf = dnl.buffer.slice(dnl.fill..);
+            buf.advance(4);
+            Some(buf.get_u16())
+        } else {
+            None
+        }
+    }
+
+    /// Returns true if the marker starts a frame of any coding process
+    pub fn is_sof(marker: u8) -> bool {
+        matches!(marker, 0xc0..=0xcf) && !matches!(marker, Jpeg::DHT | Jpeg::JPG | Jpeg::DAC)
+    }
+
+    /// Returns true if the marker is not followed by a length and payload
+    pub fn is_standalone(marker: u8) -> bool {
+        matches!(
+            marker,
+            Jpeg::SOI | Jpeg::EOI | Jpeg::TEM | Jpeg::RST0..=Jpeg::RST7
+        )
+    }
+
+    pub fn with_height(self, height: u16) -> Jpeg {
+        let segments = self
+            .segments
+            .into_iter()
+            .map(|s| {
+                if Jpeg::is_sof(s.marker()) {
+                    s.with_height(height)
+                } else {
+                    s
+                }
+            })
+            .collect();
+        Jpeg { segments }
+    }
+
+    pub fn segments(&self) -> &Vec<Segment> {
+        &self.segments
+    }
+
+    /// Returns the header of the first frame
+    pub fn frame(&self) -> Result<Option<FrameHeader>, ParseError> {
+        self.segments
+            .iter()
+            .find(|s| Jpeg::is_sof(s.marker()))
+            .map(|s| FrameHeader::parse(s.marker(), s.payload()))
+            .transpose()
+            .map_err(ParseError::from)
+    }
+
+    fn ends_with_eoi(&self) -> bool {
+        self.segments
+            .last()
+            .is_some_and(|s| s.marker() == Jpeg::EOI)
+    }
+
+    fn recover_height(
+        &self,
+        total_lines: Option<u32>,
+    ) -> Result<Option<(u16, HeightSource)>, ParseError> {
+        let total_lines = total_lines
+            .and_then(|lines| u16::try_from(lines).ok())
+            .filter(|lines| !is_placeholder_height(*lines));
+        if let Some(lines) = total_lines {
+            info!("Use jpeg height from total lines: {lines}");
+            return Ok(Some((lines, HeightSource::TotalLines)));
+        }
+        match decoder::count_complete_lines(self)? {
+            Some(lines) => {
+                let lines = u16::try_from(lines).unwrap_or(u16::MAX - 1);
+                if lines == 0 {
+                    info!("No complete MCU row found.");
+                    return Ok(None);
+                }
+                info!("Use jpeg height from complete MCU rows: {lines}");
+                Ok(Some((lines, HeightSource::McuRows)))
+            }
+            None => {
+                info!("Cannot count MCU rows of this frame type.");
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl From<Jpeg> for Bytes {
+    fn from(value: Jpeg) -> Self {
+        let size = value.segments.iter().map(|s| s.len()).sum();
+        let mut buffer = BytesMut::with_capacity(size);
+        for segment in value.segments {
+            buffer.put(segment.buffer);
+        }
+        buffer.into()
+    }
+}
+
+impl Display for Jpeg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "---------------------------------")?;
+        writeln!(
+            f,
+            "| {: <3} | {: <6} | {: <12} |",
+            "i", "marker", "total length"
+        )?;
+        writeln!(f, "---------------------------------")?;
+
+        for (i, segment) in self.segments().iter().enumerate() {
+            // marker printed in HEX
+            let marker = format!("{:X}", segment.marker());
+            let len = segment.len();
+            writeln!(f, "| {i: <3} | {marker: <6} | {len: <12} |")?;
+        }
+        Ok(())
+    }
+}
+
+impl Segment {
+    fn new(buffer: Bytes, fill: usize) -> Self {
+        Self { buffer, fill }
+    }
+
+    pub fn marker(&self) -> u8 {
+        self.buffer[self.fill + 1]
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// The payload of the segment after the length field.
+    /// For a scan only the header without the entropy-coded data is returned.
+    pub fn payload(&self) -> &[u8] {
+        let start = self.fill + 4;
+        if self.buffer.len() < start {
+            return &[];
+        }
+        let len = usize::from(u16::from_be_bytes([
+            self.buffer[self.fill + 2],
+            self.buffer[self.fill + 3],
+        ]));
+        let end = (self.fill + 2 + len).min(self.buffer.len());
+        &self.buffer[start..end.max(start)]
+    }
+
+    /// The entropy-coded data following a scan header
+    pub fn entropy_data(&self) -> &[u8] {
+        let start = self.fill + 4 + self.payload().len();
+        &self.buffer[start.min(self.buffer.len())..]
+    }
+
+    fn with_height(self, height: u16) -> Self {
+        assert!(Jpeg::is_sof(self.marker()));
+        let fill = self.fill;
+        let mut before = self.buffer;
+        let mut b = BytesMut::with_capacity(before.remaining());
+        for _ in 0..fill + 5 {
+            b.put_u8(before.get_u8());
+        }
+        b.put_u16(height);
+        before.advance(2);
+        while before.has_remaining() {
+            b.put_u8(before.get_u8());
+        }
+        Segment::new(b.into(), fill)
+    }
+}
+
+fn split_segment(buffer: &mut Bytes) -> Result<Segment, String> {
+    if buffer.remaining() < 2 {
+        return Err("buffer < 2 bytes".to_owned());
+    }
+    if buffer[0] != Jpeg::MARKER_START {
+        return Err("segment not starting with 0xff".to_owned());
+    }
+    // any marker may be preceded by fill bytes
+    let fill = buffer
+        .iter()
+        .skip(1)
+        .take_while(|b| **b == Jpeg::MARKER_START)
+        .count();
+    if buffer.remaining() < fill + 2 {
+        return Err("buffer contains only fill bytes".to_owned());
+    }
+    let marker = buffer[fill + 1];
+    let len = match marker {
+        m if Jpeg::is_standalone(m) => 2usize,
+        Jpeg::SOS => scan_for_sos_end(buffer.slice(fill..)),
+        _ => {
+            if buffer.remaining() < fill + 4 {
+                return Err("buffer < 4 bytes".to_owned());
+            }
+            let mut b = buffer.slice(fill..);
+            b.advance(2);
+            let size: usize = b.get_u16().into();
+            2usize + size
+        }
+    };
+    debug!("Segment {marker:x}: len = {len}");
+    let len = fill + len;
+    if buffer.remaining() < len {
+        return Err(format!(
+            "buffer smaller than size: {} < {}",
+            buffer.remaining(),
+            len
+        ));
+    }
+    Ok(Segment::new(buffer.split_to(len), fill))
+}
+
+fn scan_for_sos_end(mut buffer: Bytes) -> usize {
+    if buffer.remaining() < 4 {
+        return buffer.remaining();
+    }
+    // skip the scan header, the entropy-coded data follows it
+    let header_len = 2 + usize::from(u16::from_be_bytes([buffer[2], buffer[3]]));
+    let mut count = header_len.min(buffer.remaining());
+    buffer.advance(count);
+    let mut possible_marker = false;
+    loop {
+        if buffer.remaining() == 0 {
+            return count;
+        }
+        let byte = buffer.get_u8();
+        if possible_marker {
+            if is_marker(byte) {
+                count -= 1;
+                break;
+            } else {
+                possible_marker = false;
+            }
+        }
+        if byte == Jpeg::MARKER_START {
+            possible_marker = true;
+        }
+        count += 1;
+    }
+    count
+}
+
+fn is_marker(byte: u8) -> bool {
+    match byte {
+        0x00 => false,
+        b if (Jpeg::RST0..=Jpeg::RST7).contains(&b) => false,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn load_image(path: &str) -> Bytes {
+        let buffer = std::fs::read(path).unwrap();
+        buffer.into()
+    }
+
+    #[test]
+    fn split_segment_when_invalid_buffer_then_error() {
+        let mut buffer = Bytes::new();
+        assert_eq!(
+            Some("buffer < 2 bytes".to_owned()),
+            split_segment(&mut buffer).err()
+        );
+
+        buffer = vec![0x00, 0x00].into();
+        assert_eq!(
+            Some("segment not starting with 0xff".to_owned()),
+            split_segment(&mut buffer).err()
+        );
+    }
+
+    #[test]
+    fn split_segment_when_soi_then_create_segment() {
+        let mut buffer = vec![Jpeg::MARKER_START, Jpeg::SOI].into();
+        let segment = split_segment(&mut buffer).unwrap();
+        assert_eq!(buffer.remaining(), 0);
+        assert_eq!(segment.len(), 2);
+        assert_eq!(segment.marker(), Jpeg::SOI);
+    }
+
+    #[test]
+    fn split_segment_when_eoi_then_create_segment() {
+        let mut buffer = vec![Jpeg::MARKER_START, Jpeg::EOI].into();
+        let segment = split_segment(&mut buffer).unwrap();
+        assert_eq!(buffer.remaining(), 0);
+        assert_eq!(segment.len(), 2);
+        assert_eq!(segment.marker(), Jpeg::EOI);
+    }
+
+    const DNL_TEST_FILE: &str = "doc/testdata/scan_from_adf_with_dnl_header.jpeg";
+
+    #[test]
+    fn load_file_with_dnl_header() {
+        let buffer = load_image(DNL_TEST_FILE);
+        let jpeg = Jpeg::from_bytes(buffer).unwrap();
+        assert_eq!(jpeg.segments.len(), 10);
+        assert_eq!(jpeg.get_height_from_dnl(), Some(3490));
+    }
+
+    #[test]
+    fn read_segments_of_jpeg_file() {
+        let mut buffer = load_image(DNL_TEST_FILE);
+        let s1 = split_segment(&mut buffer).unwrap();
+        let s2 = split_segment(&mut buffer).unwrap();
+        let s3 = split_segment(&mut buffer).unwrap();
+        let s4 = split_segment(&mut buffer).unwrap();
+        let s5 = split_segment(&mut buffer).unwrap();
+        let s6 = split_segment(&mut buffer).unwrap();
+        let s7 = split_segment(&mut buffer).unwrap();
+        let s8 = split_segment(&mut buffer).unwrap();
+        let s9 = split_segment(&mut buffer).unwrap();
+        let s10 = split_segment(&mut buffer).unwrap();
+        let s11 = split_segment(&mut buffer);
+        assert_eq!(s1.marker(), Jpeg::SOI);
+        assert_eq!(s2.marker(), Jpeg::APP0);
+        assert_eq!(s2.len(), 18);
+        assert_eq!(s3.marker(), Jpeg::DQT);
+        assert_eq!(s3.len(), 69);
+        assert_eq!(s4.marker(), Jpeg::DQT);
+        assert_eq!(s4.len(), 69);
+        assert_eq!(s5.marker(), Jpeg::SOF0);
+        assert_eq!(s5.len(), 19);
+        assert_eq!(s6.marker(), Jpeg::DHT);
+        assert_eq!(s6.len(), 420);
+        assert_eq!(s7.marker(), Jpeg::DRI);
+        assert_eq!(s7.len(), 6);
+        assert_eq!(s8.marker(), Jpeg::SOS);
+        assert_eq!(s8.len(), 220950);
+        assert_eq!(s9.marker(), Jpeg::DNL);
+        assert_eq!(s9.len(), 6);
+        assert_eq!(s10.marker(), Jpeg::EOI);
+        assert_eq!(s10.len(), 2);
+        assert!(s11.is_err());
+    }
+
+    /// Builds a segment with the given marker and payload
+    pub(super) fn segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let len = u16::try_from(payload.len() + 2).unwrap();
+        let mut buffer = vec![Jpeg::MARKER_START, marker];
+        buffer.extend_from_slice(&len.to_be_bytes());
+        buffer.extend_from_slice(payload);
+        buffer
+    }
+
+    /// Builds a single component frame header with the given height
+    pub(super) fn frame(marker: u8, height: u16) -> Vec<u8> {
+        let [h1, h2] = height.to_be_bytes();
+        segment(marker, &[8, h1, h2, 0, 16, 1, 1, 0x11, 0])
+    }
+
+    /// Builds a scan header followed by some entropy-coded data
+    pub(super) fn scan(data: &[u8]) -> Vec<u8> {
+        let mut buffer = segment(Jpeg::SOS, &[1, 1, 0x00, 0, 63, 0]);
+        buffer.extend_from_slice(data);
+        buffer
+    }
+
+    pub(super) fn marker(marker: u8) -> Vec<u8> {
+        vec![Jpeg::MARKER_START, marker]
+    }
+
+    fn markers(jpeg: &Jpeg) -> Vec<u8> {
+        jpeg.segments().iter().map(Segment::marker).collect()
+    }
+
+    #[test]
+    fn is_sof_for_all_frame_types() {
+        let sofs: Vec<u8> = (0..=0xff).filter(|m| Jpeg::is_sof(*m)).collect();
+        assert_eq!(
+            sofs,
+            vec![
+                Jpeg::SOF0,
+                Jpeg::SOF1,
+                Jpeg::SOF2,
+                Jpeg::SOF3,
+                Jpeg::SOF5,
+                Jpeg::SOF6,
+                Jpeg::SOF7,
+                Jpeg::SOF9,
+                Jpeg::SOF10,
+                Jpeg::SOF11,
+                Jpeg::SOF13,
+                Jpeg::SOF14,
+                Jpeg::SOF15
+            ]
+        );
+    }
+
+    #[test]
+    fn fix_height_of_extended_sequential_frame() {
+        let data = [
+            marker(Jpeg::SOI),
+            frame(Jpeg::SOF1, 0),
+            scan(&[0x12, 0x34]),
+            segment(Jpeg::DNL, &[0x01, 0x02]),
+            marker(Jpeg::EOI),
+        ]
+        .concat();
+        let expected = [
+            marker(Jpeg::SOI),
+            frame(Jpeg::SOF1, 0x0102),
+            scan(&[0x12, 0x34]),
+            segment(Jpeg::DNL, &[0x01, 0x02]),
+            marker(Jpeg::EOI),
+        ]
+        .concat();
+        let (fixed, _) = fix_jpeg_height(data.into(), None).unwrap().unwrap();
+        assert_eq!(fixed.as_ref(), expected);
+    }
+
+    #[test]
+    fn fix_height_of_progressive_frame_with_multiple_scans() {
+        let dht = segment(Jpeg::DHT, &[0x00; 17]);
+        let data = [
+            marker(Jpeg::SOI),
+            frame(Jpeg::SOF2, 0xffff),
+            dht.clone(),
+            scan(&[0x01, 0xff, 0x00, 0x02]),
+            segment(Jpeg::DNL, &[0x00, 0x64]),
+            dht.clone(),
+            scan(&[0x03, 0x04]),
+            scan(&[0x05]),
+            marker(Jpeg::EOI),
+        ]
+        .concat();
+        let jpeg = Jpeg::from_bytes(data.into()).unwrap();
+        assert_eq!(
+            markers(&jpeg),
+            vec![
+                Jpeg::SOI,
+                Jpeg::SOF2,
+                Jpeg::DHT,
+                Jpeg::SOS,
+                Jpeg::DNL,
+                Jpeg::DHT,
+                Jpeg::SOS,
+                Jpeg::SOS,
+                Jpeg::EOI
+            ]
+        );
+        assert_eq!(jpeg.segments()[3].len(), 10 + 4);
+        assert_eq!(jpeg.get_height_from_dnl(), Some(100));
+        let fixed: Bytes = jpeg.with_height(100).into();
+        assert_eq!(&fixed[2..15], frame(Jpeg::SOF2, 100));
+    }
+
+    #[test]
+    fn split_segment_with_fill_bytes() {
+        let data = [
+            marker(Jpeg::SOI),
+            vec![Jpeg::MARKER_START, Jpeg::MARKER_START],
+            frame(Jpeg::SOF0, 0),
+            scan(&[0x01, 0x02, Jpeg::MARKER_START, Jpeg::MARKER_START]),
+            segment(Jpeg::DNL, &[0x00, 0x10]),
+            vec![Jpeg::MARKER_START],
+            marker(Jpeg::EOI),
+        ]
+        .concat();
+        let jpeg = Jpeg::from_bytes(data.clone().into()).unwrap();
+        assert_eq!(
+            markers(&jpeg),
+            vec![Jpeg::SOI, Jpeg::SOF0, Jpeg::SOS, Jpeg::DNL, Jpeg::EOI]
+        );
+        assert_eq!(jpeg.segments()[1].len(), 2 + 13);
+        assert_eq!(jpeg.segments()[2].len(), 10 + 2);
+        assert_eq!(jpeg.segments()[3].len(), 2 + 6);
+        assert_eq!(jpeg.segments()[4].len(), 1 + 2);
+
+        let (fixed, _) = fix_jpeg_height(data.clone().into(), None).unwrap().unwrap();
+        let mut expected = data;
+        expected[9..11].copy_from_slice(&[0x00, 0x10]);
+        assert_eq!(fixed.as_ref(), expected);
+    }
+
+    #[test]
+    fn split_segment_when_only_fill_bytes_then_error() {
+        let mut buffer = vec![Jpeg::MARKER_START, Jpeg::MARKER_START].into();
+        assert_eq!(
+            Some("buffer contains only fill bytes".to_owned()),
+            split_segment(&mut buffer).err()
+        );
+    }
+
+    #[test]
+    fn restart_markers_inside_and_between_scans() {
+        let rst1 = Jpeg::RST0 + 1;
+        let data = [
+            marker(Jpeg::SOI),
+            segment(Jpeg::DRI, &[0x00, 0x01]),
+            frame(Jpeg::SOF0, 16),
+            scan(&[0x01, Jpeg::MARKER_START, Jpeg::RST0, 0x02]),
+            segment(Jpeg::DNL, &[0x00, 0x10]),
+            marker(rst1),
+            scan(&[0x03, Jpeg::MARKER_START, Jpeg::RST7, 0x04]),
+            marker(Jpeg::TEM),
+            marker(Jpeg::EOI),
+        ]
+        .concat();
+        let jpeg = Jpeg::from_bytes(data.into()).unwrap();
+        assert_eq!(
+            markers(&jpeg),
+            vec![
+                Jpeg::SOI,
+                Jpeg::DRI,
+                Jpeg::SOF0,
+                Jpeg::SOS,
+                Jpeg::DNL,
+                rst1,
+                Jpeg::SOS,
+                Jpeg::TEM,
+                Jpeg::EOI
+            ]
+        );
+        // restart markers within the entropy-coded data belong to the scan
+        assert_eq!(jpeg.segments()[3].len(), 10 + 4);
+        assert_eq!(jpeg.segments()[5].len(), 2);
+        assert_eq!(jpeg.segments()[6].len(), 10 + 4);
+        assert_eq!(jpeg.get_height_from_dnl(), Some(16));
+    }
+
+    #[test]
+    fn scan_header_bytes_are_not_treated_as_markers() {
+        let mut sos = segment(Jpeg::SOS, &[1, Jpeg::MARKER_START, 0x00, 0, 63, 0]);
+        sos[5] = Jpeg::MARKER_START;
+        let data = [marker(Jpeg::SOI), sos, vec![0x01], marker(Jpeg::EOI)].concat();
+        let jpeg = Jpeg::from_bytes(data.into()).unwrap();
+        assert_eq!(markers(&jpeg), vec![Jpeg::SOI, Jpeg::SOS, Jpeg::EOI]);
+        assert_eq!(jpeg.segments()[1].len(), 10 + 1);
+    }
+
+    #[test]
+    fn recover_height_from_mcu_rows_without_dnl() {
+        let buffer = load_image(DNL_TEST_FILE);
+        // remove the DNL segment but keep the EOI marker
+        let data = [&buffer[..buffer.len() - 8], &END_OF_IMAGE].concat();
+        let (fixed, repair) = fix_jpeg_height(data.into(), None).unwrap().unwrap();
+        // 3490 lines rounded up to whole MCU rows of 16 lines
+        assert_eq!(repair.height, Some((3504, HeightSource::McuRows)));
+        assert!(!repair.appended_eoi);
+        let jpeg = Jpeg::from_bytes(fixed).unwrap();
+        assert_eq!(jpeg.frame().unwrap().unwrap().height, 3504);
+    }
+
+    #[test]
+    fn recover_height_of_truncated_image() {
+        let buffer = load_image(DNL_TEST_FILE);
+        let data = buffer.slice(..buffer.len() / 2);
+        let (fixed, repair) = fix_jpeg_height(data, None).unwrap().unwrap();
+        let Some((height, HeightSource::McuRows)) = repair.height else {
+            panic!("unexpected repair {repair:?}");
+        };
+        assert!(height > 0 && height < 3490);
+        assert_eq!(height % 16, 0);
+        assert!(repair.appended_eoi);
+        assert!(fixed.ends_with(&END_OF_IMAGE));
+        assert_eq!(
+            repair.to_string(),
+            format!("height {height} from complete MCU rows, appended missing EOI marker")
+        );
+    }
+
+    #[test]
+    fn recover_height_prefers_total_lines() {
+        let buffer = load_image(DNL_TEST_FILE);
+        let data = buffer.slice(..buffer.len() - 8);
+        let (_, repair) = fix_jpeg_height(data, Some(3490)).unwrap().unwrap();
+        assert_eq!(repair.height, Some((3490, HeightSource::TotalLines)));
+        assert!(repair.appended_eoi);
+    }
+
+    #[test]
+    fn nothing_to_repair_with_valid_height() {
+        let data = [
+            marker(Jpeg::SOI),
+            frame(Jpeg::SOF0, 16),
+            scan(&[0x12]),
+            marker(Jpeg::EOI),
+        ]
+        .concat();
+        assert!(fix_jpeg_height(data.into(), Some(100)).unwrap().is_none());
+    }
+}
@@ -0,0 +1,571 @@
+use bytes::Bytes;
+
+use std::f32::consts::PI;
+use std::sync::LazyLock;
+
+use crate::jpeg::decoder::{Block, Coefficients, FrameHeader};
+use crate::jpeg::{Jpeg, ParseError, encoder, fix_jpeg_height};
+use crate::raster::{GrayImage, Image, Rect, RotatedRect};
+
+/// `IDCT_TABLE[x][u]` is the contribution of frequency `u` to sample `x`
+static IDCT_TABLE: LazyLock<[[f32; 8]; 8]> = LazyLock::new(|| {
+    let mut table = [[0.0; 8]; 8];
+    for (x, row) in table.iter_mut().enumerate() {
+        for (u, value) in row.iter_mut().enumerate() {
+            let scale = if u == 0 { 1.0 / 2f32.sqrt() } else { 1.0 };
+            *value = scale / 2.0 * ((2 * x + 1) as f32 * u as f32 * PI / 16.0).cos();
+        }
+    }
+    table
+});
+
+/// Decodes the luminance of an image, which is all that is needed to analyse
+/// the content of a page. The height is fixed first if it is stored in a DNL segment.
+pub fn decode_gray(buffer: Bytes) -> Result<GrayImage, ParseError> {
+    let buffer = match fix_jpeg_height(buffer.clone(), None)? {
+        Some((fixed, _)) => fixed,
+        None => buffer,
+    };
+    let jpeg = Jpeg::from_bytes(buffer)?;
+    let coefficients = Coefficients::decode(&jpeg)?;
+    let frame = &coefficients.frame;
+    let plane = decode_plane(&coefficients, 0)?;
+    // components with less samples than the image are scaled up
+    let component = &frame.components[0];
+    let scale_x = frame.max_h() / usize::from(component.h);
+    let scale_y = frame.max_v() / usize::from(component.v);
+    let width = usize::from(frame.width);
+    let height = usize::from(frame.height);
+    if (scale_x, scale_y) == (1, 1) && plane.width == width {
+        let mut image = plane;
+        image.pixels.truncate(width * height);
+        image.height = height;
+        return Ok(image);
+    }
+    let mut image = GrayImage::new(width, height, 0);
+    for y in 0..height {
+        for x in 0..width {
+            image.set(x, y, plane.get(x / scale_x, y / scale_y));
+        }
+    }
+    Ok(image)
+}
+
+/// Decodes the samples of a grayscale or color image. Color is converted from
+/// YCbCr to RGB and subsampled components are scaled up.
+pub fn decode_image(buffer: Bytes) -> Result<Image, ParseError> {
+    let buffer = match fix_jpeg_height(buffer.clone(), None)? {
+        Some((fixed, _)) => fixed,
+        None => buffer,
+    };
+    let coefficients = Coefficients::decode(&Jpeg::from_bytes(buffer)?)?;
+    let frame = &coefficients.frame;
+    let channels = frame.components.len();
+    if channels != 1 && channels != 3 {
+        return Err(format!("images with {channels} components are not supported").into());
+    }
+    let planes = decode_planes(&coefficients)?;
+    let scales: Vec<(usize, usize)> = frame
+        .components
+        .iter()
+        .map(|c| {
+            (
+                frame.max_h() / usize::from(c.h),
+                frame.max_v() / usize::from(c.v),
+            )
+        })
+        .collect();
+    let (width, height) = (usize::from(frame.width), usize::from(frame.height));
+    let clamp = |value: f32| value.round().clamp(0.0, 255.0) as u8;
+    let mut samples = Vec::with_capacity(width * height * channels);
+    for y in 0..height {
+        for x in 0..width {
+            let sample = |index: usize| {
+                let (scale_x, scale_y) = scales[index];
+                planes[index].get(x / scale_x, y / scale_y)
+            };
+            if channels == 1 {
+                samples.push(sample(0));
+            } else {
+                let luma = f32::from(sample(0));
+                let blue = f32::from(sample(1)) - 128.0;
+                let red = f32::from(sample(2)) - 128.0;
+                samples.push(clamp(luma + 1.402 * red));
+                samples.push(clamp(luma - 0.344_136 * blue - 0.714_136 * red));
+                samples.push(clamp(luma + 1.772 * blue));
+            }
+        }
+    }
+    Ok(Image {
+        width,
+        height,
+        channels,
+        samples,
+    })
+}
+
+/// Decodes the blue and red chroma planes of a color image at their own
+/// resolution, without padding. Returns `None` for a grayscale image.
+pub fn decode_chroma(buffer: Bytes) -> Result<Option<[GrayImage; 2]>, ParseError> {
+    let buffer = match fix_jpeg_height(buffer.clone(), None)? {
+        Some((fixed, _)) => fixed,
+        None => buffer,
+    };
+    let coefficients = Coefficients::decode(&Jpeg::from_bytes(buffer)?)?;
+    let frame = &coefficients.frame;
+    if frame.components.len() != 3 {
+        return Ok(None);
+    }
+    let (width, height) = (usize::from(frame.width), usize::from(frame.height));
+    let plane = |index: usize| -> Result<GrayImage, ParseError> {
+        let (plane_width, plane_height) = component_size(frame, index, width, height);
+        let full = Rect {
+            x: 0,
+            y: 0,
+            width: plane_width,
+            height: plane_height,
+        };
+        Ok(decode_plane(&coefficients, index)?.rotate_and_crop(0.0, full, 0))
+    };
+    Ok(Some([plane(1)?, plane(2)?]))
+}
+
+/// Converts a color image into a grayscale one by dropping its chroma
+/// components. The luminance is kept without loss of quality.
+pub fn to_grayscale(buffer: Bytes) -> Result<Bytes, ParseError> {
+    let buffer = match fix_jpeg_height(buffer.clone(), None)? {
+        Some((fixed, _)) => fixed,
+        None => buffer,
+    };
+    let jpeg = Jpeg::from_bytes(buffer)?;
+    let coefficients = Coefficients::decode(&jpeg)?;
+    let frame = &coefficients.frame;
+    let luminance = frame.components[0];
+    if usize::from(luminance.h) != frame.max_h() || usize::from(luminance.v) != frame.max_v() {
+        return Err(ParseError::from("luminance is subsampled".to_owned()));
+    }
+    let mut gray_frame = frame.clone();
+    gray_frame.components.truncate(1);
+    let mut result = Coefficients::new(&gray_frame);
+    result.quantization_tables = coefficients
+        .quantization_tables
+        .iter()
+        .filter(|table| table.id == luminance.tq)
+        .cloned()
+        .collect();
+    // the blocks of an interleaved image are padded to whole MCUs
+    let (source, target) = (&coefficients.components[0], &mut result.components[0]);
+    for y in 0..target.height.min(source.height) {
+        for x in 0..target.width.min(source.width) {
+            *target.get_mut(x, y) = *source.get(x, y);
+        }
+    }
+    let segments: Vec<_> = jpeg
+        .segments()
+        .iter()
+        // an Adobe segment describes the color transform of three components
+        .filter(|s| matches!(s.marker(), Jpeg::APP0..=Jpeg::APP15 | Jpeg::COM))
+        .filter(|s| s.marker() != Jpeg::APP14)
+        .collect();
+    Ok(encoder::encode(&result, &segments)?)
+}
+
+/// Rotates the image counterclockwise by `angle` radians around its center and
+/// keeps the part within `rect`, given in pixels of the rotated image. Areas
+/// outside of the original image get the color of its edges. The image is
+/// encoded again with its original quantization tables.
+pub fn rotate_and_crop(buffer: Bytes, angle: f64, rect: Rect) -> Result<Bytes, ParseError> {
+    let buffer = match fix_jpeg_height(buffer.clone(), None)? {
+        Some((fixed, _)) => fixed,
+        None => buffer,
+    };
+    let frame = Jpeg::from_bytes(buffer.clone())?
+        .frame()?
+        .ok_or_else(|| ParseError::from("missing frame header".to_owned()))?;
+    let (width, height) = (usize::from(frame.width), usize::from(frame.height));
+    if rect.width == 0
+        || rect.height == 0
+        || rect.x + rect.width > width
+        || rect.y + rect.height > height
+    {
+        return Err(format!("invalid area {rect:?} of a {width}x{height} image").into());
+    }
+    // the center of the area in the rotated image is rotated back
+    let (sin, cos) = angle.sin_cos();
+    let dx = rect.x as f64 + (rect.width as f64 - 1.0) / 2.0 - (width as f64 - 1.0) / 2.0;
+    let dy = rect.y as f64 + (rect.height as f64 - 1.0) / 2.0 - (height as f64 - 1.0) / 2.0;
+    let area = RotatedRect {
+        center_x: (width as f64 - 1.0) / 2.0 + dx * cos - dy * sin,
+        center_y: (height as f64 - 1.0) / 2.0 + dx * sin + dy * cos,
+        width: rect.width,
+        height: rect.height,
+        angle,
+    };
+    extract_area(buffer, area)
+}
+
+/// Cuts the area out of the image and turns it upright. Parts of the area
+/// outside of the image get the color of its edges. The image is encoded
+/// again with its original quantization tables.
+pub fn extract_area(buffer: Bytes, area: RotatedRect) -> Result<Bytes, ParseError> {
+    let buffer = match fix_jpeg_height(buffer.clone(), None)? {
+        Some((fixed, _)) => fixed,
+        None => buffer,
+    };
+    let jpeg = Jpeg::from_bytes(buffer)?;
+    let coefficients = Coefficients::decode(&jpeg)?;
+    let frame = &coefficients.frame;
+    let (width, height) = (usize::from(frame.width), usize::from(frame.height));
+    if area.width == 0
+        || area.height == 0
+        || area.width > usize::from(u16::MAX)
+        || area.height > usize::from(u16::MAX)
+    {
+        return Err(format!("invalid area {area:?} of a {width}x{height} image").into());
+    }
+    let (sin, cos) = area.angle.sin_cos();
+    let center_x = (area.width as f64 - 1.0) / 2.0;
+    let center_y = (area.height as f64 - 1.0) / 2.0;
+    let planes = decode_planes(&coefficients)?
+        .into_iter()
+        .enumerate()
+        .map(|(index, plane)| {
+            // the plane without padding
+            let (plane_width, plane_height) = component_size(frame, index, width, height);
+            let full = Rect {
+                x: 0,
+                y: 0,
+                width: plane_width,
+                height: plane_height,
+            };
+            let plane = plane.rotate_and_crop(0.0, full, 0);
+            let fill = plane.border_median();
+            // size of a sample in pixels of the image
+            let scale_x = width as f64 / plane_width as f64;
+            let scale_y = height as f64 / plane_height as f64;
+            let (result_width, result_height) =
+                component_size(frame, index, area.width, area.height);
+            let mut result = GrayImage::new(result_width, result_height, fill);
+            for y in 0..result_height {
+                let dy = (y as f64 + 0.5) * scale_y - 0.5 - center_y;
+                for x in 0..result_width {
+                    let dx = (x as f64 + 0.5) * scale_x - 0.5 - center_x;
+                    let source_x = area.center_x + dx * cos - dy * sin;
+                    let source_y = area.center_y + dx * sin + dy * cos;
+                    let sample_x = (source_x + 0.5) / scale_x - 0.5;
+                    let sample_y = (source_y + 0.5) / scale_y - 0.5;
+                    if let Some(value) = plane.sample(sample_x, sample_y) {
+                        result.set(x, y, value);
+                    }
+                }
+            }
+            result
+        })
+        .collect::<Vec<GrayImage>>();
+    let result = encode_planes(
+        &coefficients,
+        area.width as u16,
+        area.height as u16,
+        &planes,
+    )?;
+    let segments: Vec<_> = jpeg
+        .segments()
+        .iter()
+        .filter(|s| matches!(s.marker(), Jpeg::APP0..=Jpeg::APP15 | Jpeg::COM))
+        .collect();
+    Ok(encoder::encode(&result, &segments)?)
+}
+
+/// Decodes the samples of every component at its own resolution. The planes
+/// cover all blocks, including the padding at the right and bottom.
+fn decode_planes(coefficients: &Coefficients) -> Result<Vec<GrayImage>, ParseError> {
+    (0..coefficients.components.len())
+        .map(|index| decode_plane(coefficients, index))
+        .collect()
+}
+
+fn decode_plane(coefficients: &Coefficients, index: usize) -> Result<GrayImage, ParseError> {
+    if coefficients.frame.precision != 8 {
+        let precision = coefficients.frame.precision;
+        return Err(format!("unsupported sample precision {precision}").into());
+    }
+    let quantization = quantization(coefficients, index)?;
+    let blocks = &coefficients.components[index];
+    let mut plane = GrayImage::new(blocks.width * 8, blocks.height * 8, 0);
+    let mut samples = [0u8; 64];
+    for block_y in 0..blocks.height {
+        for block_x in 0..blocks.width {
+            idct(blocks.get(block_x, block_y), quantization, &mut samples);
+            for (i, sample) in samples.iter().enumerate() {
+                plane.set(block_x * 8 + i % 8, block_y * 8 + i / 8, *sample);
+            }
+        }
+    }
+    Ok(plane)
+}
+
+/// Returns the size of a component in samples for an image of the given size
+fn component_size(
+    frame: &FrameHeader,
+    index: usize,
+    width: usize,
+    height: usize,
+) -> (usize, usize) {
+    let component = &frame.components[index];
+    (
+        (width * usize::from(component.h)).div_ceil(frame.max_h()),
+        (height * usize::from(component.v)).div_ceil(frame.max_v()),
+    )
+}
+
+/// Creates the coefficients of an image with a new size from one plane per
+/// component, see [`component_size`]. The components, their sampling and the
+/// quantization tables of the original image are kept, so the quality is the same.
+fn encode_planes(
+    coefficients: &Coefficients,
+    width: u16,
+    height: u16,
+    planes: &[GrayImage],
+) -> Result<Coefficients, ParseError> {
+    if planes.len() != coefficients.components.len() {
+        return Err(ParseError::from(
+            "number of planes and components differ".to_owned(),
+        ));
+    }
+    let mut frame = coefficients.frame.clone();
+    frame.width = width;
+    frame.height = height;
+    let mut result = Coefficients::new(&frame);
+    result.quantization_tables = coefficients.quantization_tables.clone();
+    for (index, plane) in planes.iter().enumerate() {
+        if plane.width == 0 || plane.height == 0 {
+            return Err("empty plane".to_owned().into());
+        }
+        let quantization = quantization(coefficients, index)?;
+        let blocks = &mut result.components[index];
+        let mut samples = [0u8; 64];
+        for block_y in 0..blocks.height {
+            for block_x in 0..blocks.width {
+                // the padding repeats the last row and column
+                for (i, sample) in samples.iter_mut().enumerate() {
+                    let x = (block_x * 8 + i % 8).min(plane.width - 1);
+                    let y = (block_y * 8 + i / 8).min(plane.height - 1);
+                    *sample = plane.get(x, y);
+                }
+                fdct(&samples, quantization, blocks.get_mut(block_x, block_y));
+            }
+        }
+    }
+    Ok(result)
+}
+
+fn quantization(coefficients: &Coefficients, index: usize) -> Result<&[u16; 64], ParseError> {
+    let tq = coefficients.frame.components[index].tq;
+    coefficients
+        .quantization_tables
+        .iter()
+        .find(|t| t.id == tq)
+        .map(|t| &t.values)
+        .ok_or_else(|| format!("missing quantization table {tq}").into())
+}
+
+/// Converts level shifted samples into quantized coefficients
+fn fdct(samples: &[u8; 64], quantization: &[u16; 64], block: &mut Block) {
+    let table = &*IDCT_TABLE;
+    let shifted = |x: usize, y: usize| f32::from(samples[y * 8 + x]) - 128.0;
+    let mut rows = [0f32; 64];
+    for y in 0..8 {
+        for u in 0..8 {
+            rows[y * 8 + u] = (0..8).map(|x| table[x][u] * shifted(x, y)).sum();
+        }
+    }
+    for (k, coefficient) in block.iter_mut().enumerate() {
+        let (v, u) = (k / 8, k % 8);
+        let value: f32 = (0..8).map(|y| table[y][v] * rows[y * 8 + u]).sum();
+        *coefficient = (value / f32::from(quantization[k].max(1))).round() as i16;
+    }
+}
+
+/// Converts a block of quantized coefficients into level shifted samples
+fn idct(block: &Block, quantization: &[u16; 64], samples: &mut [u8; 64]) {
+    let dequantized = |k: usize| f32::from(block[k]) * f32::from(quantization[k]);
+    if block[1..].iter().all(|c| *c == 0) {
+        // a block without AC coefficients has the same value everywhere
+        samples.fill(to_sample(dequantized(0) / 8.0));
+        return;
+    }
+    let table = &*IDCT_TABLE;
+    let mut rows = [0f32; 64];
+    for v in 0..8 {
+        for x in 0..8 {
+            rows[v * 8 + x] = (0..8).map(|u| table[x][u] * dequantized(v * 8 + u)).sum();
+        }
+    }
+    for y in 0..8 {
+        for x in 0..8 {
+            let value: f32 = (0..8).map(|v| table[y][v] * rows[v * 8 + x]).sum();
+            samples[y * 8 + x] = to_sample(value);
+        }
+    }
+}
+
+fn to_sample(value: f32) -> u8 {
+    (value + 128.0).round().clamp(0.0, 255.0) as u8
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn idct_of_single_frequency() {
+        let quantization = [1u16; 64];
+        let mut block = [0i16; 64];
+        block[0] = 80;
+        let mut samples = [0u8; 64];
+        idct(&block, &quantization, &mut samples);
+        assert!(samples.iter().all(|s| *s == 138));
+        // a horizontal cosine is brighter on the left than on the right
+        block[1] = 100;
+        idct(&block, &quantization, &mut samples);
+        assert!(samples[0] > samples[7]);
+        assert_eq!(samples[0], samples[56]);
+    }
+
+    #[test]
+    fn fdct_reverses_idct() {
+        let quantization = [1u16; 64];
+        let mut block = [0i16; 64];
+        block[0] = 80;
+        block[1] = -30;
+        block[9] = 12;
+        let mut samples = [0u8; 64];
+        idct(&block, &quantization, &mut samples);
+        let mut result = [0i16; 64];
+        fdct(&samples, &quantization, &mut result);
+        for (a, b) in block.iter().zip(&result) {
+            assert!((a - b).abs() <= 1, "{block:?} != {result:?}");
+        }
+    }
+
+    #[test]
+    fn encode_cropped_planes() {
+        let data = crate::jpeg::test_page(|x, y| x < 4 && y < 2);
+        let jpeg = Jpeg::from_bytes(data).unwrap();
+        let coefficients = Coefficients::decode(&jpeg).unwrap();
+        let planes = decode_planes(&coefficients).unwrap();
+        let frame = &coefficients.frame;
+        let cropped: Vec<GrayImage> = planes
+            .iter()
+            .enumerate()
+            .map(|(index, plane)| {
+                let (width, height) = component_size(frame, index, 40, 24);
+                let rect = Rect {
+                    x: 0,
+                    y: 0,
+                    width,
+                    height,
+                };
+                plane.rotate_and_crop(0.0, rect, 0)
+            })
+            .collect();
+        let result = encode_planes(&coefficients, 40, 24, &cropped).unwrap();
+        let data = crate::jpeg::encoder::encode(&result, &[]).unwrap();
+        let image = decode_gray(data).unwrap();
+        assert_eq!((image.width, image.height), (40, 24));
+        // the dark blocks are 32x16 pixels in the top left corner
+        assert!(image.get(2, 2) < 30);
+        assert!(image.get(30, 14) < 30);
+        assert!(image.get(34, 2) > 200);
+        assert!(image.get(2, 18) > 200);
+    }
+
+    #[test]
+    fn rotate_and_crop_page() {
+        let data = crate::jpeg::test_page(|x, y| (100..110).contains(&x) && (50..60).contains(&y));
+        let rect = Rect {
+            x: 700,
+            y: 300,
+            width: 200,
+            height: 160,
+        };
+        // the dark square at 800..880 x 400..480 is rotated into view
+        let rotated = rotate_and_crop(data.clone(), 0.0, rect).unwrap();
+        let image = decode_gray(rotated).unwrap();
+        assert_eq!((image.width, image.height), (200, 160));
+        assert!(image.get(140, 140) < 30);
+        assert!(image.get(20, 20) > 200);
+        let turned = rotate_and_crop(
+            data,
+            std::f64::consts::PI,
+            Rect {
+                x: 0,
+                y: 0,
+                width: 2480,
+                height: 3490,
+            },
+        )
+        .unwrap();
+        let image = decode_gray(turned).unwrap();
+        assert!(image.get(2480 - 840, 3490 - 440) < 30);
+        assert!(image.get(840, 440) > 200);
+    }
+
+    #[test]
+    fn convert_to_grayscale() {
+        let data = crate::jpeg::test_page(|x, y| (x + y) % 5 == 0);
+        let gray = to_grayscale(data.clone()).unwrap();
+        let frame = Jpeg::from_bytes(gray.clone())
+            .unwrap()
+            .frame()
+            .unwrap()
+            .unwrap();
+        assert_eq!(frame.components.len(), 1);
+        assert_eq!((frame.width, frame.height), (2480, 3490));
+        assert_eq!(
+            decode_gray(gray.clone()).unwrap(),
+            decode_gray(data).unwrap()
+        );
+        assert!(decode_chroma(gray).unwrap().is_none());
+    }
+
+    #[test]
+    fn decode_chroma_planes() {
+        let [blue, red] = decode_chroma(crate::jpeg::blank_page()).unwrap().unwrap();
+        assert_eq!((blue.width, blue.height), (1240, 1745));
+        assert_eq!((red.width, red.height), (1240, 1745));
+    }
+
+    #[test]
+    fn decode_color_image() {
+        let image = decode_image(crate::jpeg::color_page(|x, _| x < 10)).unwrap();
+        assert_eq!((image.width, image.height, image.channels), (2480, 3490, 3));
+        let pixel = |x: usize, y: usize| {
+            let offset = (y * image.width + x) * 3;
+            &image.samples[offset..offset + 3]
+        };
+        // the red blocks are twice as large in the subsampled chroma
+        let [r, g, b] = pixel(100, 100) else { panic!() };
+        assert!(r.saturating_sub(*g) > 50 && r >= b, "{r} {g} {b}");
+        let [r, g, b] = pixel(1000, 100) else {
+            panic!()
+        };
+        assert!(r.abs_diff(*g) < 5 && g.abs_diff(*b) < 5, "{r} {g} {b}");
+
+        let gray = decode_image(to_grayscale(crate::jpeg::blank_page()).unwrap()).unwrap();
+        assert_eq!(gray.channels, 1);
+        assert_eq!(
+            gray.samples,
+            decode_gray(crate::jpeg::blank_page()).unwrap().pixels
+        );
+    }
+
+    #[test]
+    fn decode_blank_page() {
+        let image = decode_gray(crate::jpeg::blank_page()).unwrap();
+        assert_eq!((image.width, image.height), (2480, 3490));
+        let expected = image.pixels[0];
+        assert!(expected > 200);
+        assert!(image.pixels.iter().all(|p| *p == expected));
+    }
+}
@@ -0,0 +1,372 @@
+use bytes::Bytes;
+use std::fmt::Display;
+
+use crate::jpeg::decoder::{Block, Coefficients, ComponentBlocks};
+use crate::jpeg::{Jpeg, ParseError, encoder, fix_jpeg_height};
+
+/// A lossless transformation of the DCT coefficients, like the ones of jpegtran
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Transform {
+    #[default]
+    None,
+    /// Rotate 90 degrees clockwise
+    Rotate90,
+    Rotate180,
+    /// Rotate 270 degrees clockwise
+    Rotate270,
+    /// Mirror left and right
+    FlipHorizontal,
+    /// Mirror top and bottom
+    FlipVertical,
+    /// Mirror along the diagonal from the top left corner
+    Transpose,
+    /// Mirror along the diagonal from the top right corner
+    Transverse,
+}
+
+/// A transform is applied as an optional transposition followed by optional
+/// horizontal and vertical flips
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Parts {
+    transpose: bool,
+    flip_h: bool,
+    flip_v: bool,
+}
+
+impl Transform {
+    fn parts(self) -> Parts {
+        let (transpose, flip_h, flip_v) = match self {
+            Transform::None => (false, false, false),
+            Transform::Rotate90 => (true, true, false),
+            Transform::Rotate180 => (false, true, true),
+            Transform::Rotate270 => (true, false, true),
+            Transform::FlipHorizontal => (false, true, false),
+            Transform::FlipVertical => (false, false, true),
+            Transform::Transpose => (true, false, false),
+            Transform::Transverse => (true, true, true),
+        };
+        Parts {
+            transpose,
+            flip_h,
+            flip_v,
+        }
+    }
+
+    fn from_parts(parts: Parts) -> Self {
+        match (parts.transpose, parts.flip_h, parts.flip_v) {
+            (false, false, false) => Transform::None,
+            (true, true, false) => Transform::Rotate90,
+            (false, true, true) => Transform::Rotate180,
+            (true, false, true) => Transform::Rotate270,
+            (false, true, false) => Transform::FlipHorizontal,
+            (false, false, true) => Transform::FlipVertical,
+            (true, false, false) => Transform::Transpose,
+            (true, true, true) => Transform::Transverse,
+        }
+    }
+
+    /// The transform that is equivalent to applying `self` and then `next`
+    pub fn then(self, next: Transform) -> Transform {
+        let a = self.parts();
+        let b = next.parts();
+        // moving the transposition of `next` in front of the flips of `self` swaps them
+        let (flip_h, flip_v) = if b.transpose {
+            (a.flip_v, a.flip_h)
+        } else {
+            (a.flip_h, a.flip_v)
+        };
+        Transform::from_parts(Parts {
+            transpose: a.transpose != b.transpose,
+            flip_h: flip_h != b.flip_h,
+            flip_v: flip_v != b.flip_v,
+        })
+    }
+
+    /// The transform that turns an image rotated clockwise by `degrees` upright
+    pub fn upright_from_rotation(degrees: u32) -> Transform {
+        match degrees % 360 {
+            90 => Transform::Rotate270,
+            180 => Transform::Rotate180,
+            270 => Transform::Rotate90,
+            _ => Transform::None,
+        }
+    }
+}
+
+impl Display for Transform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Transform::None => "none",
+            Transform::Rotate90 => "rotate 90",
+            Transform::Rotate180 => "rotate 180",
+            Transform::Rotate270 => "rotate 270",
+            Transform::FlipHorizontal => "flip horizontal",
+            Transform::FlipVertical => "flip vertical",
+            Transform::Transpose => "transpose",
+            Transform::Transverse => "transverse",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Transforms an image without decoding it to pixels, so there is no loss of quality.
+///
+/// Like `jpegtran -trim`, partial MCUs at the right or bottom edge are dropped if the
+/// transform would move them to the left or top edge. Only Huffman coded sequential
+/// images are supported. The height is fixed first if it is stored in a DNL segment.
+pub fn transform_jpeg(buffer: Bytes, transform: Transform) -> Result<Bytes, ParseError> {
+    if transform == Transform::None {
+        return Ok(buffer);
+    }
+    let buffer = match fix_jpeg_height(buffer.clone(), None)? {
+        Some((fixed, _)) => fixed,
+        None => buffer,
+    };
+    let jpeg = Jpeg::from_bytes(buffer)?;
+    let coefficients = Coefficients::decode(&jpeg)?;
+    let transformed = transform_coefficients(&coefficients, transform);
+    let segments: Vec<_> = jpeg
+        .segments()
+        .iter()
+        .filter(|s| matches!(s.marker(), Jpeg::APP0..=Jpeg::APP15 | Jpeg::COM))
+        .collect();
+    Ok(encoder::encode(&transformed, &segments)?)
+}
+
+fn transform_coefficients(coefficients: &Coefficients, transform: Transform) -> Coefficients {
+    let Parts {
+        transpose,
+        flip_h,
+        flip_v,
+    } = transform.parts();
+    let frame = &coefficients.frame;
+    // the axes of the original image that are flipped
+    let (flip_x, flip_y) = if transpose {
+        (flip_v, flip_h)
+    } else {
+        (flip_h, flip_v)
+    };
+    let mcu_width = 8 * frame.max_h();
+    let mcu_height = 8 * frame.max_v();
+    let width = usize::from(frame.width);
+    let height = usize::from(frame.height);
+    let trimmed_width = if flip_x && width >= mcu_width {
+        width / mcu_width * mcu_width
+    } else {
+        width
+    };
+    let trimmed_height = if flip_y && height >= mcu_height {
+        height / mcu_height * mcu_height
+    } else {
+        height
+    };
+
+    let mut new_frame = frame.clone();
+    if transpose {
+        new_frame.width = trimmed_height as u16;
+        new_frame.height = trimmed_width as u16;
+        for c in &mut new_frame.components {
+            std::mem::swap(&mut c.h, &mut c.v);
+        }
+    } else {
+        new_frame.width = trimmed_width as u16;
+        new_frame.height = trimmed_height as u16;
+    }
+    let components = frame
+        .components
+        .iter()
+        .zip(&coefficients.components)
+        .map(|(c, blocks)| {
+            // number of blocks of the original component that are kept
+            let width = if trimmed_width < width {
+                trimmed_width / mcu_width * usize::from(c.h)
+            } else {
+                blocks.width
+            };
+            let height = if trimmed_height < height {
+                trimmed_height / mcu_height * usize::from(c.v)
+            } else {
+                blocks.height
+            };
+            let (new_width, new_height) = if transpose {
+                (height, width)
+            } else {
+                (width, height)
+            };
+            let mut result = ComponentBlocks::new(new_width, new_height);
+            for y in 0..new_height {
+                for x in 0..new_width {
+                    let x1 = if flip_h { new_width - 1 - x } else { x };
+                    let y1 = if flip_v { new_height - 1 - y } else { y };
+                    let (sx, sy) = if transpose { (y1, x1) } else { (x1, y1) };
+                    *result.get_mut(x, y) =
+                        transform_block(blocks.get(sx, sy), transpose, flip_h, flip_v);
+                }
+            }
+            result
+        })
+        .collect();
+
+    let mut quantization_tables = coefficients.quantization_tables.clone();
+    if transpose {
+        for table in &mut quantization_tables {
+            let values = table.values;
+            for (i, value) in table.values.iter_mut().enumerate() {
+                *value = values[(i % 8) * 8 + i / 8];
+            }
+        }
+    }
+    Coefficients {
+        frame: new_frame,
+        quantization_tables,
+        components,
+    }
+}
+
+/// Transposes the block and negates the odd columns or rows to mirror it
+fn transform_block(block: &Block, transpose: bool, flip_h: bool, flip_v: bool) -> Block {
+    let mut result = [0; 64];
+    for v in 0..8 {
+        for u in 0..8 {
+            let value = if transpose {
+                block[u * 8 + v]
+            } else {
+                block[v * 8 + u]
+            };
+            let negate = (flip_h && u % 2 == 1) != (flip_v && v % 2 == 1);
+            result[v * 8 + u] = if negate { -value } else { value };
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::jpeg::decoder::FrameHeader;
+
+    const DNL_TEST_FILE: &str = "doc/testdata/scan_from_adf_with_dnl_header.jpeg";
+    const ALL: [Transform; 8] = [
+        Transform::None,
+        Transform::Rotate90,
+        Transform::Rotate180,
+        Transform::Rotate270,
+        Transform::FlipHorizontal,
+        Transform::FlipVertical,
+        Transform::Transpose,
+        Transform::Transverse,
+    ];
+
+    fn frame(jpeg: &Bytes) -> FrameHeader {
+        Jpeg::from_bytes(jpeg.clone())
+            .unwrap()
+            .frame()
+            .unwrap()
+            .unwrap()
+    }
+
+    fn decode(jpeg: &Bytes) -> Coefficients {
+        Coefficients::decode(&Jpeg::from_bytes(jpeg.clone()).unwrap()).unwrap()
+    }
+
+    /// Maps pixel positions of a `width` x `height` image like the transform
+    fn map_point(transform: Transform, x: i32, y: i32, width: i32, height: i32) -> (i32, i32) {
+        let parts = transform.parts();
+        let (x, y, width, height) = if parts.transpose {
+            (y, x, height, width)
+        } else {
+            (x, y, width, height)
+        };
+        let x = if parts.flip_h { width - 1 - x } else { x };
+        let y = if parts.flip_v { height - 1 - y } else { y };
+        (x, y)
+    }
+
+    #[test]
+    fn compose_transforms() {
+        assert_eq!(
+            Transform::Rotate90.then(Transform::Rotate90),
+            Transform::Rotate180
+        );
+        assert_eq!(
+            Transform::Rotate90.then(Transform::Rotate180),
+            Transform::Rotate270
+        );
+        assert_eq!(
+            Transform::Rotate270.then(Transform::Rotate90),
+            Transform::None
+        );
+        assert_eq!(
+            Transform::FlipHorizontal.then(Transform::FlipVertical),
+            Transform::Rotate180
+        );
+        for a in ALL {
+            for b in ALL {
+                let composed = a.then(b);
+                for (x, y) in [(0, 0), (3, 1), (1, 2)] {
+                    let (x1, y1) = map_point(a, x, y, 4, 3);
+                    let (w1, h1) = if a.parts().transpose { (3, 4) } else { (4, 3) };
+                    assert_eq!(
+                        map_point(b, x1, y1, w1, h1),
+                        map_point(composed, x, y, 4, 3),
+                        "{a} then {b}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rotation_is_lossless() {
+        let data = Bytes::from(std::fs::read(DNL_TEST_FILE).unwrap());
+        let original = decode(&fix_jpeg_height(data.clone(), None).unwrap().unwrap().0);
+        let mut rotated = data;
+        for _ in 0..4 {
+            rotated = transform_jpeg(rotated, Transform::Rotate90).unwrap();
+        }
+        let rotated = decode(&rotated);
+        // 3490 lines are trimmed to whole MCU rows of 16 lines
+        assert_eq!(rotated.frame.height, 3488);
+        assert_eq!(rotated.frame.width, 2480);
+        for (a, b) in original.components.iter().zip(&rotated.components) {
+            assert_eq!(a.width, b.width);
+            let rows = b.height;
+            assert_eq!(a.blocks[..a.width * rows], b.blocks[..]);
+        }
+        assert_eq!(original.quantization_tables, rotated.quantization_tables);
+    }
+
+    #[test]
+    fn dimensions_of_transformed_image() {
+        let data = Bytes::from(std::fs::read(DNL_TEST_FILE).unwrap());
+        let rotated = transform_jpeg(data.clone(), Transform::Rotate90).unwrap();
+        let rotated_frame = frame(&rotated);
+        assert_eq!((rotated_frame.width, rotated_frame.height), (3488, 2480));
+        assert_eq!(
+            rotated_frame
+                .components
+                .iter()
+                .map(|c| (c.h, c.v))
+                .collect::<Vec<_>>(),
+            vec![(2, 2), (1, 1), (1, 1)]
+        );
+        let flipped = transform_jpeg(data.clone(), Transform::FlipHorizontal).unwrap();
+        // the width is a multiple of the MCU width, so nothing is trimmed
+        let flipped_frame = frame(&flipped);
+        assert_eq!((flipped_frame.width, flipped_frame.height), (2480, 3490));
+        assert!(crate::jpeg::Check::new(flipped).valid);
+    }
+
+    #[test]
+    fn flipped_block_has_negated_odd_columns() {
+        let block: Block = std::array::from_fn(|i| i as i16);
+        let flipped = transform_block(&block, false, true, false);
+        assert_eq!(flipped[0], 0);
+        assert_eq!(flipped[1], -1);
+        assert_eq!(flipped[9], -9);
+        assert_eq!(flipped[10], 10);
+        let transposed = transform_block(&block, true, false, false);
+        assert_eq!(transposed[1], 8);
+        assert_eq!(transposed[8], 1);
+    }
+}
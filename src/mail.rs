@@ -0,0 +1,345 @@
+use lettre::message::header::ContentType;
+use lettre::message::{Attachment, Mailbox, MultiPart, SinglePart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Address, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use thiserror::Error;
+use tracing::info;
+use url::Url;
+
+use std::path::{Path, PathBuf};
+
+use crate::output;
+
+#[derive(Debug, Error)]
+pub enum MailError {
+    #[error("Sending scans by e-mail is not configured")]
+    NotConfigured,
+    #[error("Scans cannot be sent to {address}")]
+    NotAllowed { address: String },
+    /// The address is not part of the message, which is shown on web pages
+    #[error("Invalid e-mail address: {source}")]
+    Address {
+        address: String,
+        source: lettre::address::AddressError,
+    },
+    #[error("Cannot read {} to send it: {source}", path.display())]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error(
+        "The scan has {} and is too large to be attached, and there is no link base for a link to it",
+        megabytes(*.size)
+    )]
+    TooLarge { size: u64 },
+    #[error("Cannot create e-mail: {0}")]
+    Message(#[from] lettre::error::Error),
+    #[error("Cannot send e-mail: {0}")]
+    Smtp(#[from] lettre::transport::smtp::Error),
+}
+
+/// How the connection to the SMTP server is secured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MailSecurity {
+    /// Unencrypted, on port 25 by default
+    None,
+    /// Upgraded to TLS with STARTTLS, on port 587 by default
+    StartTls,
+    /// TLS from the start, on port 465 by default
+    Tls,
+}
+
+/// The SMTP server and sender of scans sent by e-mail
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MailConfig {
+    pub host: String,
+    /// Overrides the default port of the security mode
+    pub port: Option<u16>,
+    pub security: MailSecurity,
+    /// User name and password
+    pub credentials: Option<(String, String)>,
+    pub from: Mailbox,
+    /// Larger files are sent as a link instead of an attachment
+    pub max_size: u64,
+    /// The URL under which the written files can be downloaded. Without it,
+    /// files which are too large to be attached cannot be sent.
+    pub link_base: Option<Url>,
+}
+
+/// The recipients of the written files of a scan
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mail {
+    pub config: MailConfig,
+    pub to: Vec<Mailbox>,
+}
+
+/// The recipients which scans may be sent to from the web UI: addresses and
+/// all addresses of domains
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AllowedRecipients {
+    addresses: Vec<Address>,
+    domains: Vec<String>,
+}
+
+impl AllowedRecipients {
+    /// Parses addresses and domains given as `@DOMAIN`
+    pub fn parse(recipients: &[String]) -> Result<Self, MailError> {
+        let mut allowed = Self::default();
+        for recipient in recipients {
+            match recipient.strip_prefix('@') {
+                Some(domain) => {
+                    // a domain is valid if an address in it is
+                    let _: Address = format!("postmaster@{domain}").parse().map_err(|source| {
+                        MailError::Address {
+                            address: recipient.clone(),
+                            source,
+                        }
+                    })?;
+                    allowed.domains.push(domain.to_ascii_lowercase());
+                }
+                None => allowed.addresses.push(parse_address(recipient)?.email),
+            }
+        }
+        Ok(allowed)
+    }
+
+    /// Returns an error for the first recipient which is not allowed
+    pub fn check(&self, to: &[Mailbox]) -> Result<(), MailError> {
+        match to.iter().find(|mailbox| !self.allows(&mailbox.email)) {
+            Some(mailbox) => Err(MailError::NotAllowed {
+                address: mailbox.email.to_string(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn allows(&self, address: &Address) -> bool {
+        let domain = address.domain();
+        self.domains
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(domain))
+            || self.addresses.iter().any(|allowed| {
+                allowed.user() == address.user() && allowed.domain().eq_ignore_ascii_case(domain)
+            })
+    }
+}
+
+/// Parses a list of e-mail addresses separated by commas
+pub fn parse_addresses(addresses: &str) -> Result<Vec<Mailbox>, MailError> {
+    addresses
+        .split(',')
+        .map(str::trim)
+        .filter(|address| !address.is_empty())
+        .map(parse_address)
+        .collect()
+}
+
+pub fn parse_address(address: &str) -> Result<Mailbox, MailError> {
+    address.parse().map_err(|source| MailError::Address {
+        address: address.to_owned(),
+        source,
+    })
+}
+
+impl Mail {
+    /// Sends the file as an attachment, or a link to it if it is too large
+    /// and there is a link base
+    pub async fn send(&self, path: &Path, extension: &str) -> Result<(), MailError> {
+        let message = self.message(path, extension).await?;
+        self.config.transport()?.send(message).await?;
+        let recipients: Vec<_> = self.to.iter().map(ToString::to_string).collect();
+        info!("{} sent to {}", path.display(), recipients.join(", "));
+        Ok(())
+    }
+
+    async fn message(&self, path: &Path, extension: &str) -> Result<Message, MailError> {
+        let read_error = |source| MailError::Read {
+            path: path.to_owned(),
+            source,
+        };
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let mut builder = Message::builder()
+            .from(self.config.from.clone())
+            .subject(format!("Scan {name}"));
+        for to in &self.to {
+            builder = builder.to(to.clone());
+        }
+        let size = tokio::fs::metadata(path).await.map_err(read_error)?.len();
+        if size > self.config.max_size {
+            let Some(link) = self.config.link(path) else {
+                return Err(MailError::TooLarge { size });
+            };
+            let text = format!(
+                "The scan {name} has {} and is too large to be attached.\nIt can be downloaded from\n\n{}\n",
+                megabytes(size),
+                link,
+            );
+            return Ok(builder.header(ContentType::TEXT_PLAIN).body(text)?);
+        }
+        let data = tokio::fs::read(path).await.map_err(read_error)?;
+        let attachment = Attachment::new(name.clone()).body(data, content_type(extension));
+        let text = SinglePart::plain(format!("The scan {name} is attached.\n"));
+        let parts = MultiPart::mixed().singlepart(text).singlepart(attachment);
+        Ok(builder.multipart(parts)?)
+    }
+}
+
+impl MailConfig {
+    fn transport(&self) -> Result<AsyncSmtpTransport<Tokio1Executor>, MailError> {
+        let mut builder = match self.security {
+            MailSecurity::None => {
+                AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&self.host)
+            }
+            MailSecurity::StartTls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&self.host)?
+            }
+            MailSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&self.host)?,
+        };
+        if let Some(port) = self.port {
+            builder = builder.port(port);
+        }
+        if let Some((user, password)) = &self.credentials {
+            builder = builder.credentials(Credentials::new(user.clone(), password.clone()));
+        }
+        Ok(builder.build())
+    }
+
+    /// Returns the URL of the written file below the link base
+    fn link(&self, path: &Path) -> Option<Url> {
+        let name = path.file_name()?.to_string_lossy();
+        let mut url = self.link_base.clone()?;
+        url.path_segments_mut().ok()?.pop_if_empty().push(&name);
+        Some(url)
+    }
+
+    /// Returns true if a file of the size is sent as a link
+    pub fn links(&self, size: u64) -> bool {
+        size > self.max_size && self.link_base.is_some()
+    }
+}
+
+fn content_type(extension: &str) -> ContentType {
+    ContentType::parse(output::media_type(extension)).expect("the content type is valid")
+}
+
+fn megabytes(size: u64) -> String {
+    format!("{:.1} MB", size as f64 / 1_000_000.0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    /// An SMTP server which accepts all mails and records their data
+    async fn start_sink() -> (u16, Arc<Mutex<Vec<String>>>) {
+        let mails = Arc::new(Mutex::new(Vec::new()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let received = mails.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (reader, mut writer) = stream.into_split();
+                let mut lines = BufReader::new(reader).lines();
+                writer.write_all(b"220 sink\r\n").await.unwrap();
+                let mut data: Option<String> = None;
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let reply: &[u8] = match &mut data {
+                        Some(mail) if line == "." => {
+                            received.lock().unwrap().push(std::mem::take(mail));
+                            data = None;
+                            b"250 queued\r\n"
+                        }
+                        Some(mail) => {
+                            mail.push_str(&line);
+                            mail.push('\n');
+                            continue;
+                        }
+                        None if line == "DATA" => {
+                            data = Some(String::new());
+                            b"354 go ahead\r\n"
+                        }
+                        None if line == "QUIT" => b"221 bye\r\n",
+                        None => b"250 ok\r\n",
+                    };
+                    writer.write_all(reply).await.unwrap();
+                }
+            }
+        });
+        (port, mails)
+    }
+
+    fn mail(port: u16, max_size: u64, link_base: Option<&str>) -> Mail {
+        Mail {
+            config: MailConfig {
+                host: "127.0.0.1".to_owned(),
+                port: Some(port),
+                security: MailSecurity::None,
+                credentials: None,
+                from: parse_address("Covet <covet@example.com>").unwrap(),
+                max_size,
+                link_base: link_base.map(|url| url.parse().unwrap()),
+            },
+            to: parse_addresses("a@example.com, b@example.com").unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn send_attachment() {
+        let (port, mails) = start_sink().await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("scan_1.pdf");
+        std::fs::write(&path, b"%PDF\xff\xfe").unwrap();
+        mail(port, 100, None).send(&path, "pdf").await.unwrap();
+        let mails = mails.lock().unwrap();
+        assert_eq!(mails.len(), 1);
+        assert!(mails[0].contains("To: a@example.com, b@example.com"));
+        assert!(mails[0].contains("Subject: Scan scan_1.pdf"));
+        assert!(mails[0].contains("Content-Type: application/pdf"));
+        assert!(mails[0].contains("JVBERv/+"));
+    }
+
+    #[tokio::test]
+    async fn send_link_to_large_file() {
+        let (port, mails) = start_sink().await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("scan 2.tif");
+        std::fs::write(&path, [0; 200]).unwrap();
+        let base = Some("https://example.com/scans");
+        mail(port, 100, base).send(&path, "tif").await.unwrap();
+        let error = mail(port, 100, None).send(&path, "tif").await.unwrap_err();
+        assert!(matches!(error, MailError::TooLarge { size: 200 }));
+        let mails = mails.lock().unwrap();
+        assert_eq!(mails.len(), 1);
+        assert!(!mails[0].contains("image/tiff"));
+        assert!(mails[0].contains("https://example.com/scans/scan%202.tif"));
+    }
+
+    #[test]
+    fn reject_invalid_address() {
+        let error = parse_addresses("a@example.com, b").unwrap_err();
+        assert!(matches!(error, MailError::Address { address, .. } if address == "b"));
+    }
+
+    #[test]
+    fn allow_addresses_and_domains() {
+        let allowed = ["office@example.com", "@Example.org"].map(str::to_owned);
+        let allowed = AllowedRecipients::parse(&allowed).unwrap();
+        let to = parse_addresses("Office <office@EXAMPLE.com>, anyone@example.org").unwrap();
+        allowed.check(&to).unwrap();
+        let to = parse_addresses("office@example.com, other@example.com").unwrap();
+        let error = allowed.check(&to).unwrap_err();
+        assert!(
+            matches!(error, MailError::NotAllowed { address } if address == "other@example.com")
+        );
+        let error = AllowedRecipients::default().check(&to).unwrap_err();
+        assert!(matches!(error, MailError::NotAllowed { .. }));
+        assert!(AllowedRecipients::parse(&["@".to_owned()]).is_err());
+    }
+}
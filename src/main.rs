@@ -1,24 +1,63 @@
 #![forbid(unsafe_code)]
 
-use anyhow::Result;
-use clap::Parser;
-use std::path::Path;
+use anyhow::{Context, Result};
+use clap::{Parser, ValueEnum};
 use tokio::runtime::Runtime;
-use tracing::info;
+use tracing::{info, warn};
+use url::Url;
 
+use std::time::Duration;
+
+mod book;
+mod ccitt;
 mod cli;
+mod color;
+mod crypto;
+mod deskew;
+mod fix_height;
+mod hook;
 mod jpeg;
+mod mail;
 mod message;
+mod ocr;
+mod output;
+mod pdf;
+mod photos;
+mod png;
+mod qr;
+mod raster;
+mod s3;
 mod scanner;
+mod separate;
+mod sftp;
+mod tiff;
 mod util;
 mod web;
+mod webdav;
+mod webhook;
 
-use crate::cli::{Opt, ScanOpt, ScannerOpt};
-use crate::message::scan_job::{ColorSpace, Format};
-use crate::scanner::{Scanner, ScannerError};
+use crate::cli::{
+    JpegCommand, JpegFileOpt, JpegRotateOpt, Opt, QualityProfile, ScanOpt, ScannerOpt,
+};
+use crate::color::Monochrome;
+use crate::deskew::Straighten;
+use crate::hook::{HookInput, Hooks};
+use crate::mail::{AllowedRecipients, Mail, MailConfig, MailSecurity};
+use crate::message::scan_job::{ColorSpace, Format, ScanColor};
+use crate::output::{Destination, FolderTemplate, NameTemplate, Output};
+use crate::s3::S3;
+use crate::scanner::{PostProcessing, Scanner, ScannerError};
+use crate::sftp::Sftp;
+use crate::util::ScanSettings;
+use crate::web::MqttConfig;
+use crate::webdav::WebDav;
+use crate::webhook::WebhookConfig;
 
 fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+    // the standard output may receive the scan
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
     let opt = Opt::parse();
     match opt {
         Opt::Status(opt) => {
@@ -28,30 +67,89 @@ fn main() -> Result<()> {
             scan(&opt)?;
         }
         Opt::Web(opt) => {
-            let use_tls = !opt.scanner_opts.no_tls;
-            web::run_server(
+            let scanner = Scanner::new(
                 &opt.scanner_opts.scanner,
+                !opt.scanner_opts.no_tls,
+                opt.disable_jpeg_fix,
+            )
+            .with_jpeg_metadata(opt.metadata);
+            web::run_server(
+                scanner,
                 &opt.listen,
                 opt.port,
-                use_tls,
-                opt.disable_jpeg_fix,
+                opt.output_dir.clone(),
+                ocr_languages(&opt.ocr_profiles),
+                web::Integrations {
+                    hooks: opt.hook_opts.to_internal(),
+                    webhooks: opt.webhook_opts.to_internal()?,
+                    status_interval: Duration::from_secs(opt.status_interval),
+                    mqtt: opt.mqtt_opts.to_internal()?,
+                    mail: opt.mail_opts.to_internal()?,
+                    mail_allow: AllowedRecipients::parse(&opt.mail_allow)
+                        .context("--mail-allow")?,
+                    webdav: opt.webdav_opts.to_internal(None)?,
+                    s3: opt.s3_opts.to_internal(None)?,
+                    sftp: opt.sftp_opts.to_internal(None)?,
+                },
             )?;
         }
         Opt::FixJpegHeight(opt) => {
-            fix_jpeg_height(&opt.input, &opt.output)?;
+            let status = fix_height::run(&opt, fix_height::Kind::Jpeg)?;
+            if status != fix_height::EXIT_FIXED {
+                std::process::exit(status);
+            }
+        }
+        Opt::FixPdfHeight(opt) => {
+            let status = fix_height::run(&opt, fix_height::Kind::Pdf)?;
+            if status != fix_height::EXIT_FIXED {
+                std::process::exit(status);
+            }
+        }
+        Opt::Jpeg(JpegCommand::Inspect(opt)) => {
+            jpeg_inspect(&opt)?;
+        }
+        Opt::Jpeg(JpegCommand::Check(opt)) => {
+            if !jpeg_check(&opt)? {
+                std::process::exit(1);
+            }
+        }
+        Opt::Jpeg(JpegCommand::Rotate(opt)) => {
+            jpeg_rotate(&opt)?;
         }
     }
     Ok(())
 }
 
-fn fix_jpeg_height(input: &Path, ouput: &Path) -> Result<()> {
-    let input_buf = std::fs::read(input)?;
-    if let Some(buffer) = jpeg::fix_jpeg_height(input_buf.into())? {
-        std::fs::write(ouput, buffer)?;
+fn jpeg_inspect(opt: &JpegFileOpt) -> Result<()> {
+    let buffer = std::fs::read(&opt.file)?;
+    let jpeg = jpeg::Jpeg::from_bytes(buffer.into())?;
+    let inspection = jpeg::Inspection::new(&jpeg).map_err(anyhow::Error::msg)?;
+    if opt.json {
+        println!("{}", serde_json::to_string_pretty(&inspection)?);
+    } else {
+        print!("{inspection}");
     }
     Ok(())
 }
 
+fn jpeg_check(opt: &JpegFileOpt) -> Result<bool> {
+    let buffer = std::fs::read(&opt.file)?;
+    let check = jpeg::Check::new(buffer.into());
+    if opt.json {
+        println!("{}", serde_json::to_string_pretty(&check)?);
+    } else {
+        print!("{check}");
+    }
+    Ok(check.valid)
+}
+
+fn jpeg_rotate(opt: &JpegRotateOpt) -> Result<()> {
+    let buffer = std::fs::read(&opt.input)?;
+    let transformed = jpeg::transform_jpeg(buffer.into(), opt.transform.to_internal())?;
+    std::fs::write(&opt.output, transformed)?;
+    Ok(())
+}
+
 fn status(opt: &ScannerOpt) -> Result<(), ScannerError> {
     let scanner = Scanner::new(&opt.scanner, !opt.no_tls, false);
     let rt = Runtime::new()?;
@@ -75,33 +173,421 @@ impl cli::Format {
         match self {
             cli::Format::Pdf => Format::Pdf,
             cli::Format::Jpeg => Format::Jpeg,
+            cli::Format::Png => Format::Png,
+            cli::Format::Tiff => Format::Tiff,
         }
     }
 }
 
 impl cli::ColorSpace {
-    fn to_internal(self) -> ColorSpace {
-        match self {
+    fn to_internal(self, bit_depth: u8) -> ScanColor {
+        let space = match self {
             cli::ColorSpace::Gray => ColorSpace::Gray,
-            cli::ColorSpace::Color => ColorSpace::Color,
+            cli::ColorSpace::Color | cli::ColorSpace::Auto => ColorSpace::Color,
+            cli::ColorSpace::Lineart => return ColorSpace::Lineart.into(),
+        };
+        ScanColor { space, bit_depth }
+    }
+
+    fn monochrome(self, bilevel: Option<Option<u8>>) -> Option<Monochrome> {
+        match (self, bilevel) {
+            (cli::ColorSpace::Auto, Some(threshold)) => Some(Monochrome::Bilevel { threshold }),
+            (cli::ColorSpace::Auto, None) => Some(Monochrome::Gray),
+            _ => None,
+        }
+    }
+
+    /// The threshold at which all pages are converted to black and white if
+    /// they are not scanned in lineart by the scanner
+    fn bilevel(self, bilevel: Option<Option<u8>>) -> Option<Option<u8>> {
+        match self {
+            cli::ColorSpace::Lineart => Some(bilevel.flatten()),
+            cli::ColorSpace::Auto => None,
+            _ => {
+                if bilevel.is_some() {
+                    warn!("--bilevel only applies to --color auto or --color lineart");
+                }
+                None
+            }
+        }
+    }
+}
+
+impl cli::HookOpt {
+    fn to_internal(&self) -> Hooks {
+        Hooks {
+            commands: self.hooks.clone(),
+            input: match self.hook_input {
+                cli::HookInput::Env => HookInput::Env,
+                cli::HookInput::Json => HookInput::Json,
+            },
+            timeout: Duration::from_secs(self.hook_timeout),
+            required: self.fail_on_hook_error,
+        }
+    }
+}
+
+impl cli::WebhookOpt {
+    fn to_internal(&self) -> Result<Option<WebhookConfig>> {
+        if self.webhooks.is_empty() {
+            return Ok(None);
+        }
+        let urls = self
+            .webhooks
+            .iter()
+            .map(|url| url.parse())
+            .collect::<Result<_, _>>()?;
+        Ok(Some(WebhookConfig::new(
+            urls,
+            // the environment variable keeps the secret out of the process list
+            self.webhook_secret
+                .clone()
+                .or_else(|| std::env::var("COVET_WEBHOOK_SECRET").ok()),
+            self.webhook_queue.clone(),
+        )))
+    }
+}
+
+/// Returns the user name with the password of the option or, which keeps it
+/// out of the process list, of the environment variable
+fn credentials(
+    user: &Option<String>,
+    password: &Option<String>,
+    variable: &str,
+    option: &str,
+) -> Result<Option<(String, String)>> {
+    let Some(user) = user.clone() else {
+        return Ok(None);
+    };
+    let password = password
+        .clone()
+        .or_else(|| std::env::var(variable).ok())
+        .ok_or_else(|| anyhow::anyhow!("{option} or {variable} is required for the user {user}"))?;
+    Ok(Some((user, password)))
+}
+
+impl cli::MqttOpt {
+    fn to_internal(&self) -> Result<Option<MqttConfig>> {
+        let Some(host) = self.mqtt_broker.clone() else {
+            return Ok(None);
+        };
+        Ok(Some(MqttConfig {
+            host,
+            port: self.mqtt_port,
+            credentials: credentials(
+                &self.mqtt_user,
+                &self.mqtt_password,
+                "COVET_MQTT_PASSWORD",
+                "--mqtt-password",
+            )?,
+            topic: self.mqtt_topic.clone(),
+            discovery_prefix: self.mqtt_discovery_prefix.clone(),
+            interval: Duration::from_secs(self.mqtt_interval),
+        }))
+    }
+}
+
+impl cli::MailOpt {
+    fn to_internal(&self) -> Result<Option<MailConfig>> {
+        let Some(host) = self.smtp_host.clone() else {
+            return Ok(None);
+        };
+        let from = self.mail_from.as_deref().unwrap_or_default();
+        Ok(Some(MailConfig {
+            host,
+            port: self.smtp_port,
+            security: match self.smtp_security {
+                cli::SmtpSecurity::None => MailSecurity::None,
+                cli::SmtpSecurity::Starttls => MailSecurity::StartTls,
+                cli::SmtpSecurity::Tls => MailSecurity::Tls,
+            },
+            credentials: credentials(
+                &self.smtp_user,
+                &self.smtp_password,
+                "COVET_SMTP_PASSWORD",
+                "--smtp-password",
+            )?,
+            from: mail::parse_address(from).with_context(|| format!("--mail-from {from}"))?,
+            max_size: self.mail_max_size * 1_000_000,
+            link_base: self.mail_link_base.as_deref().map(str::parse).transpose()?,
+        }))
+    }
+}
+
+impl cli::WebDavOpt {
+    fn to_internal(&self, template: Option<NameTemplate>) -> Result<Option<WebDav>> {
+        let Some(url) = &self.webdav_url else {
+            return Ok(None);
+        };
+        let collection: Url = url.parse()?;
+        if !matches!(collection.scheme(), "http" | "https") {
+            anyhow::bail!("the WebDAV URL {url} is not an http or https URL");
         }
+        Ok(Some(WebDav {
+            collection,
+            folders: self
+                .webdav_path
+                .as_deref()
+                .map(FolderTemplate::parse)
+                .transpose()
+                .map_err(anyhow::Error::msg)?,
+            template,
+            credentials: credentials(
+                &self.webdav_user,
+                &self.webdav_password,
+                "COVET_WEBDAV_PASSWORD",
+                "--webdav-password",
+            )?,
+            attempts: self.webdav_retries + 1,
+        }))
     }
 }
 
-fn scan(opt: &ScanOpt) -> Result<(), ScannerError> {
+impl cli::S3Opt {
+    fn to_internal(&self, template: Option<NameTemplate>) -> Result<Option<S3>> {
+        let Some(bucket) = &self.s3_bucket else {
+            return Ok(None);
+        };
+        // a custom endpoint, like MinIO, may not have host names for buckets
+        let (endpoint, path_style): (Url, _) = match &self.s3_endpoint {
+            Some(url) => (url.parse()?, true),
+            None => (
+                format!("https://s3.{}.amazonaws.com", self.s3_region).parse()?,
+                false,
+            ),
+        };
+        if !matches!(endpoint.scheme(), "http" | "https") {
+            anyhow::bail!("the S3 endpoint {endpoint} is not an http or https URL");
+        }
+        let credential = |value: &Option<String>, variable: &str, option: &str| {
+            value
+                .clone()
+                .or_else(|| std::env::var(variable).ok())
+                .ok_or_else(|| anyhow::anyhow!("{option} or {variable} is required for S3"))
+        };
+        let (folders, template) = match self.s3_key.as_deref() {
+            Some(key) => path_template(key)?,
+            None => (None, template),
+        };
+        Ok(Some(S3 {
+            endpoint,
+            bucket: bucket.clone(),
+            region: self.s3_region.clone(),
+            access_key: credential(&self.s3_access_key, "AWS_ACCESS_KEY_ID", "--s3-access-key")?,
+            secret_key: credential(
+                &self.s3_secret_key,
+                "AWS_SECRET_ACCESS_KEY",
+                "--s3-secret-key",
+            )?,
+            path_style,
+            folders,
+            template,
+            part_size: self.s3_part_size as usize * 1024 * 1024,
+        }))
+    }
+}
+
+impl cli::SftpOpt {
+    fn to_internal(&self, template: Option<NameTemplate>) -> Result<Option<Sftp>> {
+        let Some(destination) = &self.sftp_host else {
+            return Ok(None);
+        };
+        let (folders, template) = match self.sftp_path.as_deref() {
+            Some(path) => path_template(path)?,
+            None => (None, template),
+        };
+        Ok(Some(Sftp {
+            destination: destination.clone(),
+            port: self.sftp_port,
+            identity: self.sftp_identity.clone(),
+            known_hosts: self.sftp_known_hosts.clone(),
+            dir: self.sftp_dir.clone().unwrap_or_default(),
+            folders,
+            template,
+        }))
+    }
+}
+
+/// Parses a template of a remote path with folders separated by slashes
+fn path_template(path: &str) -> Result<(Option<FolderTemplate>, Option<NameTemplate>)> {
+    let (folders, name) = match path.rsplit_once('/') {
+        Some((folders, name)) => (Some(folders), name),
+        None => (None, path),
+    };
+    let folders = folders
+        .map(FolderTemplate::parse)
+        .transpose()
+        .map_err(anyhow::Error::msg)?;
+    let name = NameTemplate::parse(name).map_err(anyhow::Error::msg)?;
+    Ok((folders, Some(name)))
+}
+
+impl cli::Transform {
+    fn to_internal(self) -> jpeg::Transform {
+        match self {
+            cli::Transform::Rotate90 => jpeg::Transform::Rotate90,
+            cli::Transform::Rotate180 => jpeg::Transform::Rotate180,
+            cli::Transform::Rotate270 => jpeg::Transform::Rotate270,
+            cli::Transform::FlipHorizontal => jpeg::Transform::FlipHorizontal,
+            cli::Transform::FlipVertical => jpeg::Transform::FlipVertical,
+        }
+    }
+}
+
+impl cli::Separator {
+    fn to_internal(self) -> separate::Separator {
+        match self {
+            cli::Separator::Blank => separate::Separator::Blank,
+            cli::Separator::QrCode => separate::Separator::QrCode,
+        }
+    }
+}
+
+impl cli::ReadingOrder {
+    fn to_internal(self) -> book::ReadingOrder {
+        match self {
+            cli::ReadingOrder::Ltr => book::ReadingOrder::LeftToRight,
+            cli::ReadingOrder::Rtl => book::ReadingOrder::RightToLeft,
+        }
+    }
+}
+
+fn ocr_languages(profiles: &[cli::ProfileLanguage]) -> ocr::ProfileLanguages {
+    let languages = profiles
+        .iter()
+        .map(|profile| (profile.profile, profile.language.clone()))
+        .collect();
+    ocr::ProfileLanguages::new(languages)
+}
+
+fn scan(opt: &ScanOpt) -> Result<()> {
     let scanner = Scanner::new(
         &opt.scanner_opts.scanner,
         !opt.scanner_opts.no_tls,
         opt.disable_jpeg_fix,
-    );
+    )
+    .with_jpeg_metadata(opt.metadata);
+    let profile = QualityProfile::matching(opt.resolution, opt.compression_quality);
+    let ocr_language = opt
+        .ocr
+        .as_ref()
+        .map(|language| ocr_languages(&opt.ocr_profiles).language(profile, language.as_deref()));
+    if let Some(language) = &ocr_language {
+        ocr::check_engine(language)?;
+    }
+    let output = Output {
+        destination: destination(opt)?,
+        profile: profile.map_or("custom", QualityProfile::name).to_owned(),
+        color: opt
+            .color
+            .to_possible_value()
+            .map(|value| value.get_name().to_owned())
+            .unwrap_or_default(),
+        hooks: opt.hook_opts.to_internal(),
+        mail: mail(opt)?,
+    };
+    let remote = match output.destination {
+        Destination::Stdout => Some("written to the standard output"),
+        Destination::WebDav(_) => Some("uploaded to WebDAV"),
+        Destination::S3(_) => Some("uploaded to S3"),
+        Destination::Sftp(_) => Some("uploaded over SFTP"),
+        _ => None,
+    };
+    if let Some(remote) = remote {
+        if !output.hooks.is_empty() {
+            warn!("Hooks are not run on scans {remote}");
+        }
+        if output.mail.is_some() {
+            warn!("Scans {remote} are not sent by e-mail");
+        }
+    }
+    let processing = PostProcessing {
+        transform: opt
+            .rotate
+            .map_or(jpeg::Transform::None, cli::Transform::to_internal),
+        blank_page_threshold: opt.remove_blank_pages,
+        straighten: (opt.deskew || opt.crop).then_some(Straighten {
+            deskew: opt.deskew,
+            crop: opt.crop,
+            max_angle: opt.max_skew,
+        }),
+        split_photos: opt.split_photos,
+        monochrome: opt.color.monochrome(opt.bilevel),
+        bilevel: opt.color.bilevel(opt.bilevel),
+        separator: opt.separator.map(cli::Separator::to_internal),
+        ocr_language,
+    };
+    let settings = ScanSettings {
+        format: opt.format.to_internal(),
+        color: opt.color.to_internal(opt.bit_depth),
+        source: opt.source,
+        resolution: opt.resolution,
+        quality: opt.compression_quality,
+    };
+    let webhooks = opt.webhook_opts.to_internal()?;
     let rt = Runtime::new()?;
-    rt.block_on(util::scan_to_file(
-        scanner,
-        opt.format.to_internal(),
-        opt.color.to_internal(),
-        opt.source,
-        opt.resolution,
-        opt.compression_quality,
-    ))?;
-    Ok(())
+    rt.block_on(async move {
+        let (scanner, worker) = match webhooks {
+            Some(config) => {
+                let (notifier, worker) = webhook::start(config);
+                (scanner.with_notifier(&notifier), Some(worker))
+            }
+            None => (scanner, None),
+        };
+        let result = match opt.book {
+            Some(order) => {
+                let order = order.to_internal();
+                util::scan_book(&scanner, settings, order, processing, output).await
+            }
+            None => util::scan_to_file(&scanner, settings, processing, output).await,
+        };
+        drop(scanner);
+        // the scanner is dropped, so the worker stops once the events are sent
+        if let Some(worker) = worker {
+            worker.await?;
+        }
+        Ok(result?)
+    })
+}
+
+fn mail(opt: &ScanOpt) -> Result<Option<Mail>> {
+    let Some(config) = opt.mail_opts.to_internal()? else {
+        return Ok(None);
+    };
+    if opt.mail_to.is_empty() {
+        return Ok(None);
+    }
+    let to = opt
+        .mail_to
+        .iter()
+        .map(|address| mail::parse_address(address).with_context(|| format!("--mail-to {address}")))
+        .collect::<Result<_, _>>()?;
+    Ok(Some(Mail { config, to }))
+}
+
+fn destination(opt: &ScanOpt) -> Result<Destination> {
+    let template = opt
+        .name_template
+        .as_deref()
+        .map(NameTemplate::parse)
+        .transpose()
+        .map_err(anyhow::Error::msg)?;
+    if let Some(webdav) = opt.webdav_opts.to_internal(template.clone())? {
+        return Ok(Destination::WebDav(webdav));
+    }
+    if let Some(s3) = opt.s3_opts.to_internal(template.clone())? {
+        return Ok(Destination::S3(s3));
+    }
+    if let Some(sftp) = opt.sftp_opts.to_internal(template.clone())? {
+        return Ok(Destination::Sftp(sftp));
+    }
+    let destination = match &opt.output {
+        Some(path) if path.as_os_str() == output::STDOUT => Destination::Stdout,
+        Some(path) => Destination::File(path.clone()),
+        None => Destination::Directory {
+            dir: opt.output_dir.clone(),
+            template,
+        },
+    };
+    Ok(destination)
 }
@@ -34,21 +34,78 @@ pub enum PageState {
     CanceledByDevice,
 }
 
+/// Orientation of the scanned image, which is turned upright when it is
+/// downloaded. Values which are not known leave the image as it is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageOrientation {
+    Normal,
+    Rotated90,
+    Rotated180,
+    Rotated270,
+    /// A value which is not known
+    Unknown,
+}
+
+impl ImageOrientation {
+    /// Clockwise rotation of the image content in degrees
+    pub fn degrees(self) -> u32 {
+        match self {
+            ImageOrientation::Normal | ImageOrientation::Unknown => 0,
+            ImageOrientation::Rotated90 => 90,
+            ImageOrientation::Rotated180 => 180,
+            ImageOrientation::Rotated270 => 270,
+        }
+    }
+}
+
+impl From<&str> for ImageOrientation {
+    fn from(s: &str) -> ImageOrientation {
+        match s {
+            "Normal" => ImageOrientation::Normal,
+            "Rotate90" | "Rotated90" => ImageOrientation::Rotated90,
+            "Rotate180" | "Rotated180" => ImageOrientation::Rotated180,
+            "Rotate270" | "Rotated270" => ImageOrientation::Rotated270,
+            _ => ImageOrientation::Unknown,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ScanPage {
     #[allow(dead_code)]
     number: u32,
     state: PageState,
+    total_lines: Option<u32>,
+    orientation: Option<ImageOrientation>,
 }
 
 impl ScanPage {
-    pub fn new(number: u32, state: PageState) -> ScanPage {
-        ScanPage { number, state }
+    pub fn new(
+        number: u32,
+        state: PageState,
+        total_lines: Option<u32>,
+        orientation: Option<ImageOrientation>,
+    ) -> ScanPage {
+        ScanPage {
+            number,
+            state,
+            total_lines,
+            orientation,
+        }
     }
 
     pub fn state(&self) -> &PageState {
         &self.state
     }
+
+    /// The number of scanned lines, only known after the page was uploaded
+    pub fn total_lines(&self) -> Option<u32> {
+        self.total_lines
+    }
+
+    pub fn orientation(&self) -> Option<ImageOrientation> {
+        self.orientation
+    }
 }
 
 #[derive(Debug)]
@@ -71,7 +128,14 @@ fn read_page(element: &Element) -> Result<ScanPage, ParseError> {
         "CanceledByDevice" => PageState::CanceledByDevice,
         s => return Err(ParseError::unknown_enum_value("PageState", s)),
     };
-    Ok(ScanPage::new(number, state))
+    let total_lines = util::read_child_value(element, "TotalLines")
+        .ok()
+        .map(|v| v.parse())
+        .transpose()?;
+    let orientation = util::read_child_value(element, "ImageOrientation")
+        .ok()
+        .map(|v| ImageOrientation::from(v.as_ref()));
+    Ok(ScanPage::new(number, state, total_lines, orientation))
 }
 
 impl ScanJobStatus {
@@ -210,6 +274,10 @@ mod test {
         let status = parse_job_status(FULL_JOB_STATUS);
         assert_eq!(JobState::Processing, status.state);
         check_one_page(&status, 1, PageState::PreparingScan);
+        assert_eq!(
+            Some(ImageOrientation::Normal),
+            status.pages()[0].orientation()
+        );
     }
 
     #[test]
@@ -230,6 +298,22 @@ mod test {
         let status = parse_job_status(COMPLETED);
         assert_eq!(JobState::Completed, status.state);
         check_one_page(&status, 2, PageState::UploadCompleted);
+        assert_eq!(Some(3501), status.pages()[0].total_lines());
+        assert_eq!(None, status.pages()[0].orientation());
+    }
+
+    #[test]
+    fn read_image_orientation() {
+        assert_eq!(ImageOrientation::from("Normal"), ImageOrientation::Normal);
+        assert_eq!(
+            ImageOrientation::from("Rotate90"),
+            ImageOrientation::Rotated90
+        );
+        assert_eq!(
+            ImageOrientation::from("Rotated270"),
+            ImageOrientation::Rotated270
+        );
+        assert_eq!(ImageOrientation::from("Upside"), ImageOrientation::Unknown);
     }
 
     #[test]
@@ -1,5 +1,7 @@
 pub mod error;
 pub mod job_status;
+pub mod product_config;
+pub mod scan_caps;
 pub mod scan_job;
 pub mod scan_status;
 mod util;
@@ -0,0 +1,69 @@
+use xmltree::Element;
+
+use std::io::Read;
+
+use crate::message::error::ParseError;
+use crate::message::util;
+
+/// Static information about the device from `/DevMgmt/ProductConfigDyn.xml`
+#[derive(Debug, Clone)]
+pub struct ProductConfig {
+    make_and_model: String,
+}
+
+impl ProductConfig {
+    pub fn new(make_and_model: String) -> ProductConfig {
+        ProductConfig { make_and_model }
+    }
+
+    /// The manufacturer, i.e. the first word of the product name
+    pub fn make(&self) -> &str {
+        self.make_and_model
+            .split_once(' ')
+            .map_or(&self.make_and_model, |(make, _)| make)
+    }
+
+    /// The product name without the manufacturer
+    pub fn model(&self) -> &str {
+        self.make_and_model
+            .split_once(' ')
+            .map_or(&self.make_and_model, |(_, model)| model)
+    }
+
+    pub fn read_xml<R: Read>(r: R) -> Result<ProductConfig, ParseError> {
+        let element = Element::parse(r)?;
+        let information = element
+            .get_child("ProductInformation")
+            .ok_or_else(|| ParseError::missing_element("ProductInformation"))?;
+        let make_and_model = util::read_child_value(information, "MakeAndModel")?;
+        Ok(ProductConfig::new(make_and_model.trim().to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    const PRODUCT_CONFIG: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <prdcfgdyn:ProductConfigDyn xmlns:prdcfgdyn="http://www.hp.com/schemas/imaging/con/ledm/productconfigdyn/2007/11/05" xmlns:dd="http://www.hp.com/schemas/imaging/con/dictionaries/1.0/">
+            <prdcfgdyn:ProductInformation>
+            <dd:MakeAndModel>HP ENVY 5530 series</dd:MakeAndModel>
+            <dd:SerialNumber>CN12345678</dd:SerialNumber>
+            </prdcfgdyn:ProductInformation>
+            </prdcfgdyn:ProductConfigDyn>"#;
+
+    #[test]
+    fn read_product_config_xml() {
+        let config = ProductConfig::read_xml(PRODUCT_CONFIG.as_bytes()).expect("parsing failed");
+        assert_eq!(config.make(), "HP");
+        assert_eq!(config.model(), "ENVY 5530 series");
+    }
+
+    #[test]
+    fn read_product_config_xml_without_information() {
+        let error = ProductConfig::read_xml("<ProductConfigDyn/>".as_bytes())
+            .expect_err("parsing succeeded");
+        assert_eq!(error.to_string(), "missing element ProductInformation")
+    }
+}
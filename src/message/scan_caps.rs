@@ -0,0 +1,113 @@
+use xmltree::{Element, XMLNode};
+
+use std::io::Read;
+
+use crate::message::error::ParseError;
+use crate::message::scan_job::ScanColor;
+use crate::message::util;
+
+/// The color types and formats the device can scan, from `/Scan/ScanCaps`
+#[derive(Debug)]
+pub struct ScanCaps {
+    color_entries: Vec<ColorEntry>,
+}
+
+#[derive(Debug)]
+struct ColorEntry {
+    color_type: String,
+    formats: Vec<String>,
+}
+
+impl ScanCaps {
+    /// Returns true if the device can scan in the color as JPEG images, which
+    /// are also the pages of PDF scans
+    pub fn supports(&self, color: ScanColor) -> bool {
+        let color_type = color.color_type();
+        self.color_entries
+            .iter()
+            .filter(|entry| entry.color_type == color_type)
+            .any(|entry| entry.formats.iter().any(|f| f == "Jpeg"))
+    }
+
+    pub fn read_xml<R: Read>(r: R) -> Result<ScanCaps, ParseError> {
+        let element = Element::parse(r)?;
+        let entries = element
+            .get_child("ColorEntries")
+            .ok_or_else(|| ParseError::missing_element("ColorEntries"))?;
+        let color_entries = children(entries, "ColorEntry")
+            .map(|entry| {
+                let color_type = util::read_child_value(entry, "ColorType")?;
+                let formats = entry
+                    .get_child("Formats")
+                    .map(|formats| {
+                        children(formats, "Format")
+                            .filter_map(|f| f.get_text())
+                            .map(|f| f.trim().to_owned())
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                Ok(ColorEntry {
+                    color_type: color_type.trim().to_owned(),
+                    formats,
+                })
+            })
+            .collect::<Result<_, ParseError>>()?;
+        Ok(ScanCaps { color_entries })
+    }
+}
+
+fn children<'a>(element: &'a Element, name: &'a str) -> impl Iterator<Item = &'a Element> {
+    element
+        .children
+        .iter()
+        .filter_map(XMLNode::as_element)
+        .filter(move |child| child.name == name)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::message::scan_job::ColorSpace;
+
+    const SCAN_CAPS: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <ScanCaps xmlns="http://www.hp.com/schemas/imaging/con/cnx/scan/2008/08/19">
+            <DeviceCaps>
+            <ModelName>HP ENVY 5530 series</ModelName>
+            </DeviceCaps>
+            <ColorEntries>
+            <ColorEntry>
+            <ColorType>K1</ColorType>
+            <Formats><Format>Raw</Format></Formats>
+            </ColorEntry>
+            <ColorEntry>
+            <ColorType>Gray8</ColorType>
+            <Formats><Format>Raw</Format><Format>Jpeg</Format></Formats>
+            </ColorEntry>
+            <ColorEntry>
+            <ColorType>Color8</ColorType>
+            <Formats><Format>Raw</Format><Format>Jpeg</Format></Formats>
+            </ColorEntry>
+            </ColorEntries>
+            </ScanCaps>"#;
+
+    #[test]
+    fn read_scan_caps_xml() {
+        let caps = ScanCaps::read_xml(SCAN_CAPS.as_bytes()).expect("parsing failed");
+        assert!(caps.supports(ColorSpace::Gray.into()));
+        assert!(caps.supports(ColorSpace::Color.into()));
+        // lineart is only available as raw data
+        assert!(!caps.supports(ColorSpace::Lineart.into()));
+        let deep_color = ScanColor {
+            space: ColorSpace::Color,
+            bit_depth: 16,
+        };
+        assert!(!caps.supports(deep_color));
+    }
+
+    #[test]
+    fn read_scan_caps_xml_without_color_entries() {
+        let error = ScanCaps::read_xml("<ScanCaps/>".as_bytes()).expect_err("parsing succeeded");
+        assert_eq!(error.to_string(), "missing element ColorEntries")
+    }
+}
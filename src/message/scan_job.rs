@@ -22,6 +22,21 @@ pub enum InputSource {
 pub enum Format {
     Jpeg,
     Pdf,
+    /// A lossless image decoded from a JPEG scan
+    Png,
+    /// One page per page of a PDF scan, black and white ones compressed with
+    /// CCITT Group 4 and the others decoded without further loss
+    Tiff,
+}
+
+impl Format {
+    /// The format which the scanner produces for this format
+    pub fn device_format(self) -> Format {
+        match self {
+            Format::Jpeg | Format::Png => Format::Jpeg,
+            Format::Pdf | Format::Tiff => Format::Pdf,
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
@@ -29,6 +44,37 @@ pub enum Format {
 pub enum ColorSpace {
     Color,
     Gray,
+    /// Black and white
+    Lineart,
+}
+
+/// The color space of a scan with the number of bits per sample
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScanColor {
+    pub space: ColorSpace,
+    pub bit_depth: u8,
+}
+
+impl ScanColor {
+    /// The name of the color type in the scan capabilities of the device
+    pub fn color_type(&self) -> String {
+        match self.space {
+            ColorSpace::Color => format!("Color{}", self.bit_depth),
+            ColorSpace::Gray => format!("Gray{}", self.bit_depth),
+            ColorSpace::Lineart => "K1".to_owned(),
+        }
+    }
+}
+
+impl From<ColorSpace> for ScanColor {
+    /// The color space with its usual bit depth
+    fn from(space: ColorSpace) -> Self {
+        let bit_depth = match space {
+            ColorSpace::Color | ColorSpace::Gray => 8,
+            ColorSpace::Lineart => 1,
+        };
+        ScanColor { space, bit_depth }
+    }
 }
 
 #[derive(Debug)]
@@ -37,7 +83,7 @@ pub struct ScanJob {
     resolution: u32,
     quality: u32,
     pub format: Format,
-    color_space: ColorSpace,
+    color: ScanColor,
 }
 
 impl ScanJob {
@@ -46,17 +92,21 @@ impl ScanJob {
         resolution: u32,
         quality: u32,
         format: Format,
-        color_space: ColorSpace,
+        color: ScanColor,
     ) -> ScanJob {
         ScanJob {
             input_source,
             resolution,
             quality,
             format,
-            color_space,
+            color,
         }
     }
 
+    pub fn resolution(&self) -> u32 {
+        self.resolution
+    }
+
     pub fn write_xml<W: Write>(&self, sink: W) -> Result<()> {
         let config = EmitterConfig::new()
             .write_document_declaration(true)
@@ -70,18 +120,19 @@ impl ScanJob {
         writer.write_value("YStart", "0")?;
         writer.write_value("Width", "2480")?;
         writer.write_value("Height", "3508")?;
-        let format = match self.format {
-            Format::Jpeg => "Jpeg",
-            Format::Pdf => "Pdf",
+        let format = match self.format.device_format() {
+            Format::Jpeg | Format::Png => "Jpeg",
+            Format::Pdf | Format::Tiff => "Pdf",
         };
         writer.write_value("Format", format)?;
         writer.write_value("CompressionQFactor", &self.quality.to_string())?;
-        let color = match self.color_space {
+        // lineart is gray with a single bit per pixel
+        let color = match self.color.space {
             ColorSpace::Color => "Color",
-            ColorSpace::Gray => "Gray",
+            ColorSpace::Gray | ColorSpace::Lineart => "Gray",
         };
         writer.write_value("ColorSpace", color)?;
-        writer.write_value("BitDepth", "8")?;
+        writer.write_value("BitDepth", &self.color.bit_depth.to_string())?;
         let source = match self.input_source {
             InputSource::Platen => "Platen",
             InputSource::Adf => "Adf",
@@ -101,8 +152,8 @@ impl ScanJob {
         })?;
 
         let content_type = match self.format {
-            Format::Jpeg => "Photo",
-            Format::Pdf => "Document",
+            Format::Jpeg | Format::Png => "Photo",
+            Format::Pdf | Format::Tiff => "Document",
         };
         writer.write_value("ContentType", content_type)?;
         writer.exit_elem()
@@ -219,14 +270,46 @@ mod test {
             300,
             25,
             Format::Jpeg,
-            ColorSpace::Color,
+            ColorSpace::Color.into(),
         );
         assert_eq!(JPEG_GLASS_LOW, write_to_string(job));
     }
 
     #[test]
     fn scan_job_write_xml_pdf() {
-        let job = ScanJob::new(InputSource::Adf, 600, 1, Format::Pdf, ColorSpace::Gray);
+        let job = ScanJob::new(
+            InputSource::Adf,
+            600,
+            1,
+            Format::Pdf,
+            ColorSpace::Gray.into(),
+        );
         assert_eq!(PDF_ADF_HIGH, write_to_string(job));
     }
+
+    #[test]
+    fn scan_job_write_xml_lineart_tiff() {
+        let job = ScanJob::new(
+            InputSource::Adf,
+            600,
+            1,
+            Format::Tiff,
+            ColorSpace::Lineart.into(),
+        );
+        let expected = PDF_ADF_HIGH.replace(
+            "<scan:BitDepth>8</scan:BitDepth>",
+            "<scan:BitDepth>1</scan:BitDepth>",
+        );
+        assert_eq!(expected, write_to_string(job));
+    }
+
+    #[test]
+    fn color_types() {
+        assert_eq!(ScanColor::from(ColorSpace::Lineart).color_type(), "K1");
+        let color = ScanColor {
+            space: ColorSpace::Color,
+            bit_depth: 16,
+        };
+        assert_eq!(color.color_type(), "Color16");
+    }
 }
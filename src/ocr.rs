@@ -0,0 +1,293 @@
+use bytes::Bytes;
+use thiserror::Error;
+use tracing::{debug, info};
+
+use std::io;
+
+use crate::cli::QualityProfile;
+use crate::pdf::{self, TextBox};
+
+/// Recognition language if none is given
+pub const DEFAULT_LANGUAGE: &str = "eng";
+
+/// The recognition languages of the quality profiles
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProfileLanguages(Vec<(QualityProfile, String)>);
+
+impl ProfileLanguages {
+    pub fn new(languages: Vec<(QualityProfile, String)>) -> ProfileLanguages {
+        ProfileLanguages(languages)
+    }
+
+    /// Returns the language which is given for a scan, otherwise the one of
+    /// its profile or the default language
+    pub fn language(&self, profile: Option<QualityProfile>, language: Option<&str>) -> String {
+        let profile_language = || {
+            self.0
+                .iter()
+                .rev()
+                .find(|(p, _)| Some(*p) == profile)
+                .map(|(_, language)| language.as_str())
+        };
+        language
+            .map(str::trim)
+            .filter(|language| !language.is_empty())
+            .or_else(profile_language)
+            .unwrap_or(DEFAULT_LANGUAGE)
+            .to_owned()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum OcrError {
+    #[cfg(not(feature = "ocr"))]
+    #[error("Text recognition is not supported, covet was built without the feature \"ocr\"")]
+    NotSupported,
+    #[cfg(feature = "ocr")]
+    #[error("Text recognition needs tesseract, but it was not found")]
+    EngineMissing,
+    #[error("Invalid language name")]
+    InvalidLanguage,
+    #[error("Language {language} is not installed for tesseract. Installed are: {installed}")]
+    LanguageMissing { language: String, installed: String },
+    #[cfg(feature = "ocr")]
+    #[error("Text recognition failed: {0}")]
+    Failed(String),
+    #[error(transparent)]
+    Pdf(#[from] pdf::ParseError),
+    #[error("Io error")]
+    Io(#[from] io::Error),
+}
+
+/// Checks that tesseract is installed and knows the language. Several
+/// languages can be combined with `+`, like `deu+eng`.
+pub fn check_engine(language: &str) -> Result<(), OcrError> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || "_-/".contains(c);
+    if language
+        .split('+')
+        .any(|l| l.is_empty() || !l.chars().all(valid))
+    {
+        return Err(OcrError::InvalidLanguage);
+    }
+    let installed = engine::languages()?;
+    for language in language.split('+') {
+        if !installed.iter().any(|l| l == language) {
+            return Err(OcrError::LanguageMissing {
+                language: language.to_owned(),
+                installed: installed.join(", "),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Recognizes the text on the JPEG images of the pages and adds it to the
+/// document as an invisible layer. The images are expected to cover their page.
+/// Returns `None` if no text was found.
+pub fn searchable_pdf(data: Bytes, language: &str) -> Result<Option<Bytes>, OcrError> {
+    let pages = pdf::page_jpegs(data.clone())?;
+    let mut words = Vec::with_capacity(pages.len());
+    for (index, images) in pages.iter().enumerate() {
+        let page_words = match images.first() {
+            Some(image) => parse_tsv(&engine::recognize(image, language)?),
+            None => Vec::new(),
+        };
+        debug!(
+            "Recognized {} words on page {}",
+            page_words.len(),
+            index + 1
+        );
+        words.push(page_words);
+    }
+    let count: usize = words.iter().map(Vec::len).sum();
+    let data = pdf::add_text_layer(data, &words)?;
+    if data.is_some() {
+        info!("Added {count} recognized words to pdf");
+    }
+    Ok(data)
+}
+
+/// Reads the words from the tab separated output of tesseract. Their boxes
+/// are converted to fractions of the image size given by the page entry.
+fn parse_tsv(tsv: &str) -> Vec<TextBox> {
+    const PAGE: &str = "1";
+    const WORD: &str = "5";
+    let mut size = None;
+    let mut words = Vec::new();
+    for line in tsv.lines().skip(1) {
+        let columns: Vec<&str> = line.split('\t').collect();
+        let [
+            level,
+            _,
+            _,
+            _,
+            _,
+            _,
+            left,
+            top,
+            width,
+            height,
+            confidence,
+            text,
+        ] = columns[..]
+        else {
+            continue;
+        };
+        let values: Option<Vec<f64>> = [left, top, width, height]
+            .iter()
+            .map(|value| value.parse().ok())
+            .collect();
+        let Some(&[left, top, width, height]) = values.as_deref() else {
+            continue;
+        };
+        match level {
+            PAGE if width > 0.0 && height > 0.0 => size = Some((width, height)),
+            WORD => {
+                let text = text.trim();
+                let confident = confidence.parse::<f64>().is_ok_and(|c| c >= 0.0);
+                match size {
+                    Some((page_width, page_height)) if confident && !text.is_empty() => {
+                        words.push(TextBox {
+                            text: text.to_owned(),
+                            left: left / page_width,
+                            top: top / page_height,
+                            width: width / page_width,
+                            height: height / page_height,
+                        })
+                    }
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+    }
+    words
+}
+
+#[cfg(feature = "ocr")]
+mod engine {
+    use std::io::Write;
+    use std::process::{Command, Output, Stdio};
+
+    use super::OcrError;
+
+    const TESSERACT: &str = "tesseract";
+
+    fn run(command: &mut Command) -> Result<String, OcrError> {
+        let output = command.stdin(Stdio::null()).output().map_err(|e| {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                OcrError::EngineMissing
+            } else {
+                OcrError::Io(e)
+            }
+        })?;
+        let Output {
+            status,
+            stdout,
+            stderr,
+        } = output;
+        if !status.success() {
+            let message = String::from_utf8_lossy(&stderr).trim().to_owned();
+            return Err(OcrError::Failed(message));
+        }
+        Ok(String::from_utf8_lossy(&stdout).into_owned())
+    }
+
+    pub fn languages() -> Result<Vec<String>, OcrError> {
+        let output = run(Command::new(TESSERACT).arg("--list-langs"))?;
+        // the first line names the directory of the language data
+        Ok(output
+            .lines()
+            .skip(1)
+            .map(|line| line.trim().to_owned())
+            .filter(|line| !line.is_empty())
+            .collect())
+    }
+
+    pub fn recognize(image: &[u8], language: &str) -> Result<String, OcrError> {
+        let mut file = tempfile::Builder::new().suffix(".jpeg").tempfile()?;
+        file.write_all(image)?;
+        file.flush()?;
+        run(Command::new(TESSERACT)
+            .arg(file.path())
+            .args(["stdout", "-l", language, "tsv"]))
+    }
+}
+
+#[cfg(not(feature = "ocr"))]
+mod engine {
+    use super::OcrError;
+
+    pub fn languages() -> Result<Vec<String>, OcrError> {
+        Err(OcrError::NotSupported)
+    }
+
+    pub fn recognize(_image: &[u8], _language: &str) -> Result<String, OcrError> {
+        Err(OcrError::NotSupported)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TSV: &str = "level\tpage_num\tblock_num\tpar_num\tline_num\tword_num\tleft\ttop\twidth\theight\tconf\ttext
+1\t1\t0\t0\t0\t0\t0\t0\t2000\t1000\t-1\t
+2\t1\t1\t0\t0\t0\t100\t200\t600\t50\t-1\t
+5\t1\t1\t1\t1\t1\t100\t200\t300\t50\t96.5\tHello
+5\t1\t1\t1\t1\t2\t500\t200\t200\t50\t91.0\tWorld
+5\t1\t1\t1\t1\t3\t800\t200\t20\t50\t-1\t
+";
+
+    #[test]
+    fn read_words_from_tsv() {
+        let words = parse_tsv(TSV);
+        assert_eq!(
+            words,
+            vec![
+                TextBox {
+                    text: "Hello".to_owned(),
+                    left: 0.05,
+                    top: 0.2,
+                    width: 0.15,
+                    height: 0.05,
+                },
+                TextBox {
+                    text: "World".to_owned(),
+                    left: 0.25,
+                    top: 0.2,
+                    width: 0.1,
+                    height: 0.05,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn choose_language() {
+        let languages = ProfileLanguages::new(vec![(QualityProfile::High, "deu".to_owned())]);
+        let high = Some(QualityProfile::High);
+        assert_eq!(languages.language(high, None), "deu");
+        assert_eq!(languages.language(high, Some(" fra ")), "fra");
+        assert_eq!(languages.language(high, Some("")), "deu");
+        assert_eq!(languages.language(Some(QualityProfile::Base), None), "eng");
+        assert_eq!(languages.language(None, None), "eng");
+    }
+
+    #[test]
+    fn reject_invalid_language() {
+        for language in ["", "eng+", "<b>", "deu eng"] {
+            let result = check_engine(language);
+            assert!(
+                matches!(result, Err(OcrError::InvalidLanguage)),
+                "{result:?}"
+            );
+        }
+    }
+
+    #[cfg(not(feature = "ocr"))]
+    #[test]
+    fn report_missing_feature() {
+        assert!(matches!(check_engine("eng"), Err(OcrError::NotSupported)));
+    }
+}
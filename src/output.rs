@@ -0,0 +1,565 @@
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
+use jiff::Timestamp;
+use tempfile::NamedTempFile;
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tracing::info;
+
+use std::fmt::Write as _;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::hook::Hooks;
+use crate::mail::Mail;
+use crate::s3::S3;
+use crate::scanner::ScannerError;
+use crate::sftp::Sftp;
+use crate::webdav::WebDav;
+
+/// Path which stands for the standard output
+pub const STDOUT: &str = "-";
+
+/// Names of single scans if no template is given, the extension is appended
+const SCAN_TEMPLATE: &str = "scan_{time}";
+/// Names of the photos cut out of a scan
+const PHOTO_TEMPLATE: &str = "photo_{time}_{page}";
+/// Names of the documents of a separated batch without a name of their own
+const PART_TEMPLATE: &str = "scan_{time}_{page}";
+const TIME_FORMAT: &str = "%Y%m%d_%H%M%S";
+/// Number of digits of the counter
+const COUNTER_WIDTH: usize = 4;
+/// Maximum number of characters of a file name taken from a separator sheet
+const MAX_PART_NAME_LEN: usize = 100;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Text(String),
+    /// The time of the scan in a strftime format
+    Time(String),
+    Counter,
+    Profile,
+    Source,
+    Color,
+    Resolution,
+    Page,
+    Model,
+}
+
+/// A file name without extension with placeholders in braces, like
+/// `{time:%Y-%m-%d}_{source}_{counter}`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameTemplate {
+    tokens: Vec<Token>,
+}
+
+/// The values of the placeholders of a template, apart from the counter and
+/// the page number
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameFields {
+    pub time: Timestamp,
+    pub profile: String,
+    pub source: String,
+    pub color: String,
+    pub resolution: u32,
+    pub model: String,
+}
+
+impl NameTemplate {
+    pub fn parse(template: &str) -> Result<NameTemplate, String> {
+        let mut tokens = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find(['{', '}']) {
+            if rest[start..].starts_with('}') {
+                return Err(format!("unmatched }} in template {template}"));
+            }
+            if start > 0 {
+                tokens.push(Token::Text(rest[..start].to_owned()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .map(|end| start + end)
+                .ok_or_else(|| format!("unclosed {{ in template {template}"))?;
+            let placeholder = &rest[start + 1..end];
+            tokens.push(match placeholder.split_once(':') {
+                Some(("time", format)) => Token::Time(format.to_owned()),
+                None if placeholder == "time" => Token::Time(TIME_FORMAT.to_owned()),
+                None if placeholder == "counter" => Token::Counter,
+                None if placeholder == "profile" => Token::Profile,
+                None if placeholder == "source" => Token::Source,
+                None if placeholder == "color" => Token::Color,
+                None if placeholder == "resolution" => Token::Resolution,
+                None if placeholder == "page" => Token::Page,
+                None if placeholder == "model" => Token::Model,
+                _ => return Err(format!("unknown placeholder {{{placeholder}}}")),
+            });
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            tokens.push(Token::Text(rest.to_owned()));
+        }
+        let text = tokens.iter().filter_map(|token| match token {
+            Token::Text(text) => Some(text),
+            _ => None,
+        });
+        for text in text {
+            if text.contains(['/', '\\']) {
+                return Err("the template must not contain directories, use --output-dir".into());
+            }
+        }
+        if tokens.is_empty() {
+            return Err("the template is empty".to_owned());
+        }
+        Ok(NameTemplate { tokens })
+    }
+
+    /// A template without placeholders
+    fn text(text: String) -> NameTemplate {
+        NameTemplate {
+            tokens: vec![Token::Text(text)],
+        }
+    }
+
+    /// Returns true if the template has a placeholder for the device model,
+    /// which has to be requested from the scanner
+    pub fn has_model(&self) -> bool {
+        self.tokens.contains(&Token::Model)
+    }
+
+    fn has_counter(&self) -> bool {
+        self.tokens.contains(&Token::Counter)
+    }
+
+    /// Returns the file name without extension. The values of the placeholders
+    /// are restricted to characters which are safe in file names.
+    fn render(&self, fields: &NameFields, page: usize, counter: usize) -> String {
+        let mut name = String::new();
+        for token in &self.tokens {
+            let value = match token {
+                Token::Text(text) => {
+                    name.push_str(text);
+                    continue;
+                }
+                Token::Time(format) => {
+                    let mut time = String::new();
+                    // an invalid format is written as it is
+                    if write!(time, "{}", fields.time.strftime(format)).is_err() {
+                        time = format.clone();
+                    }
+                    time
+                }
+                Token::Counter => format!("{counter:0COUNTER_WIDTH$}"),
+                Token::Profile => fields.profile.clone(),
+                Token::Source => fields.source.clone(),
+                Token::Color => fields.color.clone(),
+                Token::Resolution => fields.resolution.to_string(),
+                Token::Page => page.to_string(),
+                Token::Model => fields.model.clone(),
+            };
+            name.extend(value.chars().map(safe_char));
+        }
+        name
+    }
+}
+
+/// Folders below a directory, separated by slashes, with the placeholders of
+/// [NameTemplate], like `{time:%Y}/{time:%m}`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FolderTemplate {
+    folders: Vec<NameTemplate>,
+}
+
+impl FolderTemplate {
+    pub fn parse(template: &str) -> Result<FolderTemplate, String> {
+        let folders = template
+            .split('/')
+            .filter(|folder| !folder.is_empty())
+            .map(|folder| match folder {
+                "." | ".." => Err(format!("invalid folder {folder} in {template}")),
+                _ => NameTemplate::parse(folder),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(FolderTemplate { folders })
+    }
+
+    pub fn has_model(&self) -> bool {
+        self.folders.iter().any(NameTemplate::has_model)
+    }
+
+    /// Returns the names of the folders, a folder with an empty name is left out
+    pub fn render(&self, fields: &NameFields) -> Vec<String> {
+        self.folders
+            .iter()
+            .map(|folder| folder.render(fields, 1, 0))
+            .filter(|folder| !folder.is_empty())
+            .collect()
+    }
+}
+
+impl Default for NameTemplate {
+    fn default() -> Self {
+        builtin(SCAN_TEMPLATE)
+    }
+}
+
+/// What a file contains, which decides its name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Content<'a> {
+    Scan,
+    /// A photo cut out of a scan of the glass, numbered from 1
+    Photo(usize),
+    /// A document of a separated batch, numbered from 1, with the text of its
+    /// separator sheet
+    Part(usize, Option<&'a str>),
+}
+
+impl Content<'_> {
+    /// The number of the photo or document, or 1 for a whole scan
+    pub fn page(&self) -> usize {
+        match *self {
+            Content::Scan => 1,
+            Content::Photo(number) | Content::Part(number, _) => number,
+        }
+    }
+}
+
+/// Where a scan is written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Destination {
+    Stdout,
+    /// The file is replaced if it exists
+    File(PathBuf),
+    /// New files named by the template, or by the default names without one
+    Directory {
+        dir: PathBuf,
+        template: Option<NameTemplate>,
+    },
+    /// New files uploaded to a WebDAV server
+    WebDav(WebDav),
+    /// New objects uploaded to an S3 bucket
+    S3(S3),
+    /// New files uploaded to an SFTP server
+    Sftp(Sftp),
+}
+
+/// The destination of a scan with the settings which only appear in file names,
+/// and the hooks and recipients of the written files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Output {
+    pub destination: Destination,
+    pub profile: String,
+    pub color: String,
+    pub hooks: Hooks,
+    pub mail: Option<Mail>,
+}
+
+impl Destination {
+    /// Writes the data to the destination and returns the path of the file.
+    /// Files are written to a temporary file first, which is renamed once it
+    /// is complete. A number is appended to the name of a new file if the
+    /// file exists already, unless the template has a counter.
+    pub async fn write(
+        &self,
+        content: Content<'_>,
+        extension: &str,
+        fields: &NameFields,
+        mut stream: impl Stream<Item = Result<Bytes, ScannerError>> + Unpin,
+    ) -> Result<Option<PathBuf>, ScannerError> {
+        let dir = match self {
+            Destination::Stdout => {
+                let mut stdout = tokio::io::stdout();
+                while let Some(item) = stream.next().await {
+                    stdout.write_all(&item?).await?;
+                }
+                stdout.flush().await?;
+                return Ok(None);
+            }
+            Destination::File(path) => match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            },
+            Destination::Directory { dir, .. } => dir,
+            Destination::WebDav(webdav) => {
+                let url = webdav.upload(content, extension, fields, stream).await?;
+                info!("Uploaded to {url}");
+                return Ok(None);
+            }
+            Destination::S3(s3) => {
+                let url = s3.upload(content, extension, fields, stream).await?;
+                info!("Uploaded to {url}");
+                return Ok(None);
+            }
+            Destination::Sftp(sftp) => {
+                let path = sftp.upload(content, extension, fields, stream).await?;
+                info!("Uploaded to {}:{path}", sftp.destination);
+                return Ok(None);
+            }
+        };
+        let temp_file = temp_file_in(dir)?;
+        let mut file = File::from_std(temp_file.as_file().try_clone()?);
+        while let Some(item) = stream.next().await {
+            file.write_all(&item?).await?;
+        }
+        file.sync_all().await?;
+        drop(file);
+        let template = match self {
+            Destination::File(path) => {
+                temp_file.persist(path).map_err(|e| e.error)?;
+                return Ok(Some(path.clone()));
+            }
+            Destination::Directory { template, .. } => template.clone(),
+            Destination::Stdout
+            | Destination::WebDav(_)
+            | Destination::S3(_)
+            | Destination::Sftp(_) => None,
+        };
+        let template = file_template(template, content);
+        let mut temp_file = temp_file;
+        for number in 1.. {
+            let name = numbered_name(&template, fields, content.page(), number);
+            let path = dir.join(format!("{name}.{extension}"));
+            match temp_file.persist_noclobber(&path) {
+                Ok(_) => return Ok(Some(path)),
+                Err(e) if e.error.kind() == io::ErrorKind::AlreadyExists => temp_file = e.file,
+                Err(e) => return Err(e.error.into()),
+            }
+        }
+        unreachable!("the file name numbers are exhausted")
+    }
+}
+
+/// Returns the template of the file name of the content
+pub fn file_template(template: Option<NameTemplate>, content: Content<'_>) -> NameTemplate {
+    match content {
+        Content::Scan => template.unwrap_or_default(),
+        Content::Photo(_) => template.unwrap_or_else(|| builtin(PHOTO_TEMPLATE)),
+        // the name on the separator sheet takes precedence
+        Content::Part(_, name) => match part_name(name) {
+            Some(name) => NameTemplate::text(name),
+            None => template.unwrap_or_else(|| builtin(PART_TEMPLATE)),
+        },
+    }
+}
+
+/// Returns the file name without extension for the given attempt, starting at
+/// 1. Later attempts get a number appended, unless the template has a counter.
+pub fn numbered_name(
+    template: &NameTemplate,
+    fields: &NameFields,
+    page: usize,
+    number: usize,
+) -> String {
+    match (template.has_counter(), number) {
+        (true, _) => template.render(fields, page, number),
+        (false, 1) => template.render(fields, page, 0),
+        (false, _) => format!("{}_{number}", template.render(fields, page, 0)),
+    }
+}
+
+/// Returns the media type of files with the extension
+pub fn media_type(extension: &str) -> &'static str {
+    match extension {
+        "pdf" => "application/pdf",
+        "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "tif" => "image/tiff",
+        _ => "application/octet-stream",
+    }
+}
+
+fn builtin(template: &str) -> NameTemplate {
+    NameTemplate::parse(template).expect("valid builtin template")
+}
+
+/// Creates a temporary file which is hidden in the directory. It gets the
+/// usual permissions of new files, as it becomes the final file.
+fn temp_file_in(dir: &Path) -> Result<NamedTempFile, io::Error> {
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(".covet-").suffix(".part");
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        builder.permissions(std::fs::Permissions::from_mode(0o666));
+    }
+    builder.tempfile_in(dir)
+}
+
+/// Returns the name of a document from the text of its separator sheet if it
+/// contains characters which are safe in file names
+fn part_name(name: Option<&str>) -> Option<String> {
+    let name: String = name?
+        .chars()
+        .map(safe_char)
+        .take(MAX_PART_NAME_LEN)
+        .collect();
+    let name = name.trim_matches(|c| c == '_' || c == '.');
+    let name = name
+        .strip_suffix(".pdf")
+        .or_else(|| name.strip_suffix(".PDF"))
+        .unwrap_or(name);
+    name.chars()
+        .any(char::is_alphanumeric)
+        .then(|| name.to_owned())
+}
+
+fn safe_char(c: char) -> char {
+    if c.is_alphanumeric() || c == '-' || c == '.' {
+        c
+    } else {
+        '_'
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures_util::stream;
+
+    fn fields() -> NameFields {
+        NameFields {
+            time: Timestamp::from_second(1486905545).unwrap(),
+            profile: "high".to_owned(),
+            source: "adf".to_owned(),
+            color: "gray".to_owned(),
+            resolution: 600,
+            model: "HP ENVY 5530 series".to_owned(),
+        }
+    }
+
+    fn data(content: &'static [u8]) -> impl Stream<Item = Result<Bytes, ScannerError>> + Unpin {
+        stream::iter([Ok(Bytes::from_static(content))])
+    }
+
+    #[test]
+    fn render_template() {
+        let template =
+            NameTemplate::parse("{time:%Y-%m-%d}_{profile}_{source}_{color}_{resolution}").unwrap();
+        assert_eq!(
+            template.render(&fields(), 1, 0),
+            "2017-02-12_high_adf_gray_600"
+        );
+        let template = NameTemplate::parse("{model} p{page}_{counter}").unwrap();
+        assert_eq!(
+            template.render(&fields(), 3, 12),
+            "HP_ENVY_5530_series p3_0012"
+        );
+        assert_eq!(
+            NameTemplate::default().render(&fields(), 1, 0),
+            "scan_20170212_131905"
+        );
+        // the time may not add directories
+        let template = NameTemplate::parse("{time:%D}").unwrap();
+        assert_eq!(template.render(&fields(), 1, 0), "02_12_17");
+    }
+
+    #[test]
+    fn reject_invalid_templates() {
+        for template in ["scan_{date}", "scan_{time", "scan}", "a/{time}", ""] {
+            assert!(NameTemplate::parse(template).is_err(), "{template}");
+        }
+    }
+
+    #[test]
+    fn render_folders() {
+        let template = FolderTemplate::parse("Scans/{time:%Y}//{model}/").unwrap();
+        assert_eq!(
+            template.render(&fields()),
+            ["Scans", "2017", "HP_ENVY_5530_series"]
+        );
+        assert!(template.has_model());
+        for template in ["a/../b", "./a", "{date}"] {
+            assert!(FolderTemplate::parse(template).is_err(), "{template}");
+        }
+    }
+
+    #[test]
+    fn check_part_name() {
+        assert_eq!(part_name(None), None);
+        assert_eq!(part_name(Some("invoice 42")).unwrap(), "invoice_42");
+        assert_eq!(part_name(Some("letter.pdf")).unwrap(), "letter");
+        assert_eq!(part_name(Some("../etc/passwd")).unwrap(), "etc_passwd");
+        assert_eq!(part_name(Some("/../")), None);
+    }
+
+    #[tokio::test]
+    async fn write_new_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let destination = Destination::Directory {
+            dir: dir.path().to_path_buf(),
+            template: None,
+        };
+        let mut paths = Vec::new();
+        for content in [
+            Content::Scan,
+            Content::Scan,
+            Content::Photo(2),
+            Content::Part(1, None),
+            Content::Part(2, Some("invoice {42}")),
+        ] {
+            let path = destination
+                .write(content, "pdf", &fields(), data(b"scan"))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(std::fs::read(&path).unwrap(), b"scan");
+            paths.push(path.file_name().unwrap().to_string_lossy().into_owned());
+        }
+        assert_eq!(
+            paths,
+            [
+                "scan_20170212_131905.pdf",
+                "scan_20170212_131905_2.pdf",
+                "photo_20170212_131905_2.pdf",
+                "scan_20170212_131905_1.pdf",
+                "invoice__42.pdf",
+            ]
+        );
+        // no temporary files are left
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 5);
+    }
+
+    #[tokio::test]
+    async fn write_with_counter() {
+        let dir = tempfile::tempdir().unwrap();
+        let destination = Destination::Directory {
+            dir: dir.path().to_path_buf(),
+            template: Some(NameTemplate::parse("{source}_{counter}").unwrap()),
+        };
+        for expected in ["adf_0001.png", "adf_0002.png"] {
+            let path = destination
+                .write(Content::Scan, "png", &fields(), data(b"page"))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(path, dir.path().join(expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn replace_file_and_keep_it_on_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.pdf");
+        std::fs::write(&path, b"old").unwrap();
+        let destination = Destination::File(path.clone());
+        let written = destination
+            .write(Content::Scan, "pdf", &fields(), data(b"new"))
+            .await
+            .unwrap();
+        assert_eq!(written, Some(path.clone()));
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+
+        let failing = stream::iter([
+            Ok(Bytes::from_static(b"partial")),
+            Err(ScannerError::Canceled),
+        ]);
+        assert!(
+            destination
+                .write(Content::Scan, "pdf", &fields(), failing)
+                .await
+                .is_err()
+        );
+        // the incomplete download is never visible
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
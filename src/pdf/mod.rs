@@ -0,0 +1,868 @@
+use bytes::Bytes;
+use thiserror::Error;
+use tracing::{debug, info, warn};
+
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+
+use crate::jpeg;
+
+mod object;
+mod pages;
+mod reader;
+mod text;
+mod writer;
+
+use object::{Dictionary, Object};
+use reader::{Document, IndirectObject};
+
+pub use text::TextBox;
+pub use writer::{JpegPage, jpeg_document};
+
+#[derive(Debug, Error)]
+#[error("Failed to parse pdf: {message}")]
+pub struct ParseError {
+    message: String,
+}
+
+impl From<String> for ParseError {
+    fn from(message: String) -> Self {
+        Self { message }
+    }
+}
+
+impl From<&str> for ParseError {
+    fn from(message: &str) -> Self {
+        Self {
+            message: message.to_owned(),
+        }
+    }
+}
+
+/// Fixes the height of all DCTDecode images of a PDF document.
+///
+/// Scanners which write the real height into a DNL segment put the same
+/// placeholder height into the image dictionary. The images are repaired with
+/// [`jpeg::fix_jpeg_height`], their `/Height` entries are updated and the document
+/// is written back with a new cross-reference table. Returns the fixed document
+/// and the number of repaired images or `None` if nothing needs to be repaired.
+pub fn fix_jpeg_heights(data: Bytes) -> Result<Option<(Bytes, usize)>, ParseError> {
+    let document = Document::parse(data)?;
+    let mut replacements = HashMap::new();
+    for object in document.objects() {
+        let Object::Dictionary(dict) = &object.value else {
+            continue;
+        };
+        if !dict.has_single_filter(b"DCTDecode") {
+            continue;
+        }
+        let Some(stream) = document.stream_data(object) else {
+            continue;
+        };
+        match jpeg::fix_jpeg_height(stream, None) {
+            Ok(Some((image, repair))) => {
+                info!("Image in object {}: {repair}", object.number);
+                let mut dict = dict.clone();
+                if let Some((height, _)) = repair.height {
+                    dict.set(b"Height", Object::integer(height));
+                }
+                let replacement = Replacement {
+                    dict,
+                    stream: Some(image),
+                };
+                replacements.insert(object.number, replacement);
+            }
+            Ok(None) => debug!("Image in object {} is complete", object.number),
+            Err(e) => warn!("Skip image in object {}: {e}", object.number),
+        }
+    }
+    if replacements.is_empty() {
+        return Ok(None);
+    }
+    let data = write_document(&document, &replacements, &HashSet::new())?;
+    Ok(Some((data, replacements.len())))
+}
+
+/// A page that was removed because it looked blank
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlankPage {
+    /// Page number starting at 1
+    pub page: usize,
+    /// Content score of the page, see [`jpeg::content_score`]
+    pub score: f64,
+}
+
+/// Removes the pages whose images have a content score below `threshold` percent.
+///
+/// Only pages which consist of JPEG images are considered. If every page looks
+/// blank, the document is kept as it is. Returns the new document and the
+/// removed pages or `None` if no page was removed.
+pub fn remove_blank_pages(
+    data: Bytes,
+    threshold: f64,
+) -> Result<Option<(Bytes, Vec<BlankPage>)>, ParseError> {
+    let document = Document::parse(data)?;
+    let pages = pages::pages(&document)?;
+    let mut blank_pages = Vec::new();
+    let mut removed = HashSet::new();
+    for (index, page) in pages.iter().enumerate() {
+        let Some(score) = page_score(&document, page) else {
+            continue;
+        };
+        debug!("Page {} has a content score of {score:.3}", index + 1);
+        if score < threshold {
+            blank_pages.push(BlankPage {
+                page: index + 1,
+                score,
+            });
+            removed.insert(page.number);
+        }
+    }
+    if blank_pages.is_empty() {
+        return Ok(None);
+    }
+    if blank_pages.len() == pages.len() {
+        warn!("All pages look blank, keep them");
+        return Ok(None);
+    }
+    let data = without_pages(&document, &removed)?;
+    Ok(Some((data, blank_pages)))
+}
+
+/// Returns the JPEG images of every page in order. Other images are left out.
+pub fn page_jpegs(data: Bytes) -> Result<Vec<Vec<Bytes>>, ParseError> {
+    let document = Document::parse(data)?;
+    let pages = pages::pages(&document)?;
+    Ok(pages
+        .iter()
+        .map(|page| {
+            page.images
+                .iter()
+                .filter_map(|number| document.get(*number))
+                .filter(|object| match &object.value {
+                    Object::Dictionary(dict) => dict.has_single_filter(b"DCTDecode"),
+                    _ => false,
+                })
+                .filter_map(|object| document.stream_data(object))
+                .collect()
+        })
+        .collect())
+}
+
+/// Writes a document with only the given pages, counted from 0
+pub fn extract_pages(data: Bytes, kept: &[usize]) -> Result<Bytes, ParseError> {
+    let document = Document::parse(data)?;
+    let pages = pages::pages(&document)?;
+    if kept.is_empty() || kept.iter().any(|index| *index >= pages.len()) {
+        return Err(format!("invalid pages {kept:?} of {} pages", pages.len()).into());
+    }
+    let removed: HashSet<u32> = pages
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| !kept.contains(index))
+        .map(|(_, page)| page.number)
+        .collect();
+    without_pages(&document, &removed)
+}
+
+/// Writes the document without the given page objects and everything which
+/// is only used by them
+fn without_pages(document: &Document, removed: &HashSet<u32>) -> Result<Bytes, ParseError> {
+    let replacements: HashMap<u32, Replacement> = pages::remove_pages(document, removed)?
+        .into_iter()
+        .map(|(number, dict)| (number, Replacement { dict, stream: None }))
+        .collect();
+    let unreachable = unreachable_objects(document, &replacements);
+    write_document(document, &replacements, &unreachable)
+}
+
+/// Adds an invisible text layer to the pages, which makes the document
+/// searchable. `words` contains the recognized words of each page in order.
+/// Returns `None` if no words were recognized at all.
+pub fn add_text_layer(data: Bytes, words: &[Vec<TextBox>]) -> Result<Option<Bytes>, ParseError> {
+    let document = Document::parse(data)?;
+    let pages = pages::pages(&document)?;
+    if pages.len() != words.len() {
+        return Err(format!("text for {} of {} pages", words.len(), pages.len()).into());
+    }
+    if words.iter().all(Vec::is_empty) {
+        return Ok(None);
+    }
+    let mut next_number = next_object_number(&document);
+    let mut new_object = |replacements: &mut HashMap<u32, Replacement>, replacement| {
+        let number = next_number;
+        next_number += 1;
+        replacements.insert(number, replacement);
+        Object::Reference(number, 0)
+    };
+    let mut replacements = HashMap::new();
+    let font = new_object(
+        &mut replacements,
+        Replacement {
+            dict: text::font(),
+            stream: None,
+        },
+    );
+    // the original content may change the graphics state, so it is saved
+    // before and restored after it
+    let save_state = new_object(
+        &mut replacements,
+        Replacement {
+            dict: Dictionary::new(),
+            stream: Some(Bytes::from_static(b"q\n")),
+        },
+    );
+    for (page, words) in pages.iter().zip(words) {
+        if words.is_empty() {
+            continue;
+        }
+        let media_box = page
+            .media_box
+            .ok_or_else(|| format!("page {} has no media box", page.number))?;
+        let content = text::content(words, media_box)
+            .map_err(|e| format!("failed to write text layer: {e}"))?;
+        let text = new_object(
+            &mut replacements,
+            Replacement {
+                dict: Dictionary::new(),
+                stream: Some(content.into()),
+            },
+        );
+        let mut dict = document
+            .dictionary(page.number)
+            .cloned()
+            .ok_or_else(|| format!("invalid page {}", page.number))?;
+        let mut contents = vec![save_state.clone()];
+        match dict.get(b"Contents") {
+            Some(reference @ Object::Reference(..)) => match document.resolve(reference) {
+                Some(Object::Array(streams)) => contents.extend(streams.iter().cloned()),
+                _ => contents.push(reference.clone()),
+            },
+            Some(Object::Array(streams)) => contents.extend(streams.iter().cloned()),
+            _ => (),
+        }
+        contents.push(text);
+        let mut resources = match page.resources.as_ref().and_then(|r| document.resolve(r)) {
+            Some(Object::Dictionary(resources)) => resources.clone(),
+            _ => Dictionary::new(),
+        };
+        let mut fonts = match resources.get(b"Font").and_then(|f| document.resolve(f)) {
+            Some(Object::Dictionary(fonts)) => fonts.clone(),
+            _ => Dictionary::new(),
+        };
+        fonts.set(text::FONT_NAME.as_bytes(), font.clone());
+        resources.set(b"Font", Object::Dictionary(fonts));
+        dict.set(b"Resources", Object::Dictionary(resources));
+        dict.set(b"Contents", Object::Array(contents));
+        replacements.insert(page.number, Replacement { dict, stream: None });
+    }
+    write_document(&document, &replacements, &HashSet::new()).map(Some)
+}
+
+/// A new image of a page with its size in pixels
+#[derive(Debug, Clone)]
+pub struct PageImage {
+    pub data: Bytes,
+    pub width: usize,
+    pub height: usize,
+    pub format: ImageFormat,
+}
+
+/// The encoding of the data of a [`PageImage`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// A grayscale or color JPEG image
+    Jpeg,
+    /// A black and white image compressed with CCITT Group 4
+    Bilevel,
+}
+
+/// Replaces the images of the pages which consist of a single JPEG image.
+///
+/// `replace` is called with the index and the image of each such page and
+/// returns the new image or `None` to keep the page. A changed page is resized
+/// to its new image at the given resolution in dpi and shows nothing else.
+/// Returns `None` if no image was replaced.
+pub fn replace_page_images(
+    data: Bytes,
+    resolution: u32,
+    mut replace: impl FnMut(usize, Bytes) -> Option<PageImage>,
+) -> Result<Option<Bytes>, ParseError> {
+    let document = Document::parse(data)?;
+    let pages = pages::pages(&document)?;
+    let mut next_number = next_object_number(&document);
+    let mut replacements = HashMap::new();
+    for (index, page) in pages.iter().enumerate() {
+        let [number] = page.images[..] else {
+            continue;
+        };
+        let Some(object) = document.get(number) else {
+            continue;
+        };
+        let Object::Dictionary(image_dict) = &object.value else {
+            continue;
+        };
+        if !image_dict.has_single_filter(b"DCTDecode") || replacements.contains_key(&number) {
+            continue;
+        }
+        let Some(name) = xobject_name(&document, page, number) else {
+            continue;
+        };
+        let Some(stream) = document.stream_data(object) else {
+            continue;
+        };
+        let Some(image) = replace(index, stream) else {
+            continue;
+        };
+        let mut image_dict = image_dict.clone();
+        writer::set_image_entries(&mut image_dict, &image)?;
+        replacements.insert(
+            number,
+            Replacement {
+                dict: image_dict,
+                stream: Some(image.data),
+            },
+        );
+
+        let width = writer::points(image.width, resolution);
+        let height = writer::points(image.height, resolution);
+        let content = writer::image_content(&name, width, height)
+            .map_err(|e| format!("failed to write page content: {e}"))?;
+        let content_number = next_number;
+        next_number += 1;
+        replacements.insert(
+            content_number,
+            Replacement {
+                dict: Dictionary::new(),
+                stream: Some(content.into()),
+            },
+        );
+
+        let mut dict = document
+            .dictionary(page.number)
+            .cloned()
+            .ok_or_else(|| format!("invalid page {}", page.number))?;
+        dict.set(b"MediaBox", writer::media_box(width, height));
+        for key in [&b"CropBox"[..], b"BleedBox", b"TrimBox", b"ArtBox"] {
+            dict.remove(key);
+        }
+        dict.set(b"Contents", Object::Reference(content_number, 0));
+        replacements.insert(page.number, Replacement { dict, stream: None });
+    }
+    if replacements.is_empty() {
+        return Ok(None);
+    }
+    let unreachable = unreachable_objects(&document, &replacements);
+    write_document(&document, &replacements, &unreachable).map(Some)
+}
+
+/// Replaces the JPEG images of the pages without changing the pages.
+///
+/// `replace` is called with the index of the page and each of its JPEG images
+/// and returns the new image or `None` to keep it. The new image should have
+/// the same size, as it is drawn in the same place. Returns `None` if no image
+/// was replaced.
+pub fn replace_images(
+    data: Bytes,
+    mut replace: impl FnMut(usize, Bytes) -> Option<PageImage>,
+) -> Result<Option<Bytes>, ParseError> {
+    let document = Document::parse(data)?;
+    let pages = pages::pages(&document)?;
+    let mut replacements = HashMap::new();
+    for (index, page) in pages.iter().enumerate() {
+        for number in &page.images {
+            let Some(object) = document.get(*number) else {
+                continue;
+            };
+            let Object::Dictionary(image_dict) = &object.value else {
+                continue;
+            };
+            if !image_dict.has_single_filter(b"DCTDecode") || replacements.contains_key(number) {
+                continue;
+            }
+            let Some(stream) = document.stream_data(object) else {
+                continue;
+            };
+            let Some(image) = replace(index, stream) else {
+                continue;
+            };
+            let mut image_dict = image_dict.clone();
+            writer::set_image_entries(&mut image_dict, &image)?;
+            replacements.insert(
+                *number,
+                Replacement {
+                    dict: image_dict,
+                    stream: Some(image.data),
+                },
+            );
+        }
+    }
+    if replacements.is_empty() {
+        return Ok(None);
+    }
+    let unreachable = unreachable_objects(&document, &replacements);
+    write_document(&document, &replacements, &unreachable).map(Some)
+}
+
+/// Returns the resource name under which the page uses the image
+fn xobject_name(document: &Document, page: &pages::Page, image: u32) -> Option<Vec<u8>> {
+    let Some(Object::Dictionary(resources)) = document.resolve(page.resources.as_ref()?) else {
+        return None;
+    };
+    let Some(Object::Dictionary(xobjects)) = document.resolve(resources.get(b"XObject")?) else {
+        return None;
+    };
+    xobjects
+        .iter()
+        .find(|(_, value)| matches!(value, Object::Reference(number, _) if *number == image))
+        .map(|(name, _)| name.to_vec())
+}
+
+/// Returns the highest content score of the images of the page or `None`
+/// if the page contains other images or they cannot be decoded
+fn page_score(document: &Document, page: &pages::Page) -> Option<f64> {
+    let mut score: Option<f64> = None;
+    for number in &page.images {
+        let object = document.get(*number)?;
+        match &object.value {
+            Object::Dictionary(dict) if dict.has_single_filter(b"DCTDecode") => (),
+            _ => return None,
+        }
+        match jpeg::content_score(document.stream_data(object)?) {
+            Ok(image_score) => score = Some(score.map_or(image_score, |s| s.max(image_score))),
+            Err(e) => {
+                warn!("Cannot analyse image in object {number}: {e}");
+                return None;
+            }
+        }
+    }
+    score
+}
+
+/// A new version of an object. `stream` is `None` for objects without a stream.
+struct Replacement {
+    dict: Dictionary,
+    stream: Option<Bytes>,
+}
+
+/// Returns the first object number which is not used by the document
+fn next_object_number(document: &Document) -> u32 {
+    let size = document.trailer().get(b"Size").and_then(Object::as_integer);
+    let last = document.objects().map(|object| object.number).max();
+    size.map_or(0, |size| size as u32)
+        .max(last.map_or(1, |n| n + 1))
+}
+
+/// Returns the objects which can no longer be reached from the trailer
+fn unreachable_objects(
+    document: &Document,
+    replacements: &HashMap<u32, Replacement>,
+) -> HashSet<u32> {
+    let mut reachable = HashSet::new();
+    let mut pending = Vec::new();
+    collect_references(
+        &Object::Dictionary(document.trailer().clone()),
+        &mut pending,
+    );
+    while let Some(number) = pending.pop() {
+        if !reachable.insert(number) {
+            continue;
+        }
+        match (replacements.get(&number), document.get(number)) {
+            (Some(replacement), _) => {
+                for value in replacement.dict.values() {
+                    collect_references(value, &mut pending);
+                }
+            }
+            (None, Some(object)) => collect_references(&object.value, &mut pending),
+            (None, None) => (),
+        }
+    }
+    document
+        .objects()
+        .map(|object| object.number)
+        .filter(|number| !reachable.contains(number))
+        .collect()
+}
+
+fn collect_references(object: &Object, references: &mut Vec<u32>) {
+    match object {
+        Object::Reference(number, _) => references.push(*number),
+        Object::Array(items) => items.iter().for_each(|o| collect_references(o, references)),
+        Object::Dictionary(dict) => dict
+            .values()
+            .for_each(|o| collect_references(o, references)),
+        _ => (),
+    }
+}
+
+/// Writes the objects of the document in their original order with the given
+/// replacements and without the removed objects, followed by a single
+/// cross-reference table. Replacements for objects which do not exist are
+/// added after the existing objects.
+fn write_document(
+    document: &Document,
+    replacements: &HashMap<u32, Replacement>,
+    removed: &HashSet<u32>,
+) -> Result<Bytes, ParseError> {
+    write_objects(document, replacements, removed)
+        .map_err(|e| format!("failed to write document: {e}").into())
+}
+
+fn write_objects(
+    document: &Document,
+    replacements: &HashMap<u32, Replacement>,
+    removed: &HashSet<u32>,
+) -> std::io::Result<Bytes> {
+    let data = document.data();
+    let mut objects: Vec<&IndirectObject> = document.objects().collect();
+    objects.sort_by_key(|object| object.offset);
+    let header_len = objects.first().map_or(data.len(), |object| object.offset);
+
+    let mut output = Vec::with_capacity(data.len());
+    output.extend_from_slice(&data[..header_len]);
+    let mut offsets = Vec::with_capacity(objects.len());
+    for object in objects {
+        if removed.contains(&object.number) {
+            continue;
+        }
+        offsets.push((object.number, object.generation, output.len()));
+        match replacements.get(&object.number) {
+            Some(replacement) => {
+                write_replacement(&mut output, object.number, object.generation, replacement)?
+            }
+            None => {
+                output.extend_from_slice(&data[object.offset..object.end]);
+                output.push(b'\n');
+            }
+        }
+    }
+    let mut added: Vec<(&u32, &Replacement)> = replacements
+        .iter()
+        .filter(|(number, _)| document.get(**number).is_none())
+        .collect();
+    added.sort_by_key(|(number, _)| **number);
+    for (number, replacement) in added {
+        offsets.push((*number, 0, output.len()));
+        write_replacement(&mut output, *number, 0, replacement)?;
+    }
+    offsets.sort_by_key(|(number, _, _)| *number);
+
+    let size = offsets.last().map_or(1, |(number, _, _)| number + 1);
+    let xref_offset = output.len();
+    writeln!(output, "xref\n0 {size}")?;
+    let mut entries = offsets.into_iter().peekable();
+    for number in 0..size {
+        match entries.next_if(|(n, _, _)| *n == number) {
+            Some((_, generation, offset)) => write!(output, "{offset:010} {generation:05} n\r\n")?,
+            None if number == 0 => output.extend_from_slice(b"0000000000 65535 f\r\n"),
+            None => output.extend_from_slice(b"0000000000 00000 f\r\n"),
+        }
+    }
+    let mut trailer = document.trailer().clone();
+    trailer.remove(b"Prev");
+    trailer.remove(b"XRefStm");
+    trailer.set(b"Size", Object::integer(size));
+    output.extend_from_slice(b"trailer\n");
+    trailer.write(&mut output)?;
+    write!(output, "\nstartxref\n{xref_offset}\n%%EOF\n")?;
+    Ok(output.into())
+}
+
+fn write_replacement(
+    output: &mut Vec<u8>,
+    number: u32,
+    generation: u16,
+    Replacement { dict, stream }: &Replacement,
+) -> std::io::Result<()> {
+    writeln!(output, "{number} {generation} obj")?;
+    match stream {
+        Some(stream) => {
+            let mut dict = dict.clone();
+            dict.set(b"Length", Object::integer(stream.len() as i64));
+            dict.write(output)?;
+            output.extend_from_slice(b"\nstream\n");
+            output.extend_from_slice(stream);
+            output.extend_from_slice(b"\nendstream");
+        }
+        None => dict.write(output)?,
+    }
+    output.extend_from_slice(b"\nendobj\n");
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    const DNL_TEST_FILE: &str = "doc/testdata/scan_from_adf_with_dnl_header.jpeg";
+
+    /// Builds a document like the ones written by the scanner with one image per page
+    /// and the placeholder height of the DNL test file
+    pub fn build_pdf(images: &[Bytes]) -> Bytes {
+        let count = images.len();
+        let mut objects: Vec<Vec<u8>> = Vec::new();
+        objects.push(b"<</Type /Catalog /Pages 2 0 R>>".to_vec());
+        let kids: Vec<String> = (0..count).map(|i| format!("{} 0 R", 3 + 2 * i)).collect();
+        objects.push(
+            format!("<</Type /Pages /Kids [{}] /Count {count}>>", kids.join(" ")).into_bytes(),
+        );
+        for (i, image) in images.iter().enumerate() {
+            let image_number = 4 + 2 * i;
+            let length_number = 3 + 2 * count + i;
+            objects.push(
+                format!(
+                    "<</Type /Page /Parent 2 0 R /MediaBox [0 0 595 842] \
+                     /Resources <</XObject <</Im1 {image_number} 0 R>>>> >>"
+                )
+                .into_bytes(),
+            );
+            let mut object = format!(
+                "<</Type /XObject /Subtype /Image /Width 2480 /Height 65535 \
+                 /ColorSpace /DeviceRGB /BitsPerComponent 8 /Filter /DCTDecode \
+                 /Length {length_number} 0 R>>\nstream\n"
+            )
+            .into_bytes();
+            object.extend_from_slice(image);
+            object.extend_from_slice(b"\nendstream");
+            objects.push(object);
+        }
+        for image in images {
+            objects.push(image.len().to_string().into_bytes());
+        }
+
+        let mut pdf = b"%PDF-1.4\n%\xe2\xe3\xcf\xd3\n".to_vec();
+        let mut offsets = Vec::new();
+        for (i, object) in objects.iter().enumerate() {
+            offsets.push(pdf.len());
+            writeln!(pdf, "{} 0 obj", i + 1).unwrap();
+            pdf.extend_from_slice(object);
+            pdf.extend_from_slice(b"\nendobj\n");
+        }
+        let xref = pdf.len();
+        let size = objects.len() + 1;
+        write!(pdf, "xref\n0 {size}\n0000000000 65535 f\r\n").unwrap();
+        for offset in offsets {
+            write!(pdf, "{offset:010} 00000 n\r\n").unwrap();
+        }
+        write!(
+            pdf,
+            "trailer\n<</Size {size} /Root 1 0 R>>\nstartxref\n{xref}\n%%EOF\n"
+        )
+        .unwrap();
+        pdf.into()
+    }
+
+    fn dnl_image() -> Bytes {
+        std::fs::read(DNL_TEST_FILE).unwrap().into()
+    }
+
+    fn image_dictionary(document: &Document, number: u32) -> &Dictionary {
+        document.dictionary(number).unwrap()
+    }
+
+    #[test]
+    fn fix_image_height_and_xref() {
+        let pdf = build_pdf(&[dnl_image()]);
+        let (fixed, count) = fix_jpeg_heights(pdf).unwrap().unwrap();
+        assert_eq!(count, 1);
+
+        let document = Document::parse(fixed.clone()).unwrap();
+        for object in document.objects() {
+            let expected = format!("{} 0 obj", object.number);
+            assert!(fixed[object.offset..].starts_with(expected.as_bytes()));
+        }
+        let dict = image_dictionary(&document, 4);
+        assert_eq!(dict.get(b"Height"), Some(&Object::integer(3490)));
+        let stream = document.stream_data(document.get(4).unwrap()).unwrap();
+        assert_eq!(
+            dict.get(b"Length"),
+            Some(&Object::integer(stream.len() as i64))
+        );
+        assert_eq!(jpeg::fix_jpeg_height(stream, None).unwrap(), None);
+        assert_eq!(document.trailer().get(b"Size"), Some(&Object::integer(6)));
+
+        // the fixed document needs no further repair
+        assert!(fix_jpeg_heights(fixed).unwrap().is_none());
+    }
+
+    #[test]
+    fn remove_blank_page() {
+        let pdf = build_pdf(&[dnl_image(), jpeg::blank_page(), dnl_image()]);
+        let (data, removed) = remove_blank_pages(pdf, 0.1).unwrap().unwrap();
+        assert_eq!(
+            removed,
+            vec![BlankPage {
+                page: 2,
+                score: 0.0
+            }]
+        );
+
+        let document = Document::parse(data).unwrap();
+        let pages = pages::pages(&document).unwrap();
+        let numbers: Vec<u32> = pages.iter().map(|p| p.number).collect();
+        assert_eq!(numbers, vec![3, 7]);
+        let tree = document.dictionary(2).unwrap();
+        assert_eq!(tree.get(b"Count"), Some(&Object::integer(2)));
+        // the page, its image and the length of the image are gone
+        assert!(document.get(5).is_none());
+        assert!(document.get(6).is_none());
+        assert!(document.get(10).is_none());
+        assert_eq!(document.trailer().get(b"Size"), Some(&Object::integer(12)));
+    }
+
+    #[test]
+    fn keep_document_if_all_pages_are_blank() {
+        let pdf = build_pdf(&[jpeg::blank_page()]);
+        assert!(remove_blank_pages(pdf, 0.1).unwrap().is_none());
+    }
+
+    #[test]
+    fn extract_pages_in_order() {
+        let images = [dnl_image(), jpeg::blank_page(), dnl_image()];
+        let pdf = build_pdf(&images);
+        assert_eq!(
+            page_jpegs(pdf.clone()).unwrap(),
+            vec![
+                vec![images[0].clone()],
+                vec![images[1].clone()],
+                vec![images[2].clone()]
+            ]
+        );
+
+        let data = extract_pages(pdf.clone(), &[1, 2]).unwrap();
+        let document = Document::parse(data).unwrap();
+        let numbers: Vec<u32> = pages::pages(&document)
+            .unwrap()
+            .iter()
+            .map(|p| p.number)
+            .collect();
+        assert_eq!(numbers, vec![5, 7]);
+        assert!(document.get(4).is_none());
+        assert!(extract_pages(pdf.clone(), &[]).is_err());
+        assert!(extract_pages(pdf, &[3]).is_err());
+    }
+
+    #[test]
+    fn add_invisible_text() {
+        let pdf = build_pdf(&[dnl_image(), dnl_image()]);
+        let word = TextBox {
+            text: "arrow".to_owned(),
+            left: 0.5,
+            top: 0.5,
+            width: 0.1,
+            height: 0.02,
+        };
+        assert!(
+            add_text_layer(pdf.clone(), &[vec![], vec![]])
+                .unwrap()
+                .is_none()
+        );
+        let data = add_text_layer(pdf, &[vec![word], vec![]]).unwrap().unwrap();
+
+        let document = Document::parse(data).unwrap();
+        assert_eq!(document.trailer().get(b"Size"), Some(&Object::integer(12)));
+        let page = document.dictionary(3).unwrap();
+        assert_eq!(
+            page.get(b"Contents"),
+            Some(&Object::Array(vec![
+                Object::Reference(10, 0),
+                Object::Reference(11, 0)
+            ]))
+        );
+        let Some(Object::Dictionary(resources)) = page.get(b"Resources") else {
+            panic!("missing resources");
+        };
+        let Some(Object::Dictionary(fonts)) = resources.get(b"Font") else {
+            panic!("missing fonts");
+        };
+        assert_eq!(fonts.get(b"CovetOcr"), Some(&Object::Reference(9, 0)));
+        assert!(resources.get(b"XObject").is_some());
+        let text = document.stream_data(document.get(11).unwrap()).unwrap();
+        assert!(text.starts_with(b"Q\nBT\n3 Tr\n"));
+        // the second page has no words
+        assert!(document.dictionary(5).unwrap().get(b"Contents").is_none());
+    }
+
+    #[test]
+    fn replace_image_and_page_size() {
+        let pdf = build_pdf(&[dnl_image(), dnl_image()]);
+        let new_image = jpeg::blank_page();
+        let data = replace_page_images(pdf, 300, |index, _| {
+            (index == 0).then(|| PageImage {
+                data: new_image.clone(),
+                width: 1240,
+                height: 1745,
+                format: ImageFormat::Jpeg,
+            })
+        })
+        .unwrap()
+        .unwrap();
+
+        let document = Document::parse(data).unwrap();
+        let image = image_dictionary(&document, 4);
+        assert_eq!(image.get(b"Width"), Some(&Object::integer(1240)));
+        assert_eq!(image.get(b"Height"), Some(&Object::integer(1745)));
+        assert_eq!(
+            document.stream_data(document.get(4).unwrap()),
+            Some(new_image)
+        );
+        let page = document.dictionary(3).unwrap();
+        let pages = pages::pages(&document).unwrap();
+        assert_eq!(pages[0].media_box, Some([0.0, 0.0, 297.6, 418.8]));
+        assert_eq!(page.get(b"Contents"), Some(&Object::Reference(9, 0)));
+        let content = document.stream_data(document.get(9).unwrap()).unwrap();
+        assert_eq!(&content[..], b"q 297.60 0 0 418.80 0 0 cm /Im1 Do Q\n");
+        // the length of the replaced image is written directly
+        assert!(document.get(7).is_none());
+        assert_eq!(pages[1].media_box, Some([0.0, 0.0, 595.0, 842.0]));
+
+        let pdf = build_pdf(&[dnl_image()]);
+        assert!(
+            replace_page_images(pdf, 300, |_, _| None)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn replace_image_in_place() {
+        let pdf = build_pdf(&[dnl_image()]);
+        let original = Document::parse(pdf.clone()).unwrap();
+        let data = replace_images(pdf, |_, _| {
+            Some(PageImage {
+                data: Bytes::from_static(b"bits"),
+                width: 2480,
+                height: 3508,
+                format: ImageFormat::Bilevel,
+            })
+        })
+        .unwrap()
+        .unwrap();
+
+        let document = Document::parse(data).unwrap();
+        let image = image_dictionary(&document, 4);
+        assert_eq!(image.get(b"BitsPerComponent"), Some(&Object::integer(1)));
+        assert_eq!(
+            image.get(b"Filter"),
+            Some(&Object::Name(b"CCITTFaxDecode".to_vec()))
+        );
+        let parameters = match image.get(b"DecodeParms") {
+            Some(Object::Dictionary(parameters)) => parameters,
+            parameters => panic!("unexpected decode parameters {parameters:?}"),
+        };
+        assert_eq!(parameters.get(b"K"), Some(&Object::integer(-1)));
+        assert_eq!(parameters.get(b"Columns"), Some(&Object::integer(2480)));
+        assert_eq!(
+            document.stream_data(document.get(4).unwrap()),
+            Some(Bytes::from_static(b"bits"))
+        );
+        // the page is unchanged
+        assert_eq!(document.dictionary(3), original.dictionary(3));
+    }
+
+    #[test]
+    fn reject_invalid_document() {
+        assert!(fix_jpeg_heights(Bytes::from_static(b"not a pdf")).is_err());
+    }
+}
@@ -0,0 +1,202 @@
+use std::io::Write;
+
+/// A PDF object as far as it is needed to read and rewrite a document
+#[derive(Debug, Clone, PartialEq)]
+pub enum Object {
+    Null,
+    Boolean(bool),
+    /// Integer or real number, kept as written
+    Number(String),
+    Name(Vec<u8>),
+    String(Vec<u8>),
+    Array(Vec<Object>),
+    Dictionary(Dictionary),
+    Reference(u32, u16),
+}
+
+impl Object {
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            Object::Number(n) => n.parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Object::Number(n) => n.parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_name(&self) -> Option<&[u8]> {
+        match self {
+            Object::Name(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    pub fn integer(value: impl Into<i64>) -> Self {
+        Object::Number(value.into().to_string())
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        match self {
+            Object::Null => w.write_all(b"null"),
+            Object::Boolean(b) => write!(w, "{b}"),
+            Object::Number(n) => w.write_all(n.as_bytes()),
+            Object::Name(name) => write_name(w, name),
+            Object::String(s) => {
+                w.write_all(b"<")?;
+                for byte in s {
+                    write!(w, "{byte:02X}")?;
+                }
+                w.write_all(b">")
+            }
+            Object::Array(items) => {
+                w.write_all(b"[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        w.write_all(b" ")?;
+                    }
+                    item.write(w)?;
+                }
+                w.write_all(b"]")
+            }
+            Object::Dictionary(dict) => dict.write(w),
+            Object::Reference(num, generation) => write!(w, "{num} {generation} R"),
+        }
+    }
+}
+
+fn write_name<W: Write>(w: &mut W, name: &[u8]) -> std::io::Result<()> {
+    w.write_all(b"/")?;
+    for byte in name {
+        if byte.is_ascii_alphanumeric() || b"-_.+*".contains(byte) {
+            w.write_all(&[*byte])?;
+        } else {
+            write!(w, "#{byte:02X}")?;
+        }
+    }
+    Ok(())
+}
+
+/// A dictionary that keeps the order of its entries
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Dictionary {
+    entries: Vec<(Vec<u8>, Object)>,
+}
+
+impl Dictionary {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&Object> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &Object> {
+        self.entries.iter().map(|(_, value)| value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &Object)> {
+        self.entries
+            .iter()
+            .map(|(key, value)| (key.as_slice(), value))
+    }
+
+    /// Replaces the value of an existing entry or appends a new one
+    pub fn set(&mut self, key: &[u8], value: Object) {
+        match self.entries.iter_mut().find(|(k, _)| k == key) {
+            Some(entry) => entry.1 = value,
+            None => self.entries.push((key.to_vec(), value)),
+        }
+    }
+
+    pub fn remove(&mut self, key: &[u8]) {
+        self.entries.retain(|(k, _)| k != key);
+    }
+
+    /// Returns true if the `/Filter` entry is the given filter without any others
+    pub fn has_single_filter(&self, filter: &[u8]) -> bool {
+        match self.get(b"Filter") {
+            Some(Object::Name(name)) => name == filter,
+            Some(Object::Array(filters)) => {
+                matches!(filters.as_slice(), [Object::Name(name)] if name == filter)
+            }
+            _ => false,
+        }
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        w.write_all(b"<<")?;
+        for (key, value) in &self.entries {
+            write_name(w, key)?;
+            w.write_all(b" ")?;
+            value.write(w)?;
+        }
+        w.write_all(b">>")
+    }
+}
+
+impl<const N: usize> From<[(&[u8], Object); N]> for Dictionary {
+    fn from(entries: [(&[u8], Object); N]) -> Self {
+        Self {
+            entries: entries
+                .into_iter()
+                .map(|(key, value)| (key.to_vec(), value))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn to_string(object: &Object) -> String {
+        let mut buffer = Vec::new();
+        object.write(&mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[test]
+    fn write_objects() {
+        let dict = Dictionary::from([
+            (b"Type".as_slice(), Object::Name(b"XObject".to_vec())),
+            (b"Width", Object::integer(2480)),
+            (
+                b"Decode",
+                Object::Array(vec![Object::integer(1), Object::integer(0)]),
+            ),
+            (b"Parent", Object::Reference(3, 0)),
+            (b"Title", Object::String(b"a(b".to_vec())),
+            (b"Odd Name", Object::Null),
+        ]);
+        assert_eq!(
+            to_string(&Object::Dictionary(dict)),
+            "<</Type /XObject/Width 2480/Decode [1 0]/Parent 3 0 R/Title <612862>/Odd#20Name null>>"
+        );
+    }
+
+    #[test]
+    fn set_and_check_filter() {
+        let mut dict = Dictionary::new();
+        dict.set(b"Filter", Object::Name(b"DCTDecode".to_vec()));
+        assert!(dict.has_single_filter(b"DCTDecode"));
+        dict.set(
+            b"Filter",
+            Object::Array(vec![
+                Object::Name(b"FlateDecode".to_vec()),
+                Object::Name(b"DCTDecode".to_vec()),
+            ]),
+        );
+        assert!(!dict.has_single_filter(b"DCTDecode"));
+        dict.remove(b"Filter");
+        assert_eq!(dict.get(b"Filter"), None);
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::pdf::ParseError;
+use crate::pdf::object::{Dictionary, Object};
+use crate::pdf::reader::Document;
+
+/// Nesting limit of the page tree to guard against reference cycles
+const MAX_DEPTH: usize = 32;
+
+/// A leaf of the page tree
+#[derive(Debug, Clone, PartialEq)]
+pub struct Page {
+    /// Object number of the page dictionary
+    pub number: u32,
+    /// Object numbers of the image XObjects in the resources of the page
+    pub images: Vec<u32>,
+    /// The resources of the page, possibly inherited from the page tree
+    pub resources: Option<Object>,
+    /// The page boundaries as lower left and upper right corner
+    pub media_box: Option<[f64; 4]>,
+}
+
+/// Lists the pages of the document in order
+pub fn pages(document: &Document) -> Result<Vec<Page>, ParseError> {
+    let mut pages = Vec::new();
+    let inherited = Inherited::default();
+    collect_pages(document, pages_root(document)?, inherited, 0, &mut pages)?;
+    Ok(pages)
+}
+
+/// Returns the nodes of the page tree which change if the given pages are removed.
+/// Nodes without any pages left are removed from their parent as well.
+pub fn remove_pages(
+    document: &Document,
+    removed: &HashSet<u32>,
+) -> Result<HashMap<u32, Dictionary>, ParseError> {
+    let mut nodes = HashMap::new();
+    prune(document, pages_root(document)?, removed, 0, &mut nodes)?;
+    Ok(nodes)
+}
+
+fn pages_root(document: &Document) -> Result<u32, ParseError> {
+    let catalog = match document.trailer().get(b"Root") {
+        Some(Object::Reference(number, _)) => document.dictionary(*number),
+        _ => None,
+    }
+    .ok_or_else(|| ParseError::from("missing document catalog"))?;
+    match catalog.get(b"Pages") {
+        Some(Object::Reference(number, _)) => Ok(*number),
+        _ => Err("missing page tree".into()),
+    }
+}
+
+fn is_pages_node(node: &Dictionary) -> bool {
+    node.get(b"Type").and_then(Object::as_name) == Some(b"Pages")
+}
+
+fn node(document: &Document, number: u32, depth: usize) -> Result<&Dictionary, ParseError> {
+    if depth > MAX_DEPTH {
+        return Err("page tree is nested too deeply".into());
+    }
+    document
+        .dictionary(number)
+        .ok_or_else(|| format!("invalid page tree node {number}").into())
+}
+
+fn kids<'a>(document: &'a Document, node: &'a Dictionary) -> &'a [Object] {
+    match node.get(b"Kids").and_then(|kids| document.resolve(kids)) {
+        Some(Object::Array(kids)) => kids,
+        _ => &[],
+    }
+}
+
+/// Page attributes which can be set on any node of the page tree
+#[derive(Debug, Clone, Copy, Default)]
+struct Inherited<'a> {
+    resources: Option<&'a Object>,
+    media_box: Option<&'a Object>,
+}
+
+fn collect_pages<'a>(
+    document: &'a Document,
+    number: u32,
+    inherited: Inherited<'a>,
+    depth: usize,
+    pages: &mut Vec<Page>,
+) -> Result<(), ParseError> {
+    let node = node(document, number, depth)?;
+    let inherited = Inherited {
+        resources: node.get(b"Resources").or(inherited.resources),
+        media_box: node.get(b"MediaBox").or(inherited.media_box),
+    };
+    if !is_pages_node(node) {
+        pages.push(Page {
+            number,
+            images: inherited
+                .resources
+                .map_or_else(Vec::new, |r| images(document, r)),
+            resources: inherited.resources.cloned(),
+            media_box: inherited.media_box.and_then(|b| rectangle(document, b)),
+        });
+        return Ok(());
+    }
+    for kid in kids(document, node) {
+        if let Object::Reference(kid, _) = kid {
+            collect_pages(document, *kid, inherited, depth + 1, pages)?;
+        }
+    }
+    Ok(())
+}
+
+fn rectangle(document: &Document, object: &Object) -> Option<[f64; 4]> {
+    let Some(Object::Array(values)) = document.resolve(object) else {
+        return None;
+    };
+    let values: Vec<f64> = values
+        .iter()
+        .filter_map(|value| document.resolve(value)?.as_number())
+        .collect();
+    values.try_into().ok()
+}
+
+fn images(document: &Document, resources: &Object) -> Vec<u32> {
+    let Some(Object::Dictionary(resources)) = document.resolve(resources) else {
+        return Vec::new();
+    };
+    let Some(Object::Dictionary(xobjects)) =
+        resources.get(b"XObject").and_then(|x| document.resolve(x))
+    else {
+        return Vec::new();
+    };
+    xobjects
+        .values()
+        .filter_map(|xobject| match xobject {
+            Object::Reference(number, _) => Some(*number),
+            _ => None,
+        })
+        .filter(|number| {
+            document
+                .dictionary(*number)
+                .and_then(|dict| dict.get(b"Subtype"))
+                .and_then(Object::as_name)
+                == Some(b"Image")
+        })
+        .collect()
+}
+
+/// Removes the pages from the subtree and returns the number of pages left in it
+fn prune(
+    document: &Document,
+    number: u32,
+    removed: &HashSet<u32>,
+    depth: usize,
+    nodes: &mut HashMap<u32, Dictionary>,
+) -> Result<i64, ParseError> {
+    let node = node(document, number, depth)?;
+    if !is_pages_node(node) {
+        return Ok(if removed.contains(&number) { 0 } else { 1 });
+    }
+    let mut kids_left = Vec::new();
+    let mut count = 0;
+    for kid in kids(document, node) {
+        let kid_count = match kid {
+            Object::Reference(kid, _) => prune(document, *kid, removed, depth + 1, nodes)?,
+            _ => 0,
+        };
+        if kid_count > 0 {
+            kids_left.push(kid.clone());
+            count += kid_count;
+        }
+    }
+    let old_count = node.get(b"Count").and_then(Object::as_integer);
+    if old_count != Some(count) || kids_left.len() != kids(document, node).len() {
+        let mut node = node.clone();
+        node.set(b"Kids", Object::Array(kids_left));
+        node.set(b"Count", Object::integer(count));
+        nodes.insert(number, node);
+    }
+    Ok(count)
+}
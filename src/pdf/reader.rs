@@ -0,0 +1,537 @@
+use bytes::Bytes;
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use crate::pdf::ParseError;
+use crate::pdf::object::{Dictionary, Object};
+
+/// An object of the document and where it is stored
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndirectObject {
+    pub number: u32,
+    pub generation: u16,
+    /// Offset of the object number
+    pub offset: usize,
+    /// Offset after the `endobj` keyword
+    pub end: usize,
+    pub value: Object,
+    /// Range of the stream data, if the object is a stream
+    pub stream: Option<Range<usize>>,
+}
+
+/// A PDF document with a classic cross-reference table.
+///
+/// Only the objects referenced by the cross-reference table are read.
+/// Cross-reference streams and object streams are not supported.
+#[derive(Debug)]
+pub struct Document {
+    data: Bytes,
+    objects: BTreeMap<u32, IndirectObject>,
+    trailer: Dictionary,
+}
+
+impl Document {
+    pub fn parse(data: Bytes) -> Result<Self, ParseError> {
+        if !data.starts_with(b"%PDF-") {
+            return Err("missing PDF header".into());
+        }
+        let offsets = read_xref(&data)?;
+        let mut trailer = None;
+        let mut entries: BTreeMap<u32, (usize, u16)> = BTreeMap::new();
+        // the newest section comes first and wins
+        for (section, dict) in offsets {
+            for (number, entry) in section {
+                entries.entry(number).or_insert(entry);
+            }
+            trailer.get_or_insert(dict);
+        }
+        let mut objects = BTreeMap::new();
+        for (number, (offset, generation)) in &entries {
+            let object = read_indirect_object(&data, *offset, &entries)?;
+            if object.number != *number || object.generation != *generation {
+                return Err(format!("object {number} not found at offset {offset}").into());
+            }
+            objects.insert(*number, object);
+        }
+        Ok(Self {
+            data,
+            objects,
+            trailer: trailer.ok_or_else(|| ParseError::from("missing trailer"))?,
+        })
+    }
+
+    pub fn data(&self) -> &Bytes {
+        &self.data
+    }
+
+    pub fn objects(&self) -> impl Iterator<Item = &IndirectObject> {
+        self.objects.values()
+    }
+
+    pub fn get(&self, number: u32) -> Option<&IndirectObject> {
+        self.objects.get(&number)
+    }
+
+    /// Returns the dictionary of the object with the given number
+    pub fn dictionary(&self, number: u32) -> Option<&Dictionary> {
+        match &self.get(number)?.value {
+            Object::Dictionary(dict) => Some(dict),
+            _ => None,
+        }
+    }
+
+    /// Follows a reference to the object it points to
+    pub fn resolve<'a>(&'a self, object: &'a Object) -> Option<&'a Object> {
+        match object {
+            Object::Reference(number, _) => self.get(*number).map(|o| &o.value),
+            object => Some(object),
+        }
+    }
+
+    pub fn trailer(&self) -> &Dictionary {
+        &self.trailer
+    }
+
+    pub fn stream_data(&self, object: &IndirectObject) -> Option<Bytes> {
+        object.stream.clone().map(|range| self.data.slice(range))
+    }
+}
+
+type XrefSection = Vec<(u32, (usize, u16))>;
+
+/// Reads all cross-reference sections starting with the newest one
+fn read_xref(data: &[u8]) -> Result<Vec<(XrefSection, Dictionary)>, ParseError> {
+    let tail_start = data.len().saturating_sub(1024);
+    let startxref = find_last(&data[tail_start..], b"startxref")
+        .map(|pos| tail_start + pos + b"startxref".len())
+        .ok_or_else(|| ParseError::from("missing startxref"))?;
+    let mut lexer = Lexer::new(data, startxref);
+    let mut offset = lexer.read_usize()?;
+    let mut sections = Vec::new();
+    loop {
+        if sections.len() > 100 || offset >= data.len() {
+            return Err("invalid cross-reference offset".into());
+        }
+        let mut lexer = Lexer::new(data, offset);
+        if lexer.read_keyword() != b"xref" {
+            return Err("cross-reference streams are not supported".into());
+        }
+        let mut section = Vec::new();
+        loop {
+            lexer.skip_whitespace();
+            if lexer.peek_keyword() == b"trailer" {
+                lexer.read_keyword();
+                break;
+            }
+            let start = lexer.read_usize()?;
+            let count = lexer.read_usize()?;
+            for i in 0..count {
+                let entry_offset = lexer.read_usize()?;
+                let generation = lexer.read_usize()?;
+                let kind = lexer.read_keyword();
+                let number = u32::try_from(start + i).map_err(|_| "invalid object number")?;
+                if kind == b"n" && number > 0 {
+                    let generation = u16::try_from(generation).map_err(|_| "invalid generation")?;
+                    section.push((number, (entry_offset, generation)));
+                }
+            }
+        }
+        let Object::Dictionary(trailer) = lexer.read_object()? else {
+            return Err("invalid trailer".into());
+        };
+        let prev = trailer.get(b"Prev").and_then(Object::as_integer);
+        if trailer.get(b"XRefStm").is_some() {
+            return Err("cross-reference streams are not supported".into());
+        }
+        sections.push((section, trailer));
+        match prev {
+            Some(prev) => offset = usize::try_from(prev).map_err(|_| "invalid Prev offset")?,
+            None => return Ok(sections),
+        }
+    }
+}
+
+fn read_indirect_object(
+    data: &[u8],
+    offset: usize,
+    entries: &BTreeMap<u32, (usize, u16)>,
+) -> Result<IndirectObject, ParseError> {
+    let mut lexer = Lexer::new(data, offset);
+    let number = u32::try_from(lexer.read_usize()?).map_err(|_| "invalid object number")?;
+    let generation = u16::try_from(lexer.read_usize()?).map_err(|_| "invalid generation")?;
+    if lexer.read_keyword() != b"obj" {
+        return Err(format!("missing obj keyword at offset {offset}").into());
+    }
+    let value = lexer.read_object()?;
+    lexer.skip_whitespace();
+    let mut stream = None;
+    if lexer.peek_keyword() == b"stream" {
+        lexer.read_keyword();
+        // the keyword is followed by CRLF or LF
+        if data[lexer.pos..].starts_with(b"\r\n") {
+            lexer.pos += 2;
+        } else if data[lexer.pos..].starts_with(b"\n") {
+            lexer.pos += 1;
+        }
+        let start = lexer.pos;
+        let length = match &value {
+            Object::Dictionary(dict) => stream_length(data, dict, entries),
+            _ => None,
+        };
+        let end = length
+            .map(|length| start + length)
+            .filter(|end| is_stream_end(data, *end))
+            .or_else(|| {
+                // fall back to searching for the keyword if the length is wrong
+                find_first(&data[start..], b"endstream").map(|pos| {
+                    let end = start + pos;
+                    if data[..end].ends_with(b"\r\n") {
+                        end - 2
+                    } else if data[..end].ends_with(b"\n") || data[..end].ends_with(b"\r") {
+                        end - 1
+                    } else {
+                        end
+                    }
+                })
+            })
+            .ok_or_else(|| ParseError::from(format!("unterminated stream in object {number}")))?;
+        stream = Some(start..end);
+        lexer.pos = end;
+        lexer.skip_whitespace();
+        lexer.read_keyword();
+        lexer.skip_whitespace();
+    }
+    if lexer.peek_keyword() == b"endobj" {
+        lexer.read_keyword();
+    }
+    Ok(IndirectObject {
+        number,
+        generation,
+        offset,
+        end: lexer.pos,
+        value,
+        stream,
+    })
+}
+
+fn stream_length(
+    data: &[u8],
+    dict: &Dictionary,
+    entries: &BTreeMap<u32, (usize, u16)>,
+) -> Option<usize> {
+    let length = match dict.get(b"Length")? {
+        Object::Reference(number, _) => {
+            let (offset, _) = entries.get(number)?;
+            let mut lexer = Lexer::new(data, *offset);
+            lexer.read_usize().ok()?;
+            lexer.read_usize().ok()?;
+            lexer.read_keyword();
+            lexer.read_object().ok()?.as_integer()?
+        }
+        length => length.as_integer()?,
+    };
+    usize::try_from(length).ok()
+}
+
+fn is_stream_end(data: &[u8], end: usize) -> bool {
+    if end > data.len() {
+        return false;
+    }
+    let mut lexer = Lexer::new(data, end);
+    lexer.skip_whitespace();
+    lexer.peek_keyword() == b"endstream"
+}
+
+fn find_last(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).rposition(|w| w == needle)
+}
+
+fn find_first(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn is_whitespace(byte: u8) -> bool {
+    matches!(byte, 0 | b'\t' | b'\n' | 0x0c | b'\r' | b' ')
+}
+
+fn is_delimiter(byte: u8) -> bool {
+    b"()<>[]{}/%".contains(&byte)
+}
+
+fn is_regular(byte: u8) -> bool {
+    !is_whitespace(byte) && !is_delimiter(byte)
+}
+
+struct Lexer<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        Self { data, pos }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.data.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(byte) = self.peek() {
+            if is_whitespace(byte) {
+                self.pos += 1;
+            } else if byte == b'%' {
+                while !matches!(self.peek(), None | Some(b'\r' | b'\n')) {
+                    self.pos += 1;
+                }
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn peek_keyword(&self) -> &'a [u8] {
+        let start = self.pos;
+        let len = self.data[start..]
+            .iter()
+            .take_while(|b| is_regular(**b))
+            .count();
+        &self.data[start..start + len]
+    }
+
+    fn read_keyword(&mut self) -> &'a [u8] {
+        self.skip_whitespace();
+        let keyword = self.peek_keyword();
+        self.pos += keyword.len();
+        keyword
+    }
+
+    fn read_usize(&mut self) -> Result<usize, ParseError> {
+        let keyword = self.read_keyword();
+        std::str::from_utf8(keyword)
+            .ok()
+            .and_then(|k| k.parse().ok())
+            .ok_or_else(|| format!("expected integer at offset {}", self.pos).into())
+    }
+
+    fn read_object(&mut self) -> Result<Object, ParseError> {
+        self.skip_whitespace();
+        match self.peek() {
+            None => Err("unexpected end of data".into()),
+            Some(b'<') if self.data[self.pos..].starts_with(b"<<") => {
+                self.pos += 2;
+                let mut dict = Dictionary::new();
+                loop {
+                    self.skip_whitespace();
+                    if self.data[self.pos..].starts_with(b">>") {
+                        self.pos += 2;
+                        return Ok(Object::Dictionary(dict));
+                    }
+                    let Object::Name(key) = self.read_object()? else {
+                        return Err(format!("expected name at offset {}", self.pos).into());
+                    };
+                    let value = self.read_object()?;
+                    dict.set(&key, value);
+                }
+            }
+            Some(b'<') => {
+                self.pos += 1;
+                let mut digits = Vec::new();
+                loop {
+                    match self.peek() {
+                        None => return Err("unterminated hex string".into()),
+                        Some(b'>') => break,
+                        Some(byte) if byte.is_ascii_hexdigit() => digits.push(byte),
+                        Some(_) => (),
+                    }
+                    self.pos += 1;
+                }
+                self.pos += 1;
+                if digits.len() % 2 == 1 {
+                    digits.push(b'0');
+                }
+                let bytes = digits
+                    .chunks(2)
+                    .map(|pair| {
+                        let hex = std::str::from_utf8(pair).unwrap();
+                        u8::from_str_radix(hex, 16).unwrap()
+                    })
+                    .collect();
+                Ok(Object::String(bytes))
+            }
+            Some(b'(') => self.read_literal_string(),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                loop {
+                    self.skip_whitespace();
+                    if self.peek() == Some(b']') {
+                        self.pos += 1;
+                        return Ok(Object::Array(items));
+                    }
+                    items.push(self.read_object()?);
+                }
+            }
+            Some(b'/') => {
+                self.pos += 1;
+                let raw = self.peek_keyword();
+                self.pos += raw.len();
+                let mut name = Vec::new();
+                let mut i = 0;
+                while i < raw.len() {
+                    let decoded = (raw[i] == b'#')
+                        .then(|| raw.get(i + 1..i + 3))
+                        .flatten()
+                        .and_then(|hex| std::str::from_utf8(hex).ok())
+                        .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                    match decoded {
+                        Some(byte) => {
+                            name.push(byte);
+                            i += 3;
+                        }
+                        None => {
+                            name.push(raw[i]);
+                            i += 1;
+                        }
+                    }
+                }
+                Ok(Object::Name(name))
+            }
+            Some(_) => {
+                let keyword = self.read_keyword();
+                match keyword {
+                    b"true" => Ok(Object::Boolean(true)),
+                    b"false" => Ok(Object::Boolean(false)),
+                    b"null" => Ok(Object::Null),
+                    k if !k.is_empty()
+                        && k.iter().all(|b| b.is_ascii_digit() || b"+-.".contains(b)) =>
+                    {
+                        let number = String::from_utf8_lossy(k).into_owned();
+                        Ok(self
+                            .read_reference(&number)
+                            .unwrap_or(Object::Number(number)))
+                    }
+                    _ => Err(format!("unexpected token at offset {}", self.pos).into()),
+                }
+            }
+        }
+    }
+
+    /// Reads the rest of a reference `number generation R` if there is one
+    fn read_reference(&mut self, number: &str) -> Option<Object> {
+        let start = self.pos;
+        let reference = (|| {
+            let number = number.parse().ok()?;
+            let generation = std::str::from_utf8(self.read_keyword())
+                .ok()?
+                .parse()
+                .ok()?;
+            (self.read_keyword() == b"R").then_some(Object::Reference(number, generation))
+        })();
+        if reference.is_none() {
+            self.pos = start;
+        }
+        reference
+    }
+
+    fn read_literal_string(&mut self) -> Result<Object, ParseError> {
+        self.pos += 1;
+        let mut depth = 1;
+        let mut bytes = Vec::new();
+        while let Some(byte) = self.peek() {
+            self.pos += 1;
+            match byte {
+                b'\\' => {
+                    let Some(escaped) = self.peek() else { break };
+                    self.pos += 1;
+                    match escaped {
+                        b'n' => bytes.push(b'\n'),
+                        b'r' => bytes.push(b'\r'),
+                        b't' => bytes.push(b'\t'),
+                        b'b' => bytes.push(0x08),
+                        b'f' => bytes.push(0x0c),
+                        b'\r' => {
+                            if self.peek() == Some(b'\n') {
+                                self.pos += 1;
+                            }
+                        }
+                        b'\n' => (),
+                        b'0'..=b'7' => {
+                            let mut value = u32::from(escaped - b'0');
+                            for _ in 0..2 {
+                                match self.peek() {
+                                    Some(d @ b'0'..=b'7') => {
+                                        value = value * 8 + u32::from(d - b'0');
+                                        self.pos += 1;
+                                    }
+                                    _ => break,
+                                }
+                            }
+                            bytes.push(value as u8);
+                        }
+                        other => bytes.push(other),
+                    }
+                }
+                b'(' => {
+                    depth += 1;
+                    bytes.push(byte);
+                }
+                b')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(Object::String(bytes));
+                    }
+                    bytes.push(byte);
+                }
+                _ => bytes.push(byte),
+            }
+        }
+        Err("unterminated string".into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(s: &str) -> Object {
+        Lexer::new(s.as_bytes(), 0).read_object().unwrap()
+    }
+
+    #[test]
+    fn read_objects() {
+        assert_eq!(parse(" 12 "), Object::Number("12".to_owned()));
+        assert_eq!(parse("-0.5"), Object::Number("-0.5".to_owned()));
+        assert_eq!(parse("12 0 R"), Object::Reference(12, 0));
+        assert_eq!(parse("/A#20B"), Object::Name(b"A B".to_vec()));
+        assert_eq!(
+            parse("(a\\(b\\)c (d)\\101)"),
+            Object::String(b"a(b)c (d)A".to_vec())
+        );
+        assert_eq!(parse("<48 656>"), Object::String(b"He`".to_vec()));
+        assert_eq!(
+            parse("[1 2 R /N % comment\n true]"),
+            Object::Array(vec![
+                Object::Reference(1, 2),
+                Object::Name(b"N".to_vec()),
+                Object::Boolean(true)
+            ])
+        );
+        let Object::Dictionary(dict) = parse("<</Width 10/Sub<</A null>>>>") else {
+            panic!("not a dictionary");
+        };
+        assert_eq!(dict.get(b"Width"), Some(&Object::integer(10)));
+        assert!(matches!(dict.get(b"Sub"), Some(Object::Dictionary(_))));
+    }
+
+    #[test]
+    fn reject_cross_reference_streams() {
+        let data = b"%PDF-1.5\n1 0 obj\n<</Type /XRef>>\nendobj\nstartxref\n9\n%%EOF\n";
+        let error = Document::parse(Bytes::from_static(data)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Failed to parse pdf: cross-reference streams are not supported"
+        );
+    }
+}
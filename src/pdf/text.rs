@@ -0,0 +1,98 @@
+use std::io::Write;
+
+use crate::pdf::object::{Dictionary, Object};
+
+/// Resource name of the font of the text layer
+pub const FONT_NAME: &str = "CovetOcr";
+
+/// Average width of a Helvetica character in text space units, used to
+/// stretch the words to the width of their box
+const AVERAGE_CHAR_WIDTH: f64 = 0.5;
+
+/// A recognized word. The position is given in fractions of the page size,
+/// starting at the top left corner.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextBox {
+    pub text: String,
+    pub left: f64,
+    pub top: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+/// The font of the text layer. It is one of the standard fonts which every
+/// reader provides, so it does not need to be embedded.
+pub fn font() -> Dictionary {
+    Dictionary::from([
+        (&b"Type"[..], Object::Name(b"Font".to_vec())),
+        (b"Subtype", Object::Name(b"Type1".to_vec())),
+        (b"BaseFont", Object::Name(b"Helvetica".to_vec())),
+        (b"Encoding", Object::Name(b"WinAnsiEncoding".to_vec())),
+    ])
+}
+
+/// Returns a content stream which draws the words invisibly on top of the page.
+/// It restores the graphics state saved in front of the original content first.
+pub fn content(words: &[TextBox], [x0, y0, x1, y1]: [f64; 4]) -> std::io::Result<Vec<u8>> {
+    let (page_width, page_height) = (x1 - x0, y1 - y0);
+    let mut output = b"Q\nBT\n3 Tr\n".to_vec();
+    for word in words {
+        let text = encode(&word.text);
+        if text.is_empty() || word.width <= 0.0 || word.height <= 0.0 {
+            continue;
+        }
+        let size = word.height * page_height;
+        let width = word.width * page_width;
+        let scale = 100.0 * width / (text.len() as f64 * AVERAGE_CHAR_WIDTH * size);
+        let x = x0 + word.left * page_width;
+        let y = y1 - (word.top + word.height) * page_height;
+        write!(
+            output,
+            "/{FONT_NAME} {size:.2} Tf {scale:.2} Tz 1 0 0 1 {x:.2} {y:.2} Tm <"
+        )?;
+        for byte in text {
+            write!(output, "{byte:02X}")?;
+        }
+        output.extend_from_slice(b"> Tj\n");
+    }
+    output.extend_from_slice(b"ET\n");
+    Ok(output)
+}
+
+/// Encodes the text with the Windows ANSI encoding of the font. Characters
+/// which it does not contain are replaced by a question mark.
+fn encode(text: &str) -> Vec<u8> {
+    text.chars()
+        .filter(|c| !c.is_control())
+        .map(|c| match u32::from(c) {
+            code @ (0x20..=0x7e | 0xa0..=0xff) => code as u8,
+            _ => b'?',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn position_words_on_page() {
+        let words = [TextBox {
+            text: "Grüße".to_owned(),
+            left: 0.1,
+            top: 0.2,
+            width: 0.5,
+            height: 0.1,
+        }];
+        let content = content(&words, [0.0, 0.0, 100.0, 200.0]).unwrap();
+        assert_eq!(
+            String::from_utf8(content).unwrap(),
+            "Q\nBT\n3 Tr\n/CovetOcr 20.00 Tf 100.00 Tz 1 0 0 1 10.00 140.00 Tm <4772FCDF65> Tj\nET\n"
+        );
+    }
+
+    #[test]
+    fn replace_unknown_characters() {
+        assert_eq!(encode("a€\tb"), b"a?b");
+    }
+}
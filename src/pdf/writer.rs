@@ -0,0 +1,224 @@
+use bytes::Bytes;
+
+use std::io::Write;
+
+use crate::jpeg::{FrameHeader, Jpeg};
+use crate::pdf::object::{Dictionary, Object};
+use crate::pdf::{ImageFormat, PageImage, ParseError, Replacement, write_replacement};
+
+/// Resource name of the image of a page
+const IMAGE_NAME: &[u8] = b"Im1";
+
+/// A JPEG image which fills a page, scanned at `resolution` dpi
+#[derive(Debug, Clone)]
+pub struct JpegPage {
+    pub data: Bytes,
+    pub resolution: u32,
+}
+
+/// Writes a new document with one page per image. Each page gets the size of
+/// its image at the scan resolution.
+pub fn jpeg_document(pages: &[JpegPage]) -> Result<Bytes, ParseError> {
+    let count = pages.len();
+    // the catalog and the page tree are followed by the page, the image and
+    // the content of each page
+    let page_number = |index: usize| (3 + 3 * index) as u32;
+    let mut objects = vec![
+        Replacement {
+            dict: Dictionary::from([
+                (&b"Type"[..], Object::Name(b"Catalog".to_vec())),
+                (b"Pages", Object::Reference(2, 0)),
+            ]),
+            stream: None,
+        },
+        Replacement {
+            dict: Dictionary::from([
+                (&b"Type"[..], Object::Name(b"Pages".to_vec())),
+                (
+                    b"Kids",
+                    Object::Array(
+                        (0..count)
+                            .map(|index| Object::Reference(page_number(index), 0))
+                            .collect(),
+                    ),
+                ),
+                (b"Count", Object::integer(count as i64)),
+            ]),
+            stream: None,
+        },
+    ];
+    for (index, page) in pages.iter().enumerate() {
+        let frame = jpeg_frame(&page.data)
+            .map_err(|e| format!("invalid image of page {}: {e}", index + 1))?;
+        let width = points(usize::from(frame.width), page.resolution);
+        let height = points(usize::from(frame.height), page.resolution);
+        let number = page_number(index);
+        let xobjects = Dictionary::from([(IMAGE_NAME, Object::Reference(number + 1, 0))]);
+        let resources = Dictionary::from([(&b"XObject"[..], Object::Dictionary(xobjects))]);
+        objects.push(Replacement {
+            dict: Dictionary::from([
+                (&b"Type"[..], Object::Name(b"Page".to_vec())),
+                (b"Parent", Object::Reference(2, 0)),
+                (b"MediaBox", media_box(width, height)),
+                (b"Resources", Object::Dictionary(resources)),
+                (b"Contents", Object::Reference(number + 2, 0)),
+            ]),
+            stream: None,
+        });
+        let mut image = Dictionary::from([
+            (&b"Type"[..], Object::Name(b"XObject".to_vec())),
+            (b"Subtype", Object::Name(b"Image".to_vec())),
+        ]);
+        set_image_entries(
+            &mut image,
+            &PageImage {
+                data: page.data.clone(),
+                width: usize::from(frame.width),
+                height: usize::from(frame.height),
+                format: ImageFormat::Jpeg,
+            },
+        )?;
+        objects.push(Replacement {
+            dict: image,
+            stream: Some(page.data.clone()),
+        });
+        objects.push(Replacement {
+            dict: Dictionary::new(),
+            stream: Some(
+                image_content(IMAGE_NAME, width, height)
+                    .map_err(|e| format!("failed to write page content: {e}"))?
+                    .into(),
+            ),
+        });
+    }
+    write(&objects).map_err(|e| format!("failed to write document: {e}").into())
+}
+
+/// Sets the size and encoding of an image dictionary to those of the image
+pub fn set_image_entries(dict: &mut Dictionary, image: &PageImage) -> Result<(), ParseError> {
+    let (color_space, bits, filter): (&[u8], i64, &[u8]) = match image.format {
+        ImageFormat::Jpeg => {
+            let frame = jpeg_frame(&image.data)?;
+            let color_space: &[u8] = match frame.components.len() {
+                1 => b"DeviceGray",
+                3 => b"DeviceRGB",
+                n => return Err(format!("images with {n} components are not supported").into()),
+            };
+            (color_space, 8, b"DCTDecode")
+        }
+        ImageFormat::Bilevel => (b"DeviceGray", 1, b"CCITTFaxDecode"),
+    };
+    dict.set(b"Width", Object::integer(image.width as i64));
+    dict.set(b"Height", Object::integer(image.height as i64));
+    dict.set(b"ColorSpace", Object::Name(color_space.to_vec()));
+    dict.set(b"BitsPerComponent", Object::integer(bits));
+    dict.set(b"Filter", Object::Name(filter.to_vec()));
+    for key in [&b"DecodeParms"[..], b"Decode"] {
+        dict.remove(key);
+    }
+    if image.format == ImageFormat::Bilevel {
+        // Group 4 coding, black decodes to 0 as in DeviceGray
+        let mut parameters = Dictionary::new();
+        parameters.set(b"K", Object::integer(-1));
+        parameters.set(b"Columns", Object::integer(image.width as i64));
+        parameters.set(b"Rows", Object::integer(image.height as i64));
+        dict.set(b"DecodeParms", Object::Dictionary(parameters));
+    }
+    // the length is written with the stream, an indirect one is no longer needed
+    dict.remove(b"Length");
+    Ok(())
+}
+
+fn jpeg_frame(data: &Bytes) -> Result<FrameHeader, ParseError> {
+    Jpeg::from_bytes(data.clone())
+        .and_then(|jpeg| jpeg.frame())
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| "image has no frame header".to_owned().into())
+}
+
+/// Converts a length in pixels into points
+pub fn points(pixels: usize, resolution: u32) -> f64 {
+    pixels as f64 * 72.0 / f64::from(resolution.max(1))
+}
+
+pub fn media_box(width: f64, height: f64) -> Object {
+    Object::Array(
+        [0.0, 0.0, width, height]
+            .iter()
+            .map(|value| Object::Number(format!("{value:.2}")))
+            .collect(),
+    )
+}
+
+/// Returns a content stream which draws the image over the whole page
+pub fn image_content(name: &[u8], width: f64, height: f64) -> std::io::Result<Vec<u8>> {
+    let mut content = Vec::new();
+    write!(content, "q {width:.2} 0 0 {height:.2} 0 0 cm ")?;
+    Object::Name(name.to_vec()).write(&mut content)?;
+    writeln!(content, " Do Q")?;
+    Ok(content)
+}
+
+/// Writes the objects numbered from 1 with a cross-reference table
+fn write(objects: &[Replacement]) -> std::io::Result<Bytes> {
+    let mut output = b"%PDF-1.4\n%\xe2\xe3\xcf\xd3\n".to_vec();
+    let mut offsets = Vec::with_capacity(objects.len());
+    for (index, object) in objects.iter().enumerate() {
+        offsets.push(output.len());
+        write_replacement(&mut output, index as u32 + 1, 0, object)?;
+    }
+    let xref_offset = output.len();
+    let size = objects.len() + 1;
+    write!(output, "xref\n0 {size}\n0000000000 65535 f\r\n")?;
+    for offset in offsets {
+        write!(output, "{offset:010} 00000 n\r\n")?;
+    }
+    let trailer = Dictionary::from([
+        (&b"Size"[..], Object::integer(size as i64)),
+        (b"Root", Object::Reference(1, 0)),
+    ]);
+    output.extend_from_slice(b"trailer\n");
+    trailer.write(&mut output)?;
+    write!(output, "\nstartxref\n{xref_offset}\n%%EOF\n")?;
+    Ok(output.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::jpeg;
+    use crate::pdf::{page_jpegs, pages, reader::Document};
+
+    #[test]
+    fn write_document_of_images() {
+        let images = [jpeg::blank_page(), jpeg::test_page(|x, _| x < 10)];
+        let pages: Vec<JpegPage> = images
+            .iter()
+            .map(|data| JpegPage {
+                data: data.clone(),
+                resolution: 300,
+            })
+            .collect();
+        let data = jpeg_document(&pages).unwrap();
+
+        assert_eq!(
+            page_jpegs(data.clone()).unwrap(),
+            vec![vec![images[0].clone()], vec![images[1].clone()]]
+        );
+        let document = Document::parse(data).unwrap();
+        let pages = pages::pages(&document).unwrap();
+        assert_eq!(pages[1].media_box, Some([0.0, 0.0, 595.2, 837.6]));
+        let image = document.dictionary(pages[1].images[0]).unwrap();
+        assert_eq!(image.get(b"Height"), Some(&Object::integer(3490)));
+        assert_eq!(
+            image.get(b"ColorSpace"),
+            Some(&Object::Name(b"DeviceRGB".to_vec()))
+        );
+    }
+
+    #[test]
+    fn write_empty_document() {
+        let document = Document::parse(jpeg_document(&[]).unwrap()).unwrap();
+        assert!(pages::pages(&document).unwrap().is_empty());
+    }
+}
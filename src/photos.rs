@@ -0,0 +1,331 @@
+use bytes::Bytes;
+use tracing::{debug, info};
+
+use std::collections::VecDeque;
+
+use crate::jpeg::{self, ParseError};
+use crate::raster::{GrayImage, RotatedRect};
+
+/// Resolution in dpi at which the glass is searched for photos
+const ANALYSIS_RESOLUTION: u32 = 75;
+/// Pixels which differ less from the background belong to it
+const CONTRAST: u8 = 24;
+/// Gaps in millimeters within a photo, like light areas close to its edge,
+/// are bridged. Photos have to be placed further apart.
+const GAP: f64 = 1.5;
+/// Objects with a shorter side in millimeters are dust or scraps
+const MIN_SIZE: f64 = 20.0;
+/// Cut off all around each photo in millimeters, where its edge blends
+/// with the background
+const INSET: f64 = 0.5;
+/// Orientations in degrees which are tried first, refined around the best one
+const COARSE_STEP: f64 = 1.0;
+const FINE_STEP: f64 = 0.1;
+
+/// Cuts the photos on a scan of the glass into separate upright images, in
+/// reading order. Returns an empty list if no photo is found.
+pub fn split(data: Bytes, resolution: u32) -> Result<Vec<Bytes>, ParseError> {
+    let image = jpeg::decode_gray(data.clone())?;
+    let photos = find_photos(&image, resolution);
+    info!("Found {} photo(s)", photos.len());
+    photos
+        .into_iter()
+        .map(|photo| {
+            debug!(
+                "Photo of {}x{} at {:.0},{:.0} rotated by {:.2}°",
+                photo.width,
+                photo.height,
+                photo.center_x,
+                photo.center_y,
+                photo.angle.to_degrees()
+            );
+            jpeg::extract_area(data.clone(), photo)
+        })
+        .collect()
+}
+
+/// Finds the rectangular objects which stand out from the background. The
+/// areas are given in pixels of the image and turn the photos upright when
+/// their orientation is within 45° of it.
+pub fn find_photos(image: &GrayImage, resolution: u32) -> Vec<RotatedRect> {
+    if image.width == 0 || image.height == 0 {
+        return Vec::new();
+    }
+    let factor = (resolution / ANALYSIS_RESOLUTION).max(1) as usize;
+    let small = image.downscale(factor);
+    let pixels_per_mm = f64::from(resolution) / factor as f64 / 25.4;
+    let background = small.border_median();
+    let mask: Vec<bool> = small
+        .pixels
+        .iter()
+        .map(|pixel| pixel.abs_diff(background) > CONTRAST)
+        .collect();
+    let radius = (GAP * pixels_per_mm).round() as usize;
+    let joined = dilate(&mask, small.width, small.height, radius);
+    let min_size = MIN_SIZE * pixels_per_mm;
+    let mut photos: Vec<RotatedRect> = components(&joined, small.width, small.height)
+        .iter()
+        .filter_map(|component| {
+            let outline = outline(component, &mask, small.width, small.height);
+            let rect = min_area_rect(&outline)?;
+            if (rect.width.min(rect.height) as f64) < min_size {
+                return None;
+            }
+            // the center of a small pixel in pixels of the image
+            let scale = |value: f64| (value + 0.5) * factor as f64 - 0.5;
+            let inset = 2.0 * INSET * pixels_per_mm * factor as f64;
+            let size = |value: usize| ((value * factor) as f64 - inset).round().max(1.0) as usize;
+            Some(RotatedRect {
+                center_x: scale(rect.center_x),
+                center_y: scale(rect.center_y),
+                width: size(rect.width),
+                height: size(rect.height),
+                angle: rect.angle,
+            })
+        })
+        .collect();
+    reading_order(&mut photos);
+    photos
+}
+
+/// Grows the set pixels of the mask by `radius` in every direction
+fn dilate(mask: &[bool], width: usize, height: usize, radius: usize) -> Vec<bool> {
+    let mut rows = vec![false; mask.len()];
+    for y in 0..height {
+        let row = &mask[y * width..(y + 1) * width];
+        for x in 0..width {
+            let range = x.saturating_sub(radius)..(x + radius + 1).min(width);
+            rows[y * width + x] = row[range].contains(&true);
+        }
+    }
+    let mut result = vec![false; mask.len()];
+    for x in 0..width {
+        for y in 0..height {
+            let range = y.saturating_sub(radius)..(y + radius + 1).min(height);
+            result[y * width + x] = range.into_iter().any(|y| rows[y * width + x]);
+        }
+    }
+    result
+}
+
+/// Returns the pixel indices of each group of connected set pixels
+fn components(mask: &[bool], width: usize, height: usize) -> Vec<Vec<usize>> {
+    let mut visited = vec![false; mask.len()];
+    let mut components = Vec::new();
+    let mut pending = VecDeque::new();
+    for start in 0..mask.len() {
+        if !mask[start] || visited[start] {
+            continue;
+        }
+        visited[start] = true;
+        pending.push_back(start);
+        let mut component = Vec::new();
+        while let Some(index) = pending.pop_front() {
+            component.push(index);
+            let (x, y) = (index % width, index / width);
+            let neighbours = [
+                (x > 0).then(|| index - 1),
+                (x + 1 < width).then(|| index + 1),
+                (y > 0).then(|| index - width),
+                (y + 1 < height).then(|| index + width),
+            ];
+            for neighbour in neighbours.into_iter().flatten() {
+                if mask[neighbour] && !visited[neighbour] {
+                    visited[neighbour] = true;
+                    pending.push_back(neighbour);
+                }
+            }
+        }
+        components.push(component);
+    }
+    components
+}
+
+/// Returns the positions of the pixels of the component which are set in the
+/// mask and lie next to the background
+fn outline(component: &[usize], mask: &[bool], width: usize, height: usize) -> Vec<(f64, f64)> {
+    component
+        .iter()
+        .filter(|index| mask[**index])
+        .map(|index| (index % width, index / width))
+        .filter(|(x, y)| {
+            *x == 0
+                || *y == 0
+                || *x + 1 == width
+                || *y + 1 == height
+                || !mask[y * width + x - 1]
+                || !mask[y * width + x + 1]
+                || !mask[(y - 1) * width + x]
+                || !mask[(y + 1) * width + x]
+        })
+        .map(|(x, y)| (x as f64, y as f64))
+        .collect()
+}
+
+/// Returns the rotated rectangle with the smallest area which contains all
+/// points. Its angle is between -45° and 45°.
+fn min_area_rect(points: &[(f64, f64)]) -> Option<RotatedRect> {
+    // bounds of the points along the axes of a rectangle rotated by `degrees`
+    let bounds = |degrees: f64| {
+        let (sin, cos) = f64::to_radians(degrees).sin_cos();
+        let mut u = (f64::MAX, f64::MIN);
+        let mut v = (f64::MAX, f64::MIN);
+        for (x, y) in points {
+            let (pu, pv) = (x * cos + y * sin, -x * sin + y * cos);
+            u = (u.0.min(pu), u.1.max(pu));
+            v = (v.0.min(pv), v.1.max(pv));
+        }
+        (degrees, u, v)
+    };
+    let area = |(_, u, v): &(f64, (f64, f64), (f64, f64))| (u.1 - u.0 + 1.0) * (v.1 - v.0 + 1.0);
+    let smallest = |candidates: Vec<_>| {
+        candidates
+            .into_iter()
+            .min_by(|a, b| area(a).total_cmp(&area(b)))
+    };
+    if points.is_empty() {
+        return None;
+    }
+    let steps = (45.0 / COARSE_STEP) as i32;
+    let (coarse, _, _) = smallest(
+        (-steps..steps)
+            .map(|step| bounds(f64::from(step) * COARSE_STEP))
+            .collect(),
+    )?;
+    let fine_steps = (COARSE_STEP / FINE_STEP).round() as i32;
+    let (degrees, u, v) = smallest(
+        (-fine_steps..=fine_steps)
+            .map(|step| bounds(coarse + f64::from(step) * FINE_STEP))
+            .filter(|(degrees, _, _)| (-45.0..45.0).contains(degrees))
+            .collect(),
+    )?;
+    let (sin, cos) = f64::to_radians(degrees).sin_cos();
+    let (center_u, center_v) = ((u.0 + u.1) / 2.0, (v.0 + v.1) / 2.0);
+    Some(RotatedRect {
+        center_x: center_u * cos - center_v * sin,
+        center_y: center_u * sin + center_v * cos,
+        width: (u.1 - u.0 + 1.0).round() as usize,
+        height: (v.1 - v.0 + 1.0).round() as usize,
+        angle: degrees.to_radians(),
+    })
+}
+
+/// Sorts the photos in rows from top to bottom and from left to right within
+/// each row. A photo starts a new row if its center is below the first photo
+/// of the current row.
+fn reading_order(photos: &mut [RotatedRect]) {
+    photos.sort_by(|a, b| a.center_y.total_cmp(&b.center_y));
+    let mut start = 0;
+    while start < photos.len() {
+        let first = photos[start];
+        let bottom = first.center_y + first.height.max(first.width) as f64 / 2.0;
+        let end = photos[start..]
+            .iter()
+            .position(|photo| photo.center_y > bottom)
+            .map_or(photos.len(), |offset| start + offset);
+        photos[start..end].sort_by(|a, b| a.center_x.total_cmp(&b.center_x));
+        start = end;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Draws a photo with a light sky above a dark ground
+    fn draw_photo(image: &mut GrayImage, photo: &RotatedRect) {
+        let (sin, cos) = photo.angle.sin_cos();
+        for y in 0..image.height {
+            for x in 0..image.width {
+                let (dx, dy) = (x as f64 - photo.center_x, y as f64 - photo.center_y);
+                let (u, v) = (dx * cos + dy * sin, -dx * sin + dy * cos);
+                if u.abs() * 2.0 < photo.width as f64 && v.abs() * 2.0 < photo.height as f64 {
+                    image.set(x, y, if v < 0.0 { 180 } else { 60 });
+                }
+            }
+        }
+    }
+
+    fn photo(
+        center_x: f64,
+        center_y: f64,
+        width: usize,
+        height: usize,
+        degrees: f64,
+    ) -> RotatedRect {
+        RotatedRect {
+            center_x,
+            center_y,
+            width,
+            height,
+            angle: degrees.to_radians(),
+        }
+    }
+
+    #[test]
+    fn find_rotated_photos() {
+        let mut image = GrayImage::new(620, 870, 250);
+        let photos = [
+            photo(170.0, 200.0, 180, 120, 4.0),
+            photo(450.0, 220.0, 150, 210, -7.5),
+            photo(300.0, 600.0, 300, 200, 0.0),
+        ];
+        for photo in &photos {
+            draw_photo(&mut image, photo);
+        }
+        // dust is ignored
+        image.set(600, 850, 0);
+        let found = find_photos(&image, 75);
+        assert_eq!(found.len(), 3, "{found:?}");
+        for (expected, found) in photos.iter().zip(&found) {
+            assert!(
+                (expected.center_x - found.center_x).abs() < 1.5,
+                "{found:?}"
+            );
+            assert!(
+                (expected.center_y - found.center_y).abs() < 1.5,
+                "{found:?}"
+            );
+            // the edges are cut off by about 3 pixels
+            assert!(expected.width.abs_diff(found.width) <= 5, "{found:?}");
+            assert!(expected.height.abs_diff(found.height) <= 5, "{found:?}");
+            assert!(
+                (expected.angle - found.angle).abs().to_degrees() <= 0.15,
+                "{found:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn find_nothing_on_empty_glass() {
+        let image = GrayImage::new(620, 870, 250);
+        assert!(find_photos(&image, 75).is_empty());
+    }
+
+    #[test]
+    fn order_photos_in_rows() {
+        let mut photos = [
+            photo(400.0, 110.0, 100, 100, 0.0),
+            photo(100.0, 400.0, 100, 100, 0.0),
+            photo(100.0, 100.0, 100, 100, 0.0),
+        ];
+        reading_order(&mut photos);
+        let centers: Vec<(f64, f64)> = photos.iter().map(|p| (p.center_x, p.center_y)).collect();
+        assert_eq!(centers, [(100.0, 100.0), (400.0, 110.0), (100.0, 400.0)]);
+    }
+
+    #[test]
+    fn split_scanned_photos() {
+        // two dark squares of 40 by 30 blocks
+        let page = jpeg::test_page(|x, y| {
+            (y > 20 && y <= 50) && ((10..50).contains(&x) || (100..140).contains(&x))
+        });
+        let photos = split(page, 300).unwrap();
+        assert_eq!(photos.len(), 2);
+        for photo in photos {
+            let image = jpeg::decode_gray(photo).unwrap();
+            assert!(image.width.abs_diff(320) <= 12, "{}", image.width);
+            assert!(image.height.abs_diff(240) <= 12, "{}", image.height);
+        }
+    }
+}
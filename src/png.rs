@@ -0,0 +1,201 @@
+use bytes::Bytes;
+use flate2::write::ZlibEncoder;
+use flate2::{Compression, Crc};
+
+use std::io::Write;
+
+use crate::color::{self, BilevelPages, DecodedPage};
+use crate::jpeg::ParseError;
+use crate::raster::{GrayImage, Image};
+
+const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Color types of the header
+const GRAYSCALE: u8 = 0;
+const TRUECOLOR: u8 = 2;
+
+/// Filter types at the start of each row
+const FILTER_NONE: u8 = 0;
+const FILTER_UP: u8 = 2;
+
+/// Decodes a JPEG scan into a PNG file with the resolution in dpi
+pub fn from_jpeg(data: Bytes, resolution: u32, bilevel: BilevelPages) -> Result<Bytes, ParseError> {
+    let png = match color::decode_page(data, bilevel)? {
+        DecodedPage::Bilevel { image, threshold } => encode_bilevel(&image, threshold, resolution),
+        DecodedPage::Samples(image) => encode(&image, resolution),
+    };
+    Ok(png)
+}
+
+/// Encodes an 8 bit grayscale or color image
+pub fn encode(image: &Image, resolution: u32) -> Bytes {
+    let color_type = match image.channels {
+        1 => GRAYSCALE,
+        _ => TRUECOLOR,
+    };
+    let row_len = image.width * image.channels;
+    let mut filtered = Vec::with_capacity((row_len + 1) * image.height);
+    for y in 0..image.height {
+        // the difference to the row above compresses well for scanned paper
+        let row = &image.samples[y * row_len..(y + 1) * row_len];
+        filtered.push(FILTER_UP);
+        match y {
+            0 => filtered.extend_from_slice(row),
+            _ => {
+                let above = &image.samples[(y - 1) * row_len..y * row_len];
+                filtered.extend(row.iter().zip(above).map(|(a, b)| a.wrapping_sub(*b)));
+            }
+        }
+    }
+    write(
+        image.width,
+        image.height,
+        8,
+        color_type,
+        &filtered,
+        resolution,
+    )
+}
+
+/// Encodes a black and white image with one bit per pixel. Pixels up to and
+/// including the threshold become black.
+pub fn encode_bilevel(image: &GrayImage, threshold: u8, resolution: u32) -> Bytes {
+    let row_len = image.width.div_ceil(8);
+    let mut filtered = vec![0u8; (row_len + 1) * image.height];
+    for (y, row) in filtered.chunks_exact_mut(row_len + 1).enumerate() {
+        row[0] = FILTER_NONE;
+        for x in 0..image.width {
+            // 1 is white
+            if image.get(x, y) > threshold {
+                row[1 + x / 8] |= 0x80 >> (x % 8);
+            }
+        }
+    }
+    write(
+        image.width,
+        image.height,
+        1,
+        GRAYSCALE,
+        &filtered,
+        resolution,
+    )
+}
+
+fn write(
+    width: usize,
+    height: usize,
+    bit_depth: u8,
+    color_type: u8,
+    filtered: &[u8],
+    resolution: u32,
+) -> Bytes {
+    let mut output = SIGNATURE.to_vec();
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // no interlacing, the only compression and filter methods
+    header.extend_from_slice(&[bit_depth, color_type, 0, 0, 0]);
+    write_chunk(&mut output, b"IHDR", &header);
+    let pixels_per_meter = (f64::from(resolution) / 0.0254).round() as u32;
+    let mut physical = Vec::with_capacity(9);
+    physical.extend_from_slice(&pixels_per_meter.to_be_bytes());
+    physical.extend_from_slice(&pixels_per_meter.to_be_bytes());
+    // the unit is the meter
+    physical.push(1);
+    write_chunk(&mut output, b"pHYs", &physical);
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(filtered)
+        .expect("compressing into memory cannot fail");
+    let compressed = encoder
+        .finish()
+        .expect("compressing into memory cannot fail");
+    write_chunk(&mut output, b"IDAT", &compressed);
+    write_chunk(&mut output, b"IEND", &[]);
+    output.into()
+}
+
+fn write_chunk(output: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    output.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    output.extend_from_slice(chunk_type);
+    output.extend_from_slice(data);
+    let mut crc = Crc::new();
+    crc.update(chunk_type);
+    crc.update(data);
+    output.extend_from_slice(&crc.sum().to_be_bytes());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::read::ZlibDecoder;
+    use std::io::Read;
+
+    /// Returns the type and data of each chunk after checking its CRC
+    fn chunks(data: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+        assert_eq!(&data[..8], SIGNATURE);
+        let mut chunks = Vec::new();
+        let mut rest = &data[8..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            let chunk_type: [u8; 4] = rest[4..8].try_into().unwrap();
+            let chunk_data = rest[8..8 + len].to_vec();
+            let mut crc = Crc::new();
+            crc.update(&rest[4..8 + len]);
+            assert_eq!(rest[8 + len..12 + len], crc.sum().to_be_bytes());
+            chunks.push((chunk_type, chunk_data));
+            rest = &rest[12 + len..];
+        }
+        chunks
+    }
+
+    fn inflate(data: &[u8]) -> Vec<u8> {
+        let mut inflated = Vec::new();
+        ZlibDecoder::new(data).read_to_end(&mut inflated).unwrap();
+        inflated
+    }
+
+    #[test]
+    fn encode_color_image() {
+        let image = Image {
+            width: 2,
+            height: 2,
+            channels: 3,
+            samples: vec![10, 20, 30, 40, 50, 60, 11, 22, 33, 40, 50, 60],
+        };
+        let chunks = chunks(&encode(&image, 300));
+        let types: Vec<&[u8]> = chunks.iter().map(|(t, _)| &t[..]).collect();
+        assert_eq!(types, [b"IHDR", b"pHYs", b"IDAT", b"IEND"]);
+        assert_eq!(chunks[0].1, [0, 0, 0, 2, 0, 0, 0, 2, 8, 2, 0, 0, 0]);
+        // 300 dpi
+        assert_eq!(chunks[1].1, [0, 0, 46, 35, 0, 0, 46, 35, 1]);
+        assert_eq!(
+            inflate(&chunks[2].1),
+            [2, 10, 20, 30, 40, 50, 60, 2, 1, 2, 3, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn encode_bilevel_image() {
+        let mut image = GrayImage::new(10, 2, 200);
+        image.set(0, 0, 100);
+        image.set(9, 1, 50);
+        let chunks = chunks(&encode_bilevel(&image, 100, 600));
+        assert_eq!(chunks[0].1, [0, 0, 0, 10, 0, 0, 0, 2, 1, 0, 0, 0, 0]);
+        assert_eq!(inflate(&chunks[2].1), [0, 0x7f, 0xc0, 0, 0xff, 0x80]);
+    }
+
+    #[test]
+    fn convert_jpeg() {
+        let data = from_jpeg(jpeg_page(), 300, BilevelPages::None).unwrap();
+        let header = &chunks(&data)[0].1;
+        assert_eq!(header[..8], [0, 0, 0x09, 0xb0, 0, 0, 0x0d, 0xa2]);
+        assert_eq!(header[9], TRUECOLOR);
+        let data = from_jpeg(jpeg_page(), 300, BilevelPages::All { threshold: None }).unwrap();
+        assert_eq!(chunks(&data)[0].1[8..10], [1, GRAYSCALE]);
+    }
+
+    fn jpeg_page() -> Bytes {
+        crate::jpeg::test_page(|x, y| x < 10 && y < 10)
+    }
+}
@@ -0,0 +1,443 @@
+use crate::qr::reed_solomon;
+
+/// Error correction codewords per block, by error correction level (L, M, Q, H) and version
+const ECC_CODEWORDS_PER_BLOCK: [[u8; 41]; 4] = [
+    [
+        0, 7, 10, 15, 20, 26, 18, 20, 24, 30, 18, 20, 24, 26, 30, 22, 24, 28, 30, 28, 28, 28, 28,
+        30, 30, 26, 28, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30,
+    ],
+    [
+        0, 10, 16, 26, 18, 24, 16, 18, 22, 22, 26, 30, 22, 22, 24, 24, 28, 28, 26, 26, 26, 26, 28,
+        28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28,
+    ],
+    [
+        0, 13, 22, 18, 26, 18, 24, 18, 22, 20, 24, 28, 26, 24, 20, 30, 24, 28, 28, 26, 30, 28, 30,
+        30, 30, 30, 28, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30,
+    ],
+    [
+        0, 17, 28, 22, 16, 22, 28, 26, 26, 24, 28, 24, 28, 22, 24, 24, 30, 28, 28, 26, 28, 30, 24,
+        30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30,
+    ],
+];
+
+/// Number of error correction blocks, by error correction level (L, M, Q, H) and version
+const ECC_BLOCKS: [[u8; 41]; 4] = [
+    [
+        0, 1, 1, 1, 1, 1, 2, 2, 2, 2, 4, 4, 4, 4, 4, 6, 6, 6, 6, 7, 8, 8, 9, 9, 10, 12, 12, 12, 13,
+        14, 15, 16, 17, 18, 19, 19, 20, 21, 22, 24, 25,
+    ],
+    [
+        0, 1, 1, 1, 2, 2, 4, 4, 4, 5, 5, 5, 8, 9, 9, 10, 10, 11, 13, 14, 16, 17, 17, 18, 20, 21,
+        23, 25, 26, 28, 29, 31, 33, 35, 37, 38, 40, 43, 45, 47, 49,
+    ],
+    [
+        0, 1, 1, 2, 2, 4, 4, 6, 6, 8, 8, 8, 10, 12, 16, 12, 17, 16, 18, 21, 20, 23, 23, 25, 27, 29,
+        34, 34, 35, 38, 40, 43, 45, 48, 51, 53, 56, 59, 62, 65, 68,
+    ],
+    [
+        0, 1, 1, 2, 4, 4, 4, 5, 6, 8, 8, 11, 11, 16, 16, 18, 16, 19, 21, 25, 25, 25, 34, 30, 32,
+        35, 37, 40, 42, 45, 48, 51, 54, 57, 60, 63, 66, 70, 74, 77, 81,
+    ],
+];
+
+const ALPHANUMERIC: &[u8; 45] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ $%*+-./:";
+
+/// The modules of a QR code, `true` for dark modules
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Grid {
+    pub size: usize,
+    pub modules: Vec<bool>,
+}
+
+impl Grid {
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            modules: vec![false; size * size],
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> bool {
+        self.modules[y * self.size + x]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, dark: bool) {
+        self.modules[y * self.size + x] = dark;
+    }
+}
+
+/// Reads the payload of a QR code
+pub fn decode(grid: &Grid) -> Result<Vec<u8>, String> {
+    let version = version(grid.size)?;
+    let (level, mask) = read_format(grid)?;
+    let codewords = read_codewords(grid, version, mask);
+    let data = correct_blocks(version, level, &codewords)?;
+    decode_segments(version, &data)
+}
+
+pub fn version(size: usize) -> Result<usize, String> {
+    if size < 21 || (size - 17) % 4 != 0 || size > 177 {
+        return Err(format!("invalid size {size}"));
+    }
+    Ok((size - 17) / 4)
+}
+
+/// Returns the number of modules which contain codewords
+fn raw_data_modules(version: usize) -> usize {
+    let mut result = (16 * version + 128) * version + 64;
+    if version >= 2 {
+        let alignments = version / 7 + 2;
+        result -= (25 * alignments - 10) * alignments - 55;
+        if version >= 7 {
+            result -= 36;
+        }
+    }
+    result
+}
+
+/// Returns the row and column coordinates of the alignment pattern centers
+fn alignment_positions(version: usize) -> Vec<usize> {
+    if version == 1 {
+        return Vec::new();
+    }
+    let count = version / 7 + 2;
+    let size = version * 4 + 17;
+    let step = if version == 32 {
+        26
+    } else {
+        (version * 4 + count * 2 + 1) / (count * 2 - 2) * 2
+    };
+    let mut positions: Vec<usize> = (0..count - 1).map(|i| size - 7 - i * step).collect();
+    positions.push(6);
+    positions.reverse();
+    positions
+}
+
+/// Marks the finder, timing and alignment patterns and the format and version information
+fn function_modules(version: usize) -> Grid {
+    let size = version * 4 + 17;
+    let mut grid = Grid::new(size);
+    let mut fill = |x0: usize, y0: usize, width: usize, height: usize| {
+        for y in y0..y0 + height {
+            for x in x0..x0 + width {
+                grid.set(x, y, true);
+            }
+        }
+    };
+    fill(0, 0, 9, 9);
+    fill(size - 8, 0, 8, 9);
+    fill(0, size - 8, 9, 8);
+    fill(0, 6, size, 1);
+    fill(6, 0, 1, size);
+    let positions = alignment_positions(version);
+    let last = positions.len().saturating_sub(1);
+    for (i, y) in positions.iter().enumerate() {
+        for (j, x) in positions.iter().enumerate() {
+            let corner = [(0, 0), (0, last), (last, 0)].contains(&(i, j));
+            if !corner {
+                fill(x - 2, y - 2, 5, 5);
+            }
+        }
+    }
+    if version >= 7 {
+        fill(size - 11, 0, 3, 6);
+        fill(0, size - 11, 6, 3);
+    }
+    grid
+}
+
+fn format_bits(data: u32) -> u32 {
+    let mut remainder = data;
+    for _ in 0..10 {
+        remainder = (remainder << 1) ^ ((remainder >> 9) * 0x537);
+    }
+    ((data << 10) | remainder) ^ 0x5412
+}
+
+/// Reads both copies of the format information and returns the error
+/// correction level (0 to 3 for L, M, Q, H) and the mask pattern
+fn read_format(grid: &Grid) -> Result<(usize, usize), String> {
+    let size = grid.size;
+    let read = |positions: &[(usize, usize)]| {
+        positions
+            .iter()
+            .enumerate()
+            .fold(0u32, |bits, (i, (x, y))| {
+                bits | (u32::from(grid.get(*x, *y)) << i)
+            })
+    };
+    let mut first = Vec::new();
+    first.extend((0..6).map(|i| (8, i)));
+    first.extend([(8, 7), (8, 8), (7, 8)]);
+    first.extend((9..15).map(|i| (14 - i, 8)));
+    let mut second = Vec::new();
+    second.extend((0..8).map(|i| (size - 1 - i, 8)));
+    second.extend((8..15).map(|i| (8, size - 15 + i)));
+    let copies = [read(&first), read(&second)];
+
+    let (distance, data) = (0..32)
+        .flat_map(|data| copies.map(|bits| ((bits ^ format_bits(data)).count_ones(), data)))
+        .min()
+        .unwrap_or((u32::MAX, 0));
+    if distance > 3 {
+        return Err("unreadable format information".to_owned());
+    }
+    // the level is stored as 1, 0, 3, 2 for L, M, Q, H
+    let level = match data >> 3 {
+        1 => 0,
+        0 => 1,
+        3 => 2,
+        _ => 3,
+    };
+    Ok((level, (data & 7) as usize))
+}
+
+fn is_masked(mask: usize, x: usize, y: usize) -> bool {
+    match mask {
+        0 => (x + y) % 2 == 0,
+        1 => y % 2 == 0,
+        2 => x % 3 == 0,
+        3 => (x + y) % 3 == 0,
+        4 => (x / 3 + y / 2) % 2 == 0,
+        5 => x * y % 2 + x * y % 3 == 0,
+        6 => (x * y % 2 + x * y % 3) % 2 == 0,
+        _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+    }
+}
+
+/// Reads the codewords in the zigzag order from the bottom right corner
+fn read_codewords(grid: &Grid, version: usize, mask: usize) -> Vec<u8> {
+    let size = grid.size;
+    let function = function_modules(version);
+    let count = raw_data_modules(version) / 8;
+    let mut codewords = vec![0u8; count];
+    let mut bit = 0;
+    let mut right = size - 1;
+    while right >= 1 {
+        if right == 6 {
+            right = 5;
+        }
+        let upward = (right + 1) & 2 == 0;
+        for vertical in 0..size {
+            let y = if upward {
+                size - 1 - vertical
+            } else {
+                vertical
+            };
+            for x in [right, right - 1] {
+                if function.get(x, y) || bit >= count * 8 {
+                    continue;
+                }
+                if grid.get(x, y) != is_masked(mask, x, y) {
+                    codewords[bit / 8] |= 0x80 >> (bit % 8);
+                }
+                bit += 1;
+            }
+        }
+        if right < 2 {
+            break;
+        }
+        right -= 2;
+    }
+    codewords
+}
+
+/// Splits the interleaved codewords into blocks, corrects them and returns the data codewords
+fn correct_blocks(version: usize, level: usize, codewords: &[u8]) -> Result<Vec<u8>, String> {
+    let ecc = usize::from(ECC_CODEWORDS_PER_BLOCK[level][version]);
+    let block_count = usize::from(ECC_BLOCKS[level][version]);
+    let short_blocks = block_count - codewords.len() % block_count;
+    let short_len = codewords.len() / block_count;
+    let mut blocks: Vec<Vec<u8>> = (0..block_count)
+        .map(|i| Vec::with_capacity(short_len + usize::from(i >= short_blocks)))
+        .collect();
+    let mut input = codewords.iter();
+    for i in 0..=short_len {
+        for (j, block) in blocks.iter_mut().enumerate() {
+            // short blocks have one data codeword less
+            if i != short_len - ecc || j >= short_blocks {
+                block.push(*input.next().ok_or("missing codewords")?);
+            }
+        }
+    }
+    let mut data = Vec::new();
+    for block in &mut blocks {
+        reed_solomon::correct(block, ecc)?;
+        data.extend_from_slice(&block[..block.len() - ecc]);
+    }
+    Ok(data)
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl BitReader<'_> {
+    fn remaining(&self) -> usize {
+        self.data.len() * 8 - self.pos
+    }
+
+    fn read(&mut self, bits: usize) -> Result<u32, String> {
+        if bits > self.remaining() {
+            return Err("unexpected end of data".to_owned());
+        }
+        let mut value = 0;
+        for _ in 0..bits {
+            let bit = (self.data[self.pos / 8] >> (7 - self.pos % 8)) & 1;
+            value = (value << 1) | u32::from(bit);
+            self.pos += 1;
+        }
+        Ok(value)
+    }
+}
+
+/// Decodes the numeric, alphanumeric, byte and kanji segments of the data
+fn decode_segments(version: usize, data: &[u8]) -> Result<Vec<u8>, String> {
+    // bits of the character count of numeric, alphanumeric, byte and kanji segments
+    let count_bits = match version {
+        1..=9 => [10, 9, 8, 8],
+        10..=26 => [12, 11, 16, 10],
+        _ => [14, 13, 16, 12],
+    };
+    let mut reader = BitReader { data, pos: 0 };
+    let mut payload = Vec::new();
+    while reader.remaining() >= 4 {
+        match reader.read(4)? {
+            0 => break,
+            1 => {
+                let mut count = reader.read(count_bits[0])?;
+                while count > 0 {
+                    let digits = count.min(3);
+                    let value = reader.read([0, 4, 7, 10][digits as usize])?;
+                    let text = format!("{value:0width$}", width = digits as usize);
+                    if text.len() != digits as usize {
+                        return Err("invalid numeric segment".to_owned());
+                    }
+                    payload.extend_from_slice(text.as_bytes());
+                    count -= digits;
+                }
+            }
+            2 => {
+                let mut count = reader.read(count_bits[1])?;
+                while count > 0 {
+                    if count >= 2 {
+                        let value = reader.read(11)? as usize;
+                        let (first, second) = (value / 45, value % 45);
+                        let first = ALPHANUMERIC.get(first).ok_or("invalid character")?;
+                        payload.extend_from_slice(&[*first, ALPHANUMERIC[second]]);
+                        count -= 2;
+                    } else {
+                        let value = reader.read(6)? as usize;
+                        payload.push(*ALPHANUMERIC.get(value).ok_or("invalid character")?);
+                        count -= 1;
+                    }
+                }
+            }
+            4 => {
+                let count = reader.read(count_bits[2])?;
+                for _ in 0..count {
+                    payload.push(reader.read(8)? as u8);
+                }
+            }
+            7 => {
+                // the extended channel interpretation is ignored
+                let first = reader.read(8)?;
+                if first & 0x80 != 0 {
+                    let more = if first & 0x40 == 0 { 8 } else { 16 };
+                    reader.read(more)?;
+                }
+            }
+            8 => {
+                // kanji characters are returned as Shift JIS
+                let count = reader.read(count_bits[3])?;
+                for _ in 0..count {
+                    let value = reader.read(13)?;
+                    let code = ((value / 0xc0) << 8) | (value % 0xc0);
+                    let code = if code < 0x1f00 {
+                        code + 0x8140
+                    } else {
+                        code + 0xc140
+                    };
+                    payload.extend_from_slice(&(code as u16).to_be_bytes());
+                }
+            }
+            mode => return Err(format!("unsupported mode {mode}")),
+        }
+    }
+    Ok(payload)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use qrcode::{Color, EcLevel, QrCode, Version};
+
+    /// Returns the modules of a generated QR code
+    pub fn grid(code: &QrCode) -> Grid {
+        Grid {
+            size: code.width(),
+            modules: code.to_colors().iter().map(|c| *c == Color::Dark).collect(),
+        }
+    }
+
+    #[test]
+    fn function_modules_match_generated_code() {
+        for version in [1, 2, 7, 14, 32, 40] {
+            let code = QrCode::with_version(b"x", Version::Normal(version), EcLevel::L).unwrap();
+            let function = function_modules(version as usize);
+            let size = code.width();
+            // the generator does not count the version information as function modules
+            let version_information = |x: usize, y: usize| {
+                version >= 7
+                    && ((x + 11 >= size && x + 8 < size && y < 6)
+                        || (y + 11 >= size && y + 8 < size && x < 6))
+            };
+            for y in 0..size {
+                for x in 0..size {
+                    assert_eq!(
+                        function.get(x, y),
+                        code.is_functional(x, y) || version_information(x, y),
+                        "version {version} at ({x}, {y})"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn decode_all_versions_and_levels() {
+        let levels = [EcLevel::L, EcLevel::M, EcLevel::Q, EcLevel::H];
+        for version in 1..=40 {
+            for level in levels {
+                let text = format!("covet {version} {level:?} 0123456789 SEPARATOR/ÄÖ");
+                let code = QrCode::with_version(text.as_bytes(), Version::Normal(version), level);
+                let Ok(code) = code else { continue };
+                assert_eq!(
+                    decode(&grid(&code)).unwrap(),
+                    text.as_bytes(),
+                    "version {version} {level:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn decode_numeric_and_alphanumeric() {
+        for text in ["0123456789012", "INVOICE-2026 / 42"] {
+            let code = QrCode::with_error_correction_level(text, EcLevel::M).unwrap();
+            assert_eq!(decode(&grid(&code)).unwrap(), text.as_bytes());
+        }
+    }
+
+    #[test]
+    fn correct_damaged_modules() {
+        let code = QrCode::with_error_correction_level("damaged", EcLevel::H).unwrap();
+        let mut grid = grid(&code);
+        for i in 0..6 {
+            let (x, y) = (10 + i, 12);
+            grid.set(x, y, !grid.get(x, y));
+        }
+        assert_eq!(decode(&grid).unwrap(), b"damaged");
+    }
+}
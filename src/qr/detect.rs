@@ -0,0 +1,334 @@
+use std::cmp::Reverse;
+
+use crate::qr::decode::Grid;
+use crate::raster::GrayImage;
+
+/// Only the finder pattern candidates found most often are combined
+const MAX_CANDIDATES: usize = 12;
+
+/// The image divided into dark and light pixels
+struct Binary<'a> {
+    image: &'a GrayImage,
+    threshold: u8,
+}
+
+impl Binary<'_> {
+    fn is_dark(&self, x: i64, y: i64) -> bool {
+        x >= 0
+            && y >= 0
+            && (x as usize) < self.image.width
+            && (y as usize) < self.image.height
+            && self.image.get(x as usize, y as usize) <= self.threshold
+    }
+}
+
+/// The center of a finder pattern, the 7x7 squares in three corners of a code
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct FinderPattern {
+    x: f64,
+    y: f64,
+    module_size: f64,
+    hits: usize,
+}
+
+impl FinderPattern {
+    fn distance(&self, other: &FinderPattern) -> f64 {
+        (self.x - other.x).hypot(self.y - other.y)
+    }
+}
+
+/// Finds possible QR codes in the image and samples their modules. The codes
+/// are expected to be flat and roughly square, like printed codes on a scanned page.
+pub fn find_codes(image: &GrayImage) -> Vec<Grid> {
+    let binary = Binary {
+        image,
+        threshold: image.otsu_threshold(),
+    };
+    let mut patterns = find_finder_patterns(&binary);
+    patterns.retain(|p| p.hits >= 2);
+    patterns.sort_by_key(|p| Reverse(p.hits));
+    patterns.truncate(MAX_CANDIDATES);
+
+    let mut grids = Vec::new();
+    for (i, a) in patterns.iter().enumerate() {
+        for (j, b) in patterns.iter().enumerate().skip(i + 1) {
+            for c in patterns.iter().skip(j + 1) {
+                if let Some(corners) = arrange(*a, *b, *c) {
+                    grids.extend(sample_grids(&binary, corners));
+                }
+            }
+        }
+    }
+    grids
+}
+
+/// Checks if the run lengths of dark, light, dark, light and dark pixels
+/// have the proportions 1:1:3:1:1 of a finder pattern
+fn is_finder_ratio(counts: &[usize; 5]) -> bool {
+    let total: usize = counts.iter().sum();
+    if total < 7 {
+        return false;
+    }
+    let module = total as f64 / 7.0;
+    let tolerance = module / 2.0;
+    counts
+        .iter()
+        .zip([1.0, 1.0, 3.0, 1.0, 1.0])
+        .all(|(count, expected)| (*count as f64 - module * expected).abs() < tolerance * expected)
+}
+
+fn find_finder_patterns(binary: &Binary) -> Vec<FinderPattern> {
+    let mut patterns: Vec<FinderPattern> = Vec::new();
+    for y in 0..binary.image.height as i64 {
+        let mut counts = [0usize; 5];
+        let mut state = 0;
+        for x in 0..binary.image.width as i64 {
+            if binary.is_dark(x, y) {
+                if state % 2 == 1 {
+                    state += 1;
+                }
+                counts[state] += 1;
+            } else if state % 2 == 1 {
+                counts[state] += 1;
+            } else if counts[0] > 0 {
+                if state < 4 {
+                    state += 1;
+                    counts[state] += 1;
+                    continue;
+                }
+                if is_finder_ratio(&counts) {
+                    let center_x =
+                        x as f64 - (counts[4] + counts[3]) as f64 - counts[2] as f64 / 2.0;
+                    if let Some(pattern) = cross_check(binary, center_x, y, counts.iter().sum()) {
+                        add_pattern(&mut patterns, pattern);
+                    }
+                }
+                counts = [counts[2], counts[3], counts[4], 1, 0];
+                state = 3;
+            }
+        }
+    }
+    patterns
+}
+
+/// Counts the runs through the center of a possible finder pattern along a
+/// direction and returns the position of the center along it
+fn measure(
+    binary: &Binary,
+    (x, y): (i64, i64),
+    (dx, dy): (i64, i64),
+    max_count: usize,
+) -> Option<([usize; 5], f64)> {
+    let mut counts = [0usize; 5];
+    let run = |from: usize, step: i64, counts: &mut [usize; 5], reach: &mut i64| {
+        let mut state = from;
+        let mut i = step;
+        loop {
+            let dark = binary.is_dark(x + i * dx, y + i * dy);
+            let expected_dark = state % 2 == 0;
+            if dark != expected_dark {
+                if state == 0 || state == 4 {
+                    break;
+                }
+                state = if step > 0 { state + 1 } else { state - 1 };
+                continue;
+            }
+            counts[state] += 1;
+            if counts[state] > max_count {
+                return false;
+            }
+            i += step;
+        }
+        *reach = i;
+        true
+    };
+    if !binary.is_dark(x, y) {
+        return None;
+    }
+    counts[2] = 1;
+    let (mut start, mut end) = (0, 0);
+    if !run(2, -1, &mut counts, &mut start) || !run(2, 1, &mut counts, &mut end) {
+        return None;
+    }
+    if !is_finder_ratio(&counts) {
+        return None;
+    }
+    let center = (end - counts[4] as i64 - counts[3] as i64) as f64 - counts[2] as f64 / 2.0;
+    Some((counts, center))
+}
+
+fn cross_check(binary: &Binary, center_x: f64, y: i64, total: usize) -> Option<FinderPattern> {
+    let x = center_x as i64;
+    let (vertical, offset_y) = measure(binary, (x, y), (0, 1), total)?;
+    let center_y = y as f64 + offset_y;
+    let (horizontal, offset_x) = measure(binary, (x, center_y as i64), (1, 0), total)?;
+    let vertical_total: usize = vertical.iter().sum();
+    let horizontal_total: usize = horizontal.iter().sum();
+    // both directions have to agree with the row the pattern was found in
+    for size in [vertical_total, horizontal_total] {
+        if size.abs_diff(total) * 5 >= total * 2 {
+            return None;
+        }
+    }
+    Some(FinderPattern {
+        x: x as f64 + offset_x,
+        y: center_y,
+        module_size: (vertical_total + horizontal_total) as f64 / 14.0,
+        hits: 1,
+    })
+}
+
+fn add_pattern(patterns: &mut Vec<FinderPattern>, pattern: FinderPattern) {
+    let existing = patterns.iter_mut().find(|p| {
+        p.distance(&pattern) <= p.module_size * 2.0
+            && (p.module_size - pattern.module_size).abs() <= p.module_size * 0.5
+    });
+    match existing {
+        Some(p) => {
+            let hits = p.hits as f64;
+            p.x = (p.x * hits + pattern.x) / (hits + 1.0);
+            p.y = (p.y * hits + pattern.y) / (hits + 1.0);
+            p.module_size = (p.module_size * hits + pattern.module_size) / (hits + 1.0);
+            p.hits += 1;
+        }
+        None => patterns.push(pattern),
+    }
+}
+
+/// Returns the patterns as top left, top right and bottom left corner if they
+/// form a right isosceles triangle
+fn arrange(a: FinderPattern, b: FinderPattern, c: FinderPattern) -> Option<[FinderPattern; 3]> {
+    let sizes = [a.module_size, b.module_size, c.module_size];
+    let max = sizes.iter().cloned().fold(f64::MIN, f64::max);
+    let min = sizes.iter().cloned().fold(f64::MAX, f64::min);
+    if max > min * 1.4 {
+        return None;
+    }
+    // the top left corner is opposite of the longest side
+    let (top_left, p, q) = {
+        let (ab, ac, bc) = (a.distance(&b), a.distance(&c), b.distance(&c));
+        if bc >= ab && bc >= ac {
+            (a, b, c)
+        } else if ac >= ab {
+            (b, a, c)
+        } else {
+            (c, a, b)
+        }
+    };
+    let leg_p = top_left.distance(&p);
+    let leg_q = top_left.distance(&q);
+    if (leg_p - leg_q).abs() > leg_p.max(leg_q) * 0.15 || leg_p < 7.0 * min {
+        return None;
+    }
+    let dot = (p.x - top_left.x) * (q.x - top_left.x) + (p.y - top_left.y) * (q.y - top_left.y);
+    if (dot / (leg_p * leg_q)).abs() > 0.15 {
+        return None;
+    }
+    // with y pointing down, the top right corner is clockwise from the bottom left one
+    let cross = (p.x - top_left.x) * (q.y - top_left.y) - (p.y - top_left.y) * (q.x - top_left.x);
+    if cross > 0.0 {
+        Some([top_left, p, q])
+    } else {
+        Some([top_left, q, p])
+    }
+}
+
+/// Samples the modules for the versions closest to the estimated size of the code
+fn sample_grids(
+    binary: &Binary,
+    [top_left, top_right, bottom_left]: [FinderPattern; 3],
+) -> Vec<Grid> {
+    let module_size =
+        (top_left.module_size + top_right.module_size + bottom_left.module_size) / 3.0;
+    let distance = (top_left.distance(&top_right) + top_left.distance(&bottom_left)) / 2.0;
+    let estimate = ((distance / module_size + 7.0 - 17.0) / 4.0).round() as i64;
+    [0, -1, 1, -2, 2]
+        .iter()
+        .map(|delta| estimate + delta)
+        .filter(|version| (1..=40).contains(version))
+        .map(|version| {
+            let size = version as usize * 4 + 17;
+            let span = (size - 7) as f64;
+            let (ux, uy) = (
+                (top_right.x - top_left.x) / span,
+                (top_right.y - top_left.y) / span,
+            );
+            let (vx, vy) = (
+                (bottom_left.x - top_left.x) / span,
+                (bottom_left.y - top_left.y) / span,
+            );
+            let mut grid = Grid::new(size);
+            for row in 0..size {
+                for column in 0..size {
+                    // the finder pattern centers are in the middle of module 3
+                    let (c, r) = (column as f64 - 3.0, row as f64 - 3.0);
+                    let x = top_left.x + c * ux + r * vx;
+                    let y = top_left.y + c * uy + r * vy;
+                    let dark = binary.is_dark(x.round() as i64, y.round() as i64);
+                    grid.set(column, row, dark);
+                }
+            }
+            grid
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::qr::decode::{decode, test::grid};
+    use qrcode::{EcLevel, QrCode};
+
+    /// Draws the code with a quiet zone into an image with the given module size
+    fn render(grid: &Grid, module_size: usize, offset: (usize, usize), image: &mut GrayImage) {
+        for row in 0..grid.size {
+            for column in 0..grid.size {
+                let value = if grid.get(column, row) { 30 } else { 230 };
+                for y in 0..module_size {
+                    for x in 0..module_size {
+                        let x = offset.0 + column * module_size + x;
+                        let y = offset.1 + row * module_size + y;
+                        image.set(x, y, value);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn find_code_in_page() {
+        let code = QrCode::with_error_correction_level("covet separator", EcLevel::M).unwrap();
+        let mut image = GrayImage::new(600, 500, 230);
+        // some text-like noise
+        for x in (20..580).step_by(7) {
+            for y in 400..420 {
+                image.set(x, y, 40);
+            }
+        }
+        render(&grid(&code), 9, (150, 60), &mut image);
+        let payloads: Vec<Vec<u8>> = find_codes(&image)
+            .iter()
+            .filter_map(|grid| decode(grid).ok())
+            .collect();
+        assert_eq!(
+            payloads.first().map(Vec::as_slice),
+            Some(&b"covet separator"[..])
+        );
+    }
+
+    #[test]
+    fn find_rotated_code() {
+        let code = QrCode::with_error_correction_level("rotated", EcLevel::L).unwrap();
+        let mut upright = GrayImage::new(300, 300, 230);
+        render(&grid(&code), 6, (40, 50), &mut upright);
+        // rotate by 90 degrees clockwise
+        let mut image = GrayImage::new(300, 300, 230);
+        for y in 0..300 {
+            for x in 0..300 {
+                image.set(299 - y, x, upright.get(x, y));
+            }
+        }
+        let decoded = find_codes(&image).iter().find_map(|grid| decode(grid).ok());
+        assert_eq!(decoded.as_deref(), Some(&b"rotated"[..]));
+    }
+}
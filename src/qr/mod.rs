@@ -0,0 +1,12 @@
+use crate::raster::GrayImage;
+
+mod decode;
+mod detect;
+mod reed_solomon;
+
+/// Reads the payload of the first QR code found in the image
+pub fn read_qr_code(image: &GrayImage) -> Option<Vec<u8>> {
+    detect::find_codes(image)
+        .iter()
+        .find_map(|grid| decode::decode(grid).ok())
+}
@@ -0,0 +1,181 @@
+use std::sync::LazyLock;
+
+/// Exponent and logarithm tables of GF(256) with the QR code polynomial
+/// x^8 + x^4 + x^3 + x^2 + 1 and the generator 2
+static TABLES: LazyLock<([u8; 512], [u8; 256])> = LazyLock::new(|| {
+    let mut exp = [0u8; 512];
+    let mut log = [0u8; 256];
+    let mut value = 1u16;
+    for i in 0..255 {
+        exp[i] = value as u8;
+        exp[i + 255] = value as u8;
+        log[usize::from(value)] = i as u8;
+        value <<= 1;
+        if value & 0x100 != 0 {
+            value ^= 0x11d;
+        }
+    }
+    (exp, log)
+});
+
+fn exp(power: usize) -> u8 {
+    TABLES.0[power % 255]
+}
+
+fn mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+    let (exp, log) = &*TABLES;
+    exp[usize::from(log[usize::from(a)]) + usize::from(log[usize::from(b)])]
+}
+
+fn div(a: u8, b: u8) -> u8 {
+    if a == 0 {
+        return 0;
+    }
+    let (exp, log) = &*TABLES;
+    exp[usize::from(log[usize::from(a)]) + 255 - usize::from(log[usize::from(b)])]
+}
+
+/// Evaluates a polynomial with the lowest degree coefficient first
+fn eval(poly: &[u8], x: u8) -> u8 {
+    poly.iter().rev().fold(0, |acc, c| mul(acc, x) ^ c)
+}
+
+/// Corrects the errors of a block whose last `ecc` codewords are the error
+/// correction codewords. Returns the number of corrected codewords.
+pub fn correct(block: &mut [u8], ecc: usize) -> Result<usize, String> {
+    let n = block.len();
+    if n > 255 || ecc >= n {
+        return Err("invalid block size".to_owned());
+    }
+    let syndromes: Vec<u8> = (0..ecc)
+        .map(|j| block.iter().fold(0, |acc, c| mul(acc, exp(j)) ^ c))
+        .collect();
+    if syndromes.iter().all(|s| *s == 0) {
+        return Ok(0);
+    }
+
+    // Berlekamp-Massey finds the error locator polynomial
+    let mut locator = vec![1u8];
+    let mut previous = vec![1u8];
+    let mut errors = 0;
+    let mut shift = 1;
+    let mut previous_discrepancy = 1u8;
+    for step in 0..ecc {
+        let discrepancy = (1..=errors).fold(syndromes[step], |acc, i| {
+            acc ^ mul(*locator.get(i).unwrap_or(&0), syndromes[step - i])
+        });
+        if discrepancy == 0 {
+            shift += 1;
+            continue;
+        }
+        let factor = div(discrepancy, previous_discrepancy);
+        let mut updated = locator.clone();
+        updated.resize(updated.len().max(previous.len() + shift), 0);
+        for (i, c) in previous.iter().enumerate() {
+            updated[i + shift] ^= mul(factor, *c);
+        }
+        if 2 * errors <= step {
+            previous = std::mem::replace(&mut locator, updated);
+            errors = step + 1 - errors;
+            previous_discrepancy = discrepancy;
+            shift = 1;
+        } else {
+            locator = updated;
+            shift += 1;
+        }
+    }
+    if 2 * errors > ecc {
+        return Err("too many errors".to_owned());
+    }
+
+    // the evaluator polynomial is S(x) * L(x) mod x^ecc
+    let mut evaluator = vec![0u8; ecc];
+    for (i, s) in syndromes.iter().enumerate() {
+        for (j, l) in locator.iter().enumerate() {
+            if i + j < ecc {
+                evaluator[i + j] ^= mul(*s, *l);
+            }
+        }
+    }
+    // formal derivative, only the odd powers remain in characteristic 2
+    let derivative: Vec<u8> = locator
+        .iter()
+        .enumerate()
+        .skip(1)
+        .map(|(i, c)| if i % 2 == 1 { *c } else { 0 })
+        .collect();
+
+    // Chien search and Forney algorithm
+    let mut corrected = 0;
+    for (k, codeword) in block.iter_mut().enumerate() {
+        let power = n - 1 - k;
+        let inverse = exp(255 - power % 255);
+        if eval(&locator, inverse) != 0 {
+            continue;
+        }
+        let denominator = eval(&derivative, inverse);
+        if denominator == 0 {
+            return Err("cannot compute error value".to_owned());
+        }
+        *codeword ^= mul(exp(power), div(eval(&evaluator, inverse), denominator));
+        corrected += 1;
+    }
+    if corrected != errors {
+        return Err("cannot locate errors".to_owned());
+    }
+    Ok(corrected)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Appends the error correction codewords to the data
+    fn encode(data: &[u8], ecc: usize) -> Vec<u8> {
+        // generator polynomial with the highest degree first
+        let mut generator = vec![1u8];
+        for i in 0..ecc {
+            let mut next = vec![0u8; generator.len() + 1];
+            for (j, g) in generator.iter().enumerate() {
+                next[j] ^= g;
+                next[j + 1] ^= mul(*g, exp(i));
+            }
+            generator = next;
+        }
+        let mut remainder = data.to_vec();
+        remainder.resize(data.len() + ecc, 0);
+        for i in 0..data.len() {
+            let factor = remainder[i];
+            for (j, g) in generator.iter().enumerate() {
+                remainder[i + j] ^= mul(factor, *g);
+            }
+        }
+        [data, &remainder[data.len()..]].concat()
+    }
+
+    #[test]
+    fn correct_errors_up_to_half_the_ecc_codewords() {
+        let data: Vec<u8> = (0..40u8).map(|i| i.wrapping_mul(37)).collect();
+        let block = encode(&data, 10);
+        let mut received = block.clone();
+        assert_eq!(correct(&mut received, 10), Ok(0));
+        for (i, position) in [0, 7, 21, 39, 45].iter().enumerate() {
+            received[*position] ^= 0x5a + i as u8;
+        }
+        assert_eq!(correct(&mut received, 10), Ok(5));
+        assert_eq!(received, block);
+    }
+
+    #[test]
+    fn detect_too_many_errors() {
+        let data = [1u8, 2, 3, 4, 5, 6, 7, 8];
+        let mut received = encode(&data, 4);
+        for codeword in &mut received[..3] {
+            *codeword ^= 0xff;
+        }
+        assert!(correct(&mut received, 4).is_err());
+    }
+}
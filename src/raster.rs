@@ -0,0 +1,237 @@
+/// A rectangular area of an image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// A rectangle which is rotated counterclockwise by `angle` radians around its center
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RotatedRect {
+    pub center_x: f64,
+    pub center_y: f64,
+    pub width: usize,
+    pub height: usize,
+    pub angle: f64,
+}
+
+/// An 8 bit grayscale image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GrayImage {
+    pub width: usize,
+    pub height: usize,
+    /// Rows from top to bottom, 0 is black
+    pub pixels: Vec<u8>,
+}
+
+/// An 8 bit image with interleaved samples, one per pixel for grayscale and
+/// red, green and blue for color
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    /// 1 for grayscale, 3 for color
+    pub channels: usize,
+    /// Rows from top to bottom
+    pub samples: Vec<u8>,
+}
+
+impl GrayImage {
+    pub fn new(width: usize, height: usize, value: u8) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![value; width * height],
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> u8 {
+        self.pixels[y * self.width + x]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, value: u8) {
+        self.pixels[y * self.width + x] = value;
+    }
+
+    /// Returns the pixel at the position with bilinear interpolation or
+    /// `None` if it is outside of the image
+    pub fn sample(&self, x: f64, y: f64) -> Option<u8> {
+        let max_x = (self.width - 1) as f64;
+        let max_y = (self.height - 1) as f64;
+        if !(0.0..=max_x).contains(&x) || !(0.0..=max_y).contains(&y) {
+            return None;
+        }
+        let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+        let (x1, y1) = ((x0 + 1).min(self.width - 1), (y0 + 1).min(self.height - 1));
+        let (fx, fy) = (x - x0 as f64, y - y0 as f64);
+        let top = f64::from(self.get(x0, y0)) * (1.0 - fx) + f64::from(self.get(x1, y0)) * fx;
+        let bottom = f64::from(self.get(x0, y1)) * (1.0 - fx) + f64::from(self.get(x1, y1)) * fx;
+        Some((top * (1.0 - fy) + bottom * fy).round() as u8)
+    }
+
+    /// Reduces the size by an integer factor, averaging the pixels of each square
+    pub fn downscale(&self, factor: usize) -> GrayImage {
+        let factor = factor.max(1);
+        let width = self.width.div_ceil(factor);
+        let height = self.height.div_ceil(factor);
+        let mut sums = vec![(0u32, 0u32); width * height];
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let sum = &mut sums[(y / factor) * width + x / factor];
+                sum.0 += u32::from(self.get(x, y));
+                sum.1 += 1;
+            }
+        }
+        GrayImage {
+            width,
+            height,
+            pixels: sums
+                .iter()
+                .map(|(sum, count)| (sum / count) as u8)
+                .collect(),
+        }
+    }
+
+    /// Returns the median of the pixels at the edges of the image
+    pub fn border_median(&self) -> u8 {
+        let mut border: Vec<u8> = Vec::with_capacity(2 * (self.width + self.height));
+        for x in 0..self.width {
+            border.push(self.get(x, 0));
+            border.push(self.get(x, self.height - 1));
+        }
+        for y in 0..self.height {
+            border.push(self.get(0, y));
+            border.push(self.get(self.width - 1, y));
+        }
+        border.sort_unstable();
+        border.get(border.len() / 2).copied().unwrap_or(255)
+    }
+
+    /// Returns the median of all pixels
+    pub fn median(&self) -> u8 {
+        let mut histogram = [0usize; 256];
+        for pixel in &self.pixels {
+            histogram[usize::from(*pixel)] += 1;
+        }
+        let mut count = 0;
+        for (value, pixels) in histogram.iter().enumerate() {
+            count += pixels;
+            if count * 2 > self.pixels.len() {
+                return value as u8;
+            }
+        }
+        255
+    }
+
+    /// Rotates the image counterclockwise by `angle` radians around its center and
+    /// returns the part within `rect`, given in coordinates of the rotated image.
+    /// Areas outside of the original image are filled with `fill`.
+    pub fn rotate_and_crop(&self, angle: f64, rect: Rect, fill: u8) -> GrayImage {
+        let (sin, cos) = angle.sin_cos();
+        let cx = (self.width as f64 - 1.0) / 2.0;
+        let cy = (self.height as f64 - 1.0) / 2.0;
+        let mut result = GrayImage::new(rect.width, rect.height, fill);
+        for y in 0..rect.height {
+            let dy = (rect.y + y) as f64 - cy;
+            for x in 0..rect.width {
+                let dx = (rect.x + x) as f64 - cx;
+                // the source of each pixel is found by rotating it back
+                let source_x = cx + dx * cos - dy * sin;
+                let source_y = cy + dx * sin + dy * cos;
+                if let Some(value) = self.sample(source_x, source_y) {
+                    result.set(x, y, value);
+                }
+            }
+        }
+        result
+    }
+
+    /// Chooses the threshold between dark and light pixels which separates
+    /// the two classes best (Otsu's method). Pixels up to and including the
+    /// threshold are dark.
+    pub fn otsu_threshold(&self) -> u8 {
+        let mut histogram = [0u64; 256];
+        for pixel in &self.pixels {
+            histogram[usize::from(*pixel)] += 1;
+        }
+        let total = self.pixels.len() as f64;
+        let sum: f64 = histogram
+            .iter()
+            .enumerate()
+            .map(|(value, count)| value as f64 * *count as f64)
+            .sum();
+        let mut best = (0.0, 128);
+        let mut dark_count = 0.0;
+        let mut dark_sum = 0.0;
+        for (value, count) in histogram.iter().enumerate() {
+            dark_count += *count as f64;
+            dark_sum += value as f64 * *count as f64;
+            let light_count = total - dark_count;
+            if dark_count == 0.0 || light_count == 0.0 {
+                continue;
+            }
+            let dark_mean = dark_sum / dark_count;
+            let light_mean = (sum - dark_sum) / light_count;
+            let variance = dark_count * light_count * (dark_mean - light_mean).powi(2);
+            if variance > best.0 {
+                best = (variance, value as u8);
+            }
+        }
+        best.1
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn threshold_separates_dark_and_light() {
+        let mut image = GrayImage::new(10, 10, 230);
+        for x in 0..10 {
+            image.set(x, 0, 20);
+            image.set(x, 1, 40);
+        }
+        let threshold = image.otsu_threshold();
+        assert!((40..230).contains(&threshold), "threshold {threshold}");
+    }
+
+    #[test]
+    fn rotate_quarter_turn() {
+        let mut image = GrayImage::new(5, 5, 200);
+        image.set(4, 2, 0);
+        let full = Rect {
+            x: 0,
+            y: 0,
+            width: 5,
+            height: 5,
+        };
+        // counterclockwise with y pointing down moves the right edge to the top
+        let rotated = image.rotate_and_crop(std::f64::consts::FRAC_PI_2, full, 255);
+        assert_eq!(rotated.get(2, 0), 0);
+        let cropped = image.rotate_and_crop(
+            0.0,
+            Rect {
+                x: 3,
+                y: 1,
+                width: 3,
+                height: 2,
+            },
+            255,
+        );
+        assert_eq!(cropped.pixels, vec![200, 200, 255, 200, 0, 255]);
+    }
+
+    #[test]
+    fn downscale_averages_pixels() {
+        let mut image = GrayImage::new(3, 2, 100);
+        image.set(0, 0, 0);
+        let small = image.downscale(2);
+        assert_eq!((small.width, small.height), (2, 1));
+        assert_eq!(small.pixels, vec![75, 100]);
+        assert_eq!(image.border_median(), 100);
+        assert_eq!(image.median(), 100);
+    }
+}
@@ -84,6 +84,19 @@ class PollingService {
     }
   }
 }
+var bookId = () => {
+  let id = sessionStorage.getItem("bookId");
+  if (id === null) {
+    const bytes = crypto.getRandomValues(new Uint8Array(16));
+    id = Array.from(bytes, (b) => b.toString(16).padStart(2, "0")).join("");
+    sessionStorage.setItem("bookId", id);
+  }
+  return id;
+};
+var bookIdInput = document.getElementById("book_id");
+if (bookIdInput instanceof HTMLInputElement) {
+  bookIdInput.value = bookId();
+}
 var pollingService = new PollingService(updateStatus);
 pollingService.setupVisibilityChangeListener();
 pollingService.startPolling();
@@ -1,19 +1,38 @@
 use bytes::{Bytes, BytesMut};
 use futures_util::StreamExt;
-use futures_util::stream::{Stream, once};
-use jiff::Timestamp;
+use futures_util::stream::{BoxStream, Stream, once};
+use jiff::{Timestamp, Zoned};
 use reqwest::header::LOCATION;
 use reqwest::{Client, Response, StatusCode, Url};
 use thiserror::Error;
-use tracing::{debug, error};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+use tracing::{debug, error, info, warn};
 
-use std::io::{self, Cursor};
+use std::io::{self, Cursor, Read as _, Seek as _, SeekFrom, Write as _};
+use std::sync::Mutex;
 
-use crate::jpeg;
+use crate::color::{self, BilevelPages, Monochrome};
+use crate::deskew::{self, Straighten};
+use crate::hook::HookError;
+use crate::jpeg::{self, HeightScanner, Metadata, Transform};
+use crate::mail::MailError;
 use crate::message::error::ParseError;
-use crate::message::job_status::{PageState, ScanJobStatus};
+use crate::message::job_status::{ImageOrientation, PageState, ScanJobStatus, ScanPage};
+use crate::message::product_config::ProductConfig;
+use crate::message::scan_caps::ScanCaps;
 use crate::message::scan_job::{Format, InputSource, ScanJob};
 use crate::message::scan_status::ScanStatus;
+use crate::ocr::{self, OcrError};
+use crate::pdf;
+use crate::png;
+use crate::s3::S3Error;
+use crate::separate::Separator;
+use crate::sftp::SftpError;
+use crate::tiff;
+use crate::webdav::WebDavError;
+use crate::webhook::{EventKind, Notifier};
 
 #[derive(Debug, Error)]
 pub enum ScannerError {
@@ -38,6 +57,22 @@ pub enum ScannerError {
     JobCreationFailed(StatusCode),
     #[error("Job canceled")]
     Canceled,
+    #[error(transparent)]
+    Ocr(#[from] OcrError),
+    #[error(transparent)]
+    Pdf(#[from] pdf::ParseError),
+    #[error(transparent)]
+    Jpeg(#[from] jpeg::ParseError),
+    #[error(transparent)]
+    Hook(#[from] HookError),
+    #[error(transparent)]
+    Mail(#[from] MailError),
+    #[error(transparent)]
+    WebDav(#[from] WebDavError),
+    #[error(transparent)]
+    S3(#[from] S3Error),
+    #[error(transparent)]
+    Sftp(#[from] SftpError),
 }
 
 impl ScannerError {
@@ -63,6 +98,53 @@ pub struct Scanner {
     client: Client,
     base_url: Url,
     disable_jpeg_fix: bool,
+    /// Whether the scan time and the device model are written into JPEG scans
+    write_metadata: bool,
+    events: Notifier,
+    /// Whether the scanner answered the last status request
+    available: Mutex<Option<bool>>,
+    /// The product config, which does not change while the scanner runs
+    product_config: Mutex<Option<ProductConfig>>,
+}
+
+/// Size of the largest JPEG scan which is transformed. The coefficients of the
+/// whole image are kept in memory, which take several times the size of the file.
+const MAX_TRANSFORM_SIZE: usize = 64 * 1024 * 1024;
+
+/// Blank page threshold in percent if none is given
+pub const DEFAULT_BLANK_PAGE_THRESHOLD: f64 = 0.1;
+
+/// Processing applied to scans after they are downloaded
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PostProcessing {
+    /// Lossless transform of JPEG scans
+    pub transform: Transform,
+    /// Pages whose content score is below this percentage are removed
+    pub blank_page_threshold: Option<f64>,
+    /// Skewed pages are rotated upright and cropped to their content
+    pub straighten: Option<Straighten>,
+    /// A scan of the glass is cut into one image per photo on it
+    pub split_photos: bool,
+    /// Pages without color are converted to grayscale or black and white
+    pub monochrome: Option<Monochrome>,
+    /// All pages are converted to black and white, at the threshold or one
+    /// chosen for each page, when the scanner does not scan lineart itself
+    pub bilevel: Option<Option<u8>>,
+    /// Multi-page documents are split into several files at these sheets
+    pub separator: Option<Separator>,
+    /// A searchable text layer in this language is added to PDF scans
+    pub ocr_language: Option<String>,
+}
+
+impl PostProcessing {
+    /// The pages which are stored in black and white when they are decoded
+    fn bilevel_pages(&self) -> BilevelPages {
+        match (self.bilevel, self.monochrome) {
+            (Some(threshold), _) => BilevelPages::All { threshold },
+            (None, Some(Monochrome::Bilevel { threshold })) => BilevelPages::Gray { threshold },
+            (None, _) => BilevelPages::None,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -70,7 +152,10 @@ pub struct Job<'a> {
     scanner: &'a Scanner,
     location: Url,
     binary_url: Option<String>,
+    orientation: Option<ImageOrientation>,
     parameters: ScanJob,
+    /// The time the job was started, which is the capture time of its pages
+    started: Zoned,
 }
 
 impl Scanner {
@@ -89,21 +174,87 @@ impl Scanner {
             client,
             base_url,
             disable_jpeg_fix,
+            write_metadata: false,
+            events: Notifier::default(),
+            available: Mutex::new(None),
+            product_config: Mutex::new(None),
         }
     }
 
+    /// Sets whether the scan time and the device model are written into JPEG scans
+    pub fn with_jpeg_metadata(mut self, write_metadata: bool) -> Scanner {
+        self.write_metadata = write_metadata;
+        self
+    }
+
+    /// Sends the events of the scanner and its jobs to the notifier
+    pub fn with_notifier(mut self, notifier: &Notifier) -> Scanner {
+        self.events = notifier.for_scanner(self.host());
+        self
+    }
+
     pub fn host(&self) -> &str {
         self.base_url.host_str().unwrap()
     }
 
+    pub fn events(&self) -> &Notifier {
+        &self.events
+    }
+
+    /// Returns the status of the scanner. An event is sent when the scanner
+    /// goes offline or comes back.
     pub async fn get_scan_status(&self) -> Result<ScanStatus, ScannerError> {
-        let data = self.get("/Scan/Status").await?;
+        let result = self.get("/Scan/Status").await;
+        let available = !matches!(result, Err(ScannerError::NotAvailable { .. }));
+        let previous = self
+            .available
+            .lock()
+            .expect("the lock is not poisoned")
+            .replace(available);
+        match (previous, available) {
+            (Some(true) | None, false) => {
+                let error = result.as_ref().err();
+                self.events.notify(EventKind::ScannerOffline, None, error);
+            }
+            (Some(false), true) => self.events.notify(EventKind::ScannerOnline, None, None),
+            _ => (),
+        }
+        let data = result?;
         let c = Cursor::new(&data);
         let status =
             ScanStatus::read_xml(c).map_err(|e| ScannerError::form_parse_error(e, data))?;
         Ok(status)
     }
 
+    /// Returns the product config, which is only requested from the scanner
+    /// the first time
+    pub async fn get_product_config(&self) -> Result<ProductConfig, ScannerError> {
+        let cached = self
+            .product_config
+            .lock()
+            .expect("the lock is not poisoned")
+            .clone();
+        if let Some(config) = cached {
+            return Ok(config);
+        }
+        let data = self.get("/DevMgmt/ProductConfigDyn.xml").await?;
+        let c = Cursor::new(&data);
+        let config =
+            ProductConfig::read_xml(c).map_err(|e| ScannerError::form_parse_error(e, data))?;
+        *self
+            .product_config
+            .lock()
+            .expect("the lock is not poisoned") = Some(config.clone());
+        Ok(config)
+    }
+
+    pub async fn get_scan_caps(&self) -> Result<ScanCaps, ScannerError> {
+        let data = self.get("/Scan/ScanCaps").await?;
+        let c = Cursor::new(&data);
+        let caps = ScanCaps::read_xml(c).map_err(|e| ScannerError::form_parse_error(e, data))?;
+        Ok(caps)
+    }
+
     pub async fn start_job(&self, job: ScanJob) -> Result<Job<'_>, ScannerError> {
         let mut data: Vec<u8> = Vec::new();
         job.write_xml(&mut data).unwrap();
@@ -144,20 +295,29 @@ impl Scanner {
     async fn download_stream(
         &self,
         binary_url: &str,
-    ) -> Result<impl Stream<Item = Result<Bytes, reqwest::Error>> + use<>, ScannerError> {
+    ) -> Result<impl Stream<Item = Result<Bytes, ScannerError>> + use<>, ScannerError> {
         let url = self.base_url.join(binary_url)?;
         let response = self.client.get(url).send().await?;
-        Ok(response.bytes_stream())
+        Ok(response
+            .bytes_stream()
+            .map(|item| item.map_err(ScannerError::from)))
     }
 }
 
 impl Job<'_> {
+    /// The URL path of the job on the scanner
+    pub fn path(&self) -> &str {
+        self.location.path()
+    }
+
     fn new(scanner: &Scanner, location: Url, parameters: ScanJob) -> Job<'_> {
         Job {
             scanner,
             location,
             binary_url: None,
+            orientation: None,
             parameters,
+            started: Zoned::now(),
         }
     }
 
@@ -169,6 +329,7 @@ impl Job<'_> {
         match page_state {
             PageState::ReadyToUpload { binary_url } => {
                 self.binary_url = Some(binary_url.clone());
+                self.orientation = page.orientation();
                 Ok(true)
             }
             PageState::CanceledByDevice => Err(ScannerError::Canceled),
@@ -176,50 +337,408 @@ impl Job<'_> {
         }
     }
 
+    /// Downloads the scanned page. JPEG images are transformed losslessly,
+    /// first to undo the orientation reported by the scanner and then by the
+    /// transform of `processing`.
     pub async fn download_stream(
         self,
-    ) -> Result<impl Stream<Item = Result<Bytes, reqwest::Error>> + use<>, ScannerError> {
+        mut processing: PostProcessing,
+    ) -> Result<impl Stream<Item = Result<Bytes, ScannerError>> + use<>, ScannerError> {
+        let transform = processing.transform;
         // TODO error handling
-        let mut stream = self
+        let stream = self
             .scanner
-            .download_stream(&self.binary_url.unwrap())
+            .download_stream(self.binary_url.as_ref().unwrap())
             .await?;
-        if !self.scanner.disable_jpeg_fix
-            && self.parameters.input_source == InputSource::Adf
-            && self.parameters.format == Format::Jpeg
-        {
-            let mut data = read_all_bytes_from_stream(&mut stream).await?;
-            match jpeg::fix_jpeg_height(data.clone()) {
-                Ok(Some(buffer)) => data = buffer,
-                Ok(None) => (),
-                Err(e) => error!("Cannot fix jpeg headers. {e}"),
-            };
-            return Ok(bytes_into_stream(data).boxed());
+        let fix_height =
+            !self.scanner.disable_jpeg_fix && self.parameters.input_source == InputSource::Adf;
+        if self.parameters.format == Format::Tiff {
+            if transform != Transform::None {
+                warn!("Cannot {transform} tiff scans");
+            }
+            let resolution = self.parameters.resolution();
+            return tiff_stream(stream.boxed(), fix_height, resolution, processing).await;
+        }
+        if self.parameters.format.device_format() != Format::Jpeg {
+            if transform != Transform::None {
+                warn!("Cannot {transform} {:?} scans", self.parameters.format);
+            }
+            let remove_blank_pages = processing.blank_page_threshold.is_some();
+            let straighten = processing.straighten.is_some();
+            let ocr = processing.ocr_language.is_some();
+            let monochrome = processing.monochrome.is_some() || processing.bilevel.is_some();
+            if self.parameters.format == Format::Pdf
+                && (fix_height || remove_blank_pages || straighten || ocr || monochrome)
+            {
+                let resolution = self.parameters.resolution();
+                return process_pdf_stream(stream.boxed(), fix_height, resolution, processing)
+                    .await;
+            }
+            return Ok(stream.boxed());
+        }
+        if processing.ocr_language.is_some() {
+            warn!("Text recognition is only available for pdf scans");
+        }
+        if processing.bilevel.is_some() && self.parameters.format == Format::Jpeg {
+            warn!("Jpeg images cannot be black and white, the page is stored in grayscale");
+        }
+        let stream = if fix_height {
+            let total_lines = async || self.total_lines().await;
+            spool_with_fixed_height(stream, total_lines).await?
+        } else {
+            stream.boxed()
+        };
+        if processing.blank_page_threshold.is_some() {
+            debug!("Blank pages are only removed from pdf scans");
+        }
+        if self.orientation == Some(ImageOrientation::Unknown) {
+            warn!("The scanner reports an unknown image orientation, the page is not turned");
+        }
+        let orientation = self.orientation.map_or(0, ImageOrientation::degrees);
+        let transform = Transform::upright_from_rotation(orientation).then(transform);
+        processing.transform = transform;
+        let resolution = self.parameters.resolution();
+        let png = (self.parameters.format == Format::Png).then(|| processing.bilevel_pages());
+        let whole_page = transform != Transform::None
+            || processing.straighten.is_some()
+            || processing.monochrome.is_some()
+            || png.is_some();
+        let stream = if whole_page {
+            process_spooled(stream, move |data| {
+                process_jpeg(data, resolution, &processing, png)
+            })
+            .await?
+        } else {
+            stream
+        };
+        if png.is_some() || !self.scanner.write_metadata {
+            return Ok(stream);
+        }
+        self.with_metadata(stream).await
+    }
+
+    /// Writes the scan resolution, the start time of the job and the device
+    /// model into the image
+    async fn with_metadata(
+        &self,
+        stream: BoxStream<'static, Result<Bytes, ScannerError>>,
+    ) -> Result<BoxStream<'static, Result<Bytes, ScannerError>>, ScannerError> {
+        let resolution = u16::try_from(self.parameters.resolution()).unwrap_or(u16::MAX);
+        let mut metadata = Metadata::new(resolution);
+        metadata.date_time = Some(self.started.datetime());
+        match self.scanner.get_product_config().await {
+            Ok(config) => {
+                metadata.make = Some(config.make().to_owned());
+                metadata.model = Some(config.model().to_owned());
+            }
+            Err(e) => debug!("Cannot retrieve product config. {e}"),
+        }
+        apply_metadata_to_stream(stream, &metadata).await
+    }
+
+    /// Returns the number of lines of the scanned page reported by the scanner
+    async fn total_lines(&self) -> Option<u32> {
+        match self.scanner.get_job_status(self).await {
+            Ok(status) => status.pages().iter().find_map(ScanPage::total_lines),
+            Err(e) => {
+                debug!("Cannot retrieve total lines. {e}");
+                None
+            }
         }
-        Ok(stream.boxed())
     }
 }
 
-async fn read_all_bytes_from_stream(
-    mut stream: impl Stream<Item = Result<Bytes, reqwest::Error>> + Unpin,
-) -> Result<Bytes, ScannerError> {
-    let mut data = BytesMut::new();
+/// Writes the stream into a temporary file while looking for a DNL segment.
+/// If one is found, the height in the frame header is patched in place
+/// before the file is streamed back. This keeps the memory usage bounded
+/// even for large images.
+///
+/// Only if there is no DNL segment and the height is unknown, the image is
+/// loaded into memory to recover its height.
+async fn spool_with_fixed_height(
+    mut stream: impl Stream<Item = Result<Bytes, ScannerError>> + Unpin,
+    total_lines: impl AsyncFnOnce() -> Option<u32>,
+) -> Result<BoxStream<'static, Result<Bytes, ScannerError>>, ScannerError> {
+    let mut file = File::from_std(tempfile::tempfile()?);
+    let mut height_scanner = HeightScanner::new();
     while let Some(item) = stream.next().await {
-        data.extend_from_slice(item?.as_ref());
+        let chunk = item?;
+        height_scanner.feed(&chunk);
+        file.write_all(&chunk).await?;
+    }
+    if let Some((offset, height)) = height_scanner.height_patch() {
+        info!("Use jpeg height from DNL segment: {height}");
+        file.seek(SeekFrom::Start(offset)).await?;
+        file.write_all(&height.to_be_bytes()).await?;
+        if height_scanner.is_truncated() {
+            info!("Append missing EOI marker");
+            file.seek(SeekFrom::End(0)).await?;
+            file.write_all(&jpeg::END_OF_IMAGE).await?;
+        }
+    } else if height_scanner.needs_repair() {
+        let total_lines = total_lines().await;
+        file.rewind().await?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).await?;
+        let data = Bytes::from(data);
+        let data = match jpeg::fix_jpeg_height(data.clone(), total_lines) {
+            Ok(Some((buffer, repair))) => {
+                info!("Repaired jpeg: {repair}");
+                buffer
+            }
+            Ok(None) => data,
+            Err(e) => {
+                error!("Cannot fix jpeg headers. {e}");
+                data
+            }
+        };
+        return Ok(once(async { Ok(data) }).boxed());
+    } else if height_scanner.failed() {
+        error!("Cannot fix jpeg headers. Invalid jpeg data");
+    } else {
+        info!("No DNL segment found.");
     }
-    let data = data.freeze();
-    Ok(data)
+    file.flush().await?;
+    file.rewind().await?;
+    Ok(ReaderStream::new(file)
+        .map(|item| item.map_err(ScannerError::from))
+        .boxed())
 }
 
-fn bytes_into_stream(data: Bytes) -> impl Stream<Item = Result<Bytes, reqwest::Error>> {
-    once(async { Ok(data) })
+/// Writes the stream into a temporary file, processes its content in a
+/// blocking task and streams the result back from another temporary file.
+/// The page is only held in memory while it is processed, not while it is
+/// downloaded or passed on.
+async fn process_spooled<F>(
+    mut stream: BoxStream<'static, Result<Bytes, ScannerError>>,
+    process: F,
+) -> Result<BoxStream<'static, Result<Bytes, ScannerError>>, ScannerError>
+where
+    F: FnOnce(Bytes) -> Result<Bytes, ScannerError> + Send + 'static,
+{
+    let mut file = File::from_std(tempfile::tempfile()?);
+    while let Some(item) = stream.next().await {
+        file.write_all(&item?).await?;
+    }
+    file.flush().await?;
+    let mut input = file.into_std().await;
+    let output = tokio::task::spawn_blocking(move || -> Result<std::fs::File, ScannerError> {
+        input.rewind()?;
+        let mut data = Vec::new();
+        input.read_to_end(&mut data)?;
+        drop(input);
+        let data = process(data.into())?;
+        let mut output = tempfile::tempfile()?;
+        output.write_all(&data)?;
+        output.rewind()?;
+        Ok(output)
+    })
+    .await
+    .map_err(io::Error::other)??;
+    Ok(ReaderStream::new(File::from_std(output))
+        .map(|item| item.map_err(ScannerError::from))
+        .boxed())
 }
 
-pub fn output_file_name(format: Format, time: &Timestamp) -> String {
-    let extension = match format {
+/// Applies the steps which need the whole JPEG image: the lossless transform,
+/// straightening, the conversion of pages without color and the decoding into
+/// a PNG file. Failed steps leave the image unchanged, except for the decoding.
+fn process_jpeg(
+    mut data: Bytes,
+    resolution: u32,
+    processing: &PostProcessing,
+    png: Option<BilevelPages>,
+) -> Result<Bytes, ScannerError> {
+    let transform = processing.transform;
+    if transform != Transform::None {
+        if data.len() > MAX_TRANSFORM_SIZE {
+            warn!("Cannot {transform} jpeg, it is too large");
+        } else {
+            match jpeg::transform_jpeg(data.clone(), transform) {
+                Ok(transformed) => {
+                    info!("Applied lossless transform: {transform}");
+                    data = transformed;
+                }
+                Err(e) => error!("Cannot {transform} jpeg. {e}"),
+            }
+        }
+    }
+    if let Some(settings) = processing.straighten {
+        match deskew::straighten_jpeg(data.clone(), resolution, settings) {
+            Ok(Some((straightened, adjustment))) => {
+                info!("Page {adjustment}");
+                data = straightened;
+            }
+            Ok(None) => info!("Page needs no straightening"),
+            Err(e) => error!("Cannot straighten jpeg. {e}"),
+        }
+    }
+    if processing.monochrome.is_some() {
+        match color::convert_jpeg(data.clone(), resolution) {
+            Ok(Some(converted)) => {
+                info!("Converted page without color to grayscale");
+                data = converted;
+            }
+            Ok(None) => (),
+            Err(e) => error!("Cannot convert jpeg to grayscale. {e}"),
+        }
+    }
+    match png {
+        Some(bilevel) => Ok(png::from_jpeg(data, resolution, bilevel)?),
+        None => Ok(data),
+    }
+}
+
+/// Processes the PDF scan like [`process_pdf_stream`] and decodes its pages
+/// into a TIFF file
+async fn tiff_stream(
+    stream: BoxStream<'static, Result<Bytes, ScannerError>>,
+    fix_height: bool,
+    resolution: u32,
+    mut processing: PostProcessing,
+) -> Result<BoxStream<'static, Result<Bytes, ScannerError>>, ScannerError> {
+    if processing.ocr_language.take().is_some() {
+        warn!("Text recognition is only available for pdf scans");
+    }
+    // pages are converted to black and white when they are decoded
+    let bilevel = processing.bilevel_pages();
+    processing.bilevel = None;
+    if processing.monochrome.is_some() {
+        processing.monochrome = Some(Monochrome::Gray);
+    }
+    process_spooled(stream, move |data| {
+        let data = process_pdf(data, fix_height, resolution, &processing);
+        Ok(tiff::from_pdf(data, resolution, bilevel)?)
+    })
+    .await
+}
+
+/// Fixes the height of the JPEG images of the spooled document, removes blank
+/// pages, straightens the pages, adds a text layer and converts pages without
+/// color or all pages to black and white. Steps which fail leave the document
+/// unchanged.
+async fn process_pdf_stream(
+    stream: BoxStream<'static, Result<Bytes, ScannerError>>,
+    fix_height: bool,
+    resolution: u32,
+    processing: PostProcessing,
+) -> Result<BoxStream<'static, Result<Bytes, ScannerError>>, ScannerError> {
+    process_spooled(stream, move |data| {
+        Ok(process_pdf(data, fix_height, resolution, &processing))
+    })
+    .await
+}
+
+pub fn process_pdf(
+    mut data: Bytes,
+    fix_height: bool,
+    resolution: u32,
+    processing: &PostProcessing,
+) -> Bytes {
+    if fix_height {
+        match pdf::fix_jpeg_heights(data.clone()) {
+            Ok(Some((fixed, count))) => {
+                info!("Fixed the height of {count} image(s) in pdf");
+                data = fixed;
+            }
+            Ok(None) => (),
+            Err(e) => error!("Cannot fix pdf. {e}"),
+        }
+    }
+    if let Some(threshold) = processing.blank_page_threshold {
+        match pdf::remove_blank_pages(data.clone(), threshold) {
+            Ok(Some((processed, removed))) => {
+                let pages: Vec<String> = removed
+                    .iter()
+                    .map(|p| format!("{} (score {:.3})", p.page, p.score))
+                    .collect();
+                info!("Removed blank pages: {}", pages.join(", "));
+                data = processed;
+            }
+            Ok(None) => info!("No blank pages removed"),
+            Err(e) => error!("Cannot remove blank pages. {e}"),
+        }
+    }
+    if let Some(settings) = processing.straighten {
+        match deskew::straighten_pdf(data.clone(), resolution, settings) {
+            Ok(Some(straightened)) => data = straightened,
+            Ok(None) => info!("No pages straightened"),
+            Err(e) => error!("Cannot straighten pages. {e}"),
+        }
+    }
+    if let Some(language) = &processing.ocr_language {
+        match ocr::searchable_pdf(data.clone(), language) {
+            Ok(Some(searchable)) => data = searchable,
+            Ok(None) => info!("No text recognized"),
+            Err(e) => error!("Cannot recognize text. {e}"),
+        }
+    }
+    // after the text recognition, which needs JPEG images
+    if let Some(target) = processing.monochrome {
+        match color::convert_pdf(data.clone(), resolution, target) {
+            Ok(Some(converted)) => data = converted,
+            Ok(None) => info!("All pages have color"),
+            Err(e) => error!("Cannot convert pages without color. {e}"),
+        }
+    }
+    if let Some(threshold) = processing.bilevel {
+        match color::bilevel_pdf(data.clone(), threshold) {
+            Ok(Some(converted)) => data = converted,
+            Ok(None) => info!("No pages converted to black and white"),
+            Err(e) => error!("Cannot convert pages to black and white. {e}"),
+        }
+    }
+    data
+}
+
+/// Reads the stream until the application segments following the SOI marker
+/// are complete and replaces them with ones containing the metadata. The rest
+/// of the image is passed through unchanged.
+async fn apply_metadata_to_stream(
+    mut stream: BoxStream<'static, Result<Bytes, ScannerError>>,
+    metadata: &Metadata,
+) -> Result<BoxStream<'static, Result<Bytes, ScannerError>>, ScannerError> {
+    let mut buffer = BytesMut::new();
+    let header_len = loop {
+        match jpeg::header_len(&buffer) {
+            Ok(Some(len)) => break Some(len),
+            Ok(None) => (),
+            Err(e) => {
+                error!("Cannot write jpeg metadata. {e}");
+                break None;
+            }
+        }
+        match stream.next().await {
+            Some(item) => buffer.extend_from_slice(&item?),
+            None => {
+                error!("Cannot write jpeg metadata. Incomplete jpeg header");
+                break None;
+            }
+        }
+    };
+    let mut buffer = buffer.freeze();
+    if let Some(len) = header_len {
+        let header = buffer.split_to(len);
+        let header = jpeg::apply_metadata(header.clone(), metadata).unwrap_or_else(|e| {
+            error!("Cannot write jpeg metadata. {e}");
+            header
+        });
+        buffer = [header, buffer].concat().into();
+    }
+    Ok(once(async { Ok(buffer) }).chain(stream).boxed())
+}
+
+pub fn file_extension(format: Format) -> &'static str {
+    match format {
         Format::Pdf => "pdf",
         Format::Jpeg => "jpeg",
-    };
+        Format::Png => "png",
+        Format::Tiff => "tif",
+    }
+}
+
+pub fn output_file_name(format: Format, time: &Timestamp) -> String {
+    let extension = file_extension(format);
     let ts = time.strftime("%Y%m%d_%H%M%S");
     format!("scan_{ts}.{extension}")
 }
@@ -228,6 +747,7 @@ pub fn output_file_name(format: Format, time: &Timestamp) -> String {
 mod test {
 
     use super::*;
+    use futures_util::stream;
 
     #[test]
     fn check_output_file_name() {
@@ -240,5 +760,148 @@ mod test {
             "scan_20170212_131905.jpeg",
             output_file_name(Format::Jpeg, &time)
         );
+        assert_eq!(
+            "scan_20170212_131905.tif",
+            output_file_name(Format::Tiff, &time)
+        );
+        assert_eq!(
+            "scan_20170212_131905.png",
+            output_file_name(Format::Png, &time)
+        );
+    }
+
+    #[tokio::test]
+    async fn spooled_jpeg_is_identical_to_fixed_jpeg() {
+        let data = std::fs::read("doc/testdata/scan_from_adf_with_dnl_header.jpeg").unwrap();
+        let (fixed, _) = jpeg::fix_jpeg_height(data.clone().into(), None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(spool(&data, None).await, fixed.as_ref());
+    }
+
+    #[tokio::test]
+    async fn spooled_jpeg_without_dnl_uses_total_lines() {
+        let data = std::fs::read("doc/testdata/scan_from_adf_with_dnl_header.jpeg").unwrap();
+        // cut off the DNL segment and the EOI marker
+        let data = &data[..data.len() - 8];
+        let (fixed, repair) = jpeg::fix_jpeg_height(Bytes::copy_from_slice(data), Some(3490))
+            .unwrap()
+            .unwrap();
+        assert_eq!(repair.height, Some((3490, jpeg::HeightSource::TotalLines)));
+        assert!(repair.appended_eoi);
+        assert_eq!(spool(data, Some(3490)).await, fixed.as_ref());
+    }
+
+    #[tokio::test]
+    async fn metadata_is_written_into_streamed_jpeg() {
+        let data = std::fs::read("doc/testdata/scan_from_adf_with_dnl_header.jpeg").unwrap();
+        let metadata = Metadata::new(300);
+        let expected = jpeg::apply_metadata(data.clone().into(), &metadata).unwrap();
+        for chunk_size in [7, 8000] {
+            let stream = apply_metadata_to_stream(chunks(&data, chunk_size), &metadata)
+                .await
+                .unwrap();
+            assert_eq!(collect(stream).await, expected.as_ref());
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_jpeg_is_streamed_without_metadata() {
+        let data = b"not a jpeg";
+        let stream = apply_metadata_to_stream(chunks(data, 3), &Metadata::new(300))
+            .await
+            .unwrap();
+        assert_eq!(collect(stream).await, data);
+    }
+
+    #[tokio::test]
+    async fn product_config_is_requested_once() {
+        use axum::{Router, routing::get};
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counted = requests.clone();
+        let app = Router::new().route(
+            "/DevMgmt/ProductConfigDyn.xml",
+            get(move || async move {
+                counted.fetch_add(1, Ordering::SeqCst);
+                "<ProductConfigDyn><ProductInformation><MakeAndModel>HP ENVY 5530 series</MakeAndModel></ProductInformation></ProductConfigDyn>"
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let scanner = Scanner::new(&host, false, false);
+        for _ in 0..2 {
+            let config = scanner.get_product_config().await.unwrap();
+            assert_eq!(config.model(), "ENVY 5530 series");
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    fn chunks(data: &[u8], chunk_size: usize) -> BoxStream<'static, Result<Bytes, ScannerError>> {
+        let chunks: Vec<Result<Bytes, ScannerError>> = data
+            .chunks(chunk_size)
+            .map(|c| Ok(Bytes::copy_from_slice(c)))
+            .collect();
+        stream::iter(chunks).boxed()
+    }
+
+    async fn collect(mut stream: BoxStream<'static, Result<Bytes, ScannerError>>) -> Vec<u8> {
+        let mut data = Vec::new();
+        while let Some(item) = stream.next().await {
+            data.extend_from_slice(&item.unwrap());
+        }
+        data
+    }
+
+    async fn spool(data: &[u8], total_lines: Option<u32>) -> Vec<u8> {
+        let stream = spool_with_fixed_height(chunks(data, 8000), async || total_lines)
+            .await
+            .unwrap();
+        collect(stream).await
+    }
+
+    #[tokio::test]
+    async fn turn_page_of_reported_orientation_upright() {
+        use crate::message::scan_job::ColorSpace;
+        use axum::Router;
+        use axum::routing::get;
+
+        let data = std::fs::read("doc/testdata/scan_from_adf_with_dnl_header.jpeg").unwrap();
+        let page = data.clone();
+        let app = Router::new().route("/Scan/Jobs/1/Pages/1", get(|| async { page }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let scanner = Scanner::new(&host, false, false);
+        let parameters = ScanJob::new(
+            InputSource::Platen,
+            300,
+            25,
+            Format::Jpeg,
+            ColorSpace::Color.into(),
+        );
+        let mut job = Job::new(
+            &scanner,
+            scanner.base_url.join("/Scan/Jobs/1").unwrap(),
+            parameters,
+        );
+        job.binary_url = Some("/Scan/Jobs/1/Pages/1".to_owned());
+        // the content of the page is turned clockwise by a quarter
+        job.orientation = Some(ImageOrientation::Rotated90);
+        let processing = PostProcessing {
+            transform: Transform::FlipHorizontal,
+            ..PostProcessing::default()
+        };
+        let stream = job.download_stream(processing).await.unwrap();
+        let downloaded = collect(stream.boxed()).await;
+
+        let upright = Transform::Rotate270.then(Transform::FlipHorizontal);
+        assert_eq!(upright, Transform::Transverse);
+        let expected = jpeg::transform_jpeg(data.into(), upright).unwrap();
+        assert_eq!(downloaded, expected.as_ref());
     }
 }
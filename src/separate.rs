@@ -0,0 +1,174 @@
+use bytes::Bytes;
+use tracing::{debug, info, warn};
+
+use crate::pdf::{self, ParseError};
+use crate::{jpeg, qr};
+
+/// The kind of sheet which is put between the documents of a batch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Separator {
+    /// An empty page, see [`jpeg::content_score`]
+    Blank,
+    /// A page with a QR code whose text may name the following document
+    QrCode,
+}
+
+/// A document of a separated batch
+#[derive(Debug, Clone, PartialEq)]
+pub struct Part {
+    pub data: Bytes,
+    /// The text of the QR code on the separator sheet in front of the document
+    pub name: Option<String>,
+    /// Pages of the batch in this document, starting at 1
+    pub pages: Vec<usize>,
+}
+
+/// Splits a scanned batch at the separator pages. The separators are left out.
+/// Returns `None` if the document contains no separator or no page besides them.
+pub fn split(
+    data: Bytes,
+    separator: Separator,
+    blank_threshold: f64,
+) -> Result<Option<Vec<Part>>, ParseError> {
+    let pages = pdf::page_jpegs(data.clone())?;
+    let mut groups: Vec<(Option<String>, Vec<usize>)> = vec![(None, Vec::new())];
+    let mut found = false;
+    for (index, images) in pages.into_iter().enumerate() {
+        match check_page(&images, separator, blank_threshold) {
+            Some(name) => {
+                info!("Page {} is a separator", index + 1);
+                found = true;
+                match groups.last_mut() {
+                    Some((previous, pages)) if pages.is_empty() => {
+                        *previous = name.or(previous.take())
+                    }
+                    _ => groups.push((name, Vec::new())),
+                }
+            }
+            None => {
+                if let Some((_, pages)) = groups.last_mut() {
+                    pages.push(index);
+                }
+            }
+        }
+    }
+    groups.retain(|(_, pages)| !pages.is_empty());
+    if !found || groups.is_empty() {
+        return Ok(None);
+    }
+    groups
+        .into_iter()
+        .map(|(name, pages)| {
+            Ok(Part {
+                data: pdf::extract_pages(data.clone(), &pages)?,
+                name,
+                pages: pages.iter().map(|index| index + 1).collect(),
+            })
+        })
+        .collect::<Result<Vec<Part>, ParseError>>()
+        .map(Some)
+}
+
+/// Returns `Some` with the text of the QR code, if any, if the page is a separator
+fn check_page(
+    images: &[Bytes],
+    separator: Separator,
+    blank_threshold: f64,
+) -> Option<Option<String>> {
+    if images.is_empty() {
+        return None;
+    }
+    match separator {
+        Separator::Blank => {
+            let blank = images
+                .iter()
+                .all(|image| match jpeg::content_score(image.clone()) {
+                    Ok(score) => {
+                        debug!("Content score {score:.3}");
+                        score < blank_threshold
+                    }
+                    Err(e) => {
+                        warn!("Cannot analyse image. {e}");
+                        false
+                    }
+                });
+            blank.then_some(None)
+        }
+        Separator::QrCode => images.iter().find_map(|image| {
+            let gray = jpeg::decode_gray(image.clone())
+                .inspect_err(|e| warn!("Cannot decode image. {e}"))
+                .ok()?;
+            let text = qr::read_qr_code(&gray)?;
+            let text = String::from_utf8_lossy(&text).trim().to_owned();
+            Some((!text.is_empty()).then_some(text))
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pdf::test::build_pdf;
+    use qrcode::{Color, EcLevel, QrCode};
+
+    const DNL_TEST_FILE: &str = "doc/testdata/scan_from_adf_with_dnl_header.jpeg";
+
+    fn scanned_page() -> Bytes {
+        std::fs::read(DNL_TEST_FILE).unwrap().into()
+    }
+
+    /// A page with a QR code whose modules are 2x2 blocks of 8x8 pixels
+    fn separator_page(text: &str) -> Bytes {
+        let code = QrCode::with_error_correction_level(text, EcLevel::M).unwrap();
+        let width = code.width();
+        let colors = code.to_colors();
+        jpeg::test_page(|x, y| {
+            let (column, row) = (x / 2, y / 2);
+            (10..10 + width).contains(&column)
+                && (10..10 + width).contains(&row)
+                && colors[(row - 10) * width + column - 10] == Color::Dark
+        })
+    }
+
+    #[test]
+    fn split_at_qr_codes() {
+        let pdf = build_pdf(&[
+            scanned_page(),
+            separator_page("invoice 42"),
+            scanned_page(),
+            scanned_page(),
+            separator_page("letter"),
+        ]);
+        let parts = split(pdf, Separator::QrCode, 0.1).unwrap().unwrap();
+        let summary: Vec<(Option<&str>, &[usize])> = parts
+            .iter()
+            .map(|part| (part.name.as_deref(), part.pages.as_slice()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![(None, &[1][..]), (Some("invoice 42"), &[3, 4][..])]
+        );
+        assert_eq!(pdf::page_jpegs(parts[1].data.clone()).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn split_at_blank_pages() {
+        let pdf = build_pdf(&[
+            jpeg::blank_page(),
+            scanned_page(),
+            jpeg::blank_page(),
+            jpeg::blank_page(),
+            scanned_page(),
+        ]);
+        let parts = split(pdf, Separator::Blank, 0.1).unwrap().unwrap();
+        let pages: Vec<&[usize]> = parts.iter().map(|part| part.pages.as_slice()).collect();
+        assert_eq!(pages, vec![&[2][..], &[5][..]]);
+        assert!(parts.iter().all(|part| part.name.is_none()));
+    }
+
+    #[test]
+    fn keep_document_without_separators() {
+        let pdf = build_pdf(&[scanned_page(), scanned_page()]);
+        assert!(split(pdf, Separator::QrCode, 0.1).unwrap().is_none());
+    }
+}
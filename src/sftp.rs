@@ -0,0 +1,779 @@
+use bytes::{Buf, Bytes};
+use futures_util::{Stream, StreamExt};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::process::Command;
+use tracing::debug;
+
+use std::collections::HashSet;
+use std::io;
+use std::path::PathBuf;
+use std::process::Stdio;
+
+use crate::output::{self, Content, FolderTemplate, NameFields, NameTemplate};
+use crate::scanner::ScannerError;
+
+/// Version 3 of the protocol, which OpenSSH implements
+const VERSION: u32 = 3;
+const FXP_INIT: u8 = 1;
+const FXP_VERSION: u8 = 2;
+const FXP_OPEN: u8 = 3;
+const FXP_CLOSE: u8 = 4;
+const FXP_WRITE: u8 = 6;
+const FXP_REMOVE: u8 = 13;
+const FXP_MKDIR: u8 = 14;
+const FXP_STAT: u8 = 17;
+const FXP_RENAME: u8 = 18;
+const FXP_STATUS: u8 = 101;
+const FXP_HANDLE: u8 = 102;
+const FXP_ATTRS: u8 = 105;
+const FXF_WRITE: u32 = 0x02;
+const FXF_CREAT: u32 = 0x08;
+const FXF_EXCL: u32 = 0x20;
+const FX_OK: u32 = 0;
+const FX_NO_SUCH_FILE: u32 = 2;
+/// Largest write request, which all servers accept
+const MAX_WRITE: usize = 32 * 1024;
+/// Number of write requests which are sent before their replies arrive
+const WINDOW: usize = 64;
+/// Largest reply which is read
+const MAX_PACKET: usize = 256 * 1024;
+
+#[derive(Debug, Error)]
+pub enum SftpError {
+    #[error("Cannot run ssh: {0}")]
+    Spawn(io::Error),
+    #[error("SFTP connection to {destination} failed: {message}")]
+    Connection {
+        destination: String,
+        message: String,
+    },
+    #[error("SFTP {operation} of {path} failed: {message}")]
+    Status {
+        operation: &'static str,
+        path: String,
+        code: u32,
+        message: String,
+    },
+    #[error("Invalid SFTP reply: {0}")]
+    Protocol(String),
+    #[error("SFTP connection lost: {0}")]
+    Io(io::Error),
+}
+
+/// A directory on an SFTP server, which new files are uploaded to. The
+/// connection is made by `ssh`, which only logs in with a key and only to
+/// hosts in the known hosts file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sftp {
+    /// Host name or `ssh_config` alias, optionally with the user, like
+    /// `scanner@archive.example.com`
+    pub destination: String,
+    pub port: Option<u16>,
+    /// Private key, which `ssh` takes from its configuration without it
+    pub identity: Option<PathBuf>,
+    /// Known hosts file, which `ssh` takes from its configuration without it
+    pub known_hosts: Option<PathBuf>,
+    /// Remote directory, or the home directory if it is empty
+    pub dir: String,
+    /// Folders in the directory, which are created if needed
+    pub folders: Option<FolderTemplate>,
+    pub template: Option<NameTemplate>,
+}
+
+impl Sftp {
+    pub fn has_model(&self) -> bool {
+        self.template.as_ref().is_some_and(NameTemplate::has_model)
+            || self.folders.as_ref().is_some_and(FolderTemplate::has_model)
+    }
+
+    /// Uploads the data to a new file and returns its remote path. The data
+    /// is written to a temporary file as it arrives, which is renamed once it
+    /// is complete. The name gets a number if the file exists already.
+    pub async fn upload(
+        &self,
+        content: Content<'_>,
+        extension: &str,
+        fields: &NameFields,
+        stream: impl Stream<Item = Result<Bytes, ScannerError>> + Unpin,
+    ) -> Result<String, ScannerError> {
+        let mut child = self.command().spawn().map_err(SftpError::Spawn)?;
+        let mut stderr = child.stderr.take().expect("stderr is piped");
+        let log = tokio::spawn(async move {
+            let mut log = String::new();
+            let _ = stderr.read_to_string(&mut log).await;
+            log
+        });
+        let reader = child.stdout.take().expect("stdout is piped");
+        let writer = child.stdin.take().expect("stdin is piped");
+        let result = match Session::start(reader, writer).await {
+            Ok(session) => {
+                self.transfer(session, content, extension, fields, stream)
+                    .await
+            }
+            Err(e) => Err(e.into()),
+        };
+        // ssh exits once the session is closed
+        let _ = child.wait().await;
+        let log = log.await.unwrap_or_default();
+        if !log.trim().is_empty() {
+            debug!("ssh: {}", log.trim());
+        }
+        match result {
+            Err(ScannerError::Sftp(SftpError::Io(e))) => {
+                let message = log.lines().last().map_or(e.to_string(), str::to_owned);
+                Err(SftpError::Connection {
+                    destination: self.destination.clone(),
+                    message,
+                }
+                .into())
+            }
+            result => result,
+        }
+    }
+
+    /// Returns the `ssh` command which starts the SFTP subsystem. It fails
+    /// instead of asking for passwords or accepting unknown host keys.
+    fn command(&self) -> Command {
+        let mut command = Command::new("ssh");
+        command.args([
+            "-o",
+            "BatchMode=yes",
+            "-o",
+            "StrictHostKeyChecking=yes",
+            "-o",
+            "PreferredAuthentications=publickey",
+        ]);
+        if let Some(port) = self.port {
+            command.arg("-p").arg(port.to_string());
+        }
+        if let Some(identity) = &self.identity {
+            command.arg("-i").arg(identity);
+            command.args(["-o", "IdentitiesOnly=yes"]);
+        }
+        if let Some(known_hosts) = &self.known_hosts {
+            command
+                .arg("-o")
+                .arg(format!("UserKnownHostsFile=\"{}\"", known_hosts.display()));
+        }
+        command.args(["-s", "--", &self.destination, "sftp"]);
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        command
+    }
+
+    async fn transfer<R, W>(
+        &self,
+        mut session: Session<R, W>,
+        content: Content<'_>,
+        extension: &str,
+        fields: &NameFields,
+        stream: impl Stream<Item = Result<Bytes, ScannerError>> + Unpin,
+    ) -> Result<String, ScannerError>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut dir = self.dir.clone();
+        let folders = self.folders.as_ref().map(|folders| folders.render(fields));
+        for name in folders.into_iter().flatten() {
+            dir = join(&dir, &name);
+            if !session.exists(&dir).await? {
+                session.mkdir(&dir).await?;
+            }
+        }
+        let part_name = format!(
+            ".covet-{}-{}.part",
+            fields.time.as_nanosecond(),
+            content.page()
+        );
+        let part = join(&dir, &part_name);
+        let handle = session.open(&part).await?;
+        let written = session.write(&handle, &part, stream).await;
+        let closed = session.close(&handle, &part).await;
+        if let Err(e) = written.and(closed.map_err(ScannerError::from)) {
+            if let Err(e) = session.remove(&part).await {
+                debug!("Cannot remove {part}. {e}");
+            }
+            return Err(e);
+        }
+
+        let template = output::file_template(self.template.clone(), content);
+        for number in 1.. {
+            let name = output::numbered_name(&template, fields, content.page(), number);
+            let path = join(&dir, &format!("{name}.{extension}"));
+            if !session.exists(&path).await? {
+                session.rename(&part, &path).await?;
+                return Ok(path);
+            }
+        }
+        unreachable!("the file name numbers are exhausted")
+    }
+}
+
+/// An SFTP session over the standard input and output of `ssh`
+struct Session<R, W> {
+    reader: R,
+    writer: W,
+    next_id: u32,
+}
+
+/// A reply to a request
+struct Reply {
+    kind: u8,
+    id: u32,
+    data: Bytes,
+}
+
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> Session<R, W> {
+    async fn start(reader: R, writer: W) -> Result<Self, SftpError> {
+        let mut session = Session {
+            reader,
+            writer,
+            next_id: 0,
+        };
+        let mut packet = vec![FXP_INIT];
+        put_u32(&mut packet, VERSION);
+        session.write_packet(&packet).await?;
+        let mut data = session.read_packet().await?;
+        if data.first() != Some(&FXP_VERSION) {
+            return Err(SftpError::Protocol("no version".to_owned()));
+        }
+        data.advance(1);
+        let version = get_u32(&mut data)?;
+        if version < VERSION {
+            return Err(SftpError::Protocol(format!("version {version}")));
+        }
+        Ok(session)
+    }
+
+    /// Opens a new file for writing and returns its handle
+    async fn open(&mut self, path: &str) -> Result<Bytes, SftpError> {
+        let mut fields = string(path.as_bytes());
+        put_u32(&mut fields, FXF_WRITE | FXF_CREAT | FXF_EXCL);
+        // no attributes
+        put_u32(&mut fields, 0);
+        let mut reply = self.request(FXP_OPEN, &fields).await?;
+        match reply.kind {
+            FXP_HANDLE => get_string(&mut reply.data),
+            _ => Err(status_error(reply, "creation", path)?),
+        }
+    }
+
+    /// Writes the data to the file and returns its size. The replies to the
+    /// write requests are only awaited once the window is full.
+    async fn write(
+        &mut self,
+        handle: &[u8],
+        path: &str,
+        mut stream: impl Stream<Item = Result<Bytes, ScannerError>> + Unpin,
+    ) -> Result<u64, ScannerError> {
+        let mut offset = 0;
+        let mut pending = HashSet::new();
+        let mut result = Ok(());
+        while let Some(item) = stream.next().await {
+            let data = match item {
+                Ok(data) => data,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            };
+            for chunk in data.chunks(MAX_WRITE) {
+                let mut fields = string(handle);
+                put_u64(&mut fields, offset);
+                fields.extend_from_slice(&string(chunk));
+                pending.insert(self.send(FXP_WRITE, &fields).await?);
+                offset += chunk.len() as u64;
+                if pending.len() >= WINDOW {
+                    self.acknowledge(&mut pending, path).await?;
+                }
+            }
+        }
+        while !pending.is_empty() {
+            self.acknowledge(&mut pending, path).await?;
+        }
+        result.map(|()| offset)
+    }
+
+    /// Waits for the reply to one of the pending write requests
+    async fn acknowledge(
+        &mut self,
+        pending: &mut HashSet<u32>,
+        path: &str,
+    ) -> Result<(), SftpError> {
+        loop {
+            let reply = self.receive().await?;
+            if pending.remove(&reply.id) {
+                return check(reply, "write", path);
+            }
+        }
+    }
+
+    async fn close(&mut self, handle: &[u8], path: &str) -> Result<(), SftpError> {
+        let reply = self.request(FXP_CLOSE, &string(handle)).await?;
+        check(reply, "close", path)
+    }
+
+    async fn exists(&mut self, path: &str) -> Result<bool, SftpError> {
+        let reply = self.request(FXP_STAT, &string(path.as_bytes())).await?;
+        match reply.kind {
+            FXP_ATTRS => Ok(true),
+            _ => {
+                let status = status_error(reply, "lookup", path)?;
+                match status {
+                    SftpError::Status {
+                        code: FX_NO_SUCH_FILE,
+                        ..
+                    } => Ok(false),
+                    e => Err(e),
+                }
+            }
+        }
+    }
+
+    async fn mkdir(&mut self, path: &str) -> Result<(), SftpError> {
+        let mut fields = string(path.as_bytes());
+        put_u32(&mut fields, 0);
+        let reply = self.request(FXP_MKDIR, &fields).await?;
+        check(reply, "creation", path)
+    }
+
+    /// Renames the file, which fails if the new name exists
+    async fn rename(&mut self, from: &str, to: &str) -> Result<(), SftpError> {
+        let mut fields = string(from.as_bytes());
+        fields.extend_from_slice(&string(to.as_bytes()));
+        let reply = self.request(FXP_RENAME, &fields).await?;
+        check(reply, "rename", to)
+    }
+
+    async fn remove(&mut self, path: &str) -> Result<(), SftpError> {
+        let reply = self.request(FXP_REMOVE, &string(path.as_bytes())).await?;
+        check(reply, "removal", path)
+    }
+
+    /// Sends a request and returns its reply. Replies to earlier requests
+    /// which were abandoned are skipped.
+    async fn request(&mut self, kind: u8, fields: &[u8]) -> Result<Reply, SftpError> {
+        let id = self.send(kind, fields).await?;
+        loop {
+            let reply = self.receive().await?;
+            if reply.id == id {
+                return Ok(reply);
+            }
+        }
+    }
+
+    /// Sends a request and returns its ID
+    async fn send(&mut self, kind: u8, fields: &[u8]) -> Result<u32, SftpError> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let mut packet = Vec::with_capacity(fields.len() + 5);
+        packet.push(kind);
+        put_u32(&mut packet, id);
+        packet.extend_from_slice(fields);
+        self.write_packet(&packet).await?;
+        Ok(id)
+    }
+
+    async fn receive(&mut self) -> Result<Reply, SftpError> {
+        let mut data = self.read_packet().await?;
+        if data.len() < 5 {
+            return Err(SftpError::Protocol("short reply".to_owned()));
+        }
+        let kind = data.get_u8();
+        let id = data.get_u32();
+        Ok(Reply { kind, id, data })
+    }
+
+    async fn write_packet(&mut self, packet: &[u8]) -> Result<(), SftpError> {
+        let length = u32::try_from(packet.len()).expect("packets are small");
+        self.writer
+            .write_all(&length.to_be_bytes())
+            .await
+            .map_err(SftpError::Io)?;
+        self.writer.write_all(packet).await.map_err(SftpError::Io)?;
+        self.writer.flush().await.map_err(SftpError::Io)
+    }
+
+    async fn read_packet(&mut self) -> Result<Bytes, SftpError> {
+        let length = self.reader.read_u32().await.map_err(SftpError::Io)? as usize;
+        if length > MAX_PACKET {
+            return Err(SftpError::Protocol(format!("reply of {length} bytes")));
+        }
+        let mut packet = vec![0; length];
+        self.reader
+            .read_exact(&mut packet)
+            .await
+            .map_err(SftpError::Io)?;
+        Ok(packet.into())
+    }
+}
+
+/// Returns an error unless the reply is a successful status
+fn check(reply: Reply, operation: &'static str, path: &str) -> Result<(), SftpError> {
+    match status_error(reply, operation, path)? {
+        SftpError::Status { code: FX_OK, .. } => Ok(()),
+        e => Err(e),
+    }
+}
+
+/// Returns the status of the reply as an error
+fn status_error(
+    mut reply: Reply,
+    operation: &'static str,
+    path: &str,
+) -> Result<SftpError, SftpError> {
+    if reply.kind != FXP_STATUS {
+        return Err(SftpError::Protocol(format!("reply type {}", reply.kind)));
+    }
+    let code = get_u32(&mut reply.data)?;
+    let message = get_string(&mut reply.data)?;
+    Ok(SftpError::Status {
+        operation,
+        path: path.to_owned(),
+        code,
+        message: String::from_utf8_lossy(&message).into_owned(),
+    })
+}
+
+/// Returns the path of the name in the directory
+fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{}/{name}", dir.trim_end_matches('/'))
+    }
+}
+
+fn put_u32(packet: &mut Vec<u8>, value: u32) {
+    packet.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_u64(packet: &mut Vec<u8>, value: u64) {
+    packet.extend_from_slice(&value.to_be_bytes());
+}
+
+/// Returns the data with its length in front
+fn string(data: &[u8]) -> Vec<u8> {
+    let mut string = Vec::with_capacity(data.len() + 4);
+    put_u32(
+        &mut string,
+        u32::try_from(data.len()).expect("strings are small"),
+    );
+    string.extend_from_slice(data);
+    string
+}
+
+fn get_u32(data: &mut Bytes) -> Result<u32, SftpError> {
+    if data.len() < 4 {
+        return Err(SftpError::Protocol("truncated reply".to_owned()));
+    }
+    Ok(data.get_u32())
+}
+
+fn get_string(data: &mut Bytes) -> Result<Bytes, SftpError> {
+    let length = get_u32(data)? as usize;
+    if data.len() < length {
+        return Err(SftpError::Protocol("truncated reply".to_owned()));
+    }
+    Ok(data.split_to(length))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures_util::stream;
+    use jiff::Timestamp;
+    use std::io::{Seek, Write};
+    use std::path::Path;
+    use tokio::io::{DuplexStream, ReadHalf, WriteHalf};
+
+    /// An SFTP server which serves a local directory over an in-memory
+    /// connection and records the requests
+    async fn serve(
+        root: PathBuf,
+        mut stream: DuplexStream,
+        requests: tokio::sync::mpsc::UnboundedSender<String>,
+    ) {
+        let mut files = Vec::new();
+        let length = stream.read_u32().await.unwrap();
+        let mut init = vec![0; length as usize];
+        stream.read_exact(&mut init).await.unwrap();
+        assert_eq!(init[0], FXP_INIT);
+        let mut version = vec![FXP_VERSION];
+        put_u32(&mut version, VERSION);
+        reply(&mut stream, &version).await;
+        while let Ok(length) = stream.read_u32().await {
+            let mut packet = vec![0; length as usize];
+            stream.read_exact(&mut packet).await.unwrap();
+            let mut data = Bytes::from(packet);
+            let kind = data.get_u8();
+            let id = data.get_u32();
+            let path = |data: &mut Bytes| {
+                let path = get_string(data).unwrap();
+                let path = String::from_utf8(path.to_vec()).unwrap();
+                (root.join(path.trim_start_matches('/')), path)
+            };
+            let status = match kind {
+                FXP_OPEN => {
+                    let (local, path) = path(&mut data);
+                    requests.send(format!("open {path}")).unwrap();
+                    assert_eq!(data.get_u32(), FXF_WRITE | FXF_CREAT | FXF_EXCL);
+                    match std::fs::File::create_new(&local) {
+                        Ok(file) => {
+                            files.push(file);
+                            let mut handle = vec![FXP_HANDLE];
+                            put_u32(&mut handle, id);
+                            handle.extend_from_slice(&string(&(files.len() - 1).to_be_bytes()));
+                            reply(&mut stream, &handle).await;
+                            continue;
+                        }
+                        Err(_) => 4,
+                    }
+                }
+                FXP_WRITE => {
+                    let handle = get_string(&mut data).unwrap();
+                    let index = usize::from_be_bytes(handle[..].try_into().unwrap());
+                    let offset = data.get_u64();
+                    let chunk = get_string(&mut data).unwrap();
+                    files[index].seek(io::SeekFrom::Start(offset)).unwrap();
+                    files[index].write_all(&chunk).unwrap();
+                    FX_OK
+                }
+                FXP_CLOSE => FX_OK,
+                FXP_STAT => {
+                    let (local, path) = path(&mut data);
+                    requests.send(format!("stat {path}")).unwrap();
+                    if local.exists() {
+                        let mut attrs = vec![FXP_ATTRS];
+                        put_u32(&mut attrs, id);
+                        put_u32(&mut attrs, 0);
+                        reply(&mut stream, &attrs).await;
+                        continue;
+                    }
+                    FX_NO_SUCH_FILE
+                }
+                FXP_MKDIR => {
+                    let (local, path) = path(&mut data);
+                    requests.send(format!("mkdir {path}")).unwrap();
+                    std::fs::create_dir(local).map_or(4, |()| FX_OK)
+                }
+                FXP_RENAME => {
+                    let (from, _) = path(&mut data);
+                    let (to, path) = path(&mut data);
+                    requests.send(format!("rename {path}")).unwrap();
+                    if to.exists() {
+                        4
+                    } else {
+                        std::fs::rename(from, to).unwrap();
+                        FX_OK
+                    }
+                }
+                FXP_REMOVE => {
+                    let (local, path) = path(&mut data);
+                    requests.send(format!("remove {path}")).unwrap();
+                    std::fs::remove_file(local).map_or(4, |()| FX_OK)
+                }
+                _ => 8,
+            };
+            let mut packet = vec![FXP_STATUS];
+            put_u32(&mut packet, id);
+            put_u32(&mut packet, status);
+            packet.extend_from_slice(&string(b"status"));
+            packet.extend_from_slice(&string(b""));
+            reply(&mut stream, &packet).await;
+        }
+    }
+
+    async fn reply(stream: &mut DuplexStream, packet: &[u8]) {
+        stream.write_u32(packet.len() as u32).await.unwrap();
+        stream.write_all(packet).await.unwrap();
+    }
+
+    type TestSession = Session<ReadHalf<DuplexStream>, WriteHalf<DuplexStream>>;
+
+    async fn connect(root: &Path) -> (TestSession, tokio::sync::mpsc::UnboundedReceiver<String>) {
+        let (client, server) = tokio::io::duplex(1024 * 1024);
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(serve(root.to_owned(), server, sender));
+        let (reader, writer) = tokio::io::split(client);
+        (Session::start(reader, writer).await.unwrap(), receiver)
+    }
+
+    fn fields() -> NameFields {
+        NameFields {
+            time: Timestamp::from_second(1486905545).unwrap(),
+            profile: "high".to_owned(),
+            source: "adf".to_owned(),
+            color: "gray".to_owned(),
+            resolution: 600,
+            model: "HP ENVY 5530 series".to_owned(),
+        }
+    }
+
+    fn sftp() -> Sftp {
+        Sftp {
+            destination: "scanner@archive.example.com".to_owned(),
+            port: Some(2222),
+            identity: Some(PathBuf::from("/etc/covet/id_ed25519")),
+            known_hosts: Some(PathBuf::from("/etc/covet/known hosts")),
+            dir: "/scans".to_owned(),
+            folders: Some(FolderTemplate::parse("{time:%Y}/{source}").unwrap()),
+            template: None,
+        }
+    }
+
+    async fn transfer(
+        sftp: &Sftp,
+        root: &Path,
+        stream: impl Stream<Item = Result<Bytes, ScannerError>> + Unpin,
+    ) -> (Result<String, ScannerError>, Vec<String>) {
+        let (session, mut requests) = connect(root).await;
+        let result = sftp
+            .transfer(session, Content::Scan, "pdf", &fields(), stream)
+            .await;
+        let mut recorded = Vec::new();
+        while let Ok(request) = requests.try_recv() {
+            recorded.push(request);
+        }
+        (result, recorded)
+    }
+
+    #[test]
+    fn ssh_command() {
+        let command = sftp().command();
+        let args: Vec<_> = command.as_std().get_args().collect();
+        assert_eq!(
+            args,
+            [
+                "-o",
+                "BatchMode=yes",
+                "-o",
+                "StrictHostKeyChecking=yes",
+                "-o",
+                "PreferredAuthentications=publickey",
+                "-p",
+                "2222",
+                "-i",
+                "/etc/covet/id_ed25519",
+                "-o",
+                "IdentitiesOnly=yes",
+                "-o",
+                "UserKnownHostsFile=\"/etc/covet/known hosts\"",
+                "-s",
+                "--",
+                "scanner@archive.example.com",
+                "sftp",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn upload_and_rename() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("scans")).unwrap();
+        let sftp = sftp();
+        // larger than the window of write requests
+        let data = vec![3; MAX_WRITE * WINDOW * 2 + 5];
+        let chunks: Vec<_> = data
+            .chunks(100_000)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+        let (path, requests) = transfer(&sftp, root.path(), stream::iter(chunks)).await;
+        assert_eq!(path.unwrap(), "/scans/2017/adf/scan_20170212_131905.pdf");
+        let part = "/scans/2017/adf/.covet-1486905545000000000-1.part";
+        assert_eq!(
+            requests,
+            [
+                "stat /scans/2017",
+                "mkdir /scans/2017",
+                "stat /scans/2017/adf",
+                "mkdir /scans/2017/adf",
+                &format!("open {part}"),
+                "stat /scans/2017/adf/scan_20170212_131905.pdf",
+                "rename /scans/2017/adf/scan_20170212_131905.pdf",
+            ]
+        );
+        let local = root.path().join("scans/2017/adf/scan_20170212_131905.pdf");
+        assert_eq!(std::fs::read(local).unwrap(), data);
+
+        let chunks = [Ok(Bytes::from_static(b"second"))];
+        let (path, _) = transfer(&sftp, root.path(), stream::iter(chunks)).await;
+        assert_eq!(path.unwrap(), "/scans/2017/adf/scan_20170212_131905_2.pdf");
+        let local = root
+            .path()
+            .join("scans/2017/adf/scan_20170212_131905_2.pdf");
+        assert_eq!(std::fs::read(local).unwrap(), b"second");
+        assert_eq!(
+            std::fs::read_dir(root.path().join("scans/2017/adf"))
+                .unwrap()
+                .count(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn remove_failed_upload() {
+        let root = tempfile::tempdir().unwrap();
+        let mut sftp = sftp();
+        sftp.dir = String::new();
+        sftp.folders = None;
+        let chunks = [
+            Ok(Bytes::from_static(b"01234")),
+            Err(ScannerError::Canceled),
+        ];
+        let (result, requests) = transfer(&sftp, root.path(), stream::iter(chunks)).await;
+        assert!(matches!(result, Err(ScannerError::Canceled)));
+        assert_eq!(
+            requests,
+            [
+                "open .covet-1486905545000000000-1.part",
+                "remove .covet-1486905545000000000-1.part",
+            ]
+        );
+        assert_eq!(std::fs::read_dir(root.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn report_missing_directory() {
+        let root = tempfile::tempdir().unwrap();
+        let mut sftp = sftp();
+        sftp.folders = None;
+        let chunks = [Ok(Bytes::from_static(b"01234"))];
+        let (result, _) = transfer(&sftp, root.path(), stream::iter(chunks)).await;
+        assert!(matches!(
+            result,
+            Err(ScannerError::Sftp(SftpError::Status {
+                operation: "creation",
+                ..
+            }))
+        ));
+    }
+
+    /// Uploads to a local sshd, like one started with
+    /// `/usr/sbin/sshd -D -p 2222 -o AuthorizedKeysFile=...` for the current
+    /// user. Its host key must be in `~/.ssh/known_hosts`.
+    #[tokio::test]
+    #[ignore = "needs an SSH server"]
+    async fn upload_to_sshd() {
+        let dir = tempfile::tempdir().unwrap();
+        let sftp = Sftp {
+            destination: std::env::var("SFTP_DESTINATION").unwrap_or("localhost".to_owned()),
+            port: Some(2222),
+            identity: std::env::var_os("SFTP_IDENTITY").map(PathBuf::from),
+            known_hosts: None,
+            dir: dir.path().display().to_string(),
+            ..sftp()
+        };
+        let chunks = [Ok(Bytes::from_static(b"%PDF"))];
+        let path = sftp
+            .upload(Content::Scan, "pdf", &fields(), stream::iter(chunks))
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), b"%PDF");
+    }
+}
@@ -0,0 +1,365 @@
+use bytes::Bytes;
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+use tracing::{debug, warn};
+
+use std::io::Write;
+
+use crate::ccitt;
+use crate::color::{self, BilevelPages, DecodedPage};
+use crate::pdf;
+use crate::raster::{GrayImage, Image};
+
+/// Tags of the image file directory, which have to be written in ascending order
+const NEW_SUBFILE_TYPE: u16 = 254;
+const IMAGE_WIDTH: u16 = 256;
+const IMAGE_LENGTH: u16 = 257;
+const BITS_PER_SAMPLE: u16 = 258;
+const COMPRESSION: u16 = 259;
+const PHOTOMETRIC_INTERPRETATION: u16 = 262;
+const STRIP_OFFSETS: u16 = 273;
+const SAMPLES_PER_PIXEL: u16 = 277;
+const ROWS_PER_STRIP: u16 = 278;
+const STRIP_BYTE_COUNTS: u16 = 279;
+const X_RESOLUTION: u16 = 282;
+const Y_RESOLUTION: u16 = 283;
+const T6_OPTIONS: u16 = 293;
+const RESOLUTION_UNIT: u16 = 296;
+const PAGE_NUMBER: u16 = 297;
+const PREDICTOR: u16 = 317;
+
+/// Field types
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+const RATIONAL: u16 = 5;
+
+/// A page of a TIFF file
+#[derive(Debug, Clone)]
+pub struct Page {
+    /// The compressed samples
+    pub data: Vec<u8>,
+    pub width: usize,
+    pub height: usize,
+    /// Resolution in dpi
+    pub resolution: u32,
+    pub encoding: Encoding,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// Black and white, compressed with CCITT Group 4
+    G4,
+    /// 8 bit samples of one or three channels with horizontal differencing,
+    /// compressed with Deflate
+    Deflate { channels: usize },
+}
+
+impl Page {
+    /// Compresses a black and white page. Pixels up to and including the
+    /// threshold become black.
+    pub fn g4(image: &GrayImage, threshold: u8, resolution: u32) -> Page {
+        Page {
+            data: ccitt::encode_g4(image, threshold),
+            width: image.width,
+            height: image.height,
+            resolution,
+            encoding: Encoding::G4,
+        }
+    }
+
+    /// Compresses a grayscale or color page without loss
+    pub fn deflate(image: &Image, resolution: u32) -> Page {
+        let channels = image.channels;
+        let row_len = image.width * channels;
+        let mut differences = image.samples.clone();
+        if row_len > 0 {
+            for row in differences.chunks_exact_mut(row_len) {
+                for i in (channels..row_len).rev() {
+                    row[i] = row[i].wrapping_sub(row[i - channels]);
+                }
+            }
+        }
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(&differences)
+            .expect("compressing into memory cannot fail");
+        Page {
+            data: encoder
+                .finish()
+                .expect("compressing into memory cannot fail"),
+            width: image.width,
+            height: image.height,
+            resolution,
+            encoding: Encoding::Deflate { channels },
+        }
+    }
+}
+
+/// Decodes the JPEG images of the pages of a PDF document into a TIFF file
+/// with one page each. Pages without a JPEG image are left out.
+pub fn from_pdf(
+    data: Bytes,
+    resolution: u32,
+    bilevel: BilevelPages,
+) -> Result<Bytes, pdf::ParseError> {
+    let mut pages = Vec::new();
+    for (index, images) in pdf::page_jpegs(data)?.into_iter().enumerate() {
+        let Some(image) = images.into_iter().next() else {
+            warn!("Page {} has no jpeg image and is left out", index + 1);
+            continue;
+        };
+        let page = color::decode_page(image, bilevel)
+            .map_err(|e| format!("invalid image of page {}: {e}", index + 1))?;
+        pages.push(match page {
+            DecodedPage::Bilevel { image, threshold } => {
+                debug!(
+                    "Page {} in black and white at threshold {threshold}",
+                    index + 1
+                );
+                Page::g4(&image, threshold, resolution)
+            }
+            DecodedPage::Samples(image) => Page::deflate(&image, resolution),
+        });
+    }
+    if pages.is_empty() {
+        return Err("no pages with jpeg images".to_owned().into());
+    }
+    write(&pages).map_err(|e| e.into())
+}
+
+/// Writes a little endian TIFF file with one image file directory per page
+pub fn write(pages: &[Page]) -> Result<Bytes, String> {
+    let long = |value: usize| {
+        u32::try_from(value).map_err(|_| format!("value {value} too large for tiff"))
+    };
+    let short = |value: usize| {
+        u16::try_from(value).map_err(|_| format!("value {value} too large for tiff"))
+    };
+    let mut output = b"II*\0".to_vec();
+    // the offset of each directory is filled in when it is written
+    let mut next_offset_position = output.len();
+    output.extend_from_slice(&[0; 4]);
+    for (index, page) in pages.iter().enumerate() {
+        let data_offset = long(output.len())?;
+        output.extend_from_slice(&page.data);
+        if output.len() % 2 == 1 {
+            output.push(0);
+        }
+        let (compression, photometric, channels) = match page.encoding {
+            // white is zero, as in CCITT compression
+            Encoding::G4 => (4, 0, 1),
+            // black is zero
+            Encoding::Deflate { channels: 1 } => (8, 1, 1),
+            Encoding::Deflate { channels } => (8, 2, short(channels)?),
+        };
+        let bits = match page.encoding {
+            Encoding::G4 => 1,
+            Encoding::Deflate { .. } => 8,
+        };
+        let mut fields: Vec<(u16, u16, u32, u32)> = vec![
+            // a page of a multi-page document
+            (NEW_SUBFILE_TYPE, LONG, 1, 2),
+            (IMAGE_WIDTH, LONG, 1, long(page.width)?),
+            (IMAGE_LENGTH, LONG, 1, long(page.height)?),
+            (BITS_PER_SAMPLE, SHORT, u32::from(channels), bits),
+            (COMPRESSION, SHORT, 1, compression),
+            (PHOTOMETRIC_INTERPRETATION, SHORT, 1, photometric),
+            (STRIP_OFFSETS, LONG, 1, data_offset),
+            (SAMPLES_PER_PIXEL, SHORT, 1, u32::from(channels)),
+            (ROWS_PER_STRIP, LONG, 1, long(page.height)?),
+            (STRIP_BYTE_COUNTS, LONG, 1, long(page.data.len())?),
+            (X_RESOLUTION, RATIONAL, 1, 0),
+            (Y_RESOLUTION, RATIONAL, 1, 0),
+            // inches
+            (RESOLUTION_UNIT, SHORT, 1, 2),
+            (
+                PAGE_NUMBER,
+                SHORT,
+                2,
+                u32::from(short(index)?) | (u32::from(short(pages.len())?) << 16),
+            ),
+        ];
+        match page.encoding {
+            Encoding::G4 => fields.push((T6_OPTIONS, LONG, 1, 0)),
+            // horizontal differencing
+            Encoding::Deflate { .. } => fields.push((PREDICTOR, SHORT, 1, 2)),
+        }
+        fields.sort_by_key(|(tag, ..)| *tag);
+        // values which do not fit into an entry follow the directory
+        let directory_offset = output.len();
+        let extra_offset = directory_offset + 2 + fields.len() * 12 + 4;
+        let mut extra = Vec::new();
+        for (tag, field_type, count, value) in &mut fields {
+            let size = match *field_type {
+                SHORT => 2,
+                RATIONAL => 8,
+                _ => 4,
+            } * *count;
+            if size <= 4 {
+                continue;
+            }
+            let offset = long(extra_offset + extra.len())?;
+            match *tag {
+                X_RESOLUTION | Y_RESOLUTION => {
+                    extra.extend_from_slice(&page.resolution.max(1).to_le_bytes());
+                    extra.extend_from_slice(&1u32.to_le_bytes());
+                }
+                // the same short for every sample
+                _ => {
+                    for _ in 0..*count {
+                        extra.extend_from_slice(&(*value as u16).to_le_bytes());
+                    }
+                    if extra.len() % 4 != 0 {
+                        extra.extend_from_slice(&[0; 2]);
+                    }
+                }
+            }
+            *value = offset;
+        }
+        output[next_offset_position..next_offset_position + 4]
+            .copy_from_slice(&long(directory_offset)?.to_le_bytes());
+        output.extend_from_slice(&short(fields.len())?.to_le_bytes());
+        for (tag, field_type, count, value) in fields {
+            output.extend_from_slice(&tag.to_le_bytes());
+            output.extend_from_slice(&field_type.to_le_bytes());
+            output.extend_from_slice(&count.to_le_bytes());
+            output.extend_from_slice(&value.to_le_bytes());
+        }
+        next_offset_position = output.len();
+        output.extend_from_slice(&[0; 4]);
+        output.extend_from_slice(&extra);
+    }
+    Ok(output.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::jpeg;
+    use std::io::Read;
+
+    fn u16_at(data: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([data[offset], data[offset + 1]])
+    }
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// Returns the tags and values of each directory
+    fn directories(data: &[u8]) -> Vec<Vec<(u16, u32)>> {
+        assert_eq!(&data[..4], b"II*\0");
+        let mut directories = Vec::new();
+        let mut offset = u32_at(data, 4) as usize;
+        while offset != 0 {
+            let count = usize::from(u16_at(data, offset));
+            let entries = (0..count)
+                .map(|i| {
+                    let entry = offset + 2 + i * 12;
+                    (u16_at(data, entry), u32_at(data, entry + 8))
+                })
+                .collect();
+            directories.push(entries);
+            offset = u32_at(data, offset + 2 + count * 12) as usize;
+        }
+        directories
+    }
+
+    fn value(directory: &[(u16, u32)], tag: u16) -> u32 {
+        directory.iter().find(|(t, _)| *t == tag).unwrap().1
+    }
+
+    #[test]
+    fn write_pages() {
+        let mut image = GrayImage::new(100, 40, 255);
+        image.set(10, 10, 0);
+        let color = Image {
+            width: 30,
+            height: 20,
+            channels: 3,
+            samples: vec![128; 30 * 20 * 3],
+        };
+        let pages = [Page::g4(&image, 128, 300), Page::deflate(&color, 600)];
+        let data = write(&pages).unwrap();
+
+        let directories = directories(&data);
+        assert_eq!(directories.len(), 2);
+        for (index, (directory, page)) in directories.iter().zip(&pages).enumerate() {
+            let tags: Vec<u16> = directory.iter().map(|(tag, _)| *tag).collect();
+            assert!(tags.is_sorted());
+            assert_eq!(value(directory, IMAGE_WIDTH), page.width as u32);
+            assert_eq!(value(directory, IMAGE_LENGTH), page.height as u32);
+            assert_eq!(value(directory, PAGE_NUMBER), index as u32 | (2 << 16));
+            let offset = value(directory, STRIP_OFFSETS) as usize;
+            let len = value(directory, STRIP_BYTE_COUNTS) as usize;
+            assert_eq!(&data[offset..offset + len], &page.data[..]);
+            let resolution = value(directory, X_RESOLUTION) as usize;
+            assert_eq!(u32_at(&data, resolution), page.resolution);
+            assert_eq!(u32_at(&data, resolution + 4), 1);
+        }
+        let (bilevel, color) = (&directories[0], &directories[1]);
+        assert_eq!(value(bilevel, COMPRESSION), 4);
+        assert_eq!(value(bilevel, BITS_PER_SAMPLE), 1);
+        assert_eq!(value(color, COMPRESSION), 8);
+        assert_eq!(value(color, SAMPLES_PER_PIXEL), 3);
+        assert_eq!(value(color, PREDICTOR), 2);
+        let bits = value(color, BITS_PER_SAMPLE) as usize;
+        assert_eq!([8, 8, 8], [0, 2, 4].map(|i| u16_at(&data, bits + i)));
+    }
+
+    #[test]
+    fn deflate_with_differences() {
+        let image = Image {
+            width: 3,
+            height: 2,
+            channels: 1,
+            samples: vec![10, 12, 11, 200, 200, 200],
+        };
+        let page = Page::deflate(&image, 300);
+        let mut inflated = Vec::new();
+        flate2::read::ZlibDecoder::new(&page.data[..])
+            .read_to_end(&mut inflated)
+            .unwrap();
+        assert_eq!(inflated, [10, 2, 255, 200, 0, 0]);
+    }
+
+    #[test]
+    fn convert_pdf() {
+        let pages: Vec<pdf::JpegPage> = [
+            jpeg::blank_page(),
+            jpeg::to_grayscale(jpeg::test_page(|x, _| x < 10)).unwrap(),
+        ]
+        .into_iter()
+        .map(|data| pdf::JpegPage {
+            data,
+            resolution: 300,
+        })
+        .collect();
+        let document = pdf::jpeg_document(&pages).unwrap();
+        let bilevel = BilevelPages::Gray { threshold: None };
+        let data = from_pdf(document, 300, bilevel).unwrap();
+
+        let directories = directories(&data);
+        assert_eq!(directories.len(), 2);
+        // the color page keeps its samples
+        assert_eq!(value(&directories[0], COMPRESSION), 8);
+        assert_eq!(value(&directories[0], SAMPLES_PER_PIXEL), 3);
+        let directory = &directories[1];
+        assert_eq!(value(directory, IMAGE_WIDTH), 2480);
+        let offset = value(directory, STRIP_OFFSETS) as usize;
+        let len = value(directory, STRIP_BYTE_COUNTS) as usize;
+        let mut rows = 0;
+        fax::decoder::decode_g4(
+            data[offset..offset + len].iter().copied(),
+            2480,
+            None,
+            |line| {
+                // the dark blocks on the left
+                assert_eq!(line, [0, 80]);
+                rows += 1;
+            },
+        )
+        .unwrap();
+        assert_eq!(rows, 3490);
+    }
+}
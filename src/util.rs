@@ -1,67 +1,488 @@
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use futures_util::stream::{Stream, StreamExt};
 use jiff::Timestamp;
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
-use tracing::info;
+use tracing::{error, info, warn};
 
+use std::io;
 use std::time::Duration;
 
+use crate::book::{self, ReadingOrder};
 use crate::cli::Source;
-use crate::message::scan_job::{ColorSpace, Format, InputSource, ScanJob};
+use crate::color::Monochrome;
+use crate::jpeg::Transform;
+use crate::message::scan_job::{ColorSpace, Format, InputSource, ScanColor, ScanJob};
 use crate::message::scan_status::AdfState;
-use crate::scanner::{self, Scanner, ScannerError};
+use crate::output::{Content, Destination, NameFields, Output};
+use crate::pdf::{self, JpegPage};
+use crate::photos;
+use crate::scanner::{self, Job, PostProcessing, Scanner, ScannerError};
+use crate::separate::{self, Separator};
+use crate::webhook::{EventKind, Notifier};
+
+/// The parameters of a scan job
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ScanSettings {
+    pub format: Format,
+    pub color: ScanColor,
+    pub source: Source,
+    pub resolution: u32,
+    pub quality: u32,
+}
 
 pub(crate) async fn scan_to_file(
-    scanner: Scanner,
-    format: Format,
-    color: ColorSpace,
-    source: Source,
+    scanner: &Scanner,
+    mut settings: ScanSettings,
+    mut processing: PostProcessing,
+    output: Output,
+) -> Result<(), ScannerError> {
+    let split_photos = processing.split_photos;
+    if split_photos {
+        if settings.format != Format::Jpeg {
+            info!("Photos are stored as jpeg");
+        }
+        if let Source::Adf = settings.source {
+            warn!("Photos are scanned from the glass");
+        }
+        // every photo is straightened on its own
+        processing.straighten = None;
+        settings.format = Format::Jpeg;
+        settings.source = Source::Glass;
+    }
+    let separator = match (processing.separator, settings.format) {
+        (Some(separator), Format::Pdf) => Some(separator),
+        (Some(_), _) => {
+            warn!("Only pdf scans can be split into documents");
+            None
+        }
+        (None, _) => None,
+    };
+    let blank_threshold = processing
+        .blank_page_threshold
+        .unwrap_or(scanner::DEFAULT_BLANK_PAGE_THRESHOLD);
+    if separator == Some(Separator::Blank) {
+        // blank pages have to remain until they are recognized as separators
+        processing.blank_page_threshold = None;
+    }
+    // separator sheets are recognized in the JPEG images of the scan
+    let (monochrome, bilevel) = match separator {
+        Some(_) => (processing.monochrome.take(), processing.bilevel.take()),
+        None => (None, None),
+    };
+    if separator.is_some() && settings.color.space == ColorSpace::Lineart {
+        settings.color = ColorSpace::Gray.into();
+    }
+    let (input_source, stream) = scan_to_stream(scanner, settings, processing).await?;
+    let fields = name_fields(scanner, &output, input_source, settings.resolution).await;
+    let extension = scanner::file_extension(settings.format);
+    let resolution = settings.resolution;
+    if split_photos {
+        let data = collect(stream).await?;
+        let scan = data.clone();
+        let result = tokio::task::spawn_blocking(move || photos::split(scan, resolution))
+            .await
+            .map_err(io::Error::other)?;
+        match result {
+            Ok(photos) if !photos.is_empty() => {
+                for (index, photo) in photos.into_iter().enumerate() {
+                    let content = Content::Photo(index + 1);
+                    deliver(
+                        &output,
+                        content,
+                        extension,
+                        &fields,
+                        once_stream(photo),
+                        &format!("Photo {}", index + 1),
+                    )
+                    .await?;
+                }
+                return Ok(());
+            }
+            Ok(_) => info!("No photos found"),
+            Err(e) => error!("Cannot split photos. {e}"),
+        }
+        deliver(
+            &output,
+            Content::Scan,
+            extension,
+            &fields,
+            once_stream(data),
+            "Scan",
+        )
+        .await?;
+        return Ok(());
+    }
+    if let Some(separator) = separator {
+        let data = collect(stream).await?;
+        let batch = data.clone();
+        let result =
+            tokio::task::spawn_blocking(move || separate::split(batch, separator, blank_threshold))
+                .await
+                .map_err(io::Error::other)?;
+        match result {
+            Ok(Some(parts)) => {
+                for (index, part) in parts.iter().enumerate() {
+                    let data =
+                        convert_colors(part.data.clone(), resolution, monochrome, bilevel).await?;
+                    let content = Content::Part(index + 1, part.name.as_deref());
+                    deliver(
+                        &output,
+                        content,
+                        extension,
+                        &fields,
+                        once_stream(data),
+                        &format!("Pages {:?}", part.pages),
+                    )
+                    .await?;
+                }
+                return Ok(());
+            }
+            Ok(None) => info!("No separator pages found"),
+            Err(e) => error!("Cannot split document. {e}"),
+        }
+        let data = convert_colors(data, resolution, monochrome, bilevel).await?;
+        deliver(
+            &output,
+            Content::Scan,
+            extension,
+            &fields,
+            once_stream(data),
+            "Scan",
+        )
+        .await?;
+        return Ok(());
+    }
+    deliver(&output, Content::Scan, extension, &fields, stream, "Scan").await?;
+    Ok(())
+}
+
+/// Writes the data to the destination, sends the written file by e-mail and
+/// runs the hooks on it
+pub(crate) async fn deliver(
+    output: &Output,
+    content: Content<'_>,
+    extension: &str,
+    fields: &NameFields,
+    stream: impl Stream<Item = Result<Bytes, ScannerError>> + Unpin,
+    description: &str,
+) -> Result<(), ScannerError> {
+    let path = output
+        .destination
+        .write(content, extension, fields, stream)
+        .await?;
+    match path {
+        Some(path) => {
+            info!("{description} written to {}", path.display());
+            if let Some(mail) = &output.mail {
+                mail.send(&path, extension).await?;
+            }
+            output
+                .hooks
+                .run(&path, extension, fields, content.page())
+                .await?;
+        }
+        None if output.destination == Destination::Stdout => {
+            info!("{description} written to standard output");
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+/// Returns the values for the file names of a scan and the metadata passed to
+/// hooks. The device model is only requested if it is needed.
+pub(crate) async fn name_fields(
+    scanner: &Scanner,
+    output: &Output,
+    source: InputSource,
+    resolution: u32,
+) -> NameFields {
+    let in_template = match &output.destination {
+        Destination::Directory {
+            template: Some(template),
+            ..
+        } => template.has_model(),
+        Destination::WebDav(webdav) => webdav.has_model(),
+        Destination::S3(s3) => s3.has_model(),
+        Destination::Sftp(sftp) => sftp.has_model(),
+        _ => false,
+    };
+    let model = if in_template || !output.hooks.is_empty() {
+        match scanner.get_product_config().await {
+            Ok(config) => config.model().to_owned(),
+            Err(e) => {
+                warn!("Cannot retrieve the device model. {e}");
+                "unknown".to_owned()
+            }
+        }
+    } else {
+        String::new()
+    };
+    let source = match source {
+        InputSource::Platen => "glass",
+        InputSource::Adf => "adf",
+    };
+    NameFields {
+        time: Timestamp::now(),
+        profile: output.profile.clone(),
+        source: source.to_owned(),
+        color: output.color.clone(),
+        resolution,
+        model,
+    }
+}
+
+pub(crate) fn once_stream(data: Bytes) -> impl Stream<Item = Result<Bytes, ScannerError>> + Unpin {
+    futures_util::stream::iter([Ok(data)])
+}
+
+/// Converts the pages without color or all pages of a PDF document to black
+/// and white. On failure the document is returned unchanged.
+async fn convert_colors(
+    data: Bytes,
     resolution: u32,
-    quality: u32,
+    monochrome: Option<Monochrome>,
+    bilevel: Option<Option<u8>>,
+) -> Result<Bytes, ScannerError> {
+    if monochrome.is_none() && bilevel.is_none() {
+        return Ok(data);
+    }
+    let processing = PostProcessing {
+        monochrome,
+        bilevel,
+        ..PostProcessing::default()
+    };
+    tokio::task::spawn_blocking(move || scanner::process_pdf(data, false, resolution, &processing))
+        .await
+        .map_err(|e| io::Error::other(e).into())
+}
+
+/// Scans an open book spread by spread from the glass until the user is done
+/// and writes its pages into a single PDF file
+pub(crate) async fn scan_book(
+    scanner: &Scanner,
+    settings: ScanSettings,
+    order: ReadingOrder,
+    processing: PostProcessing,
+    output: Output,
 ) -> Result<(), ScannerError> {
-    let mut stream = scan_to_stream(&scanner, format, color, source, resolution, quality).await?;
-    let file_name = scanner::output_file_name(format, &Timestamp::now());
-    let mut file = File::create(file_name).await?;
-    while let Some(item) = stream.next().await {
-        file.write_all(item?.as_ref()).await?;
+    let mut pages = Vec::new();
+    loop {
+        let spread = scan_spread(scanner, settings, order, processing.transform).await;
+        match spread {
+            Ok(spread) => {
+                pages.extend(spread);
+                info!("{} pages scanned", pages.len());
+            }
+            Err(e) => error!("Cannot scan spread. {e}"),
+        }
+        if !ask_for_next_spread().await? {
+            break;
+        }
     }
+    if pages.is_empty() {
+        info!("No pages scanned");
+        return Ok(());
+    }
+    let data = book_document(pages, processing).await?;
+    let fields = name_fields(scanner, &output, InputSource::Platen, settings.resolution).await;
+    let extension = scanner::file_extension(Format::Pdf);
+    deliver(
+        &output,
+        Content::Scan,
+        extension,
+        &fields,
+        once_stream(data),
+        "Book",
+    )
+    .await?;
     Ok(())
 }
 
+/// Returns false if the user wants to finish the book
+async fn ask_for_next_spread() -> Result<bool, io::Error> {
+    tokio::task::spawn_blocking(|| {
+        eprint!("Turn the page and press Enter to scan the next spread, or enter q to finish: ");
+        let mut line = String::new();
+        let read = io::stdin().read_line(&mut line)?;
+        Ok(read > 0 && !line.trim().eq_ignore_ascii_case("q"))
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+/// Scans an open book on the glass and splits the scan into its two pages.
+/// If it cannot be split, the whole scan becomes a page.
+pub(crate) async fn scan_spread(
+    scanner: &Scanner,
+    settings: ScanSettings,
+    order: ReadingOrder,
+    transform: Transform,
+) -> Result<Vec<JpegPage>, ScannerError> {
+    let resolution = settings.resolution;
+    let settings = ScanSettings {
+        format: Format::Jpeg,
+        source: Source::Glass,
+        ..settings
+    };
+    // everything else is applied to the finished book
+    let processing = PostProcessing {
+        transform,
+        ..PostProcessing::default()
+    };
+    let (_, stream) = scan_to_stream(scanner, settings, processing).await?;
+    let data = collect(stream).await?;
+    let spread = data.clone();
+    let result = tokio::task::spawn_blocking(move || book::split(spread, resolution, order))
+        .await
+        .map_err(io::Error::other)?;
+    let pages = result.unwrap_or_else(|e| {
+        error!("Cannot split book. {e}");
+        vec![data]
+    });
+    Ok(pages
+        .into_iter()
+        .map(|data| JpegPage { data, resolution })
+        .collect())
+}
+
+/// Writes the pages of a book into a PDF document, which is processed like
+/// a PDF scan
+pub(crate) async fn book_document(
+    pages: Vec<JpegPage>,
+    processing: PostProcessing,
+) -> Result<Bytes, ScannerError> {
+    tokio::task::spawn_blocking(move || {
+        let data = pdf::jpeg_document(&pages)?;
+        let resolution = pages.first().map_or(0, |page| page.resolution);
+        Ok(scanner::process_pdf(data, false, resolution, &processing))
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+/// Loads the whole scan into memory
+pub(crate) async fn collect(
+    mut stream: impl Stream<Item = Result<Bytes, ScannerError>> + Unpin,
+) -> Result<Bytes, ScannerError> {
+    let mut buffer = BytesMut::new();
+    while let Some(item) = stream.next().await {
+        buffer.extend_from_slice(&item?);
+    }
+    Ok(buffer.freeze())
+}
+
+/// Scans with the settings and returns the source the pages are scanned from
+/// with the stream of the processed scan
 pub(crate) async fn scan_to_stream(
     scanner: &Scanner,
-    format: Format,
-    color: ColorSpace,
-    source: Source,
-    resolution: u32,
-    quality: u32,
-) -> Result<impl Stream<Item = Result<Bytes, reqwest::Error>> + use<>, ScannerError> {
+    settings: ScanSettings,
+    mut processing: PostProcessing,
+) -> Result<
+    (
+        InputSource,
+        impl Stream<Item = Result<Bytes, ScannerError>> + use<>,
+    ),
+    ScannerError,
+> {
     let status = scanner.get_scan_status().await?;
     if !status.is_idle() {
         return Err(ScannerError::Busy);
     }
-    let input_source = choose_source(source, status.adf_state())?;
-    let mut job = scanner
+    let input_source = choose_source(settings.source, status.adf_state())?;
+    let color = choose_color(scanner, settings.format, settings.color, &mut processing).await;
+    let events = scanner.events();
+    let job = scanner
         .start_job(ScanJob::new(
             input_source,
-            resolution,
-            quality,
-            format,
+            settings.resolution,
+            settings.quality,
+            settings.format,
             color,
         ))
-        .await?;
+        .await
+        .inspect_err(|e| events.notify(EventKind::JobFailed, None, Some(e)))?;
     info!("Job: {job:?}");
+    let path = job.path().to_owned();
+    events.notify(EventKind::JobStarted, Some(&path), None);
+    match download_job(job, processing, events).await {
+        Ok(stream) => Ok((input_source, events.notify_end(stream.boxed(), path))),
+        Err(e) => {
+            let event = match e {
+                ScannerError::Canceled => EventKind::JobCanceled,
+                _ => EventKind::JobFailed,
+            };
+            events.notify(event, Some(&path), Some(&e));
+            Err(e)
+        }
+    }
+}
+
+/// Waits until the page of the job is scanned and starts its download
+async fn download_job(
+    mut job: Job<'_>,
+    processing: PostProcessing,
+    events: &Notifier,
+) -> Result<impl Stream<Item = Result<Bytes, ScannerError>> + use<>, ScannerError> {
     loop {
         let ready = job.retrieve_status().await?;
         if ready {
             info!("Job: {job:?}");
-            return job.download_stream().await;
+            events.notify(EventKind::PageReady, Some(job.path()), None);
+            return job.download_stream(processing).await;
         }
         tokio::time::sleep(Duration::from_millis(500)).await;
     }
 }
 
+/// Chooses the color the scanner scans in. Lineart and bit depths other than 8
+/// are only requested if the scanner supports them, otherwise the page is
+/// scanned with 8 bits and converted to black and white locally.
+async fn choose_color(
+    scanner: &Scanner,
+    format: Format,
+    color: ScanColor,
+    processing: &mut PostProcessing,
+) -> ScanColor {
+    let fallback = match color.space {
+        ColorSpace::Lineart => ColorSpace::Gray.into(),
+        space => ScanColor::from(space),
+    };
+    // lineart is thresholded from the JPEG images of the scan if other
+    // processing steps need them or the pages are decoded into another format
+    let needs_jpeg = processing.blank_page_threshold.is_some()
+        || processing.straighten.is_some()
+        || processing.ocr_language.is_some();
+    let local_lineart = color.space == ColorSpace::Lineart
+        && (format != Format::Pdf || needs_jpeg || processing.separator.is_some());
+    if local_lineart || color == fallback {
+        return fallback;
+    }
+    match scanner.get_scan_caps().await {
+        Ok(caps) if caps.supports(color) => {
+            if color.space == ColorSpace::Lineart {
+                // the scanner delivers black and white pages already
+                processing.bilevel = None;
+            }
+            color
+        }
+        Ok(_) => {
+            warn!(
+                "The scanner cannot scan in {}, scanning in {} instead",
+                color.color_type(),
+                fallback.color_type()
+            );
+            fallback
+        }
+        Err(e) => {
+            warn!(
+                "Cannot retrieve scan capabilities, scanning in {}. {e}",
+                fallback.color_type()
+            );
+            fallback
+        }
+    }
+}
+
 pub(crate) fn choose_source(
     source: Source,
     adf_state: Option<AdfState>,
@@ -3,9 +3,12 @@ use axum::{
     Form, Json, Router,
     body::Body,
     extract::{DefaultBodyLimit, State},
-    response::{IntoResponse, Response},
+    response::{IntoResponse, Redirect, Response},
     routing::{get, post},
 };
+use bytes::Bytes;
+use futures_util::future;
+use futures_util::stream::{self, BoxStream, Stream, StreamExt};
 use headers::HeaderMapExt;
 use hyper::{
     StatusCode,
@@ -13,24 +16,60 @@ use hyper::{
 };
 use jiff::Timestamp;
 use serde::{Deserialize, Serialize};
+use tempfile::TempDir;
+use tokio::fs::File;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
 use tokio::runtime::Runtime;
+use tokio::sync::Mutex;
+use tokio::sync::mpsc::{self, Sender};
+use tokio_util::io::ReaderStream;
 use tower_http::trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer};
 use tracing::Level;
-use tracing::{error, info};
+use tracing::{debug, error, info};
 
+use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
 
-use crate::cli::Source;
-use crate::message::scan_job::{ColorSpace, Format};
+use crate::book::ReadingOrder;
+use crate::cli::{QualityProfile, Source};
+use crate::color::Monochrome;
+use crate::deskew::{self, Straighten};
+use crate::hook::Hooks;
+use crate::jpeg;
+use crate::mail::{self, AllowedRecipients, Mail, MailConfig, MailError};
+use crate::message::scan_job::{ColorSpace, Format, InputSource, ScanColor};
 use crate::message::scan_status::ScannerState;
-use crate::scanner::{self, Scanner, ScannerError};
-use crate::util::scan_to_stream;
+use crate::ocr::{self, ProfileLanguages};
+use crate::output::{Content, Destination, Output};
+use crate::pdf::JpegPage;
+use crate::s3::S3;
+use crate::scanner::{self, PostProcessing, Scanner, ScannerError};
+use crate::sftp::Sftp;
+use crate::util::{self, ScanSettings, scan_to_stream};
 use crate::web::static_content::StaticContent;
+use crate::webdav::WebDav;
+use crate::webhook::{self, Notifier, WebhookConfig};
 
+mod mqtt;
 mod static_content;
 
+pub use mqtt::MqttConfig;
+
 const ERROR_TEMPLATE: &str = include_str!("../resources/error.html");
+const BOOK_TEMPLATE: &str = include_str!("../resources/book.html");
+
+/// Maximum number of books which are scanned at the same time
+const MAX_BOOKS: usize = 4;
+/// Maximum number of pages of a book
+const MAX_BOOK_PAGES: usize = 1000;
+/// Time after which a book which is not continued may be discarded
+const BOOK_EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Number of chunks of a scan which may wait for the upload
+const UPLOAD_CHANNEL_CAPACITY: usize = 4;
 
 const TEXT_HTML: &str = "text/html";
 const TEXT_JS: &str = "text/javascript";
@@ -46,23 +85,189 @@ static STYLE_CSS: LazyLock<StaticContent> =
 static FAVICON: LazyLock<StaticContent> =
     LazyLock::new(|| StaticContent::new(include_bytes!("../resources/favicon.ico"), IMAGE_ICON));
 
+/// The services which are told about scans and the scanner
+#[derive(Debug, Default)]
+pub struct Integrations {
+    pub hooks: Hooks,
+    pub webhooks: Option<WebhookConfig>,
+    /// Time between the status requests which send the events of the scanner to webhooks
+    pub status_interval: Duration,
+    pub mqtt: Option<MqttConfig>,
+    pub mail: Option<MailConfig>,
+    /// The recipients which scans may be sent to
+    pub mail_allow: AllowedRecipients,
+    pub webdav: Option<WebDav>,
+    pub s3: Option<S3>,
+    pub sftp: Option<Sftp>,
+}
+
 pub fn run_server(
-    scanner_host: &str,
+    scanner: Scanner,
     listen_addr: &str,
     listen_port: u16,
-    use_tls: bool,
-    disable_jpeg_fix: bool,
+    output_dir: PathBuf,
+    ocr_languages: ProfileLanguages,
+    integrations: Integrations,
 ) -> Result<()> {
     let addr = SocketAddr::new(listen_addr.parse()?, listen_port);
     info!("Running on http://{listen_addr}:{listen_port}/");
-    let scanner = Scanner::new(scanner_host, use_tls, disable_jpeg_fix);
     let rt = Runtime::new()?;
-    rt.block_on(run_server_async(addr, scanner))
+    rt.block_on(async move {
+        // the workers run as long as the server
+        let mut notifier = Notifier::default();
+        let poll_status = integrations.webhooks.is_some() && integrations.mqtt.is_none();
+        if let Some(config) = integrations.webhooks {
+            notifier = notifier.merge(webhook::start(config).0);
+        }
+        let (notifier, mqtt) = match integrations.mqtt {
+            Some(config) => {
+                let (events, receiver) = webhook::channel();
+                (notifier.merge(events), Some((config, receiver)))
+            }
+            None => (notifier, None),
+        };
+        let state = Arc::new(AppState {
+            scanner: scanner.with_notifier(&notifier),
+            hooks: integrations.hooks,
+            mail: integrations.mail,
+            mail_allow: integrations.mail_allow,
+            upload: integrations
+                .webdav
+                .map(Destination::WebDav)
+                .or(integrations.s3.map(Destination::S3))
+                .or(integrations.sftp.map(Destination::Sftp)),
+            output_dir,
+            ocr_languages,
+            books: Mutex::new(HashMap::new()),
+        });
+        if let Some((config, receiver)) = mqtt {
+            mqtt::start(config, state.clone(), receiver);
+        }
+        if poll_status {
+            let state = state.clone();
+            repeat(integrations.status_interval, move || {
+                let state = state.clone();
+                async move {
+                    // only the events are of interest
+                    if let Err(e) = state.scanner.get_scan_status().await {
+                        debug!("Cannot retrieve scanner status. {e}");
+                    }
+                    true
+                }
+            });
+        }
+        run_server_async(addr, state).await
+    })
+}
+
+/// Runs `tick` every `interval` until it returns false
+fn repeat<F, T>(interval: Duration, mut tick: F)
+where
+    F: FnMut() -> T + Send + 'static,
+    T: Future<Output = bool> + Send,
+{
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            if !tick().await {
+                break;
+            }
+        }
+    });
 }
 
-async fn run_server_async(addr: SocketAddr, scanner: Scanner) -> Result<()> {
-    let scanner = Arc::new(scanner);
+struct AppState {
+    scanner: Scanner,
+    /// Run on a temporary copy of each scan before it is sent
+    hooks: Hooks,
+    /// The server for scans sent by e-mail
+    mail: Option<MailConfig>,
+    /// The recipients which the form may send scans to
+    mail_allow: AllowedRecipients,
+    /// The WebDAV collection, S3 bucket or SFTP directory which receives a copy of each scan
+    /// before it is sent
+    upload: Option<Destination>,
+    /// Keeps the scans started by MQTT commands and the scans sent by e-mail
+    output_dir: PathBuf,
+    /// The text recognition languages of the quality profiles
+    ocr_languages: ProfileLanguages,
+    /// The books which are being scanned, by the id which the form passes along
+    books: Mutex<HashMap<String, Book>>,
+}
+
+impl AppState {
+    /// Returns true if the scan is uploaded, sent by e-mail or passed to hooks
+    fn passes_on(&self, info: &HookInfo) -> bool {
+        self.upload.is_some() || info.mail.is_some() || !self.hooks.is_empty()
+    }
+}
+
+struct Book {
+    /// Keeps the pages until the book is finished
+    dir: TempDir,
+    pages: Vec<BookPage>,
+    /// The processing chosen for the last spread, which is applied to the whole book
+    processing: PostProcessing,
+    hook_info: HookInfo,
+    /// When the last spread was scanned
+    updated: Instant,
+}
 
+struct BookPage {
+    path: PathBuf,
+    resolution: u32,
+}
+
+impl Book {
+    fn new() -> std::io::Result<Self> {
+        Ok(Self {
+            dir: tempfile::tempdir()?,
+            pages: Vec::new(),
+            processing: PostProcessing::default(),
+            hook_info: HookInfo::default(),
+            updated: Instant::now(),
+        })
+    }
+
+    /// Writes the pages of the spread into the directory of the book
+    async fn add(&mut self, pages: Vec<JpegPage>) -> Result<(), ScannerError> {
+        for page in pages {
+            let name = format!("page-{:04}.jpg", self.pages.len() + 1);
+            let path = self.dir.path().join(name);
+            tokio::fs::write(&path, &page.data).await?;
+            self.pages.push(BookPage {
+                path,
+                resolution: page.resolution,
+            });
+        }
+        self.updated = Instant::now();
+        Ok(())
+    }
+
+    /// Loads the pages of the book into memory
+    async fn read_pages(&self) -> Result<Vec<JpegPage>, ScannerError> {
+        let mut pages = Vec::with_capacity(self.pages.len());
+        for page in &self.pages {
+            pages.push(JpegPage {
+                data: tokio::fs::read(&page.path).await?.into(),
+                resolution: page.resolution,
+            });
+        }
+        Ok(pages)
+    }
+}
+
+/// The settings of a scan which are passed to hooks, and its recipients
+#[derive(Default)]
+struct HookInfo {
+    profile: String,
+    color: String,
+    resolution: u32,
+    mail: Option<Mail>,
+}
+
+async fn run_server_async(addr: SocketAddr, state: Arc<AppState>) -> Result<()> {
     let app = Router::new()
         .route("/", get(index))
         .route("/style.css", get(style_css))
@@ -70,13 +275,15 @@ async fn run_server_async(addr: SocketAddr, scanner: Scanner) -> Result<()> {
         .route("/favicon.ico", get(favicon))
         .route("/scan", post(handle_scan_form))
         .route("/status", get(status))
+        .route("/book/finish", post(finish_book))
+        .route("/book/discard", post(discard_book))
         .layer(DefaultBodyLimit::max(1024 * 32))
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
                 .on_response(DefaultOnResponse::new().level(Level::INFO)),
         )
-        .with_state(scanner);
+        .with_state(state);
 
     let listener = tokio::net::TcpListener::bind(addr).await?;
     axum::serve(listener, app).await?;
@@ -102,59 +309,275 @@ async fn favicon(headers: HeaderMap) -> impl IntoResponse {
 #[derive(Deserialize, Debug)]
 struct ScanInput {
     format: Option<Format>,
-    colorspace: Option<ColorSpace>,
+    colorspace: Option<ColorMode>,
     source: Option<Source>,
     quality: Option<QualityProfile>,
+    rotate: Option<Rotation>,
+    blank_pages: Option<BlankPages>,
+    book: Option<BookMode>,
+    straighten: Option<StraightenMode>,
+    ocr: Option<TextRecognition>,
+    ocr_language: Option<String>,
+    mail_to: Option<String>,
+    /// Identifies the book which a spread is added to
+    book_id: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct BookInput {
+    book_id: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+enum ColorMode {
+    Color,
+    Gray,
+    Auto,
+    #[serde(rename = "auto-bilevel")]
+    AutoBilevel,
+    Lineart,
+}
+
+impl ColorMode {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Color => "color",
+            Self::Gray => "gray",
+            Self::Auto => "auto",
+            Self::AutoBilevel => "auto-bilevel",
+            Self::Lineart => "lineart",
+        }
+    }
+
+    fn color_space(&self) -> ColorSpace {
+        match self {
+            Self::Gray => ColorSpace::Gray,
+            Self::Color | Self::Auto | Self::AutoBilevel => ColorSpace::Color,
+            Self::Lineart => ColorSpace::Lineart,
+        }
+    }
+
+    fn monochrome(&self) -> Option<Monochrome> {
+        match self {
+            Self::Color | Self::Gray | Self::Lineart => None,
+            Self::Auto => Some(Monochrome::Gray),
+            Self::AutoBilevel => Some(Monochrome::Bilevel { threshold: None }),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Rotation {
+    None,
+    #[serde(rename = "90")]
+    Rotate90,
+    #[serde(rename = "180")]
+    Rotate180,
+    #[serde(rename = "270")]
+    Rotate270,
+    #[serde(rename = "flip-horizontal")]
+    FlipHorizontal,
+    #[serde(rename = "flip-vertical")]
+    FlipVertical,
+}
+
+impl Rotation {
+    fn transform(&self) -> jpeg::Transform {
+        match self {
+            Self::None => jpeg::Transform::None,
+            Self::Rotate90 => jpeg::Transform::Rotate90,
+            Self::Rotate180 => jpeg::Transform::Rotate180,
+            Self::Rotate270 => jpeg::Transform::Rotate270,
+            Self::FlipHorizontal => jpeg::Transform::FlipHorizontal,
+            Self::FlipVertical => jpeg::Transform::FlipVertical,
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "lowercase")]
-enum QualityProfile {
-    Base,
-    High,
-    Best,
+enum BlankPages {
+    Keep,
+    Remove,
 }
 
-impl QualityProfile {
-    fn resolution(&self) -> u32 {
+impl BlankPages {
+    fn threshold(&self) -> Option<f64> {
         match self {
-            Self::Base => 300,
-            Self::High => 600,
-            Self::Best => 600,
+            Self::Keep => None,
+            Self::Remove => Some(scanner::DEFAULT_BLANK_PAGE_THRESHOLD),
         }
     }
+}
 
-    fn quality(&self) -> u32 {
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+enum BookMode {
+    Off,
+    Ltr,
+    Rtl,
+}
+
+impl BookMode {
+    fn order(&self) -> Option<ReadingOrder> {
         match self {
-            Self::Base => 25,
-            Self::High => 25,
-            Self::Best => 1,
+            Self::Off => None,
+            Self::Ltr => Some(ReadingOrder::LeftToRight),
+            Self::Rtl => Some(ReadingOrder::RightToLeft),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+enum StraightenMode {
+    Off,
+    Deskew,
+    Crop,
+}
+
+impl StraightenMode {
+    fn settings(&self) -> Option<Straighten> {
+        let crop = match self {
+            Self::Off => return None,
+            Self::Deskew => false,
+            Self::Crop => true,
+        };
+        Some(Straighten {
+            deskew: true,
+            crop,
+            max_angle: deskew::DEFAULT_MAX_ANGLE,
+        })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+enum TextRecognition {
+    Off,
+    On,
+}
+
+impl TextRecognition {
+    /// Returns the entered language, or else the one of the profile
+    fn language(
+        &self,
+        languages: &ProfileLanguages,
+        profile: QualityProfile,
+        language: Option<&str>,
+    ) -> Option<String> {
+        match self {
+            Self::Off => None,
+            Self::On => Some(languages.language(Some(profile), language)),
         }
     }
 }
 
 async fn handle_scan_form(
-    State(scanner): State<Arc<Scanner>>,
+    State(state): State<Arc<AppState>>,
     Form(input): Form<ScanInput>,
 ) -> impl IntoResponse {
     let format = input.format.unwrap_or(Format::Pdf);
-    let color = input.colorspace.unwrap_or(ColorSpace::Color);
+    let color_mode = input.colorspace.unwrap_or(ColorMode::Color);
+    let color = ScanColor::from(color_mode.color_space());
     let source = input.source.unwrap_or(Source::Auto);
-    let quality = input.quality.unwrap_or(QualityProfile::Base);
-    let resolution = quality.resolution();
-    let quality = quality.quality();
+    let profile = input.quality.unwrap_or(QualityProfile::Base);
+    let resolution = profile.resolution();
+    let quality = profile.quality();
+    let mail = match mail(&state, input.mail_to.as_deref()) {
+        Ok(mail) => mail,
+        Err(e) => return render_error(&e),
+    };
+    let hook_info = HookInfo {
+        profile: profile.name().to_owned(),
+        color: color_mode.name().to_owned(),
+        resolution,
+        mail,
+    };
+    let processing = PostProcessing {
+        transform: input.rotate.unwrap_or(Rotation::None).transform(),
+        blank_page_threshold: input.blank_pages.unwrap_or(BlankPages::Keep).threshold(),
+        straighten: input.straighten.unwrap_or(StraightenMode::Off).settings(),
+        split_photos: false,
+        monochrome: color_mode.monochrome(),
+        // thresholded locally unless the scanner scans lineart
+        bilevel: (color.space == ColorSpace::Lineart).then_some(None),
+        separator: None,
+        ocr_language: input.ocr.unwrap_or(TextRecognition::Off).language(
+            &state.ocr_languages,
+            profile,
+            input.ocr_language.as_deref(),
+        ),
+    };
     info!(
-        "Scan parameters: format={format:?}, color={color:?}, source={source:?}, resolution={resolution}, quality={quality}"
+        "Scan parameters: format={format:?}, color={color:?}, source={source:?}, resolution={resolution}, quality={quality}, processing={processing:?}"
     );
-    let stream = match scan_to_stream(&scanner, format, color, source, resolution, quality).await {
+    if let Some(language) = processing.ocr_language.clone() {
+        let checked = tokio::task::spawn_blocking(move || ocr::check_engine(&language)).await;
+        match checked {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => return render_error(&e.into()),
+            Err(e) => return render_error(&std::io::Error::other(e).into()),
+        }
+    }
+    let settings = ScanSettings {
+        format,
+        color,
+        source,
+        resolution,
+        quality,
+    };
+    if let Some(order) = input.book.unwrap_or(BookMode::Off).order() {
+        let Some(id) = book_id(input.book_id.as_deref()) else {
+            return error_page("The book id is missing or invalid");
+        };
+        let spread = util::scan_spread(&state.scanner, settings, order, processing.transform).await;
+        let pages = match spread {
+            Ok(pages) => pages,
+            Err(e) => return render_error(&e),
+        };
+        let mut books = state.books.lock().await;
+        let book = match open_book(&mut books, id) {
+            Ok(Some(book)) => book,
+            Ok(None) => {
+                return error_page("Too many books are being scanned, finish or discard one first");
+            }
+            Err(e) => return render_error(&e.into()),
+        };
+        if book.pages.len() + pages.len() > MAX_BOOK_PAGES {
+            return error_page(&format!(
+                "The book has reached the limit of {MAX_BOOK_PAGES} pages"
+            ));
+        }
+        if let Err(e) = book.add(pages).await {
+            return render_error(&e);
+        }
+        book.processing = processing;
+        book.hook_info = hook_info;
+        return book_page(book.pages.len(), id);
+    }
+    let (input_source, stream) = match scan_to_stream(&state.scanner, settings, processing).await {
         Ok(s) => s,
         Err(e) => return render_error(&e),
     };
-    let mut response = Response::new(Body::from_stream(stream));
+    let filename = scanner::output_file_name(format, &Timestamp::now());
+    let body = if !state.passes_on(&hook_info) {
+        Body::from_stream(stream)
+    } else {
+        // the scan is only sent once it is uploaded, sent by e-mail and the
+        // hooks are finished, so that failures are shown
+        let result = deliver_copy(&state, stream, &filename, input_source, &hook_info).await;
+        match result {
+            Ok(spool) => Body::from_stream(ReaderStream::new(spool)),
+            Err(e) => return render_error(&e),
+        }
+    };
+    let mut response = Response::new(body);
     response
         .headers_mut()
         .insert(CONTENT_TYPE, content_type(format));
-    let filename = scanner::output_file_name(format, &Timestamp::now());
     response
         .headers_mut()
         .insert(CONTENT_DISPOSITION, content_disposition(&filename));
@@ -175,8 +598,226 @@ enum Status {
     Error,
 }
 
-async fn status(State(scanner): State<Arc<Scanner>>) -> Json<StatusResponse> {
-    let (status, message) = match scanner.get_scan_status().await {
+/// Writes the pages of the book into a PDF document and removes the book
+async fn finish_book(
+    State(state): State<Arc<AppState>>,
+    Form(input): Form<BookInput>,
+) -> Response<Body> {
+    let Some(id) = book_id(input.book_id.as_deref()) else {
+        return error_page("The book id is missing or invalid");
+    };
+    let Some(book) = state.books.lock().await.remove(id) else {
+        return error_page("No pages of a book were scanned");
+    };
+    let pages = match book.read_pages().await {
+        Ok(pages) => pages,
+        Err(e) => return render_error(&e),
+    };
+    let Book {
+        processing,
+        hook_info,
+        ..
+    } = book;
+    let filename = scanner::output_file_name(Format::Pdf, &Timestamp::now());
+    let data = match util::book_document(pages, processing).await {
+        Ok(data) => data,
+        Err(e) => return render_error(&e),
+    };
+    let body = if !state.passes_on(&hook_info) {
+        Body::from(data)
+    } else {
+        let stream = util::once_stream(data);
+        let result = deliver_copy(&state, stream, &filename, InputSource::Platen, &hook_info).await;
+        match result {
+            Ok(spool) => Body::from_stream(ReaderStream::new(spool)),
+            Err(e) => return render_error(&e),
+        }
+    };
+    let mut response = Response::new(body);
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, content_type(Format::Pdf));
+    response
+        .headers_mut()
+        .insert(CONTENT_DISPOSITION, content_disposition(&filename));
+    response
+}
+
+/// Uploads the scan to WebDAV, S3 or SFTP while it is written to a temporary
+/// file, runs the hooks on a copy of it and sends it by e-mail, and returns
+/// the temporary file. The copy is written to a temporary directory, which is
+/// removed once the hooks are finished and the e-mail is sent, unless the scan
+/// is too large to be attached and a link to it is sent. Then the copy is kept
+/// in the output directory, where the link points to, and is not removed.
+async fn deliver_copy(
+    state: &AppState,
+    stream: impl Stream<Item = Result<Bytes, ScannerError>> + Unpin,
+    filename: &str,
+    source: InputSource,
+    info: &HookInfo,
+) -> Result<File, ScannerError> {
+    let extension = filename.rsplit('.').next().unwrap_or_default();
+    let mut spool = File::from_std(tempfile::tempfile()?);
+    match &state.upload {
+        Some(destination) => {
+            let output = Output {
+                destination: destination.clone(),
+                profile: info.profile.clone(),
+                color: info.color.clone(),
+                hooks: Hooks::default(),
+                mail: None,
+            };
+            let (sender, receiver) = mpsc::channel(UPLOAD_CHANNEL_CAPACITY);
+            let upload = stream::unfold(receiver, |mut receiver| async move {
+                receiver.recv().await.map(|item| (item, receiver))
+            });
+            let upload = deliver(state, &output, upload.boxed(), extension, source, info);
+            let (spooled, uploaded) =
+                future::join(spool_copy(stream, &mut spool, Some(sender)), upload).await;
+            spooled?;
+            uploaded?;
+        }
+        None => spool_copy(stream, &mut spool, None).await?,
+    }
+    if state.hooks.is_empty() && info.mail.is_none() {
+        return rewound(&spool).await;
+    }
+    let dir = tempfile::tempdir()?;
+    // a scan which is sent as a link has to stay where the link points to
+    let size = spool.metadata().await?.len();
+    let linked = info
+        .mail
+        .as_ref()
+        .is_some_and(|mail| mail.config.links(size));
+    let destination = if linked {
+        Destination::Directory {
+            dir: state.output_dir.clone(),
+            template: None,
+        }
+    } else {
+        Destination::File(dir.path().join(filename))
+    };
+    let output = Output {
+        destination,
+        profile: info.profile.clone(),
+        color: info.color.clone(),
+        hooks: state.hooks.clone(),
+        mail: info.mail.clone(),
+    };
+    let copy = ReaderStream::new(rewound(&spool).await?).map(|item| Ok(item?));
+    deliver(state, &output, copy.boxed(), extension, source, info).await?;
+    rewound(&spool).await
+}
+
+async fn deliver(
+    state: &AppState,
+    output: &Output,
+    stream: BoxStream<'_, Result<Bytes, ScannerError>>,
+    extension: &str,
+    source: InputSource,
+    info: &HookInfo,
+) -> Result<(), ScannerError> {
+    let fields = util::name_fields(&state.scanner, output, source, info.resolution).await;
+    util::deliver(output, Content::Scan, extension, &fields, stream, "Scan").await
+}
+
+/// Writes the scan into the temporary file and passes it on to the upload,
+/// which fails if the scan is incomplete
+async fn spool_copy(
+    mut stream: impl Stream<Item = Result<Bytes, ScannerError>> + Unpin,
+    spool: &mut File,
+    sender: Option<Sender<Result<Bytes, ScannerError>>>,
+) -> Result<(), ScannerError> {
+    while let Some(item) = stream.next().await {
+        let data = match item {
+            Ok(data) => data,
+            Err(e) => {
+                if let Some(sender) = &sender {
+                    let failed = std::io::Error::other("the scan failed");
+                    let _ = sender.send(Err(failed.into())).await;
+                }
+                return Err(e);
+            }
+        };
+        spool.write_all(&data).await?;
+        if let Some(sender) = &sender {
+            // the scan is still returned if the upload stops early
+            let _ = sender.send(Ok(data)).await;
+        }
+    }
+    spool.flush().await?;
+    Ok(())
+}
+
+/// Returns a handle of the temporary file which reads it from the start
+async fn rewound(spool: &File) -> Result<File, ScannerError> {
+    let mut file = spool.try_clone().await?;
+    file.rewind().await?;
+    Ok(file)
+}
+
+/// Returns the recipients entered in the form, separated by commas
+fn mail(state: &AppState, addresses: Option<&str>) -> Result<Option<Mail>, ScannerError> {
+    let to = mail::parse_addresses(addresses.unwrap_or_default())?;
+    if to.is_empty() {
+        return Ok(None);
+    }
+    let Some(config) = state.mail.clone() else {
+        return Err(MailError::NotConfigured.into());
+    };
+    state.mail_allow.check(&to)?;
+    Ok(Some(Mail { config, to }))
+}
+
+async fn discard_book(
+    State(state): State<Arc<AppState>>,
+    Form(input): Form<BookInput>,
+) -> Redirect {
+    if let Some(id) = book_id(input.book_id.as_deref()) {
+        state.books.lock().await.remove(id);
+    }
+    Redirect::to("../")
+}
+
+/// Returns the id of a book if it is valid. It is generated by the form.
+fn book_id(id: Option<&str>) -> Option<&str> {
+    id.filter(|id| {
+        !id.is_empty()
+            && id.len() <= 64
+            && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    })
+}
+
+/// Returns the book with the id, or starts it unless too many books are
+/// scanned. Books which were not continued for a day are discarded to make
+/// room for a new one.
+fn open_book<'a>(
+    books: &'a mut HashMap<String, Book>,
+    id: &str,
+) -> std::io::Result<Option<&'a mut Book>> {
+    if !books.contains_key(id) {
+        books.retain(|_, book| book.updated.elapsed() < BOOK_EXPIRY);
+        if books.len() >= MAX_BOOKS {
+            return Ok(None);
+        }
+        books.insert(id.to_owned(), Book::new()?);
+    }
+    Ok(books.get_mut(id))
+}
+
+fn book_page(page_count: usize, id: &str) -> Response<Body> {
+    let page = BOOK_TEMPLATE
+        .replace("{page_count}", &page_count.to_string())
+        .replace("{book_id}", id);
+    let mut response = Response::new(Body::from(page));
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(TEXT_HTML));
+    response
+}
+
+async fn status(State(state): State<Arc<AppState>>) -> Json<StatusResponse> {
+    let (status, message) = match state.scanner.get_scan_status().await {
         Ok(status) => match status.scanner_state() {
             ScannerState::Idle => (Status::Idle, "idle".to_owned()),
             ScannerState::BusyWithScanJob => (Status::Busy, "busy".to_owned()),
@@ -194,6 +835,8 @@ fn content_type(format: Format) -> HeaderValue {
     match format {
         Format::Pdf => HeaderValue::from_static("application/pdf"),
         Format::Jpeg => HeaderValue::from_static("image/jpeg"),
+        Format::Png => HeaderValue::from_static("image/png"),
+        Format::Tiff => HeaderValue::from_static("image/tiff"),
     }
 }
 
@@ -213,13 +856,21 @@ fn render_error(error: &ScannerError) -> Response<Body> {
         ScannerError::AdfEmpty => error_page("ADF is empty"),
         ScannerError::Busy => error_page("Scanner is busy"),
         ScannerError::NotAvailable { ref source } => {
-            error_page(format!("{error}<p>Cause: {source}</p>").as_str())
+            error_page_with_cause(&error.to_string(), &source.to_string())
         }
         ScannerError::Parse {
             ref source,
             data: _,
-        } => error_page(format!("{error}<p>Cause: {source}</p>").as_str()),
+        } => error_page_with_cause(&error.to_string(), &source.to_string()),
         ScannerError::Canceled => error_page("Scan cancelled"),
+        ScannerError::Ocr(ref source) => error_page(&source.to_string()),
+        ScannerError::Pdf(ref source) => error_page(&source.to_string()),
+        ScannerError::Jpeg(ref source) => error_page(&source.to_string()),
+        ScannerError::Hook(ref source) => error_page(&source.to_string()),
+        ScannerError::Mail(ref source) => error_page(&source.to_string()),
+        ScannerError::WebDav(ref source) => error_page(&source.to_string()),
+        ScannerError::S3(ref source) => error_page(&source.to_string()),
+        ScannerError::Sftp(ref source) => error_page(&source.to_string()),
         _ => {
             error!("InternalServerError: Failed to scan. {error:?}");
             let mut response = Response::new(Body::empty());
@@ -229,9 +880,22 @@ fn render_error(error: &ScannerError) -> Response<Body> {
     }
 }
 
-fn error_page(error_message: &str) -> Response<Body> {
-    #[allow(clippy::all)]
-    let page = ERROR_TEMPLATE.replace("{error_message}", error_message);
+/// Returns the error page with the message, which may contain text from the
+/// request or a remote server and is escaped
+fn error_page(message: &str) -> Response<Body> {
+    html_error_page(&escape_html(message))
+}
+
+fn error_page_with_cause(message: &str, cause: &str) -> Response<Body> {
+    html_error_page(&format!(
+        "{}<p>Cause: {}</p>",
+        escape_html(message),
+        escape_html(cause)
+    ))
+}
+
+fn html_error_page(html: &str) -> Response<Body> {
+    let page = ERROR_TEMPLATE.replace("{error_message}", html);
     let mut response = Response::new(Body::from(page));
     response
         .headers_mut()
@@ -239,6 +903,21 @@ fn error_page(error_message: &str) -> Response<Body> {
     response
 }
 
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
 #[cfg(test)]
 mod test {
 
@@ -255,4 +934,89 @@ mod test {
             String::from_utf8_lossy(content_disposition("äöü.txt").as_bytes())
         );
     }
+
+    #[tokio::test]
+    async fn escape_error_message() {
+        let response = error_page("Invalid <script>alert('x')</script> & more");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(
+            body.contains("Invalid &lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt; &amp; more")
+        );
+        assert!(!body.contains("<script>"));
+    }
+
+    #[tokio::test]
+    async fn spool_and_pass_on_scan() {
+        let chunks = [b"0123".as_slice(), b"4567", b"89"];
+        let scan = stream::iter(chunks.map(|chunk| Ok(Bytes::from_static(chunk))));
+        let mut spool = File::from_std(tempfile::tempfile().unwrap());
+        let (sender, mut receiver) =
+            mpsc::channel::<Result<Bytes, ScannerError>>(UPLOAD_CHANNEL_CAPACITY);
+        let upload = async {
+            let mut uploaded = Vec::new();
+            while let Some(item) = receiver.recv().await {
+                uploaded.extend_from_slice(&item.unwrap());
+            }
+            uploaded
+        };
+        let (spooled, uploaded) =
+            future::join(spool_copy(scan, &mut spool, Some(sender)), upload).await;
+        spooled.unwrap();
+        assert_eq!(uploaded, b"0123456789");
+        let mut copy = Vec::new();
+        let mut file = rewound(&spool).await.unwrap();
+        tokio::io::AsyncReadExt::read_to_end(&mut file, &mut copy)
+            .await
+            .unwrap();
+        assert_eq!(copy, b"0123456789");
+    }
+
+    #[tokio::test]
+    async fn fail_upload_of_incomplete_scan() {
+        let scan = stream::iter([Ok(Bytes::from_static(b"0123")), Err(ScannerError::Busy)]);
+        let mut spool = File::from_std(tempfile::tempfile().unwrap());
+        let (sender, mut receiver) = mpsc::channel(UPLOAD_CHANNEL_CAPACITY);
+        let error = spool_copy(scan, &mut spool, Some(sender))
+            .await
+            .unwrap_err();
+        assert!(matches!(error, ScannerError::Busy));
+        assert!(receiver.recv().await.unwrap().is_ok());
+        assert!(receiver.recv().await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn keep_books_apart() {
+        let mut books = HashMap::new();
+        let page = |data: &'static [u8]| JpegPage {
+            data: Bytes::from_static(data),
+            resolution: 300,
+        };
+        let book = open_book(&mut books, "first").unwrap().unwrap();
+        book.add(vec![page(b"1"), page(b"2")]).await.unwrap();
+        let book = open_book(&mut books, "second").unwrap().unwrap();
+        book.add(vec![page(b"a")]).await.unwrap();
+        let book = open_book(&mut books, "first").unwrap().unwrap();
+        book.add(vec![page(b"3")]).await.unwrap();
+
+        let pages = books["first"].read_pages().await.unwrap();
+        let pages: Vec<_> = pages.iter().map(|page| &page.data[..]).collect();
+        assert_eq!(pages, [b"1", b"2", b"3"]);
+        assert_eq!(books["second"].pages.len(), 1);
+        for id in ["third", "fourth"] {
+            assert!(open_book(&mut books, id).unwrap().is_some());
+        }
+        assert!(open_book(&mut books, "fifth").unwrap().is_none());
+    }
+
+    #[test]
+    fn check_book_id() {
+        assert_eq!(book_id(Some("0f3a-b9")), Some("0f3a-b9"));
+        assert_eq!(book_id(Some("")), None);
+        assert_eq!(book_id(Some("<script>")), None);
+        assert_eq!(book_id(Some(&"a".repeat(65))), None);
+        assert_eq!(book_id(None), None);
+    }
 }
@@ -0,0 +1,580 @@
+use rumqttc::{AsyncClient, Event as MqttEvent, LastWill, MqttOptions, Packet, QoS};
+use serde_json::json;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tracing::{debug, error, info, warn};
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::cli::{QualityProfile, Source};
+use crate::message::scan_job::{ColorSpace, Format};
+use crate::output::{Destination, Output};
+use crate::scanner::{PostProcessing, ScannerError};
+use crate::util::{self, ScanSettings};
+use crate::web::AppState;
+use crate::webhook::{Event, EventKind};
+
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Number of requests waiting for the connection to the broker
+const CAPACITY: usize = 64;
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+/// Scanner state while it does not answer
+const UNAVAILABLE: &str = "Unavailable";
+/// Adf state of scanners without one
+const NO_ADF: &str = "None";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    /// User name and password
+    pub credentials: Option<(String, String)>,
+    /// Prefix of the topics of the scanner
+    pub topic: String,
+    /// Prefix of the Home Assistant discovery topics
+    pub discovery_prefix: String,
+    /// Time between status requests to the scanner
+    pub interval: Duration,
+}
+
+impl MqttConfig {
+    fn topic(&self, name: &str) -> String {
+        format!("{}/{name}", self.topic)
+    }
+}
+
+/// Input of the publishing loop
+#[derive(Debug)]
+enum Message {
+    Connected,
+    Disconnected,
+    Tick,
+    Event(Event),
+    /// A scan was requested with the payload of the command
+    Scan(String),
+}
+
+/// The states last published to the broker
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Published {
+    scanner_state: String,
+    adf_state: String,
+}
+
+/// Connects to the broker and publishes the states of the scanner and the
+/// events of its jobs until the events end. The connection is established
+/// again after it fails.
+pub fn start(config: MqttConfig, state: Arc<AppState>, mut events: UnboundedReceiver<Event>) {
+    let node = node_id(state.scanner.host());
+    let mut options = MqttOptions::new(format!("covet-{node}"), &config.host, config.port);
+    options.set_keep_alive(KEEP_ALIVE);
+    options.set_last_will(LastWill::new(
+        config.topic("status"),
+        OFFLINE,
+        QoS::AtLeastOnce,
+        true,
+    ));
+    if let Some((user, password)) = &config.credentials {
+        options.set_credentials(user, password);
+    }
+    let (client, mut eventloop) = AsyncClient::new(options, CAPACITY);
+    let (sender, receiver) = mpsc::unbounded_channel();
+
+    let command_topic = config.topic("scan");
+    let connection = sender.clone();
+    tokio::spawn(async move {
+        loop {
+            let message = match eventloop.poll().await {
+                Ok(MqttEvent::Incoming(Packet::ConnAck(_))) => {
+                    info!("Connected to MQTT broker");
+                    Message::Connected
+                }
+                Ok(MqttEvent::Incoming(Packet::Publish(publish)))
+                    if publish.topic == command_topic =>
+                {
+                    Message::Scan(String::from_utf8_lossy(&publish.payload).into_owned())
+                }
+                Ok(_) => continue,
+                Err(e) => {
+                    warn!("MQTT connection failed, reconnecting. {e}");
+                    if connection.send(Message::Disconnected).is_err() {
+                        break;
+                    }
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                    continue;
+                }
+            };
+            if connection.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    let events_sender = sender.clone();
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            if events_sender.send(Message::Event(event)).is_err() {
+                break;
+            }
+        }
+    });
+
+    super::repeat(config.interval, move || {
+        let sent = sender.send(Message::Tick).is_ok();
+        async move { sent }
+    });
+
+    tokio::spawn(publish(config, node, state, client, receiver));
+}
+
+async fn publish(
+    config: MqttConfig,
+    node: String,
+    state: Arc<AppState>,
+    client: AsyncClient,
+    mut receiver: UnboundedReceiver<Message>,
+) {
+    let publisher = Publisher { config, client };
+    let mut published = None;
+    let mut connected = false;
+    while let Some(message) = receiver.recv().await {
+        match message {
+            Message::Connected => {
+                connected = true;
+                publisher.subscribe("scan");
+                publisher.send("status", ONLINE.into(), true);
+                publisher.send_discovery(&node, &state).await;
+                // the broker may have lost the retained states
+                published = None;
+                update_status(&publisher, &state, &mut published).await;
+            }
+            Message::Disconnected => connected = false,
+            // the states are published after connecting
+            Message::Tick if !connected => {}
+            Message::Tick => update_status(&publisher, &state, &mut published).await,
+            Message::Event(event) => match event.event {
+                EventKind::ScannerOffline | EventKind::ScannerOnline if !connected => {}
+                EventKind::ScannerOffline | EventKind::ScannerOnline => {
+                    update_status(&publisher, &state, &mut published).await;
+                }
+                _ => {
+                    let payload = serde_json::to_string(&event).expect("the event serializes");
+                    publisher.send("job", payload, true);
+                }
+            },
+            Message::Scan(payload) => scan(&state, payload.trim()),
+        }
+    }
+}
+
+struct Publisher {
+    config: MqttConfig,
+    client: AsyncClient,
+}
+
+impl Publisher {
+    /// Queues a message for the broker. Messages are dropped while the
+    /// connection is down for long, the retained states are published again
+    /// after reconnecting.
+    fn send(&self, name: &str, payload: String, retain: bool) {
+        self.send_to(self.config.topic(name), payload, retain);
+    }
+
+    fn send_to(&self, topic: String, payload: String, retain: bool) {
+        if let Err(e) = self
+            .client
+            .try_publish(topic, QoS::AtLeastOnce, retain, payload)
+        {
+            debug!("Cannot publish MQTT message. {e}");
+        }
+    }
+
+    fn subscribe(&self, name: &str) {
+        if let Err(e) = self
+            .client
+            .try_subscribe(self.config.topic(name), QoS::AtLeastOnce)
+        {
+            error!("Cannot subscribe to MQTT commands. {e}");
+        }
+    }
+
+    /// Publishes the Home Assistant discovery messages of a device with
+    /// sensors for the states and the last job and a button per profile
+    async fn send_discovery(&self, node: &str, state: &AppState) {
+        let model = match state.scanner.get_product_config().await {
+            Ok(config) => config.model().to_owned(),
+            Err(e) => {
+                debug!("Cannot retrieve the device model. {e}");
+                "HP scanner".to_owned()
+            }
+        };
+        let device = json!({
+            "identifiers": [format!("covet_{node}")],
+            "name": format!("Scanner {}", state.scanner.host()),
+            "manufacturer": "HP",
+            "model": model,
+            "sw_version": env!("CARGO_PKG_VERSION"),
+        });
+        let sensors = [
+            ("scanner_state", "State", None),
+            ("adf_state", "Document feeder", None),
+            ("job", "Last job", Some("{{ value_json.event }}")),
+        ];
+        for (name, title, template) in sensors {
+            let mut config = self.entity(node, name, title, &device);
+            config["state_topic"] = self.config.topic(name).into();
+            if let Some(template) = template {
+                config["value_template"] = template.into();
+                config["json_attributes_topic"] = self.config.topic(name).into();
+            }
+            self.send_to(
+                self.discovery_topic("sensor", node, name),
+                config.to_string(),
+                true,
+            );
+        }
+        for profile in QualityProfile::ALL {
+            let name = format!("scan_{}", profile.name());
+            let title = format!("Scan {}", profile.name());
+            let mut config = self.entity(node, &name, &title, &device);
+            config["command_topic"] = self.config.topic("scan").into();
+            config["payload_press"] = profile.name().into();
+            self.send_to(
+                self.discovery_topic("button", node, &name),
+                config.to_string(),
+                true,
+            );
+        }
+    }
+
+    fn entity(
+        &self,
+        node: &str,
+        name: &str,
+        title: &str,
+        device: &serde_json::Value,
+    ) -> serde_json::Value {
+        json!({
+            "name": title,
+            "unique_id": format!("covet_{node}_{name}"),
+            "availability_topic": self.config.topic("status"),
+            "device": device,
+        })
+    }
+
+    fn discovery_topic(&self, component: &str, node: &str, name: &str) -> String {
+        format!(
+            "{}/{component}/covet_{node}/{name}/config",
+            self.config.discovery_prefix
+        )
+    }
+}
+
+/// Requests the status of the scanner and publishes the states which changed
+async fn update_status(publisher: &Publisher, state: &AppState, published: &mut Option<Published>) {
+    let current = match state.scanner.get_scan_status().await {
+        Ok(status) => Published {
+            scanner_state: format!("{:?}", status.scanner_state()),
+            adf_state: status
+                .adf_state()
+                .map_or(NO_ADF.to_owned(), |adf| format!("{adf:?}")),
+        },
+        Err(e) => {
+            debug!("Cannot retrieve the scanner status. {e}");
+            Published {
+                scanner_state: UNAVAILABLE.to_owned(),
+                adf_state: published
+                    .as_ref()
+                    .map_or(NO_ADF.to_owned(), |p| p.adf_state.clone()),
+            }
+        }
+    };
+    let previous = published.replace(current.clone());
+    if previous.as_ref().map(|p| &p.scanner_state) != Some(&current.scanner_state) {
+        publisher.send("scanner_state", current.scanner_state, true);
+    }
+    if previous.as_ref().map(|p| &p.adf_state) != Some(&current.adf_state) {
+        publisher.send("adf_state", current.adf_state, true);
+    }
+}
+
+/// Starts a PDF scan with the profile named in the payload. Its progress is
+/// published with the job events.
+fn scan(state: &Arc<AppState>, payload: &str) {
+    let Some(profile) = QualityProfile::ALL
+        .into_iter()
+        .find(|profile| profile.name() == payload)
+    else {
+        warn!("Unknown scan profile {payload:?} in MQTT command");
+        return;
+    };
+    info!("Scan with profile {} requested over MQTT", profile.name());
+    let settings = ScanSettings {
+        format: Format::Pdf,
+        color: ColorSpace::Color.into(),
+        source: Source::Auto,
+        resolution: profile.resolution(),
+        quality: profile.quality(),
+    };
+    let output = Output {
+        destination: state
+            .upload
+            .clone()
+            .unwrap_or_else(|| Destination::Directory {
+                dir: state.output_dir.clone(),
+                template: None,
+            }),
+        profile: profile.name().to_owned(),
+        color: "color".to_owned(),
+        hooks: state.hooks.clone(),
+        mail: None,
+    };
+    let state = state.clone();
+    tokio::spawn(async move {
+        let processing = PostProcessing::default();
+        let result: Result<(), ScannerError> =
+            util::scan_to_file(&state.scanner, settings, processing, output).await;
+        if let Err(e) = result {
+            error!("Scan requested over MQTT failed. {e}");
+        }
+    });
+}
+
+/// Returns the host name with the characters allowed in discovery topics
+fn node_id(host: &str) -> String {
+    host.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hook::Hooks;
+    use crate::mail::AllowedRecipients;
+    use crate::ocr::ProfileLanguages;
+    use crate::scanner::Scanner;
+    use crate::webhook;
+    use axum::Router;
+    use axum::routing::{get, post};
+    use bytes::BytesMut;
+    use hyper::StatusCode;
+    use rumqttc::{ConnAck, ConnectReturnCode, PubAck, Publish, SubAck, SubscribeReasonCode};
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::broadcast;
+
+    const MAX_PACKET: usize = 1024 * 1024;
+    const STATUS: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+        <ScanStatus xmlns="http://www.hp.com/schemas/imaging/con/cnx/scan/2008/08/19">
+        <ScannerState>Idle</ScannerState>
+        <AdfState>Loaded</AdfState>
+        </ScanStatus>"#;
+
+    /// A broker which forwards messages to the subscribers of their exact
+    /// topic and records all of them
+    #[derive(Default)]
+    struct Broker {
+        connections: Mutex<u32>,
+        messages: Mutex<Vec<(String, String)>>,
+        subscribers: Mutex<Vec<(String, mpsc::UnboundedSender<Publish>)>>,
+    }
+
+    impl Broker {
+        fn messages(&self, topic: &str) -> Vec<String> {
+            let messages = self.messages.lock().unwrap();
+            messages
+                .iter()
+                .filter(|(t, _)| t == topic)
+                .map(|(_, payload)| payload.clone())
+                .collect()
+        }
+
+        fn publish(&self, publish: Publish) {
+            let payload = String::from_utf8_lossy(&publish.payload).into_owned();
+            let message = (publish.topic.clone(), payload);
+            self.messages.lock().unwrap().push(message);
+            let subscribers = self.subscribers.lock().unwrap();
+            for (topic, subscriber) in subscribers.iter() {
+                if *topic == publish.topic {
+                    let forwarded =
+                        Publish::new(&publish.topic, QoS::AtMostOnce, publish.payload.to_vec());
+                    let _ = subscriber.send(forwarded);
+                }
+            }
+        }
+    }
+
+    async fn start_broker() -> (u16, Arc<Broker>, broadcast::Sender<()>) {
+        let broker = Arc::new(Broker::default());
+        let (disconnect, _) = broadcast::channel(1);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let shared = broker.clone();
+        let disconnects = disconnect.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                *shared.connections.lock().unwrap() += 1;
+                tokio::spawn(serve(stream, shared.clone(), disconnects.subscribe()));
+            }
+        });
+        (port, broker, disconnect)
+    }
+
+    async fn serve(
+        mut stream: TcpStream,
+        broker: Arc<Broker>,
+        mut disconnect: broadcast::Receiver<()>,
+    ) {
+        let (sender, mut forwarded) = mpsc::unbounded_channel();
+        let mut buffer = BytesMut::new();
+        loop {
+            while let Ok(publish) = forwarded.try_recv() {
+                write(&mut stream, Packet::Publish(publish)).await;
+            }
+            if disconnect.try_recv().is_ok() {
+                return;
+            }
+            let packet = match Packet::read(&mut buffer, MAX_PACKET) {
+                Ok(packet) => packet,
+                Err(_) => {
+                    let read = tokio::time::timeout(
+                        Duration::from_millis(20),
+                        stream.read_buf(&mut buffer),
+                    )
+                    .await;
+                    match read {
+                        Ok(Ok(0)) | Ok(Err(_)) => return,
+                        _ => continue,
+                    }
+                }
+            };
+            let reply = match packet {
+                Packet::Connect(..) => Some(Packet::ConnAck(ConnAck::new(
+                    ConnectReturnCode::Success,
+                    false,
+                ))),
+                Packet::Subscribe(subscribe) => {
+                    let mut subscribers = broker.subscribers.lock().unwrap();
+                    for filter in &subscribe.filters {
+                        subscribers.push((filter.path.clone(), sender.clone()));
+                    }
+                    let codes = vec![
+                        SubscribeReasonCode::Success(QoS::AtMostOnce);
+                        subscribe.filters.len()
+                    ];
+                    Some(Packet::SubAck(SubAck::new(subscribe.pkid, codes)))
+                }
+                Packet::Publish(publish) => {
+                    let pkid = publish.pkid;
+                    let qos = publish.qos;
+                    broker.publish(publish);
+                    (qos == QoS::AtLeastOnce).then(|| Packet::PubAck(PubAck::new(pkid)))
+                }
+                Packet::PingReq => Some(Packet::PingResp),
+                Packet::Disconnect => return,
+                _ => None,
+            };
+            if let Some(reply) = reply {
+                write(&mut stream, reply).await;
+            }
+        }
+    }
+
+    async fn write(stream: &mut TcpStream, packet: Packet) {
+        let mut buffer = BytesMut::new();
+        packet.write(&mut buffer, MAX_PACKET).unwrap();
+        let _ = stream.write_all(&buffer).await;
+    }
+
+    /// A scanner which is idle with a loaded feeder and fails to create jobs
+    async fn start_scanner() -> (String, Arc<Mutex<Vec<String>>>) {
+        let jobs = Arc::new(Mutex::new(Vec::new()));
+        let recorded = jobs.clone();
+        let app = Router::new()
+            .route("/Scan/Status", get(|| async { STATUS }))
+            .route(
+                "/Scan/Jobs",
+                post(move |body: String| async move {
+                    recorded.lock().unwrap().push(body);
+                    StatusCode::SERVICE_UNAVAILABLE
+                }),
+            );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (host, jobs)
+    }
+
+    async fn wait_for(condition: impl Fn() -> bool) {
+        for _ in 0..200 {
+            if condition() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(25)).await;
+        }
+        panic!("condition not reached");
+    }
+
+    #[tokio::test]
+    async fn publish_states_and_scan_on_command() {
+        let (port, broker, disconnect) = start_broker().await;
+        let (host, jobs) = start_scanner().await;
+        let (notifier, events) = webhook::channel();
+        let dir = tempfile::tempdir().unwrap();
+        let state = Arc::new(AppState {
+            scanner: Scanner::new(&host, false, false).with_notifier(&notifier),
+            hooks: Hooks::default(),
+            mail: None,
+            mail_allow: AllowedRecipients::default(),
+            upload: None,
+            output_dir: dir.path().to_path_buf(),
+            ocr_languages: ProfileLanguages::default(),
+            books: tokio::sync::Mutex::new(HashMap::new()),
+        });
+        drop(notifier);
+        let config = MqttConfig {
+            host: "127.0.0.1".to_owned(),
+            port,
+            credentials: None,
+            topic: "covet".to_owned(),
+            discovery_prefix: "homeassistant".to_owned(),
+            interval: Duration::from_secs(60),
+        };
+        start(config, state, events);
+
+        wait_for(|| !broker.messages("covet/adf_state").is_empty()).await;
+        assert_eq!(broker.messages("covet/status"), [ONLINE]);
+        assert_eq!(broker.messages("covet/scanner_state"), ["Idle"]);
+        assert_eq!(broker.messages("covet/adf_state"), ["Loaded"]);
+        let button = broker.messages("homeassistant/button/covet_127_0_0_1/scan_high/config");
+        let button: serde_json::Value = serde_json::from_str(&button[0]).unwrap();
+        assert_eq!(button["command_topic"], "covet/scan");
+        assert_eq!(button["payload_press"], "high");
+        assert_eq!(button["device"]["identifiers"][0], "covet_127_0_0_1");
+
+        // a command starts a scan with the resolution of the profile
+        broker.publish(Publish::new("covet/scan", QoS::AtMostOnce, "high"));
+        wait_for(|| !broker.messages("covet/job").is_empty()).await;
+        assert!(jobs.lock().unwrap()[0].contains("<scan:XResolution>600</scan:XResolution>"));
+        let event: serde_json::Value =
+            serde_json::from_str(&broker.messages("covet/job")[0]).unwrap();
+        assert_eq!(event["event"], "job_failed");
+
+        // the client connects again and publishes its status
+        disconnect.send(()).unwrap();
+        wait_for(|| broker.messages("covet/scanner_state").len() == 2).await;
+        assert_eq!(*broker.connections.lock().unwrap(), 2);
+        assert_eq!(broker.messages("covet/status"), [ONLINE, ONLINE]);
+    }
+
+    #[test]
+    fn check_node_id() {
+        assert_eq!(node_id("envy-5530.local"), "envy_5530_local");
+    }
+}
@@ -0,0 +1,527 @@
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt, future, stream};
+use reqwest::header::{CONTENT_LENGTH, CONTENT_RANGE};
+use reqwest::{Body, Client, Method, RequestBuilder, Response, StatusCode};
+use thiserror::Error;
+use tokio::fs::File;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::mpsc::{self, Sender};
+use tokio_util::io::ReaderStream;
+use tracing::{debug, info, warn};
+use url::Url;
+
+use std::io::{self, SeekFrom};
+use std::time::Duration;
+
+use crate::output::{self, Content, FolderTemplate, NameFields, NameTemplate};
+use crate::scanner::ScannerError;
+
+/// Delay before the first retry of an upload, doubled for every further retry
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Number of chunks of the scan which may wait for the upload
+const CHANNEL_CAPACITY: usize = 4;
+
+#[derive(Debug, Error)]
+pub enum WebDavError {
+    #[error("WebDAV {method} {url} failed: received status {status}")]
+    Status {
+        method: Method,
+        url: Url,
+        status: StatusCode,
+    },
+    #[error("WebDAV request failed: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("Invalid WebDAV URL {0}")]
+    Url(Url),
+    #[error("Cannot keep a copy of the upload: {0}")]
+    Spool(#[from] io::Error),
+}
+
+/// A collection on a WebDAV server, like a Nextcloud folder, which new files
+/// are uploaded to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebDav {
+    pub collection: Url,
+    /// Folders in the collection, which are created if needed
+    pub folders: Option<FolderTemplate>,
+    pub template: Option<NameTemplate>,
+    /// User name and password, which may be an app password
+    pub credentials: Option<(String, String)>,
+    /// Number of attempts to upload a file
+    pub attempts: u32,
+}
+
+impl WebDav {
+    pub fn has_model(&self) -> bool {
+        self.template.as_ref().is_some_and(NameTemplate::has_model)
+            || self.folders.as_ref().is_some_and(FolderTemplate::has_model)
+    }
+
+    /// Uploads the data to a new file and returns its URL. The data is
+    /// streamed to a temporary file on the server while a local copy is kept.
+    /// A failed upload is continued from the local copy where the server
+    /// supports partial updates and repeated otherwise. The complete file is
+    /// moved to its name, which gets a number if it exists already.
+    pub async fn upload(
+        &self,
+        content: Content<'_>,
+        extension: &str,
+        fields: &NameFields,
+        stream: impl Stream<Item = Result<Bytes, ScannerError>> + Unpin,
+    ) -> Result<Url, ScannerError> {
+        let client = Client::new();
+        let folder = self.create_folders(&client, fields).await?;
+        let part_name = format!(
+            ".covet-{}-{}.part",
+            fields.time.as_nanosecond(),
+            content.page()
+        );
+        let part = join(&folder, &part_name)?;
+
+        let mut spool = File::from_std(tempfile::tempfile()?);
+        let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        let body = stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.map(|item| (item, receiver))
+        });
+        let put = self
+            .request(&client, Method::PUT, &part)
+            .body(Body::wrap_stream(body))
+            .send();
+        let (copied, response) = future::join(copy(stream, &mut spool, sender), put).await;
+        let size = match copied {
+            Ok(size) => size,
+            Err(e) => {
+                self.delete(&client, &part).await;
+                return Err(e);
+            }
+        };
+
+        let mut result = response
+            .map_err(WebDavError::from)
+            .and_then(|response| check(response, Method::PUT, &part));
+        let mut delay = RETRY_DELAY;
+        for _ in 1..self.attempts {
+            let Err(e) = &result else {
+                break;
+            };
+            warn!("{e}. Retrying in {} seconds", delay.as_secs_f64());
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+            result = self.resume(&client, &part, &mut spool, size).await;
+        }
+        if let Err(e) = result {
+            self.delete(&client, &part).await;
+            return Err(e.into());
+        }
+
+        let template = output::file_template(self.template.clone(), content);
+        for number in 1.. {
+            let name = output::numbered_name(&template, fields, content.page(), number);
+            let url = join(&folder, &format!("{name}.{extension}"))?;
+            let response = self
+                .request(&client, move_method(), &part)
+                .header("Destination", url.as_str())
+                .header("Overwrite", "F")
+                .send()
+                .await
+                .map_err(WebDavError::from)?;
+            if response.status() != StatusCode::PRECONDITION_FAILED {
+                check(response, move_method(), &part)?;
+                return Ok(url);
+            }
+        }
+        unreachable!("the file name numbers are exhausted")
+    }
+
+    /// Creates the folders of the template in the collection and returns the
+    /// URL of the innermost one
+    async fn create_folders(
+        &self,
+        client: &Client,
+        fields: &NameFields,
+    ) -> Result<Url, WebDavError> {
+        let mut folder = self.collection.clone();
+        let folders = self.folders.as_ref().map(|folders| folders.render(fields));
+        for name in folders.into_iter().flatten() {
+            folder = join(&folder, &name)?;
+            folder
+                .path_segments_mut()
+                .map_err(|()| WebDavError::Url(self.collection.clone()))?
+                .push("");
+            let method = Method::from_bytes(b"MKCOL").expect("valid method");
+            let response = self.request(client, method.clone(), &folder).send().await?;
+            // the folder exists already
+            if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+                check(response, method, &folder)?;
+            }
+        }
+        Ok(folder)
+    }
+
+    /// Uploads the local copy again, or only the part which is missing on the
+    /// server if it supports a PUT with a Content-Range
+    async fn resume(
+        &self,
+        client: &Client,
+        part: &Url,
+        spool: &mut File,
+        size: u64,
+    ) -> Result<(), WebDavError> {
+        let uploaded = self.uploaded_size(client, part).await;
+        if let Some(offset) = uploaded.filter(|&offset| offset > 0 && offset < size) {
+            let response = self
+                .request(client, Method::PUT, part)
+                .header(CONTENT_RANGE, format!("bytes {offset}-{}/{size}", size - 1))
+                .body(read_from(spool, offset).await?)
+                .send()
+                .await?;
+            let status = response.status();
+            if !status.is_success() {
+                debug!("The server does not continue the upload: received status {status}");
+            } else {
+                // servers which ignore the Content-Range replace the file
+                match self.uploaded_size(client, part).await {
+                    Some(uploaded) if uploaded == size => {
+                        info!("Resumed the upload of {part} at {offset} bytes");
+                        return Ok(());
+                    }
+                    _ => {
+                        warn!("The resumed upload of {part} has the wrong size, uploading it again")
+                    }
+                }
+            }
+        }
+        let response = self
+            .request(client, Method::PUT, part)
+            .body(read_from(spool, 0).await?)
+            .send()
+            .await?;
+        check(response, Method::PUT, part)
+    }
+
+    /// Returns the size of the file on the server
+    async fn uploaded_size(&self, client: &Client, url: &Url) -> Option<u64> {
+        let response = self.request(client, Method::HEAD, url).send().await.ok()?;
+        if !response.status().is_success() {
+            return None;
+        }
+        response
+            .headers()
+            .get(CONTENT_LENGTH)?
+            .to_str()
+            .ok()?
+            .parse()
+            .ok()
+    }
+
+    /// Removes the temporary file of a failed upload, if it exists
+    async fn delete(&self, client: &Client, url: &Url) {
+        if let Err(e) = self.request(client, Method::DELETE, url).send().await {
+            debug!("Cannot delete {url}. {e}");
+        }
+    }
+
+    fn request(&self, client: &Client, method: Method, url: &Url) -> RequestBuilder {
+        let request = client.request(method, url.clone());
+        match &self.credentials {
+            Some((user, password)) => request.basic_auth(user, Some(password)),
+            None => request,
+        }
+    }
+}
+
+/// Passes the data on to the upload and writes it to the local copy. The
+/// upload fails if the data is incomplete.
+async fn copy(
+    mut stream: impl Stream<Item = Result<Bytes, ScannerError>> + Unpin,
+    spool: &mut File,
+    sender: Sender<io::Result<Bytes>>,
+) -> Result<u64, ScannerError> {
+    let mut size = 0;
+    while let Some(item) = stream.next().await {
+        let data = match item {
+            Ok(data) => data,
+            Err(e) => {
+                let _ = sender.send(Err(io::Error::other("the scan failed"))).await;
+                return Err(e);
+            }
+        };
+        spool.write_all(&data).await?;
+        size += data.len() as u64;
+        // a failed upload is continued from the local copy
+        let _ = sender.send(Ok(data)).await;
+    }
+    spool.flush().await?;
+    Ok(size)
+}
+
+async fn read_from(spool: &mut File, offset: u64) -> Result<Body, io::Error> {
+    let mut file = spool.try_clone().await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    Ok(Body::wrap_stream(ReaderStream::new(file)))
+}
+
+/// Returns the URL of the file or folder with the name in the folder
+fn join(folder: &Url, name: &str) -> Result<Url, WebDavError> {
+    let mut url = folder.clone();
+    url.path_segments_mut()
+        .map_err(|()| WebDavError::Url(folder.clone()))?
+        .pop_if_empty()
+        .push(name);
+    Ok(url)
+}
+
+fn check(response: Response, method: Method, url: &Url) -> Result<(), WebDavError> {
+    let status = response.status();
+    if status.is_success() {
+        Ok(())
+    } else {
+        Err(WebDavError::Status {
+            method,
+            url: url.clone(),
+            status,
+        })
+    }
+}
+
+fn move_method() -> Method {
+    Method::from_bytes(b"MOVE").expect("valid method")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::Router;
+    use axum::body::Body as AxumBody;
+    use axum::extract::State;
+    use axum::http::{HeaderMap, Uri};
+    use axum::response::Response as AxumResponse;
+    use base64::Engine;
+    use jiff::Timestamp;
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    /// A WebDAV server which keeps its files in memory. The first PUT of a
+    /// file only stores half of the data and fails.
+    #[derive(Default)]
+    struct Server {
+        files: Mutex<BTreeMap<String, Vec<u8>>>,
+        requests: Mutex<Vec<String>>,
+        /// Accept a PUT with a Content-Range
+        partial: bool,
+        /// Replace the file with the body of a PUT with a Content-Range
+        ignore_range: bool,
+    }
+
+    async fn handle(
+        State(server): State<Arc<Server>>,
+        method: Method,
+        uri: Uri,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> AxumResponse {
+        let path = uri.path().to_owned();
+        let range = headers
+            .get(CONTENT_RANGE)
+            .map(|range| range.to_str().unwrap());
+        server.requests.lock().unwrap().push(match range {
+            Some(range) => format!("{method} {path} {range}"),
+            None => format!("{method} {path}"),
+        });
+        let credentials = base64::engine::general_purpose::STANDARD.encode("user:app-password");
+        if headers.get("authorization").unwrap() != &format!("Basic {credentials}") {
+            return status(StatusCode::UNAUTHORIZED);
+        }
+        let mut files = server.files.lock().unwrap();
+        match method.as_str() {
+            "MKCOL" if files.contains_key(&path) => status(StatusCode::METHOD_NOT_ALLOWED),
+            "MKCOL" => {
+                files.insert(path, Vec::new());
+                status(StatusCode::CREATED)
+            }
+            "PUT" => match (range, files.get_mut(&path)) {
+                (None, None) => {
+                    files.insert(path, body[..body.len() / 2].to_vec());
+                    status(StatusCode::BAD_GATEWAY)
+                }
+                (None, Some(file)) => {
+                    *file = body.to_vec();
+                    status(StatusCode::NO_CONTENT)
+                }
+                (Some(_), Some(file)) if server.ignore_range => {
+                    *file = body.to_vec();
+                    status(StatusCode::NO_CONTENT)
+                }
+                (Some(_), Some(file)) if server.partial => {
+                    file.extend_from_slice(&body);
+                    status(StatusCode::NO_CONTENT)
+                }
+                (Some(_), _) => status(StatusCode::BAD_REQUEST),
+            },
+            "HEAD" => match files.get(&path) {
+                Some(file) => AxumResponse::builder()
+                    .header(CONTENT_LENGTH, file.len())
+                    .body(AxumBody::empty())
+                    .unwrap(),
+                None => status(StatusCode::NOT_FOUND),
+            },
+            "MOVE" => {
+                let destination = headers.get("destination").unwrap().to_str().unwrap();
+                let destination = Url::parse(destination).unwrap().path().to_owned();
+                if files.contains_key(&destination) {
+                    return status(StatusCode::PRECONDITION_FAILED);
+                }
+                let file = files.remove(&path).unwrap();
+                files.insert(destination, file);
+                status(StatusCode::CREATED)
+            }
+            _ => status(StatusCode::METHOD_NOT_ALLOWED),
+        }
+    }
+
+    fn status(status: StatusCode) -> AxumResponse {
+        AxumResponse::builder()
+            .status(status)
+            .body(AxumBody::empty())
+            .unwrap()
+    }
+
+    async fn start_server(server: Server) -> (Url, Arc<Server>) {
+        let server = Arc::new(server);
+        let app = Router::new().fallback(handle).with_state(server.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/dav/files/user", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (url.parse().unwrap(), server)
+    }
+
+    fn fields() -> NameFields {
+        NameFields {
+            time: Timestamp::from_second(1486905545).unwrap(),
+            profile: "high".to_owned(),
+            source: "adf".to_owned(),
+            color: "gray".to_owned(),
+            resolution: 600,
+            model: "HP ENVY 5530 series".to_owned(),
+        }
+    }
+
+    fn webdav(collection: Url) -> WebDav {
+        WebDav {
+            collection,
+            folders: Some(FolderTemplate::parse("Scans/{time:%Y}").unwrap()),
+            template: None,
+            credentials: Some(("user".to_owned(), "app-password".to_owned())),
+            attempts: 2,
+        }
+    }
+
+    async fn upload(webdav: &WebDav, data: &'static [u8]) -> Result<Url, ScannerError> {
+        // the data arrives in several chunks
+        let chunks = data.chunks(4).map(|chunk| Ok(Bytes::from_static(chunk)));
+        webdav
+            .upload(Content::Scan, "pdf", &fields(), stream::iter(chunks))
+            .await
+    }
+
+    #[tokio::test]
+    async fn resume_failed_upload() {
+        let server = Server {
+            partial: true,
+            ..Server::default()
+        };
+        let (url, server) = start_server(server).await;
+        let webdav = webdav(url);
+        let uploaded = upload(&webdav, b"0123456789").await.unwrap();
+        assert!(
+            uploaded
+                .as_str()
+                .ends_with("/dav/files/user/Scans/2017/scan_20170212_131905.pdf")
+        );
+        let uploaded = upload(&webdav, b"abcdefghij").await.unwrap();
+        assert!(
+            uploaded
+                .as_str()
+                .ends_with("/Scans/2017/scan_20170212_131905_2.pdf")
+        );
+
+        let files = server.files.lock().unwrap();
+        let folder = "/dav/files/user/Scans/2017";
+        assert_eq!(
+            files[&format!("{folder}/scan_20170212_131905.pdf")],
+            b"0123456789"
+        );
+        assert_eq!(
+            files[&format!("{folder}/scan_20170212_131905_2.pdf")],
+            b"abcdefghij"
+        );
+        assert_eq!(files.len(), 4);
+        let requests = server.requests.lock().unwrap();
+        assert_eq!(requests[0], "MKCOL /dav/files/user/Scans/");
+        assert_eq!(requests[1], "MKCOL /dav/files/user/Scans/2017/");
+        let part = "/dav/files/user/Scans/2017/.covet-1486905545000000000-1.part";
+        assert_eq!(requests[2], format!("PUT {part}"));
+        assert_eq!(requests[3], format!("HEAD {part}"));
+        assert_eq!(requests[4], format!("PUT {part} bytes 5-9/10"));
+        assert_eq!(requests[5], format!("HEAD {part}"));
+        assert_eq!(requests[6], format!("MOVE {part}"));
+        assert_eq!(requests[7], "MKCOL /dav/files/user/Scans/");
+    }
+
+    #[tokio::test]
+    async fn repeat_upload_with_ignored_range() {
+        let server = Server {
+            ignore_range: true,
+            ..Server::default()
+        };
+        let (url, server) = start_server(server).await;
+        let mut webdav = webdav(url);
+        webdav.folders = None;
+        upload(&webdav, b"0123456789").await.unwrap();
+        let files = server.files.lock().unwrap();
+        assert_eq!(
+            files["/dav/files/user/scan_20170212_131905.pdf"],
+            b"0123456789"
+        );
+        let requests = server.requests.lock().unwrap();
+        assert!(requests[2].ends_with(".part bytes 5-9/10"));
+        assert!(requests[3].starts_with("HEAD"));
+        assert!(requests[4].starts_with("PUT") && requests[4].ends_with(".part"));
+        assert!(requests[5].starts_with("MOVE"));
+    }
+
+    #[tokio::test]
+    async fn repeat_failed_upload() {
+        let (url, server) = start_server(Server::default()).await;
+        let mut webdav = webdav(url);
+        webdav.folders = None;
+        upload(&webdav, b"0123456789").await.unwrap();
+        let files = server.files.lock().unwrap();
+        assert_eq!(
+            files["/dav/files/user/scan_20170212_131905.pdf"],
+            b"0123456789"
+        );
+        let requests = server.requests.lock().unwrap();
+        assert!(requests[2].ends_with(".part bytes 5-9/10"));
+        assert!(requests[3].starts_with("PUT") && requests[3].ends_with(".part"));
+    }
+
+    #[tokio::test]
+    async fn fail_after_attempts() {
+        let (url, server) = start_server(Server::default()).await;
+        let mut webdav = webdav(url);
+        webdav.attempts = 1;
+        let error = upload(&webdav, b"0123456789").await.unwrap_err();
+        assert!(matches!(
+            error,
+            ScannerError::WebDav(WebDavError::Status {
+                status: StatusCode::BAD_GATEWAY,
+                ..
+            })
+        ));
+        // the incomplete file is removed
+        let requests = server.requests.lock().unwrap();
+        assert!(requests.last().unwrap().starts_with("DELETE"));
+    }
+}
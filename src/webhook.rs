@@ -0,0 +1,572 @@
+use bytes::Bytes;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use jiff::Timestamp;
+use reqwest::Client;
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, error, info, warn};
+use url::Url;
+
+use std::fmt::Write as _;
+use std::io::{self, Write as _};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::crypto::hmac_sha256;
+use crate::scanner::ScannerError;
+
+/// Header with the HMAC-SHA256 of the body, as `sha256=<hex>`
+const SIGNATURE_HEADER: &str = "X-Covet-Signature";
+const EVENT_HEADER: &str = "X-Covet-Event";
+/// Events which could not be delivered beyond this number are dropped, oldest first
+const MAX_QUEUED: usize = 100;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    JobStarted,
+    PageReady,
+    JobCompleted,
+    JobCanceled,
+    JobFailed,
+    ScannerOffline,
+    ScannerOnline,
+}
+
+/// The JSON payload of a webhook
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Event {
+    pub event: EventKind,
+    /// RFC 3339 time at which the event happened
+    pub time: String,
+    /// The host name of the scanner
+    pub scanner: String,
+    /// The URL path of the scan job
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub job: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// An event waiting for another delivery attempt
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Queued {
+    url: String,
+    event: Event,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebhookConfig {
+    pub urls: Vec<Url>,
+    /// Key of the signature header, which is left out without one
+    pub secret: Option<String>,
+    /// File keeping the events which could not be delivered across restarts
+    pub queue: Option<PathBuf>,
+    /// Number of delivery attempts before an event is queued
+    pub attempts: u32,
+    /// Wait after the first failed attempt, doubled after each further one
+    pub backoff: Duration,
+    /// Time between the attempts to send the queued events while no new events arrive
+    pub retry_interval: Duration,
+}
+
+impl WebhookConfig {
+    pub fn new(urls: Vec<Url>, secret: Option<String>, queue: Option<PathBuf>) -> Self {
+        WebhookConfig {
+            urls,
+            secret,
+            queue,
+            attempts: 4,
+            backoff: Duration::from_millis(500),
+            retry_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Sends events to the webhook worker and other receivers. The default
+/// notifier drops them.
+#[derive(Debug, Clone, Default)]
+pub struct Notifier {
+    senders: Vec<UnboundedSender<Event>>,
+    scanner: String,
+}
+
+/// Returns a notifier sending its events to the receiver
+pub fn channel() -> (Notifier, UnboundedReceiver<Event>) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let notifier = Notifier {
+        senders: vec![sender],
+        scanner: String::new(),
+    };
+    (notifier, receiver)
+}
+
+impl Notifier {
+    /// The notifier for events of the scanner with the host name
+    pub fn for_scanner(&self, host: &str) -> Notifier {
+        Notifier {
+            senders: self.senders.clone(),
+            scanner: host.to_owned(),
+        }
+    }
+
+    /// Returns a notifier sending the events to the receivers of both
+    pub fn merge(mut self, other: Notifier) -> Notifier {
+        self.senders.extend(other.senders);
+        self
+    }
+
+    pub fn notify(&self, event: EventKind, job: Option<&str>, error: Option<&ScannerError>) {
+        if self.senders.is_empty() {
+            return;
+        }
+        let event = Event {
+            event,
+            time: Timestamp::now().to_string(),
+            scanner: self.scanner.clone(),
+            job: job.map(str::to_owned),
+            error: error.map(ToString::to_string),
+        };
+        debug!("Event: {event:?}");
+        for sender in &self.senders {
+            if sender.send(event.clone()).is_err() {
+                warn!("The receiver of events is stopped, the event is dropped");
+            }
+        }
+    }
+
+    /// Sends the completed event once the stream has ended, the failed
+    /// event if it returns an error, or the canceled event if it is dropped
+    /// before, like when the client disconnects during the download
+    pub fn notify_end(
+        &self,
+        stream: BoxStream<'static, Result<Bytes, ScannerError>>,
+        job: String,
+    ) -> BoxStream<'static, Result<Bytes, ScannerError>> {
+        let end = JobEnd {
+            events: self.clone(),
+            job: Some(job),
+        };
+        stream::unfold((stream, end), |(mut stream, mut end)| async move {
+            match stream.next().await {
+                Some(item) => {
+                    if let Err(e) = &item {
+                        end.notify(EventKind::JobFailed, Some(e));
+                    }
+                    Some((item, (stream, end)))
+                }
+                None => {
+                    end.notify(EventKind::JobCompleted, None);
+                    None
+                }
+            }
+        })
+        .boxed()
+    }
+}
+
+/// The last event of a job, which is sent only once
+struct JobEnd {
+    events: Notifier,
+    job: Option<String>,
+}
+
+impl JobEnd {
+    fn notify(&mut self, event: EventKind, error: Option<&ScannerError>) {
+        if let Some(job) = self.job.take() {
+            self.events.notify(event, Some(&job), error);
+        }
+    }
+}
+
+impl Drop for JobEnd {
+    fn drop(&mut self) {
+        self.notify(EventKind::JobCanceled, None);
+    }
+}
+
+/// Starts the worker delivering the events in order. It finishes once all
+/// notifiers are dropped and the last event is delivered or queued.
+pub fn start(config: WebhookConfig) -> (Notifier, JoinHandle<()>) {
+    let (notifier, receiver) = channel();
+    let worker = tokio::spawn(deliver_events(config, receiver));
+    (notifier, worker)
+}
+
+async fn deliver_events(config: WebhookConfig, mut receiver: UnboundedReceiver<Event>) {
+    let client = Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .expect("the client has a valid configuration");
+    let mut queue = match &config.queue {
+        Some(path) => load_queue(path).unwrap_or_else(|e| {
+            error!("Cannot read webhook queue {}. {e}", path.display());
+            Vec::new()
+        }),
+        None => Vec::new(),
+    };
+    // the first tick completes at once and retries the loaded queue
+    let mut retries = tokio::time::interval(config.retry_interval);
+    retries.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            event = receiver.recv() => {
+                let Some(event) = event else {
+                    break;
+                };
+                retry_queued(&client, &config, &mut queue).await;
+                for url in &config.urls {
+                    if let Err(e) = deliver(&client, &config, url, &event).await {
+                        error!("Cannot send {:?} event to {url}. {e}", event.event);
+                        queue.push(Queued {
+                            url: url.to_string(),
+                            event: event.clone(),
+                        });
+                    }
+                }
+                if queue.len() > MAX_QUEUED {
+                    let dropped = queue.len() - MAX_QUEUED;
+                    warn!("Dropping {dropped} undelivered webhook events");
+                    queue.drain(..dropped);
+                }
+                save(&config, &queue);
+            }
+            _ = retries.tick() => {
+                if !queue.is_empty() {
+                    retry_queued(&client, &config, &mut queue).await;
+                    save(&config, &queue);
+                }
+            }
+        }
+    }
+}
+
+/// Sends each queued event once. The rest of the events of a webhook stay
+/// queued after a failure, so that a webhook which is down is not retried
+/// over and over.
+async fn retry_queued(client: &Client, config: &WebhookConfig, queue: &mut Vec<Queued>) {
+    if queue.is_empty() {
+        return;
+    }
+    let single = WebhookConfig {
+        attempts: 1,
+        ..config.clone()
+    };
+    let mut failed: Vec<String> = Vec::new();
+    let mut remaining = Vec::new();
+    for queued in queue.drain(..) {
+        if failed.contains(&queued.url) {
+            remaining.push(queued);
+            continue;
+        }
+        let delivered = match Url::parse(&queued.url) {
+            Ok(url) => deliver(client, &single, &url, &queued.event).await.is_ok(),
+            Err(e) => {
+                warn!("Dropping webhook event for invalid URL {}. {e}", queued.url);
+                continue;
+            }
+        };
+        if delivered {
+            info!(
+                "Queued {:?} event sent to {}",
+                queued.event.event, queued.url
+            );
+        } else {
+            failed.push(queued.url.clone());
+            remaining.push(queued);
+        }
+    }
+    *queue = remaining;
+    save(config, queue);
+}
+
+/// Posts the event, retrying with exponential backoff
+async fn deliver(
+    client: &Client,
+    config: &WebhookConfig,
+    url: &Url,
+    event: &Event,
+) -> Result<(), reqwest::Error> {
+    let body = serde_json::to_vec(event).expect("the event serializes to json");
+    let event_name = serde_json::to_value(event.event).expect("the event kind serializes");
+    let mut backoff = config.backoff;
+    let mut attempt = 1;
+    loop {
+        let mut request = client
+            .post(url.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, event_name.as_str().unwrap_or_default())
+            .body(body.clone());
+        if let Some(secret) = &config.secret {
+            request = request.header(SIGNATURE_HEADER, signature(secret.as_bytes(), &body));
+        }
+        let result = request
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status);
+        match result {
+            Ok(_) => return Ok(()),
+            Err(e) if attempt >= config.attempts => return Err(e),
+            Err(e) => debug!("Webhook attempt {attempt} failed, retrying. {e}"),
+        }
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+        attempt += 1;
+    }
+}
+
+/// Returns the value of the signature header
+fn signature(key: &[u8], body: &[u8]) -> String {
+    let mut value = "sha256=".to_owned();
+    for byte in hmac_sha256(key, body) {
+        write!(value, "{byte:02x}").expect("writing to a string cannot fail");
+    }
+    value
+}
+
+fn load_queue(path: &Path) -> Result<Vec<Queued>, io::Error> {
+    let data = match std::fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut queue = Vec::new();
+    for line in data.lines().filter(|line| !line.trim().is_empty()) {
+        match serde_json::from_str(line) {
+            Ok(queued) => queue.push(queued),
+            Err(e) => warn!("Skipping invalid webhook queue entry. {e}"),
+        }
+    }
+    Ok(queue)
+}
+
+fn save(config: &WebhookConfig, queue: &[Queued]) {
+    if let Some(path) = &config.queue {
+        if let Err(e) = save_queue(path, queue) {
+            error!("Cannot write webhook queue {}. {e}", path.display());
+        }
+    }
+}
+
+/// Replaces the queue file, which is removed if the queue is empty
+fn save_queue(path: &Path, queue: &[Queued]) -> Result<(), io::Error> {
+    if queue.is_empty() {
+        return match std::fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
+    }
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    for queued in queue {
+        serde_json::to_writer(&mut file, queued)?;
+        file.write_all(b"\n")?;
+    }
+    file.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::Router;
+    use axum::extract::State;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::post;
+    use std::sync::{Arc, Mutex};
+
+    /// A webhook receiver which fails the first requests
+    #[derive(Default)]
+    struct Receiver {
+        failures: Mutex<u32>,
+        requests: Mutex<Vec<(HeaderMap, Vec<u8>)>>,
+    }
+
+    async fn receive(
+        State(receiver): State<Arc<Receiver>>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> StatusCode {
+        let mut failures = receiver.failures.lock().unwrap();
+        if *failures > 0 {
+            *failures -= 1;
+            return StatusCode::SERVICE_UNAVAILABLE;
+        }
+        receiver
+            .requests
+            .lock()
+            .unwrap()
+            .push((headers, body.to_vec()));
+        StatusCode::NO_CONTENT
+    }
+
+    async fn start_receiver(failures: u32) -> (Url, Arc<Receiver>) {
+        let receiver = Arc::new(Receiver {
+            failures: Mutex::new(failures),
+            ..Receiver::default()
+        });
+        let app = Router::new()
+            .route("/hook", post(receive))
+            .with_state(receiver.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (url.parse().unwrap(), receiver)
+    }
+
+    fn config(url: Url, queue: Option<PathBuf>) -> WebhookConfig {
+        WebhookConfig {
+            attempts: 3,
+            backoff: Duration::from_millis(10),
+            ..WebhookConfig::new(vec![url], Some("secret".to_owned()), queue)
+        }
+    }
+
+    async fn wait_for_requests(receiver: &Receiver, count: usize) {
+        for _ in 0..100 {
+            if receiver.requests.lock().unwrap().len() >= count {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("the requests did not arrive");
+    }
+
+    fn events(receiver: &Receiver) -> Vec<Event> {
+        let requests = receiver.requests.lock().unwrap();
+        requests
+            .iter()
+            .map(|(_, body)| serde_json::from_slice(body).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn compute_hmac() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            signature(b"Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        // keys longer than a block are hashed, RFC 4231 test case 6
+        assert_eq!(
+            signature(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            ),
+            "sha256=60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[tokio::test]
+    async fn send_signed_events_with_retries() {
+        let (url, receiver) = start_receiver(2).await;
+        let (unnamed, worker) = start(config(url, None));
+        let notifier = unnamed.for_scanner("envy");
+        drop(unnamed);
+        notifier.notify(EventKind::JobStarted, Some("/Scan/Jobs/1"), None);
+        notifier.notify(EventKind::JobCanceled, Some("/Scan/Jobs/1"), None);
+        drop(notifier);
+        worker.await.unwrap();
+        let events = events(&receiver);
+        let kinds: Vec<_> = events.iter().map(|event| event.event).collect();
+        assert_eq!(kinds, [EventKind::JobStarted, EventKind::JobCanceled]);
+        assert_eq!(events[0].scanner, "envy");
+        assert_eq!(events[0].job.as_deref(), Some("/Scan/Jobs/1"));
+        let requests = receiver.requests.lock().unwrap();
+        let (headers, body) = &requests[0];
+        assert_eq!(headers[EVENT_HEADER], "job_started");
+        assert_eq!(headers[SIGNATURE_HEADER], signature(b"secret", body));
+    }
+
+    #[tokio::test]
+    async fn notify_end_of_stream() {
+        let (url, receiver) = start_receiver(0).await;
+        let (notifier, worker) = start(config(url, None));
+        let data = stream::iter([Ok(Bytes::from_static(b"scan"))]).boxed();
+        let collected: Vec<_> = notifier
+            .notify_end(data, "/Scan/Jobs/2".into())
+            .collect()
+            .await;
+        assert_eq!(collected.len(), 1);
+        let data = stream::iter([Err(ScannerError::Canceled)]).boxed();
+        let _: Vec<_> = notifier
+            .notify_end(data, "/Scan/Jobs/3".into())
+            .take(1)
+            .collect()
+            .await;
+        drop(notifier);
+        worker.await.unwrap();
+        let kinds: Vec<_> = events(&receiver).iter().map(|event| event.event).collect();
+        assert_eq!(kinds, [EventKind::JobCompleted, EventKind::JobFailed]);
+    }
+
+    #[tokio::test]
+    async fn notify_cancel_of_dropped_stream() {
+        let (notifier, mut receiver) = channel();
+        let data = stream::iter([Ok(Bytes::from_static(b"page")), Ok(Bytes::new())]).boxed();
+        let mut stream = notifier.notify_end(data, "/Scan/Jobs/4".into());
+        stream.next().await.unwrap().unwrap();
+        assert!(receiver.try_recv().is_err());
+        drop(stream);
+        let event = receiver.try_recv().unwrap();
+        assert_eq!(event.event, EventKind::JobCanceled);
+        assert_eq!(event.job.as_deref(), Some("/Scan/Jobs/4"));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn queue_undelivered_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = dir.path().join("queue.jsonl");
+        // nothing listens on the port anymore
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let down: Url = format!("http://{}/hook", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        drop(listener);
+        let (notifier, worker) = start(config(down.clone(), Some(queue.clone())));
+        notifier.notify(EventKind::ScannerOffline, None, None);
+        drop(notifier);
+        worker.await.unwrap();
+        let queued = load_queue(&queue).unwrap();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].url, down.as_str());
+
+        // the queued event is sent first once the webhook is up again
+        let (url, receiver) = start_receiver(0).await;
+        let mut contents = std::fs::read_to_string(&queue).unwrap();
+        contents = contents.replace(down.as_str(), url.as_str());
+        std::fs::write(&queue, contents).unwrap();
+        let (notifier, worker) = start(config(url, Some(queue.clone())));
+        notifier.notify(EventKind::ScannerOnline, None, None);
+        drop(notifier);
+        worker.await.unwrap();
+        let kinds: Vec<_> = events(&receiver).iter().map(|event| event.event).collect();
+        assert_eq!(kinds, [EventKind::ScannerOffline, EventKind::ScannerOnline]);
+        assert!(!queue.exists());
+    }
+
+    #[tokio::test]
+    async fn retry_queued_events_without_new_ones() {
+        // all attempts of the first delivery fail
+        let (url, receiver) = start_receiver(3).await;
+        let config = WebhookConfig {
+            retry_interval: Duration::from_millis(100),
+            ..config(url, None)
+        };
+        let (notifier, worker) = start(config);
+        notifier.notify(EventKind::ScannerOffline, None, None);
+        wait_for_requests(&receiver, 1).await;
+        let kinds: Vec<_> = events(&receiver).iter().map(|event| event.event).collect();
+        assert_eq!(kinds, [EventKind::ScannerOffline]);
+        drop(notifier);
+        worker.await.unwrap();
+    }
+}